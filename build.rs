@@ -1,3 +1,9 @@
 fn main() -> std::io::Result<()> {
-    prost_build::compile_protos(&["./prometheus.proto"], &["./"])
+    // without `gen-proto` the vendored code in src/generated is used
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "gen-proto")]
+    prost_build::compile_protos(&["./prometheus.proto", "./write_v2.proto"], &["./"])?;
+
+    Ok(())
 }
@@ -0,0 +1,236 @@
+use crate::batcher::MetricOperation;
+use crate::registry::KeyMap;
+use metrics::Key;
+use std::sync::Arc;
+use std::sync::PoisonError;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// Set in [`AtomicCounter::updated`] after an increment.
+const INCREMENTED: u8 = 1;
+
+/// Set in [`AtomicCounter::updated`] after an absolute value was set.
+const SET: u8 = 2;
+
+/// Counters and gauges updated in place by their handles, read by the worker
+/// at each write instead of receiving every operation.
+#[derive(Debug, Default)]
+pub struct Atomics {
+    counters: RwLock<KeyMap<Arc<AtomicCounter>>>,
+    gauges: RwLock<KeyMap<Arc<AtomicGauge>>>,
+}
+
+impl Atomics {
+    /// The counter `key`, created if new.
+    pub fn counter(&self, key: &Key) -> Arc<AtomicCounter> {
+        get_or_insert(&self.counters, key)
+    }
+
+    /// The gauge `key`, created if new.
+    pub fn gauge(&self, key: &Key) -> Arc<AtomicGauge> {
+        get_or_insert(&self.gauges, key)
+    }
+
+    /// Operations bringing the registry up to date with every series
+    /// updated since the last collection.
+    ///
+    /// Slots no handle holds any more are dropped once collected, as handles
+    /// are only handed out under the lock.
+    pub fn collect(&self) -> Vec<(Key, MetricOperation)> {
+        let mut operations = vec![];
+
+        let mut counters = self
+            .counters
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        counters.retain(|key, counter| {
+            let updated = counter.updated.swap(0, Ordering::Relaxed);
+            if updated & SET != 0 {
+                let value = counter.absolute.load(Ordering::Relaxed);
+                operations.push((key.clone(), MetricOperation::SetCounter(value)));
+            }
+            // only increments since the value was last set are kept
+            if updated & INCREMENTED != 0 {
+                let value = counter.increments.swap(0, Ordering::Relaxed);
+                if value != 0 {
+                    operations.push((key.clone(), MetricOperation::IncrementCounter(value)));
+                }
+            }
+            Arc::strong_count(counter) > 1
+        });
+        drop(counters);
+
+        let mut gauges = self.gauges.write().unwrap_or_else(PoisonError::into_inner);
+        gauges.retain(|key, gauge| {
+            if gauge.updated.swap(false, Ordering::Relaxed) {
+                let value = f64::from_bits(gauge.value.load(Ordering::Relaxed));
+                operations.push((key.clone(), MetricOperation::SetGauge(value)));
+            }
+            Arc::strong_count(gauge) > 1
+        });
+
+        operations
+    }
+
+    /// Discard updates to the counter or gauge `key` not yet collected, so
+    /// handles recording to it again start it afresh. The slot itself is
+    /// dropped at the next collection once no handle holds it.
+    pub fn remove(&self, key: &Key) {
+        let counters = self.counters.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(counter) = counters.get(key) {
+            counter.updated.store(0, Ordering::Relaxed);
+            counter.increments.store(0, Ordering::Relaxed);
+        }
+        drop(counters);
+
+        let gauges = self.gauges.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(gauge) = gauges.get(key) {
+            gauge.updated.store(false, Ordering::Relaxed);
+            gauge.value.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Look up `key`, only taking the write lock to insert it.
+fn get_or_insert<T: Default>(map: &RwLock<KeyMap<Arc<T>>>, key: &Key) -> Arc<T> {
+    if let Some(value) = map.read().unwrap_or_else(PoisonError::into_inner).get(key) {
+        return value.clone();
+    }
+
+    map.write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(key.clone())
+        .or_default()
+        .clone()
+}
+
+/// A counter's absolute value and the increments since it was set, as
+/// updated since last collected.
+#[derive(Debug, Default)]
+pub struct AtomicCounter {
+    increments: AtomicU64,
+    absolute: AtomicU64,
+    /// Which of the values were updated, flagged after updating them.
+    updated: AtomicU8,
+}
+
+impl metrics::CounterFn for AtomicCounter {
+    fn increment(&self, value: u64) {
+        self.increments.fetch_add(value, Ordering::Relaxed);
+        self.updated.fetch_or(INCREMENTED, Ordering::Relaxed);
+    }
+
+    fn absolute(&self, value: u64) {
+        // earlier increments are superseded by the new value
+        self.absolute.store(value, Ordering::Relaxed);
+        self.increments.store(0, Ordering::Relaxed);
+        self.updated.fetch_or(SET, Ordering::Relaxed);
+    }
+}
+
+/// A gauge's value, stored as the bits of an `f64`.
+#[derive(Debug, Default)]
+pub struct AtomicGauge {
+    value: AtomicU64,
+    updated: AtomicBool,
+}
+
+impl AtomicGauge {
+    fn update(&self, f: impl Fn(f64) -> f64) {
+        let _ = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some(f(f64::from_bits(bits)).to_bits())
+            });
+        self.updated.store(true, Ordering::Relaxed);
+    }
+}
+
+impl metrics::GaugeFn for AtomicGauge {
+    fn increment(&self, value: f64) {
+        self.update(|current| current + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|current| current - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.value.store(value.to_bits(), Ordering::Relaxed);
+        self.updated.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::CounterFn;
+    use metrics::GaugeFn;
+
+    #[test]
+    fn updates_collected_once() {
+        let atomics = Atomics::default();
+        let requests = Key::from_name("requests");
+        let temperature = Key::from_name("temperature");
+
+        let counter = atomics.counter(&requests);
+        counter.increment(2);
+        atomics.counter(&requests).increment(3);
+        let gauge = atomics.gauge(&temperature);
+        gauge.set(20.0);
+        gauge.increment(1.5);
+
+        let mut operations = atomics.collect();
+        operations.sort_by(|a, b| a.0.cmp(&b.0));
+        assert!(matches!(
+            operations.as_slice(),
+            [
+                (_, MetricOperation::IncrementCounter(5)),
+                (_, MetricOperation::SetGauge(21.5)),
+            ]
+        ));
+        assert!(atomics.collect().is_empty());
+
+        counter.absolute(10);
+        counter.increment(1);
+        assert!(matches!(
+            atomics.collect().as_slice(),
+            [
+                (_, MetricOperation::SetCounter(10)),
+                (_, MetricOperation::IncrementCounter(1)),
+            ]
+        ));
+
+        // increments before a set are superseded by it
+        counter.increment(4);
+        counter.absolute(20);
+        assert!(matches!(
+            atomics.collect().as_slice(),
+            [(_, MetricOperation::SetCounter(20))]
+        ));
+
+        counter.increment(0);
+        assert!(atomics.collect().is_empty());
+    }
+
+    #[test]
+    fn slots_cleared_and_dropped() {
+        let atomics = Atomics::default();
+        let requests = Key::from_name("requests");
+
+        let counter = atomics.counter(&requests);
+        counter.increment(2);
+        atomics.remove(&requests);
+        assert!(atomics.collect().is_empty());
+
+        // kept while a handle holds it, dropped after
+        counter.increment(1);
+        assert_eq!(atomics.collect().len(), 1);
+        drop(counter);
+        atomics.collect();
+        assert!(atomics.counters.read().unwrap().is_empty());
+    }
+}
@@ -1,22 +1,98 @@
+use crate::atomics::Atomics;
+use crate::clock::Clock;
+use crate::clock::CoarseClock;
+use crate::clock::SharedClock;
+use crate::compression::Compression;
+use crate::counter_state;
+use crate::delivery::Delivery;
+use crate::delivery::Outputs;
+use crate::error::BuildError;
+use crate::exposition;
+use crate::fanout::Fanout;
+use crate::file_sink::FileSink;
+use crate::filter::Filter;
+use crate::filter::glob_match;
+use crate::handle::FlushGuard;
+use crate::handle::Handle;
+use crate::histogram::Distribution;
+use crate::histogram::HistogramMode;
+use crate::interner::Interner;
+use crate::interner::KeyId;
+use crate::interner::SeriesKey;
+use crate::label_cache::LabelCache;
+use crate::names::NameSanitization;
+use crate::names::NameValidation;
+use crate::outcome::OnResult;
+use crate::outcome::WriteOutcome;
+use crate::protocol;
+use crate::protocol::CreatedSeries;
+use crate::protocol::Protocol;
+use crate::queue_config::QueueConfig;
+use crate::registry::CounterReset;
+use crate::registry::GaugeAggregation;
+use crate::registry::KeyMap;
 use crate::registry::Registry;
+use crate::registry::SampleEviction;
+use crate::registry::Samples;
+use crate::registry::sorted;
+use crate::remote::Remote;
+use crate::remote::Stats;
+use crate::retry::RetryPolicy;
+use crate::sink::SharedSink;
+use crate::sink::Sink;
+#[cfg(feature = "opentelemetry")]
+use crate::span_exemplar;
+use crate::status::Health;
+use crate::summary::Window;
+use crate::thread_buffer::Buffered;
+use crate::thread_buffer::ThreadBuffers;
+use crate::throttle::LogThrottle;
+use crate::transport::SharedTransport;
+use crate::transport::Transport;
 use crate::types;
 use crossbeam::channel::Receiver;
+use crossbeam::channel::Select;
+use crossbeam::channel::SendTimeoutError;
 use crossbeam::channel::Sender;
-use crossbeam::channel::select;
+use crossbeam::channel::TryRecvError;
+use crossbeam::channel::TrySendError;
+use crossbeam::queue::ArrayQueue;
+use crossbeam::utils::Backoff;
 use metrics::Key;
 use metrics::KeyName;
 use metrics::Recorder;
-use metrics::SetRecorderError;
 use metrics::SharedString;
 use metrics::Unit;
 use prost::Message;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+#[cfg(feature = "scrape")]
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
 use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 use tracing::debug;
+use tracing::debug_span;
 use tracing::error;
+use tracing::warn;
 use types::metric_metadata::MetricType;
 
+/// Sample value marking a series as stale, ending it on the receiver.
+const STALE_NAN: f64 = f64::from_bits(0x7ff0000000000002);
+
 #[derive(Debug)]
 pub enum MetricOperation {
     IncrementCounter(u64),
@@ -24,35 +100,249 @@ pub enum MetricOperation {
     IncrementGauge(f64),
     DecrementGauge(f64),
     SetGauge(f64),
+    RecordHistogram(f64),
+    /// Stop sending the series, marking it stale.
+    Remove,
+    /// Attach an exemplar with the given labels to the series.
+    Exemplar(f64, Vec<types::Label>),
 }
 
 #[derive(Debug)]
 pub enum Command {
-    #[allow(dead_code)]
     Metadata(KeyName, MetricType, Option<Unit>, SharedString),
     Operation(SystemTime, Key, MetricOperation),
+    /// An operation on the series of a key registered with the interner.
+    Interned(SystemTime, KeyId, MetricOperation),
+    /// Operations buffered by a recording thread.
+    Batch(Vec<Buffered>),
+    /// Resend dead-lettered batches, replying with the number delivered.
+    ReplayDeadLetters(Sender<usize>),
+    /// Write everything immediately, replying whether it was delivered.
+    Flush(Sender<bool>),
+    /// Reply with the latest value of every series in the text format.
+    Render(Sender<String>),
+    /// Write historical samples, replying whether they were delivered.
+    Backfill(Vec<(Key, SystemTime, f64)>, Sender<bool>),
+    /// Resource usage of the process, recorded under the standard names.
+    #[cfg(feature = "process-metrics")]
+    Process(SystemTime, Vec<crate::process::Sample>),
+    /// Write everything queued and stop the worker, replying once done.
+    Shutdown(Sender<()>),
+}
+
+impl Command {
+    /// Whether this is an operation on series, queued on the recording
+    /// thread's shard.
+    fn is_operation(&self) -> bool {
+        matches!(
+            self,
+            Command::Operation(..) | Command::Interned(..) | Command::Batch(_)
+        )
+    }
+
+    /// Number of operations lost if this command is dropped, counting each
+    /// of a batch.
+    fn dropped_count(&self) -> u64 {
+        match self {
+            Command::Batch(operations) => operations.len() as u64,
+            _ => 1,
+        }
+    }
+}
+
+/// Lock-free queues of operations, one per shard.
+type OperationQueues = Arc<[ArrayQueue<Command>]>;
+
+/// How often the worker drains the lock-free queues.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Index of the next thread to send an operation.
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Index of the current thread, choosing its command shard.
+    static THREAD_INDEX: usize = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
 }
 
 /// Builder for the [`Batcher`].
 #[derive(Debug, Clone)]
 pub struct Builder {
-    endpoint: String,
-    batch_interval: Duration,
+    pub(crate) endpoints: Vec<String>,
+    pub(crate) batch_interval: Duration,
+    pub(crate) global_labels: Vec<(String, String)>,
+    pub(crate) prefix: String,
+    pub(crate) min_level: metrics::Level,
+    pub(crate) targets: Vec<String>,
+    pub(crate) allow_metrics: Vec<String>,
+    pub(crate) deny_metrics: Vec<String>,
+    pub(crate) compression: Compression,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) write_timeout: Duration,
+    pub(crate) backfill_interval: Duration,
+    pub(crate) max_buffered_samples: usize,
+    pub(crate) sample_age_limit: Option<Duration>,
+    pub(crate) max_series_samples: Option<usize>,
+    pub(crate) max_registry_samples: Option<usize>,
+    pub(crate) sample_eviction: SampleEviction,
+    pub(crate) max_request_bytes: Option<usize>,
+    pub(crate) max_samples_per_send: Option<usize>,
+    pub(crate) max_in_flight: usize,
+    pub(crate) queue_shards: (usize, usize),
+    pub(crate) encode_threads: usize,
+    pub(crate) max_redirects: u32,
+    pub(crate) fail_fast: bool,
+    pub(crate) wal_dir: Option<PathBuf>,
+    pub(crate) dead_letter_dir: Option<PathBuf>,
+    pub(crate) circuit_breaker: Option<(u32, Duration)>,
+    pub(crate) queue_capacity: Option<usize>,
+    pub(crate) command_shards: usize,
+    pub(crate) lock_free_queue: Option<usize>,
+    pub(crate) pipelined_sends: bool,
+    pub(crate) overflow: OverflowPolicy,
+    pub(crate) worker_name: String,
+    pub(crate) worker_stack_size: Option<usize>,
+    #[cfg(feature = "affinity")]
+    pub(crate) worker_core: Option<usize>,
+    pub(crate) flush_on_panic: Option<Duration>,
+    pub(crate) self_metrics: bool,
+    pub(crate) created_timestamps: bool,
+    pub(crate) counter_reset: CounterReset,
+    pub(crate) counter_state: Option<PathBuf>,
+    pub(crate) heartbeat: Option<Duration>,
+    pub(crate) flush_intervals: Vec<(String, Duration)>,
+    pub(crate) clock: SharedClock,
+    pub(crate) timestamp_resolution: Option<Duration>,
+    pub(crate) aligned_timestamps: bool,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) idle_stale_markers: bool,
+    pub(crate) on_result: Option<OnResult>,
+    pub(crate) file_sink: Option<PathBuf>,
+    pub(crate) sinks: Vec<SharedSink>,
+    pub(crate) transport: Option<SharedTransport>,
+    pub(crate) root_certificates: Option<Vec<u8>>,
+    pub(crate) protocol: Protocol,
+    pub(crate) raw_samples: bool,
+    pub(crate) send_on_change: bool,
+    pub(crate) atomic_handles: bool,
+    pub(crate) thread_batching: Option<Duration>,
+    pub(crate) intern_keys: bool,
+    pub(crate) gauge_aggregation: GaugeAggregation,
+    pub(crate) gauge_aggregations: HashMap<String, GaugeAggregation>,
+    pub(crate) histogram_mode: HistogramMode,
+    pub(crate) histogram_modes: HashMap<String, HistogramMode>,
+    pub(crate) histogram_schema: i32,
+    pub(crate) summary_window: Option<Duration>,
+    pub(crate) summary_age_buckets: u32,
+    pub(crate) name_validation: NameValidation,
+    pub(crate) name_sanitization: NameSanitization,
+    pub(crate) unit_suffixes: bool,
+    pub(crate) total_suffix: bool,
+    #[cfg(feature = "opentelemetry")]
+    pub(crate) span_exemplars: Option<f64>,
+    #[cfg(feature = "scrape")]
+    pub(crate) scrape_endpoint: Option<SocketAddr>,
+    #[cfg(feature = "process-metrics")]
+    pub(crate) process_metrics: Option<Duration>,
 }
 
 impl Builder {
     fn new() -> Self {
         Self {
-            endpoint: "http://localhost:9090/api/v1/write".to_owned(),
+            endpoints: vec!["http://localhost:9090/api/v1/write".to_owned()],
             batch_interval: Duration::from_millis(100),
+            global_labels: vec![],
+            prefix: String::new(),
+            min_level: metrics::Level::TRACE,
+            targets: vec![],
+            allow_metrics: vec![],
+            deny_metrics: vec![],
+            compression: Compression::default(),
+            retry: RetryPolicy::default(),
+            write_timeout: Duration::from_millis(100),
+            backfill_interval: Duration::from_millis(100),
+            max_buffered_samples: 100_000,
+            sample_age_limit: None,
+            max_series_samples: None,
+            max_registry_samples: None,
+            sample_eviction: SampleEviction::default(),
+            max_request_bytes: None,
+            max_samples_per_send: None,
+            max_in_flight: 1,
+            queue_shards: (1, 1),
+            encode_threads: 1,
+            max_redirects: 0,
+            fail_fast: false,
+            wal_dir: None,
+            dead_letter_dir: None,
+            circuit_breaker: None,
+            queue_capacity: None,
+            command_shards: 1,
+            lock_free_queue: None,
+            pipelined_sends: false,
+            overflow: OverflowPolicy::default(),
+            worker_name: "prom-write-worker".to_owned(),
+            worker_stack_size: None,
+            #[cfg(feature = "affinity")]
+            worker_core: None,
+            flush_on_panic: None,
+            self_metrics: false,
+            created_timestamps: false,
+            counter_reset: CounterReset::default(),
+            counter_state: None,
+            heartbeat: None,
+            flush_intervals: vec![],
+            clock: SharedClock::default(),
+            timestamp_resolution: None,
+            aligned_timestamps: false,
+            idle_timeout: None,
+            idle_stale_markers: true,
+            on_result: None,
+            file_sink: None,
+            sinks: vec![],
+            transport: None,
+            root_certificates: None,
+            protocol: Protocol::default(),
+            raw_samples: false,
+            send_on_change: false,
+            atomic_handles: false,
+            thread_batching: None,
+            intern_keys: false,
+            gauge_aggregation: GaugeAggregation::default(),
+            gauge_aggregations: HashMap::new(),
+            histogram_mode: HistogramMode::default(),
+            histogram_modes: HashMap::new(),
+            histogram_schema: 3,
+            summary_window: None,
+            summary_age_buckets: 5,
+            name_validation: NameValidation::default(),
+            name_sanitization: NameSanitization::default(),
+            unit_suffixes: false,
+            total_suffix: false,
+            #[cfg(feature = "opentelemetry")]
+            span_exemplars: None,
+            #[cfg(feature = "scrape")]
+            scrape_endpoint: None,
+            #[cfg(feature = "process-metrics")]
+            process_metrics: None,
         }
     }
 
     /// Prometheus Endpoint URI
     ///
-    /// Default is `http://localhost:9090/api/v1/write`.
+    /// Calling it again replaces the endpoint, use [`remote`](Self::remote)
+    /// to write to more than one. Default is
+    /// `http://localhost:9090/api/v1/write`.
     pub fn endpoint(mut self, uri: impl Into<String>) -> Self {
-        self.endpoint = uri.into();
+        self.endpoints[0] = uri.into();
+        self
+    }
+
+    /// Add another endpoint that receives every batch, e.g. a hosted
+    /// Prometheus alongside a local one.
+    ///
+    /// Each endpoint retries, buffers and persists independently.
+    pub fn remote(mut self, uri: impl Into<String>) -> Self {
+        self.endpoints.push(uri.into());
         self
     }
 
@@ -64,279 +354,5114 @@ impl Builder {
         self
     }
 
-    /// Set the global recorder
-    pub fn install(self) -> Result<(), SetRecorderError<Batcher>> {
-        let (tx_cmds, rx_cmd) = crossbeam::channel::unbounded();
+    /// Send metrics whose name, before any prefix, matches `pattern` every
+    /// `interval` rather than every [`batch_interval`](Self::batch_interval),
+    /// e.g. to push a heartbeat gauge every second but bulk counters only
+    /// every 30 seconds. `*` in the pattern matches any run of characters.
+    ///
+    /// The first matching pattern applies. Intervals are rounded to the
+    /// shortest interval configured. Default is none.
+    pub fn flush_interval_for(mut self, pattern: impl Into<String>, interval: Duration) -> Self {
+        self.flush_intervals.push((pattern.into(), interval));
+        self
+    }
 
-        std::thread::spawn(move || batch_worker(rx_cmd, self.endpoint, self.batch_interval));
+    /// Interval the worker wakes up at to write, the shortest of the batch
+    /// and flush intervals.
+    pub(crate) fn tick_interval(&self) -> Duration {
+        self.flush_intervals
+            .iter()
+            .map(|(_, interval)| *interval)
+            .fold(self.batch_interval, Duration::min)
+    }
 
-        metrics::set_global_recorder(Batcher {
-            inner: Arc::new(BatcherInner { tx_cmds }),
-        })
+    /// Index of the flush interval applying to the metric `name`, or the
+    /// number of overrides for the batch interval.
+    fn flush_group(&self, name: &str) -> usize {
+        self.flush_intervals
+            .iter()
+            .position(|(pattern, _)| glob_match(pattern, name))
+            .unwrap_or(self.flush_intervals.len())
     }
-}
 
-/// Batch sample sender.
-pub struct Batcher {
-    inner: Arc<BatcherInner>,
-}
+    /// Add a label that is appended to every series.
+    ///
+    /// Equivalent to Prometheus `external_labels`, e.g. `env=prod`.
+    pub fn global_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.global_labels.push((key.into(), value.into()));
+        self
+    }
 
-impl Batcher {
-    /// See [`Builder`].
-    pub fn builder() -> Builder {
-        Builder::new()
+    /// Add `job` and `instance` labels to every series.
+    ///
+    /// `instance` is set to `hostname:pid` so replicas pushing to the same
+    /// endpoint remain distinguishable.
+    pub fn instance_labels(self, job: impl Into<String>) -> Self {
+        let instance = format!(
+            "{}:{}",
+            gethostname::gethostname().to_string_lossy(),
+            std::process::id()
+        );
+
+        self.global_label("job", job)
+            .global_label("instance", instance)
     }
 
-    /// Send a command to the worker thread.
-    fn send(&self, command: Command) {
-        self.inner.send(command);
+    /// Add Kubernetes `pod`, `namespace` and `node` labels to every series.
+    ///
+    /// Values are read from the `POD_NAME`, `POD_NAMESPACE` (or `NAMESPACE`)
+    /// and `NODE_NAME` environment variables, typically populated through the
+    /// downward API. Unset variables are skipped.
+    pub fn kubernetes_labels(self) -> Self {
+        self.kubernetes_labels_from(|name| std::env::var(name).ok())
     }
-}
 
-impl Recorder for Batcher {
-    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, desc: SharedString) {
-        self.send(Command::Metadata(key, MetricType::Counter, unit, desc));
+    /// Add the Kubernetes labels with values from `var`.
+    fn kubernetes_labels_from(mut self, var: impl Fn(&str) -> Option<String>) -> Self {
+        let vars: [(&str, &[&str]); 3] = [
+            ("pod", &["POD_NAME"]),
+            ("namespace", &["POD_NAMESPACE", "NAMESPACE"]),
+            ("node", &["NODE_NAME"]),
+        ];
+
+        for (label, names) in vars {
+            if let Some(value) = names.iter().find_map(|name| var(name)) {
+                self = self.global_label(label, value);
+            }
+        }
+
+        self
     }
 
-    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, desc: SharedString) {
-        self.send(Command::Metadata(key, MetricType::Gauge, unit, desc));
+    /// Prepend a namespace to every metric name, e.g. `myapp_`.
+    ///
+    /// Default is no prefix.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
     }
 
-    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _desc: SharedString) {
-        unimplemented!("Histogram not yet supported.")
+    /// Ignore metrics registered below `level`, e.g. `DEBUG` metrics in
+    /// production. Ignored metrics cost nothing to record.
+    ///
+    /// Default is [`Level::TRACE`](metrics::Level::TRACE), i.e. every metric.
+    pub fn min_level(mut self, level: metrics::Level) -> Self {
+        self.min_level = level;
+        self
     }
 
-    fn register_counter(&self, key: &Key, _meta: &metrics::Metadata<'_>) -> metrics::Counter {
-        metrics::Counter::from_arc(Arc::new(Counter {
-            key: key.clone(),
-            inner: self.inner.clone(),
-        }))
+    /// Only record metrics whose target is `target` or one of its
+    /// submodules, e.g. `my_app` for `my_app::db`. Can be called multiple
+    /// times to allow several targets. The `metrics` macros default the
+    /// target to the module path.
+    ///
+    /// Default is every target.
+    pub fn allow_target(mut self, target: impl Into<String>) -> Self {
+        self.targets.push(target.into());
+        self
     }
 
-    fn register_gauge(&self, key: &Key, _meta: &metrics::Metadata<'_>) -> metrics::Gauge {
-        metrics::Gauge::from_arc(Arc::new(Gauge {
-            key: key.clone(),
-            inner: self.inner.clone(),
-        }))
+    /// Only record metrics whose name, before any prefix, matches
+    /// `pattern`, in which `*` matches any characters, e.g. `http_*`. Can be
+    /// called multiple times to allow several patterns.
+    ///
+    /// Default is every name.
+    pub fn allow_metric(mut self, pattern: impl Into<String>) -> Self {
+        self.allow_metrics.push(pattern.into());
+        self
     }
 
-    fn register_histogram(&self, _key: &Key, _meta: &metrics::Metadata<'_>) -> metrics::Histogram {
-        unimplemented!("Histogram not yet supported.")
+    /// Ignore metrics whose name, before any prefix, matches `pattern`, even
+    /// if allowed by [`allow_metric`](Self::allow_metric), e.g. the metrics
+    /// of a noisy library. Ignored metrics cost nothing to record.
+    ///
+    /// Default is no pattern.
+    pub fn deny_metric(mut self, pattern: impl Into<String>) -> Self {
+        self.deny_metrics.push(pattern.into());
+        self
     }
-}
 
-pub struct Counter {
-    key: Key,
-    inner: Arc<BatcherInner>,
-}
+    /// Characters allowed in metric and label names, including those of
+    /// [`global_label`](Self::global_label).
+    ///
+    /// Default is [`NameValidation::Legacy`].
+    pub fn name_validation(mut self, validation: NameValidation) -> Self {
+        self.name_validation = validation;
+        self
+    }
 
-impl metrics::CounterFn for Counter {
-    fn increment(&self, value: u64) {
-        self.inner.send(Command::Operation(
-            SystemTime::now(),
-            self.key.clone(),
-            MetricOperation::IncrementCounter(value),
-        ));
+    /// How names invalid under [`NameValidation::Legacy`] are made valid,
+    /// rather than having the receiver reject the whole request.
+    ///
+    /// Default is [`NameSanitization::Underscores`].
+    pub fn name_sanitization(mut self, sanitization: NameSanitization) -> Self {
+        self.name_sanitization = sanitization;
+        self
     }
 
-    fn absolute(&self, value: u64) {
-        self.inner.send(Command::Operation(
-            SystemTime::now(),
-            self.key.clone(),
-            MetricOperation::SetCounter(value),
-        ));
+    /// Append the unit given to `describe_*`, e.g. `_seconds` or `_bytes`,
+    /// to metric names not already ending with it, following Prometheus
+    /// naming conventions.
+    ///
+    /// Metrics described after they are first recorded are renamed from then
+    /// on. Default is disabled.
+    pub fn unit_suffixes(mut self, enabled: bool) -> Self {
+        self.unit_suffixes = enabled;
+        self
     }
-}
 
-pub struct Gauge {
-    key: Key,
-    inner: Arc<BatcherInner>,
-}
+    /// Append `_total` to counter names not already ending with it, as
+    /// Prometheus naming conventions expect.
+    ///
+    /// Default is disabled.
+    pub fn total_suffix(mut self, enabled: bool) -> Self {
+        self.total_suffix = enabled;
+        self
+    }
 
-impl metrics::GaugeFn for Gauge {
-    fn increment(&self, value: f64) {
-        self.inner.send(Command::Operation(
-            SystemTime::now(),
-            self.key.clone(),
-            MetricOperation::IncrementGauge(value),
-        ));
+    /// Compression applied to the request body.
+    ///
+    /// Zstandard compression needs [`Protocol::V2`].
+    ///
+    /// Default is [`Compression::Snappy`].
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
     }
 
-    fn decrement(&self, value: f64) {
-        self.inner.send(Command::Operation(
-            SystemTime::now(),
-            self.key.clone(),
-            MetricOperation::DecrementGauge(value),
-        ));
+    /// Remote write protocol version sent to the endpoints.
+    ///
+    /// Default is [`Protocol::V1`].
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
     }
 
-    fn set(&self, value: f64) {
-        self.inner.send(Command::Operation(
-            SystemTime::now(),
-            self.key.clone(),
-            MetricOperation::SetGauge(value),
-        ));
+    /// Send every increment and set as its own sample, rather than
+    /// coalescing the operations on a series within the same millisecond,
+    /// for receivers accepting duplicate or out-of-order timestamps.
+    ///
+    /// Default is disabled.
+    pub fn raw_samples(mut self, enabled: bool) -> Self {
+        self.raw_samples = enabled;
+        self
     }
-}
 
-struct BatcherInner {
-    tx_cmds: Sender<Command>,
-}
+    /// Only send counter and gauge samples whose value differs from the one
+    /// sent before, saving bandwidth for mostly static series. Receivers
+    /// mark series stale after five minutes without samples, which a
+    /// [`heartbeat`](Self::heartbeat) prevents.
+    ///
+    /// Default is disabled.
+    pub fn send_on_change(mut self, enabled: bool) -> Self {
+        self.send_on_change = enabled;
+        self
+    }
 
-impl BatcherInner {
-    /// Send a command to the worker thread.
-    pub fn send(&self, command: Command) {
-        if let Err(err) = self.tx_cmds.send(command) {
-            error!("Failed to send: {}", err);
-        }
+    /// Have counter and gauge handles update atomics the worker reads at
+    /// each write, rather than queueing every operation, removing the
+    /// allocation and queueing per operation at high rates.
+    ///
+    /// Samples are then stamped with the time of the write, one per series
+    /// per write, and span exemplars are only attached to histograms.
+    /// Default is disabled.
+    pub fn atomic_handles(mut self, enabled: bool) -> Self {
+        self.atomic_handles = enabled;
+        self
     }
-}
 
-fn batch_worker(rx_cmd: Receiver<Command>, endpoint: String, interval: Duration) {
-    let rx_tick = crossbeam::channel::tick(interval);
-    let mut registry = Registry::new();
+    /// Buffer the operations of each recording thread for up to `window`,
+    /// merging consecutive increments and sets of a series, and queue them
+    /// together, cutting queue traffic for hot metrics. Buffers are also
+    /// collected at each write, so idle threads don't hold operations back.
+    ///
+    /// Default is disabled.
+    pub fn thread_batching(mut self, window: Duration) -> Self {
+        self.thread_batching = Some(window);
+        self
+    }
 
-    fn write(registry: &mut Registry, endpoint: &str) {
-        let mut timeseries = vec![];
+    /// Have metric handles send an id with each operation instead of a copy
+    /// of their key's name and labels. Interned keys are kept for the life
+    /// of the exporter, even once their series expired, so this suits a
+    /// bounded set of series.
+    ///
+    /// Default is disabled.
+    pub fn intern_keys(mut self, enabled: bool) -> Self {
+        self.intern_keys = enabled;
+        self
+    }
 
-        for (key, samples) in &registry.counters {
-            // skip if this metric has already been sent
-            if samples.is_sent() {
-                continue;
-            }
+    /// How the values a gauge was set to between two writes are sent.
+    ///
+    /// Default is [`GaugeAggregation::All`].
+    pub fn gauge_aggregation(mut self, aggregation: GaugeAggregation) -> Self {
+        self.gauge_aggregation = aggregation;
+        self
+    }
 
-            let mut labels = vec![types::Label {
-                name: "__name__".to_owned(),
-                value: key.name().to_owned(),
-            }];
+    /// How the values the gauge `name`, before any prefix, was set to
+    /// between two writes are sent, overriding
+    /// [`gauge_aggregation`](Self::gauge_aggregation).
+    pub fn gauge_aggregation_for(
+        mut self,
+        name: impl Into<String>,
+        aggregation: GaugeAggregation,
+    ) -> Self {
+        self.gauge_aggregations.insert(name.into(), aggregation);
+        self
+    }
 
-            for label in key.labels() {
-                labels.push(types::Label {
-                    name: label.key().to_string(),
-                    value: label.value().to_string(),
-                })
-            }
+    /// How `histogram!` recordings are aggregated.
+    ///
+    /// Default is [`HistogramMode::Native`].
+    pub fn histogram_mode(mut self, mode: HistogramMode) -> Self {
+        self.histogram_mode = mode;
+        self
+    }
 
-            timeseries.push(types::TimeSeries {
-                labels,
-                samples: samples.all().clone(),
-                exemplars: vec![],
-            })
-        }
+    /// How `histogram!` recordings of the metric `name`, before any prefix,
+    /// are aggregated, overriding [`histogram_mode`](Self::histogram_mode).
+    pub fn histogram_mode_for(mut self, name: impl Into<String>, mode: HistogramMode) -> Self {
+        self.histogram_modes.insert(name.into(), mode);
+        self
+    }
 
-        for (key, samples) in &registry.gauges {
-            // skip if this metric has already been sent
-            if samples.is_sent() {
-                continue;
-            }
+    /// Mode of the histogram metric `name`.
+    pub(crate) fn histogram_mode_of(&self, name: &str) -> &HistogramMode {
+        self.histogram_modes
+            .get(name)
+            .unwrap_or(&self.histogram_mode)
+    }
 
-            let mut labels = vec![types::Label {
-                name: "__name__".to_owned(),
-                value: key.name().to_owned(),
-            }];
+    /// Estimate the quantiles of [`HistogramMode::Summary`] over a rolling
+    /// window ending now, instead of over every observation. Observations
+    /// expire as time passes even when no new ones arrive, and the quantiles
+    /// are sent again when they do. `_sum` and `_count` still cover every
+    /// observation.
+    ///
+    /// Default is no window.
+    pub fn summary_window(mut self, window: Duration) -> Self {
+        self.summary_window = Some(window);
+        self
+    }
 
-            for label in key.labels() {
-                labels.push(types::Label {
-                    name: label.key().to_string(),
-                    value: label.value().to_string(),
-                })
-            }
+    /// Number of buckets the [`summary_window`](Self::summary_window) is
+    /// divided into. Observations expire a bucket at a time, so more buckets
+    /// track the window more closely at the cost of memory.
+    ///
+    /// Default is 5.
+    pub fn summary_age_buckets(mut self, buckets: u32) -> Self {
+        self.summary_age_buckets = buckets;
+        self
+    }
 
-            timeseries.push(types::TimeSeries {
-                labels,
-                samples: samples.all().clone(),
-                exemplars: vec![],
-            })
-        }
+    /// Resolution of native histograms recorded with `histogram!`, from -4
+    /// to 8. Each power of two is divided into `2^schema` buckets.
+    ///
+    /// Default is 3, i.e. buckets growing by a factor of about 1.09.
+    pub fn histogram_schema(mut self, schema: i32) -> Self {
+        self.histogram_schema = schema;
+        self
+    }
 
-        if timeseries.is_empty() {
-            debug!("no new samples. skipping send");
-            return;
-        }
+    /// Retry behaviour for failed writes.
+    ///
+    /// Default is [`RetryPolicy::default`].
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
 
-        let write_request = types::WriteRequest {
-            timeseries,
-            // doesn't do anything in v.0.1.0 protocol
-            metadata: vec![],
-        };
+    /// Time allowed for each write request, including connecting.
+    ///
+    /// Requests run on the exporter's thread, so a slow endpoint delays
+    /// ingestion by up to this much per request. Default is 100ms.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
 
-        let compressed =
-            match snap::raw::Encoder::new().compress_vec(&write_request.encode_to_vec()) {
-                Ok(c) => c,
-                Err(err) => {
-                    error!("Compression failed: {err:?}");
-                    return;
-                }
-            };
+    /// Pause between the requests of a
+    /// [`Handle::backfill`](crate::Handle::backfill), so a large backfill
+    /// doesn't swamp the receiver.
+    ///
+    /// Default is 100ms.
+    pub fn backfill_interval(mut self, interval: Duration) -> Self {
+        self.backfill_interval = interval;
+        self
+    }
 
-        match ureq::post(endpoint)
-            .config()
-            .http_status_as_error(false)
-            .timeout_global(Some(Duration::from_millis(100)))
-            .build()
-            .content_type("application/x-protobuf")
-            .header("Content-Encoding", "snappy")
-            .header("User-Agent", "prom-push")
-            .header("X-Prometheus-Remote-Write-Version", "1.0.0")
-            .send(&compressed)
-        {
-            Ok(mut response) => {
-                if response.status().is_client_error() {
-                    error!(
-                        "Prometheus returned a client error: {:?}",
-                        response.body_mut().read_to_string()
-                    );
-                }
+    /// Maximum number of unsent samples retained per endpoint while writes
+    /// are failing, and held between writes.
+    ///
+    /// When exceeded, the oldest batches are dropped, and the oldest samples
+    /// of each series before they are batched. Default is 100,000.
+    pub fn max_buffered_samples(mut self, max: usize) -> Self {
+        self.max_buffered_samples = max;
+        self
+    }
 
-                if response.status().is_server_error() {
-                    error!(
-                        "Prometheus returned a server error: {:?}",
-                        response.body_mut().read_to_string()
-                    );
-                }
+    /// Drop unsent samples older than `limit` rather than sending them, e.g.
+    /// after a long outage, as receivers reject them as out of order.
+    ///
+    /// Applies to batches buffered in memory and in the WAL. Default is no
+    /// limit.
+    pub fn sample_age_limit(mut self, limit: Duration) -> Self {
+        self.sample_age_limit = Some(limit);
+        self
+    }
 
-                if response.status().is_success() {
-                    registry.sent();
-                }
-            }
-            Err(err) => {
-                error!("Request failed: {err:?}");
-            }
-        };
-    }
-
-    loop {
-        select! {
-            recv(rx_cmd) -> cmd => {
-                match cmd {
-                    Ok(Command::Operation(timestamp, key, op)) => match op {
-                        MetricOperation::IncrementCounter(value) => {
-                            registry.counter_increment(timestamp, key, value);
-                        },
-                        MetricOperation::SetCounter(value) => {
-                            registry.counter_set(timestamp, key, value);
-                        },
-                        MetricOperation::IncrementGauge(value) => {
-                            registry.gauge_increment(timestamp, key, value);
-                        },
-                        MetricOperation::DecrementGauge(value) => {
-                            registry.gauge_decrement(timestamp, key, value);
-                        },
-                        MetricOperation::SetGauge(value) => {
-                            registry.gauge_set(timestamp, key, value);
-                        },
-                    }
-                    Ok(Command::Metadata(_, _, _, _)) => {
-                        debug!("metadata not yet implemented");
-                    },
-                    Err(err) => {
-                        error!("{}", err);
-                    },
-                };
-            },
-            recv(rx_tick) -> _ => {
-                write(&mut registry, &endpoint);
-            },
+    /// Maximum number of unsent samples kept per counter or gauge, e.g. for
+    /// gauges set at a high rate while writes are slow or failing.
+    ///
+    /// When exceeded, samples are evicted according to
+    /// [`sample_eviction`](Self::sample_eviction). Default is unlimited.
+    pub fn max_series_samples(mut self, max: usize) -> Self {
+        self.max_series_samples = Some(max);
+        self
+    }
+
+    /// Maximum number of samples held across every series, checked before
+    /// each write, bounding the memory used while writes are slow or
+    /// failing or series keep being added. Each histogram counts as one.
+    ///
+    /// Unlike [`max_series_samples`](Self::max_series_samples), which caps
+    /// each series as samples arrive, this caps the registry as a whole, so
+    /// also bounds the number of series. Unlike
+    /// [`max_buffered_samples`](Self::max_buffered_samples), which only
+    /// drops unsent samples, it may forget whole series.
+    ///
+    /// When exceeded, the older unsent samples of each series are evicted
+    /// first, keeping its latest value, then the least recently updated
+    /// series. An evicted counter or histogram starts again from zero when
+    /// next recorded, which queries see as a reset. Evictions are counted by
+    /// the `prometheus_write_samples_evicted_total` self metric. Default is
+    /// unlimited.
+    pub fn max_registry_samples(mut self, max: usize) -> Self {
+        self.max_registry_samples = Some(max);
+        self
+    }
+
+    /// Which samples are discarded once a series has
+    /// [`max_series_samples`](Self::max_series_samples).
+    ///
+    /// Default is [`SampleEviction::Coalesce`].
+    pub fn sample_eviction(mut self, eviction: SampleEviction) -> Self {
+        self.sample_eviction = eviction;
+        self
+    }
+
+    /// Split writes into multiple requests so each encoded request stays
+    /// under this many bytes.
+    ///
+    /// Useful for receivers with body size limits. A single series larger
+    /// than the limit is still sent on its own. Default is unlimited.
+    pub fn max_request_bytes(mut self, max: usize) -> Self {
+        self.max_request_bytes = Some(max);
+        self
+    }
+
+    /// Split writes into multiple requests of at most this many samples.
+    ///
+    /// Requests are sent one after another in the same tick. Series with more
+    /// samples than the limit are split across requests. Default is
+    /// unlimited.
+    pub fn max_samples_per_send(mut self, max: usize) -> Self {
+        self.max_samples_per_send = Some(max);
+        self
+    }
+
+    /// Maximum number of requests sent concurrently to each endpoint.
+    ///
+    /// Only matters when a tick produces several requests or failed requests
+    /// are being resent. Concurrent requests may arrive out of order. Default
+    /// is 1.
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.max_in_flight = max;
+        self
+    }
+
+    /// Encode requests with many series on up to `threads` threads, keeping
+    /// writes of large registries short.
+    ///
+    /// Each thread encodes thousands of series, so smaller requests are
+    /// encoded on the worker. Only applies to Remote Write 1.0. Default is 1.
+    pub fn encode_threads(mut self, threads: usize) -> Self {
+        self.encode_threads = threads;
+        self
+    }
+
+    /// Follow up to this many redirects per request.
+    ///
+    /// Redirects keep the method, body and headers of the original request.
+    /// Default is 0, treating redirects as failed writes.
+    pub fn max_redirects(mut self, max: u32) -> Self {
+        self.max_redirects = max;
+        self
+    }
+
+    /// Probe every endpoint when building, failing if one is unreachable or
+    /// returns 404.
+    ///
+    /// Catches a wrong endpoint at startup rather than on every tick. Default
+    /// is disabled.
+    pub fn fail_fast(mut self, enabled: bool) -> Self {
+        self.fail_fast = enabled;
+        self
+    }
+
+    /// Persist batches to this directory while the endpoint is unreachable.
+    ///
+    /// Persisted batches are sent, oldest first, once writes succeed again.
+    /// Each endpoint uses a subdirectory derived from its URL, so reordering
+    /// endpoints keeps their persisted batches. Default is to only buffer in
+    /// memory.
+    pub fn wal_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.wal_dir = Some(dir.into());
+        self
+    }
+
+    /// Persist batches the endpoint rejects, or still fails to accept once
+    /// retries ran out, to this directory.
+    ///
+    /// They can be resent with [`Handle::replay_dead_letters`] once the
+    /// receiver has been fixed. Each endpoint uses a subdirectory derived
+    /// from its URL, so reordering endpoints keeps their dead letters.
+    /// Default is to drop rejected batches.
+    pub fn dead_letter_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dead_letter_dir = Some(dir.into());
+        self
+    }
+
+    /// Stop sending for `cooldown` after `threshold` consecutive failed
+    /// writes.
+    ///
+    /// Samples keep buffering in memory while the circuit is open. Once the
+    /// cooldown has passed a single probe write decides whether to resume.
+    /// Default is disabled.
+    pub fn circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.circuit_breaker = Some((threshold, cooldown));
+        self
+    }
+
+    /// Maximum number of queued operations waiting for the worker thread.
+    ///
+    /// Default is unbounded.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Spread operations over `shards` queues, each recording thread using
+    /// one, reducing contention between threads recording at high rates.
+    /// The operations of each thread stay in order.
+    ///
+    /// [`queue_capacity`](Self::queue_capacity) applies to each shard.
+    /// Default is 1.
+    pub fn command_shards(mut self, shards: usize) -> Self {
+        self.command_shards = shards;
+        self
+    }
+
+    /// Queue operations in lock-free queues holding up to `capacity`
+    /// operations each, for applications that can't tolerate the allocations
+    /// and locking of a channel when recording.
+    ///
+    /// The worker isn't woken by new operations but drains the queues every
+    /// millisecond, taking at most `capacity` operations from each, so
+    /// `capacity` must cover the operations recorded in between. Polling
+    /// wakes the worker a thousand times a second even when idle, costing a
+    /// little CPU time in exchange. There is a queue per
+    /// [`command_shards`](Self::command_shards) shard, and the
+    /// [`overflow_policy`](Self::overflow_policy) applies once one is full;
+    /// [`OverflowPolicy::Block`] waits for the next drain, spinning briefly
+    /// before sleeping. Other commands still use the channel. Default is
+    /// disabled.
+    pub fn lock_free_queue(mut self, capacity: usize) -> Self {
+        self.lock_free_queue = Some(capacity);
+        self
+    }
+
+    /// Encode, compress and send write requests on a thread of their own, so
+    /// slow endpoints don't hold up the worker applying operations.
+    ///
+    /// A couple of writes are queued for the sender thread; while it is
+    /// behind, later ones are held back and sent together, up to
+    /// [`max_buffered_samples`](Self::max_buffered_samples) with the
+    /// [`overflow_policy`](Self::overflow_policy) applied beyond. Flushes and
+    /// shutdown still wait for everything to be sent. Default is false.
+    pub fn pipelined_sends(mut self, enabled: bool) -> Self {
+        self.pipelined_sends = enabled;
+        self
+    }
+
+    /// What to do with new operations when the bounded queue is full.
+    ///
+    /// Only applies when [`Builder::queue_capacity`] or
+    /// [`Builder::lock_free_queue`] is set, and to the writes held back by
+    /// [`Builder::pipelined_sends`]. Default is
+    /// [`OverflowPolicy::DropNewest`].
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow = policy;
+        self
+    }
+
+    /// Apply Prometheus style queue settings.
+    ///
+    /// Overrides [`Builder::queue_capacity`], [`Builder::batch_interval`],
+    /// [`Builder::max_samples_per_send`] and the backoff of
+    /// [`Builder::retry`]. Shards other than 1 are rejected when building.
+    pub fn queue_config(mut self, queue: QueueConfig) -> Self {
+        self.queue_capacity = Some(queue.capacity);
+        self.batch_interval = queue.batch_send_deadline;
+        self.retry.min_backoff = queue.min_backoff;
+        self.retry.max_backoff = queue.max_backoff;
+        self.max_samples_per_send = Some(queue.max_samples_per_send);
+        self.queue_shards = (queue.min_shards, queue.max_shards);
+        self
+    }
+
+    /// Name of the worker thread, as shown in profilers and debuggers.
+    ///
+    /// Default is `prom-write-worker`.
+    pub fn worker_name(mut self, name: impl Into<String>) -> Self {
+        self.worker_name = name.into();
+        self
+    }
+
+    /// Stack size of the worker thread in bytes.
+    ///
+    /// Default is the platform default for spawned threads.
+    pub fn worker_stack_size(mut self, size: usize) -> Self {
+        self.worker_stack_size = Some(size);
+        self
+    }
+
+    /// Pin the worker thread to the given CPU core.
+    ///
+    /// Keeps the exporter off cores reserved for latency-critical threads.
+    /// Ignored on platforms that don't support affinity.
+    #[cfg(feature = "affinity")]
+    pub fn worker_core(mut self, core: usize) -> Self {
+        self.worker_core = Some(core);
+        self
+    }
+
+    /// Register a panic hook that writes everything recorded so far before
+    /// the panic unwinds or aborts the process.
+    ///
+    /// The hook is registered by [`install`](Self::install) and the other
+    /// `install` methods once the recorder is set. It waits at most `timeout`
+    /// for the write and runs before any previously registered hook. Panics
+    /// later caught with [`std::panic::catch_unwind`] write too, as whether a
+    /// panic is caught isn't known yet when the hook runs. Default is
+    /// disabled.
+    pub fn flush_on_panic(mut self, timeout: Duration) -> Self {
+        self.flush_on_panic = Some(timeout);
+        self
+    }
+
+    /// Record the exporter's own health metrics, e.g.
+    /// `prometheus_write_request_failures_total`, alongside the application's.
+    ///
+    /// Sending them counts as traffic too, so while enabled the exporter
+    /// writes every batch interval even if nothing else is recorded. Default
+    /// is disabled.
+    pub fn self_metrics(mut self, enabled: bool) -> Self {
+        self.self_metrics = enabled;
+        self
+    }
+
+    /// Attach the OpenTelemetry trace and span id of the current `tracing`
+    /// span as an exemplar to this fraction of operations.
+    ///
+    /// Requires a `tracing_opentelemetry` layer. Operations outside a traced
+    /// span are unaffected. Default is disabled.
+    #[cfg(feature = "opentelemetry")]
+    pub fn span_exemplars(mut self, sample_rate: f64) -> Self {
+        self.span_exemplars = Some(sample_rate);
+        self
+    }
+
+    /// Also serve the latest value of every series at `/metrics` on this
+    /// address, for scraping while migrating to remote write or for
+    /// inspecting live state.
+    ///
+    /// Default is disabled.
+    #[cfg(feature = "scrape")]
+    pub fn scrape_endpoint(mut self, addr: SocketAddr) -> Self {
+        self.scrape_endpoint = Some(addr);
+        self
+    }
+
+    /// Record the CPU time, resident memory, open file descriptors and
+    /// thread count of this process every `interval`, as the
+    /// `process_cpu_seconds_total`, `process_resident_memory_bytes`,
+    /// `process_open_fds` and `process_threads` gauges.
+    ///
+    /// These are the names other Prometheus clients use. CPU time is a gauge
+    /// rather than a counter as counters only hold whole numbers, so it's
+    /// sent with gauge metadata but `rate()` works on it all the same. The
+    /// series keep these names whatever the [`prefix`](Self::prefix), and are
+    /// recorded regardless of [`min_level`](Self::min_level),
+    /// [`allow_target`](Self::allow_target) and the metric name filters.
+    ///
+    /// Only Linux is supported. Default is disabled.
+    #[cfg(feature = "process-metrics")]
+    pub fn process_metrics(mut self, interval: Duration) -> Self {
+        self.process_metrics = Some(interval);
+        self
+    }
+
+    /// Send when each counter was first observed, so receivers can compute
+    /// accurate rates across restarts and for short-lived jobs.
+    ///
+    /// Remote Write 1.0 gets a `<name>_created` series holding the creation
+    /// time in seconds, 2.0 the series' created timestamp. Default is
+    /// disabled.
+    pub fn created_timestamps(mut self, enabled: bool) -> Self {
+        self.created_timestamps = enabled;
+        self
+    }
+
+    /// How counters set with `absolute` to a value lower than their
+    /// previous one are sent.
+    ///
+    /// Default is [`CounterReset::Emit`].
+    pub fn counter_reset(mut self, reset: CounterReset) -> Self {
+        self.counter_reset = reset;
+        self
+    }
+
+    /// Save the total of every counter to the file at `path` on shutdown and
+    /// restore them from it on startup, so counters pushed from frequently
+    /// restarted processes keep increasing.
+    ///
+    /// Meant for counters recorded with `increment`. Default is disabled.
+    pub fn counter_state(mut self, path: impl Into<PathBuf>) -> Self {
+        self.counter_state = Some(path.into());
+        self
+    }
+
+    /// Re-send the last value of every counter and gauge every `interval`,
+    /// even if unchanged, so receivers don't mark quiet series stale after
+    /// five minutes and dashboards show no gaps.
+    ///
+    /// Default is disabled.
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    /// Source of the time samples are stamped with, e.g. the `test-util`
+    /// feature's `ManualClock` in tests.
+    ///
+    /// Default is the [`SystemClock`](crate::SystemClock).
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Stamp operations with the time read every `resolution` by a thread
+    /// of its own, rather than reading the clock on every operation, which
+    /// is measurable at millions of operations per second. Samples are then
+    /// stamped up to `resolution` early.
+    ///
+    /// Default is reading the clock on every operation.
+    pub fn timestamp_resolution(mut self, resolution: Duration) -> Self {
+        self.timestamp_resolution = Some(resolution);
+        self
+    }
+
+    /// Stamp samples with the time of the write they're sent in, rounded
+    /// down to a multiple of the batch interval, or of the flush interval of
+    /// their metric if overridden, rather than the time they were recorded,
+    /// so series are evenly spaced like scraped ones. Only the latest sample
+    /// of each series is sent per write, and a series already sent at the
+    /// current multiple waits for the next, e.g. after a flush.
+    ///
+    /// Default is disabled.
+    pub fn aligned_timestamps(mut self, enabled: bool) -> Self {
+        self.aligned_timestamps = enabled;
+        self
+    }
+
+    /// Forget series not updated for `timeout` once they were sent, bounding
+    /// memory when series come and go, e.g. with per-request labels. A
+    /// counter recorded again after being forgotten restarts from zero.
+    ///
+    /// Default is never.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Mark series forgotten after the [`idle_timeout`](Self::idle_timeout)
+    /// stale, so queries stop returning them straight away.
+    ///
+    /// Default is enabled.
+    pub fn idle_stale_markers(mut self, enabled: bool) -> Self {
+        self.idle_stale_markers = enabled;
+        self
+    }
+
+    /// Call `callback` after every write request, including failed ones,
+    /// e.g. to feed a health endpoint.
+    ///
+    /// The callback runs on the exporter's threads and should return quickly
+    /// as it delays further writes.
+    pub fn on_result(mut self, callback: impl Fn(&WriteOutcome) + Send + Sync + 'static) -> Self {
+        self.on_result = Some(OnResult(Arc::new(callback)));
+        self
+    }
+
+    /// Append write requests to a file instead of sending them to an
+    /// endpoint, e.g. for air-gapped capture and later replay.
+    ///
+    /// Requests are stored uncompressed, each prefixed with its length as a
+    /// varint. Endpoints and HTTP options are ignored. Default is disabled.
+    pub fn file_sink(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file_sink = Some(path.into());
+        self
+    }
+
+    /// Deliver write requests to a custom [`Sink`] instead of the endpoints.
+    ///
+    /// Can be called multiple times, every sink receives every request.
+    /// Endpoints and HTTP options are ignored when a sink is set.
+    pub fn sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(SharedSink::new(sink));
+        self
+    }
+
+    /// HTTP client used to send write requests, e.g. one already
+    /// instrumented by the application.
+    ///
+    /// Default is a [ureq](https://docs.rs/ureq) agent. Without the `ureq`
+    /// feature, the `reqwest` or `hyper` transport is used if enabled.
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(SharedTransport(Arc::new(transport)));
+        self
+    }
+
+    /// The configured transport, falling back to the built-in one.
+    pub(crate) fn http_transport(&self) -> Result<Arc<dyn Transport>, BuildError> {
+        match &self.transport {
+            Some(transport) => Ok(transport.0.clone()),
+            None => crate::transport::default_transport(self.root_certificates.as_deref()),
+        }
+    }
+
+    /// Trust only the CA certificates in the PEM bundle `pem` for `https`
+    /// endpoints, e.g. of a private CA. Used by the built-in `ureq` and
+    /// `reqwest` transports.
+    ///
+    /// Default is the transport's bundled web PKI roots.
+    pub fn root_certificates(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.root_certificates = Some(pem.into());
+        self
+    }
+
+    /// Check the configuration for errors.
+    fn validate(&self) -> Result<(), BuildError> {
+        for endpoint in &self.endpoints {
+            let uri: http::Uri = endpoint
+                .parse()
+                .map_err(|err| BuildError::InvalidEndpoint(format!("{endpoint}: {err}")))?;
+
+            if !matches!(uri.scheme_str(), Some("http") | Some("https")) {
+                return Err(BuildError::InvalidEndpoint(format!(
+                    "{endpoint}: scheme must be http or https"
+                )));
+            }
+
+            if uri.host().is_none() {
+                return Err(BuildError::InvalidEndpoint(format!(
+                    "{endpoint}: missing host"
+                )));
+            }
+        }
+
+        // they would share a WAL and receive every batch twice
+        if let Some((_, endpoint)) = self
+            .endpoints
+            .iter()
+            .enumerate()
+            .find(|(index, endpoint)| self.endpoints[..*index].contains(endpoint))
+        {
+            return Err(BuildError::InvalidConfig(format!(
+                "{endpoint} is configured more than once"
+            )));
+        }
+
+        if self.root_certificates.is_some() {
+            if self.transport.is_some() {
+                return Err(BuildError::InvalidConfig(
+                    "root certificates are not used by a custom transport".to_owned(),
+                ));
+            }
+            self.http_transport()?;
+        }
+
+        // the built-in hyper client has no TLS connector
+        #[cfg(all(feature = "hyper", not(any(feature = "ureq", feature = "reqwest"))))]
+        if self.transport.is_none()
+            && let Some(endpoint) = self
+                .endpoints
+                .iter()
+                .find(|endpoint| endpoint.starts_with("https:"))
+        {
+            return Err(BuildError::InvalidTls(format!(
+                "{endpoint}: https needs a `HyperTransport::with_client` with a TLS connector"
+            )));
+        }
+
+        if self
+            .flush_intervals
+            .iter()
+            .any(|(_, interval)| interval.is_zero())
+        {
+            return Err(BuildError::InvalidConfig(
+                "flush intervals must be greater than zero".to_owned(),
+            ));
+        }
+
+        #[cfg(feature = "process-metrics")]
+        if self.process_metrics == Some(Duration::ZERO) {
+            return Err(BuildError::InvalidConfig(
+                "process metrics interval must be greater than zero".to_owned(),
+            ));
+        }
+
+        if self.timestamp_resolution == Some(Duration::ZERO) {
+            return Err(BuildError::InvalidConfig(
+                "timestamp resolution must be greater than zero".to_owned(),
+            ));
+        }
+
+        if self.command_shards == 0 {
+            return Err(BuildError::InvalidConfig(
+                "command shards must be at least one".to_owned(),
+            ));
+        }
+
+        if self.lock_free_queue == Some(0) {
+            return Err(BuildError::InvalidConfig(
+                "lock-free queue capacity must be greater than zero".to_owned(),
+            ));
+        }
+
+        if self.queue_capacity == Some(0) {
+            return Err(BuildError::InvalidConfig(
+                "queue capacity must be greater than zero".to_owned(),
+            ));
+        }
+
+        if self.queue_shards != (1, 1) {
+            return Err(BuildError::InvalidConfig(
+                "requests aren't sharded, use max_in_flight for concurrent requests".to_owned(),
+            ));
+        }
+
+        // the 1.0 spec only allows snappy
+        #[cfg(feature = "zstd")]
+        if self.protocol == Protocol::V1 && matches!(self.compression, Compression::Zstd(_)) {
+            return Err(BuildError::InvalidConfig(
+                "zstd compression needs Remote Write 2.0".to_owned(),
+            ));
+        }
+
+        if self.retry.max_attempts == 0 {
+            return Err(BuildError::InvalidConfig(
+                "retry max attempts must be at least one".to_owned(),
+            ));
+        }
+
+        if self.retry.min_backoff > self.retry.max_backoff {
+            return Err(BuildError::InvalidConfig(
+                "retry min backoff must not exceed max backoff".to_owned(),
+            ));
+        }
+
+        if matches!(self.circuit_breaker, Some((0, _))) {
+            return Err(BuildError::InvalidConfig(
+                "circuit breaker threshold must be at least one".to_owned(),
+            ));
+        }
+
+        if self.max_request_bytes == Some(0) {
+            return Err(BuildError::InvalidConfig(
+                "max request bytes must be greater than zero".to_owned(),
+            ));
+        }
+
+        if self.max_series_samples == Some(0) {
+            return Err(BuildError::InvalidConfig(
+                "max series samples must be greater than zero".to_owned(),
+            ));
+        }
+
+        if self.max_registry_samples == Some(0) {
+            return Err(BuildError::InvalidConfig(
+                "max registry samples must be greater than zero".to_owned(),
+            ));
+        }
+
+        if self.max_samples_per_send == Some(0) {
+            return Err(BuildError::InvalidConfig(
+                "max samples per send must be greater than zero".to_owned(),
+            ));
+        }
+
+        if self.write_timeout.is_zero() {
+            return Err(BuildError::InvalidConfig(
+                "write timeout must be greater than zero".to_owned(),
+            ));
+        }
+
+        if self.max_in_flight == 0 {
+            return Err(BuildError::InvalidConfig(
+                "max in flight must be at least one".to_owned(),
+            ));
+        }
+
+        if self.encode_threads == 0 {
+            return Err(BuildError::InvalidConfig(
+                "encode threads must be at least one".to_owned(),
+            ));
+        }
+
+        if self.batch_interval.is_zero() {
+            return Err(BuildError::InvalidConfig(
+                "batch interval must be greater than zero".to_owned(),
+            ));
+        }
+
+        for mode in std::iter::once(&self.histogram_mode).chain(self.histogram_modes.values()) {
+            mode.validate()
+                .map_err(|err| BuildError::InvalidConfig(err.to_owned()))?;
+        }
+
+        if self.summary_age_buckets == 0 {
+            return Err(BuildError::InvalidConfig(
+                "summary age buckets must be at least one".to_owned(),
+            ));
+        }
+
+        if let Some(window) = self.summary_window
+            && window.as_millis() < u128::from(self.summary_age_buckets)
+        {
+            return Err(BuildError::InvalidConfig(
+                "summary window must be at least a millisecond per age bucket".to_owned(),
+            ));
+        }
+
+        if !(-4..=8).contains(&self.histogram_schema) {
+            return Err(BuildError::InvalidConfig(
+                "histogram schema must be between -4 and 8".to_owned(),
+            ));
+        }
+
+        #[cfg(feature = "opentelemetry")]
+        if let Some(rate) = self.span_exemplars
+            && !(rate > 0.0 && rate <= 1.0)
+        {
+            return Err(BuildError::InvalidConfig(
+                "span exemplar sample rate must be in (0, 1]".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Validate the configuration, start the worker thread and return the
+    /// recorder without installing it globally.
+    pub fn build(self) -> Result<Batcher, BuildError> {
+        let mut thread = std::thread::Builder::new().name(self.worker_name.clone());
+        if let Some(size) = self.worker_stack_size {
+            thread = thread.stack_size(size);
+        }
+
+        #[cfg(feature = "affinity")]
+        let worker_core = self.worker_core;
+
+        let (batcher, batch_loop) = self.start()?;
+
+        let worker = thread
+            .spawn(move || {
+                #[cfg(feature = "affinity")]
+                if let Some(core) = worker_core
+                    && !core_affinity::set_for_current(core_affinity::CoreId { id: core })
+                {
+                    error!("Failed to pin worker thread to core {core}");
+                }
+
+                batch_loop.run()
+            })
+            .map_err(BuildError::Spawn)?;
+
+        *batcher
+            .inner
+            .worker
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(worker);
+
+        Ok(batcher)
+    }
+
+    /// Validate the configuration and return the recorder along with the
+    /// batch loop, for running the worker on a thread or executor of your
+    /// own.
+    ///
+    /// Worker thread options are ignored. Nothing is written until the loop
+    /// is run or polled.
+    pub fn build_worker(self) -> Result<(Batcher, BatchLoop), BuildError> {
+        self.start()
+    }
+
+    /// Create the recorder and the batch loop that feeds the endpoints.
+    fn start(self) -> Result<(Batcher, BatchLoop), BuildError> {
+        self.validate()?;
+
+        if self.fail_fast && self.file_sink.is_none() && self.sinks.is_empty() {
+            let transport = self.http_transport()?;
+            for endpoint in &self.endpoints {
+                crate::remote::probe(&self, &*transport, endpoint)
+                    .map_err(|err| BuildError::Unreachable(format!("{endpoint}: {err}")))?;
+            }
+        }
+
+        let channel = || match self.queue_capacity {
+            Some(capacity) => crossbeam::channel::bounded(capacity),
+            None => crossbeam::channel::unbounded(),
+        };
+        let (tx_cmds, rx_cmds) = channel();
+        // a single shard is the main queue
+        let shards: Vec<_> = if self.command_shards > 1 {
+            (0..self.command_shards).map(|_| channel()).collect()
+        } else {
+            vec![]
+        };
+        let rx_shards = shards.iter().map(|(_, rx)| rx.clone()).collect();
+        let queues: Option<OperationQueues> = self.lock_free_queue.map(|capacity| {
+            (0..self.command_shards)
+                .map(|_| ArrayQueue::new(capacity))
+                .collect()
+        });
+
+        let clock = match self.timestamp_resolution {
+            Some(resolution) => SharedClock::new(
+                CoarseClock::spawn(self.clock.clone(), resolution).map_err(BuildError::Spawn)?,
+            ),
+            None => self.clock.clone(),
+        };
+
+        let atomics = self.atomic_handles.then(Arc::<Atomics>::default);
+        let interner = self.intern_keys.then(Arc::<Interner>::default);
+        let thread_buffers = self
+            .thread_batching
+            .map(|window| Arc::new(ThreadBuffers::new(window)));
+
+        let inner = Arc::new(BatcherInner {
+            tx_cmds,
+            rx_cmds: rx_cmds.clone(),
+            shards,
+            queues: queues.clone(),
+            overflow: self.overflow,
+            dropped: Arc::new(AtomicU64::new(0)),
+            health: Arc::new(if self.file_sink.is_none() && self.sinks.is_empty() {
+                Health::new(&self.endpoints)
+            } else {
+                Health::default()
+            }),
+            shutdown: AtomicBool::new(false),
+            stopped: Mutex::new(None),
+            worker: Mutex::new(None),
+            filter: Filter {
+                min_level: self.min_level,
+                targets: self.targets.clone(),
+                allow: self.allow_metrics.clone(),
+                deny: self.deny_metrics.clone(),
+            },
+            clock,
+            atomics: atomics.clone(),
+            interner: interner.clone(),
+            thread_buffers: thread_buffers.clone(),
+            #[cfg(feature = "opentelemetry")]
+            span_exemplars: self.span_exemplars,
+        });
+        let dropped = inner.dropped.clone();
+        let health = inner.health.clone();
+
+        #[cfg(feature = "scrape")]
+        if let Some(addr) = self.scrape_endpoint {
+            let listener = std::net::TcpListener::bind(addr).map_err(BuildError::Listen)?;
+            crate::scrape::spawn(listener, Arc::downgrade(&inner)).map_err(BuildError::Spawn)?;
+        }
+
+        #[cfg(feature = "process-metrics")]
+        if let Some(interval) = self.process_metrics {
+            crate::process::spawn(Arc::downgrade(&inner), interval).map_err(BuildError::Spawn)?;
+        }
+
+        let mut worker = Worker::new(self, dropped, health)?;
+        worker.atomics = atomics;
+        worker.interner = interner.unwrap_or_default();
+        worker.thread_buffers = thread_buffers;
+        worker.queues = queues;
+
+        Ok((
+            Batcher { inner },
+            BatchLoop::new(rx_cmds, rx_shards, worker),
+        ))
+    }
+
+    /// Set the global recorder
+    pub fn install(self) -> Result<Handle, BuildError> {
+        let flush_on_panic = self.flush_on_panic;
+        let batcher = self.build()?;
+        let inner = batcher.inner.clone();
+        metrics::set_global_recorder(batcher)?;
+
+        if let Some(timeout) = flush_on_panic {
+            flush_on_panic_hook(&inner, timeout);
+        }
+        Ok(Handle::new(inner))
+    }
+
+    /// Set the global recorder, returning a guard that flushes and stops the
+    /// exporter when dropped.
+    pub fn install_scoped(self) -> Result<FlushGuard, BuildError> {
+        Ok(FlushGuard::new(self.install()?))
+    }
+
+    /// Set the global recorder to one forwarding metrics to both this
+    /// exporter and `other`, e.g. a local scrape endpoint or a debugging
+    /// recorder.
+    pub fn install_with(
+        self,
+        other: impl Recorder + Send + Sync + 'static,
+    ) -> Result<Handle, BuildError> {
+        let flush_on_panic = self.flush_on_panic;
+        let batcher = self.build()?;
+        let inner = batcher.inner.clone();
+        metrics::set_global_recorder(Fanout::new().with(batcher).with(other))?;
+
+        if let Some(timeout) = flush_on_panic {
+            flush_on_panic_hook(&inner, timeout);
+        }
+        Ok(Handle::new(inner))
+    }
+}
+
+/// Chain a panic hook that flushes the exporter before deferring to the
+/// previous hook.
+fn flush_on_panic_hook(inner: &Arc<BatcherInner>, timeout: Duration) {
+    let worker = inner
+        .worker
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .map(|worker| worker.thread().id());
+    let inner = Arc::downgrade(inner);
+    let previous = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        // the worker can't flush itself, and a dropped exporter has nothing
+        // left to flush
+        if Some(std::thread::current().id()) != worker
+            && let Some(inner) = inner.upgrade()
+            && !inner.shutdown.load(Ordering::Relaxed)
+            && !Handle::new(inner).flush(timeout)
+        {
+            error!("Failed to flush metrics before panicking");
+        }
+
+        previous(info);
+    }));
+}
+
+/// Batch sample sender.
+pub struct Batcher {
+    pub(crate) inner: Arc<BatcherInner>,
+}
+
+impl Batcher {
+    /// See [`Builder`].
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// Get a handle for interacting with the exporter after installation.
+    pub fn handle(&self) -> Handle {
+        Handle::new(self.inner.clone())
+    }
+
+    /// Send a command to the worker thread.
+    fn send(&self, command: Command) {
+        self.inner.send(command);
+    }
+}
+
+impl Recorder for Batcher {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, desc: SharedString) {
+        self.send(Command::Metadata(key, MetricType::Counter, unit, desc));
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, desc: SharedString) {
+        self.send(Command::Metadata(key, MetricType::Gauge, unit, desc));
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, desc: SharedString) {
+        self.send(Command::Metadata(key, MetricType::Histogram, unit, desc));
+    }
+
+    fn register_counter(&self, key: &Key, meta: &metrics::Metadata<'_>) -> metrics::Counter {
+        if !self.inner.filter.allows(key, meta) {
+            return metrics::Counter::noop();
+        }
+        if let Some(atomics) = &self.inner.atomics {
+            return metrics::Counter::from_arc(atomics.counter(key));
+        }
+
+        metrics::Counter::from_arc(Arc::new(Counter {
+            series: self.inner.series_key(key),
+            inner: self.inner.clone(),
+        }))
+    }
+
+    fn register_gauge(&self, key: &Key, meta: &metrics::Metadata<'_>) -> metrics::Gauge {
+        if !self.inner.filter.allows(key, meta) {
+            return metrics::Gauge::noop();
+        }
+        if let Some(atomics) = &self.inner.atomics {
+            return metrics::Gauge::from_arc(atomics.gauge(key));
+        }
+
+        metrics::Gauge::from_arc(Arc::new(Gauge {
+            series: self.inner.series_key(key),
+            inner: self.inner.clone(),
+        }))
+    }
+
+    fn register_histogram(&self, key: &Key, meta: &metrics::Metadata<'_>) -> metrics::Histogram {
+        if !self.inner.filter.allows(key, meta) {
+            return metrics::Histogram::noop();
+        }
+
+        metrics::Histogram::from_arc(Arc::new(Histogram {
+            series: self.inner.series_key(key),
+            inner: self.inner.clone(),
+        }))
+    }
+}
+
+pub struct Counter {
+    series: SeriesKey,
+    inner: Arc<BatcherInner>,
+}
+
+impl metrics::CounterFn for Counter {
+    fn increment(&self, value: u64) {
+        self.inner
+            .operation(&self.series, MetricOperation::IncrementCounter(value));
+    }
+
+    fn absolute(&self, value: u64) {
+        self.inner
+            .operation(&self.series, MetricOperation::SetCounter(value));
+    }
+}
+
+pub struct Gauge {
+    series: SeriesKey,
+    inner: Arc<BatcherInner>,
+}
+
+impl metrics::GaugeFn for Gauge {
+    fn increment(&self, value: f64) {
+        self.inner
+            .operation(&self.series, MetricOperation::IncrementGauge(value));
+    }
+
+    fn decrement(&self, value: f64) {
+        self.inner
+            .operation(&self.series, MetricOperation::DecrementGauge(value));
+    }
+
+    fn set(&self, value: f64) {
+        self.inner
+            .operation(&self.series, MetricOperation::SetGauge(value));
+    }
+}
+
+pub struct Histogram {
+    series: SeriesKey,
+    inner: Arc<BatcherInner>,
+}
+
+impl metrics::HistogramFn for Histogram {
+    fn record(&self, value: f64) {
+        self.inner
+            .operation(&self.series, MetricOperation::RecordHistogram(value));
+    }
+}
+
+/// What to do with an operation when the bounded queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Discard the new operation.
+    #[default]
+    DropNewest,
+    /// Discard the oldest queued operation to make room for the new one.
+    ///
+    /// Queued flushes and metric descriptions are kept.
+    DropOldest,
+    /// Block the caller for up to the given duration, then discard the new
+    /// operation.
+    Block(Duration),
+}
+
+pub(crate) struct BatcherInner {
+    tx_cmds: Sender<Command>,
+    rx_cmds: Receiver<Command>,
+    /// Queues for the operations of recording threads, if sharded.
+    shards: Vec<(Sender<Command>, Receiver<Command>)>,
+    /// Lock-free queues replacing the channels for operations, if enabled.
+    queues: Option<OperationQueues>,
+    overflow: OverflowPolicy,
+    pub(crate) dropped: Arc<AtomicU64>,
+    pub(crate) health: Arc<Health>,
+    /// Set once shutdown has started, after which operations are ignored.
+    pub(crate) shutdown: AtomicBool,
+    /// Reply to the shutdown request once sent, disconnected once the worker
+    /// stopped, for every caller to wait on.
+    pub(crate) stopped: Mutex<Option<Receiver<()>>>,
+    pub(crate) worker: Mutex<Option<JoinHandle<()>>>,
+    /// Metrics recorded, others are given no-op handles.
+    filter: Filter,
+    /// Time operations are stamped with.
+    pub(crate) clock: SharedClock,
+    /// Counters and gauges updated in place, if enabled.
+    atomics: Option<Arc<Atomics>>,
+    /// Keys of the registered metric handles, if interned.
+    interner: Option<Arc<Interner>>,
+    /// Operations buffered by each recording thread, if enabled.
+    thread_buffers: Option<Arc<ThreadBuffers>>,
+    /// Fraction of operations given an exemplar from the current span.
+    #[cfg(feature = "opentelemetry")]
+    span_exemplars: Option<f64>,
+}
+
+impl BatcherInner {
+    /// Send an operation on a series, along with an exemplar from the
+    /// current span if enabled.
+    fn operation(&self, series: &SeriesKey, op: MetricOperation) {
+        let timestamp = self.clock.now();
+
+        #[cfg(feature = "opentelemetry")]
+        let exemplar = self.span_exemplar(&op);
+
+        self.queue(timestamp, series.clone(), op);
+
+        #[cfg(feature = "opentelemetry")]
+        if let Some(exemplar) = exemplar {
+            self.queue(timestamp, series.clone(), exemplar);
+        }
+    }
+
+    /// How the handles of `key` refer to its series.
+    fn series_key(&self, key: &Key) -> SeriesKey {
+        match &self.interner {
+            Some(interner) => SeriesKey::Interned(interner.intern(key)),
+            None => SeriesKey::Key(key.clone()),
+        }
+    }
+
+    /// Queue an operation, or buffer it if thread batching is enabled.
+    fn queue(&self, timestamp: SystemTime, series: SeriesKey, op: MetricOperation) {
+        if let Some(buffers) = &self.thread_buffers {
+            if let Some(operations) = buffers.push((timestamp, series, op)) {
+                self.send(Command::Batch(operations));
+            }
+            return;
+        }
+
+        match series {
+            SeriesKey::Key(key) => self.send(Command::Operation(timestamp, key, op)),
+            SeriesKey::Interned(id) => self.send(Command::Interned(timestamp, id, op)),
+        }
+    }
+
+    /// Sample an exemplar for `op` from the current span.
+    #[cfg(feature = "opentelemetry")]
+    fn span_exemplar(&self, op: &MetricOperation) -> Option<MetricOperation> {
+        let rate = self.span_exemplars?;
+        if fastrand::f64() >= rate {
+            return None;
+        }
+
+        let value = match *op {
+            MetricOperation::IncrementCounter(value) | MetricOperation::SetCounter(value) => {
+                value as f64
+            }
+            MetricOperation::IncrementGauge(value)
+            | MetricOperation::SetGauge(value)
+            | MetricOperation::RecordHistogram(value) => value,
+            MetricOperation::DecrementGauge(value) => -value,
+            MetricOperation::Remove | MetricOperation::Exemplar(_, _) => return None,
+        };
+
+        Some(MetricOperation::Exemplar(value, span_exemplar::labels()?))
+    }
+
+    /// Number of commands waiting for the worker thread.
+    pub fn queue_depth(&self) -> usize {
+        let queued = self.queues.iter().flat_map(|queues| queues.iter());
+        self.rx_cmds.len()
+            + self.shards.iter().map(|(_, rx)| rx.len()).sum::<usize>()
+            + queued.map(ArrayQueue::len).sum::<usize>()
+    }
+
+    /// Send a control command, unless the queue stays full until
+    /// `deadline`.
+    ///
+    /// Returns `false` if the command wasn't sent.
+    pub fn control_until(&self, command: Command, deadline: Instant) -> bool {
+        match self.tx_cmds.send_deadline(command, deadline) {
+            Ok(()) => true,
+            Err(SendTimeoutError::Timeout(_)) => false,
+            Err(err) => {
+                error!("Failed to send: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Send a command to the worker thread.
+    pub fn send(&self, command: Command) {
+        if self.shutdown.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if let Some(queues) = &self.queues
+            && command.is_operation()
+        {
+            let queue = &queues[THREAD_INDEX.with(|index| *index) % queues.len()];
+            return self.push(queue, command);
+        }
+
+        let (tx_cmds, rx_cmds) = match self.shards.len() {
+            0 => (&self.tx_cmds, &self.rx_cmds),
+            _ if !command.is_operation() => (&self.tx_cmds, &self.rx_cmds),
+            shards => {
+                let (tx, rx) = &self.shards[THREAD_INDEX.with(|index| *index) % shards];
+                (tx, rx)
+            }
+        };
+
+        let result = match self.overflow {
+            OverflowPolicy::DropNewest => tx_cmds.try_send(command),
+            OverflowPolicy::DropOldest => self.evict_oldest(tx_cmds, rx_cmds, command),
+            OverflowPolicy::Block(timeout) => {
+                tx_cmds
+                    .send_timeout(command, timeout)
+                    .map_err(|err| match err {
+                        SendTimeoutError::Timeout(c) => TrySendError::Full(c),
+                        SendTimeoutError::Disconnected(c) => TrySendError::Disconnected(c),
+                    })
+            }
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(TrySendError::Full(command)) => {
+                self.dropped
+                    .fetch_add(command.dropped_count(), Ordering::Relaxed);
+            }
+            Err(err @ TrySendError::Disconnected(_)) => {
+                error!("Failed to send: {}", err);
+            }
+        }
+    }
+
+    /// Send a command, evicting the oldest queued operations to make room.
+    ///
+    /// Control commands and metadata are never evicted, they're requeued
+    /// behind the operations without blocking. The command is rejected if
+    /// nothing but those is queued, and a requeued control command is only
+    /// dropped if other threads keep refilling the queue.
+    fn evict_oldest(
+        &self,
+        tx_cmds: &Sender<Command>,
+        rx_cmds: &Receiver<Command>,
+        command: Command,
+    ) -> Result<(), TrySendError<Command>> {
+        // requeued control commands go ahead of `command`, which is last
+        let mut waiting = VecDeque::from([command]);
+        // each queued command is looked at once at most
+        let mut evictions = tx_cmds.capacity().unwrap_or_default();
+        while let Some(next) = waiting.pop_front() {
+            match tx_cmds.try_send(next) {
+                Ok(()) => {}
+                Err(TrySendError::Full(rejected)) if evictions > 0 => {
+                    evictions -= 1;
+                    waiting.push_front(rejected);
+                    match rx_cmds.try_recv() {
+                        Ok(oldest) if oldest.is_operation() => {
+                            self.dropped
+                                .fetch_add(oldest.dropped_count(), Ordering::Relaxed);
+                        }
+                        Ok(oldest) => waiting.push_front(oldest),
+                        Err(_) => {}
+                    }
+                }
+                Err(err) if waiting.is_empty() => return Err(err),
+                Err(_) => error!("Failed to requeue a control command, the queue is full"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Push an operation to a lock-free queue, applying the overflow policy
+    /// if it is full.
+    fn push(&self, queue: &ArrayQueue<Command>, command: Command) {
+        let dropped = match self.overflow {
+            OverflowPolicy::DropNewest => queue.push(command).err(),
+            OverflowPolicy::DropOldest => queue.force_push(command),
+            OverflowPolicy::Block(timeout) => {
+                let deadline = Instant::now() + timeout;
+                let backoff = Backoff::new();
+                let mut command = command;
+                loop {
+                    match queue.push(command) {
+                        Ok(()) => break None,
+                        Err(rejected) => {
+                            let now = Instant::now();
+                            if now >= deadline {
+                                break Some(rejected);
+                            }
+                            command = rejected;
+                            // the worker drains the queue at its next poll
+                            if backoff.is_completed() {
+                                std::thread::sleep(QUEUE_POLL_INTERVAL.min(deadline - now) / 4);
+                            } else {
+                                backoff.snooze();
+                            }
+                        }
+                    }
+                }
+            }
+        };
+
+        if let Some(command) = dropped {
+            self.dropped
+                .fetch_add(command.dropped_count(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Convert a metric key into labels, applying the prefix and global labels.
+///
+/// Labels are sorted by name as the remote write spec requires. Labels with
+/// empty values are omitted, and the series' own labels take precedence
+/// over global labels of the same name.
+///
+/// Fails with the offending name if a name is invalid and
+/// [`NameSanitization::Drop`] is configured.
+fn series_labels(
+    key: &Key,
+    metric_type: MetricType,
+    unit: Option<&Unit>,
+    config: &Builder,
+) -> Result<Vec<types::Label>, String> {
+    // the process collector's series keep their standard names
+    #[cfg(feature = "process-metrics")]
+    let prefix = match key
+        .labels()
+        .any(|label| label.key() == crate::process::MARKER)
+    {
+        true => "",
+        false => config.prefix.as_str(),
+    };
+    #[cfg(not(feature = "process-metrics"))]
+    let prefix = config.prefix.as_str();
+
+    let mut labels = vec![types::Label {
+        name: "__name__".to_owned(),
+        value: metric_name(key.name(), prefix, metric_type, unit, config)
+            .ok_or_else(|| format!("{prefix}{}", key.name()))?,
+    }];
+
+    let names = key
+        .labels()
+        .map(|label| (label.key(), label.value()))
+        .chain(
+            config
+                .global_labels
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+
+    for (name, value) in names {
+        labels.push(types::Label {
+            name: config
+                .name_validation
+                .label_name(name, config.name_sanitization)
+                .ok_or_else(|| name.to_owned())?
+                .into_owned(),
+            value: value.to_owned(),
+        })
+    }
+
+    // an empty value is the same as the label being absent
+    labels.retain(|label| !label.value.is_empty());
+    // the sort is stable, so the first of any duplicates is kept
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    labels.dedup_by(|label, previous| label.name == previous.name);
+
+    Ok(labels)
+}
+
+/// Full name of a metric as sent to the receiver, or `None` if the series
+/// should be dropped.
+fn metric_name(
+    name: &str,
+    prefix: &str,
+    metric_type: MetricType,
+    unit: Option<&Unit>,
+    config: &Builder,
+) -> Option<String> {
+    let mut name = format!("{prefix}{name}");
+
+    // the unit goes before `_total`
+    let total = metric_type == MetricType::Counter
+        && (config.total_suffix || name.ends_with(protocol::TOTAL_SUFFIX));
+    if total && name.ends_with(protocol::TOTAL_SUFFIX) {
+        name.truncate(name.len() - protocol::TOTAL_SUFFIX.len());
+    }
+
+    // counts are dimensionless, Prometheus names them without a unit
+    if config.unit_suffixes
+        && let Some(unit) = unit.filter(|unit| **unit != Unit::Count)
+    {
+        let suffix = format!("_{}", unit.as_str());
+        if !name.ends_with(&suffix) {
+            name.push_str(&suffix);
+        }
+    }
+
+    if total {
+        name.push_str(protocol::TOTAL_SUFFIX);
+    }
+
+    config
+        .name_validation
+        .metric_name(&name, config.name_sanitization)
+        .map(Cow::into_owned)
+}
+
+/// Series of a histogram, with `exemplars` attached. Only the series with
+/// exemplars are returned once the histogram was sent.
+fn histogram_series(
+    labels: Vec<types::Label>,
+    histogram: &Distribution,
+    exemplars: Vec<types::Exemplar>,
+) -> Vec<types::TimeSeries> {
+    match histogram {
+        Distribution::Native(histogram) => vec![types::TimeSeries {
+            labels,
+            samples: vec![],
+            exemplars,
+            histograms: if histogram.is_sent() {
+                vec![]
+            } else {
+                vec![histogram.to_proto()]
+            },
+        }],
+        Distribution::Classic(_) | Distribution::Summary(_) | Distribution::Gauges(_) => {
+            let mut series: Vec<_> = sample_series(&labels, histogram)
+                .into_iter()
+                .map(|(labels, value)| types::TimeSeries {
+                    labels,
+                    samples: if histogram.is_sent() {
+                        vec![]
+                    } else {
+                        vec![types::Sample {
+                            value,
+                            timestamp: histogram.timestamp(),
+                        }]
+                    },
+                    exemplars: vec![],
+                    histograms: vec![],
+                })
+                .collect();
+
+            // exemplars go on the bucket containing their value, or on the
+            // `_count` of other modes
+            for exemplar in exemplars {
+                let index = match histogram {
+                    Distribution::Classic(histogram) => histogram.bucket(exemplar.value),
+                    _ => series.len() - 1,
+                };
+                series[index].exemplars.push(exemplar);
+            }
+
+            series.retain(|series| !series.samples.is_empty() || !series.exemplars.is_empty());
+            series
+        }
+    }
+}
+
+/// Write a backfill request to the sinks, or every endpoint if there are
+/// none, returning `true` if it was accepted everywhere.
+fn write_backfill(
+    config: &Builder,
+    sinks: &[SharedSink],
+    transport: Option<&dyn Transport>,
+    request: &types::WriteRequest,
+) -> bool {
+    let mut delivered = true;
+    if !sinks.is_empty() {
+        for sink in sinks {
+            if let Err(err) = sink.send(request) {
+                error!("Sink failed: {err}");
+                delivered = false;
+            }
+        }
+        return delivered;
+    }
+
+    let Some(transport) = transport else {
+        return false;
+    };
+    for endpoint in &config.endpoints {
+        if let Err(err) = crate::remote::send_blocking(config, transport, endpoint, request) {
+            error!("Backfill to {endpoint} failed: {err}");
+            delivered = false;
+        }
+    }
+    delivered
+}
+
+/// Labels and values of the float series of a histogram: `_bucket`, `_sum`
+/// and `_count` for classic histograms, each quantile, `_sum` and `_count`
+/// for summaries, `_min`, `_max`, `_sum` and `_count` for gauges.
+///
+/// Native histograms have no float representation, they're given a single
+/// `+Inf` bucket.
+fn sample_series(
+    labels: &[types::Label],
+    histogram: &Distribution,
+) -> Vec<(Vec<types::Label>, f64)> {
+    let (mut series, sum, count) = match histogram {
+        Distribution::Native(histogram) => (
+            vec![(
+                suffixed(labels, "_bucket", Some(("le", "+Inf".to_owned()))),
+                histogram.count() as f64,
+            )],
+            histogram.sum(),
+            histogram.count(),
+        ),
+        Distribution::Classic(histogram) => (
+            histogram
+                .buckets()
+                .map(|(bound, count)| {
+                    let le = exposition::format_value(bound);
+                    (suffixed(labels, "_bucket", Some(("le", le))), count as f64)
+                })
+                .collect(),
+            histogram.sum(),
+            histogram.count(),
+        ),
+        Distribution::Summary(summary) => (
+            summary
+                .quantiles()
+                .map(|(quantile, value)| {
+                    let quantile = exposition::format_value(quantile);
+                    (suffixed(labels, "", Some(("quantile", quantile))), value)
+                })
+                .collect(),
+            summary.sum(),
+            summary.count(),
+        ),
+        Distribution::Gauges(histogram) => (
+            vec![
+                (suffixed(labels, "_min", None), histogram.min()),
+                (suffixed(labels, "_max", None), histogram.max()),
+            ],
+            histogram.sum(),
+            histogram.count(),
+        ),
+    };
+
+    series.push((suffixed(labels, "_sum", None), sum));
+    series.push((suffixed(labels, "_count", None), count as f64));
+    series
+}
+
+/// Copy of `labels` with `suffix` appended to the metric name and an
+/// optional extra label, e.g. `le`.
+fn suffixed(
+    labels: &[types::Label],
+    suffix: &str,
+    extra: Option<(&str, String)>,
+) -> Vec<types::Label> {
+    let mut labels = labels.to_vec();
+    for label in &mut labels {
+        if label.name == "__name__" {
+            label.value.push_str(suffix);
+        }
+    }
+
+    if let Some((name, value)) = extra {
+        labels.retain(|label| label.name != name);
+        labels.push(types::Label {
+            name: name.to_owned(),
+            value,
+        });
+        labels.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    labels
+}
+
+/// State owned by the worker thread.
+struct Worker {
+    config: Builder,
+    registry: Registry,
+    delivery: Delivery,
+    /// Traffic counters of each remote, keyed by endpoint.
+    remote_stats: Vec<(String, Arc<Stats>)>,
+    /// Operations dropped by the recorder because the queue was full.
+    dropped: Arc<AtomicU64>,
+    /// Value of `dropped` when last recorded as a self-metric.
+    dropped_recorded: u64,
+    /// Samples evicted over the registry budget, not yet recorded as a
+    /// self-metric.
+    evicted: u64,
+    /// Repeated warnings about dropped samples, logged at most once a
+    /// minute.
+    warnings: LogThrottle,
+    /// When the last values are next re-sent, if enabled.
+    next_heartbeat: Instant,
+    /// When the metrics of each flush interval override are next sent,
+    /// followed by the rest.
+    next_flushes: Vec<Instant>,
+    /// Counters and gauges updated in place by their handles, if enabled.
+    atomics: Option<Arc<Atomics>>,
+    /// Keys of the registered metric handles, shared with the recorder.
+    interner: Arc<Interner>,
+    /// Operations buffered by each recording thread, if enabled.
+    thread_buffers: Option<Arc<ThreadBuffers>>,
+    /// Lock-free queues of operations, if enabled.
+    queues: Option<OperationQueues>,
+    /// Series of the last write, cleared and filled again by the next.
+    timeseries: Vec<types::TimeSeries>,
+    /// Labels of each series as sent, reused across writes.
+    label_cache: LabelCache,
+    /// Created series of counters in the last write.
+    created: CreatedSeries,
+    /// Aligned timestamp each series was last sent at, if enabled.
+    aligned: KeyMap<i64>,
+    /// Described metrics, keyed by prefixed metric family name.
+    descriptions: HashMap<String, types::MetricMetadata>,
+    /// Invalid names already warned about.
+    invalid_names: HashSet<String>,
+    /// Units of described metrics, keyed by unprefixed name.
+    units: HashMap<String, Unit>,
+    /// Sinks backfills are written to, shared with the outputs.
+    sinks: Vec<SharedSink>,
+    /// Transport backfills are sent with if there are no sinks.
+    transport: Option<Arc<dyn Transport>>,
+}
+
+impl Worker {
+    fn new(
+        config: Builder,
+        dropped: Arc<AtomicU64>,
+        health: Arc<Health>,
+    ) -> Result<Self, BuildError> {
+        let mut sinks = config.sinks.clone();
+        if let Some(path) = &config.file_sink {
+            let file = FileSink::open(path).map_err(BuildError::File)?;
+            sinks.push(SharedSink::new(file));
+        }
+
+        let transport = if sinks.is_empty() {
+            Some(config.http_transport()?)
+        } else {
+            None
+        };
+        let remotes = match &transport {
+            Some(transport) => config
+                .endpoints
+                .iter()
+                .map(|endpoint| {
+                    Remote::new(endpoint.clone(), &config, transport.clone(), health.clone())
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        let mut registry = Registry::new();
+        registry.counter_reset = config.counter_reset;
+        registry.raw_samples = config.raw_samples;
+        registry.sample_limit = config
+            .max_series_samples
+            .map(|max| (max, config.sample_eviction));
+        if let Some(path) = &config.counter_state {
+            match counter_state::load(path) {
+                Ok(saved) => {
+                    let now = config.clock.now();
+                    for counter in saved {
+                        registry.counter_restore(now, counter.key, counter.value, counter.created);
+                    }
+                }
+                Err(err) => warn!("Failed to restore counters from {}: {err}", path.display()),
+            }
+        }
+
+        let next_heartbeat = config.clock.instant();
+        let next_flushes = config
+            .flush_intervals
+            .iter()
+            .map(|(_, interval)| *interval)
+            .chain([config.batch_interval])
+            .map(|interval| next_heartbeat + interval)
+            .collect();
+
+        let remote_stats = remotes
+            .iter()
+            .map(|remote| (remote.endpoint().to_owned(), remote.stats().clone()))
+            .collect();
+        let delivery = Delivery::new(&config, Outputs::new(remotes, sinks.clone()))
+            .map_err(BuildError::Spawn)?;
+
+        Ok(Self {
+            config,
+            registry,
+            delivery,
+            remote_stats,
+            dropped,
+            dropped_recorded: 0,
+            evicted: 0,
+            warnings: LogThrottle::new(crate::remote::ERROR_SUMMARY_INTERVAL),
+            next_heartbeat,
+            next_flushes,
+            atomics: None,
+            interner: Arc::default(),
+            thread_buffers: None,
+            queues: None,
+            timeseries: vec![],
+            label_cache: LabelCache::default(),
+            created: CreatedSeries::new(),
+            aligned: KeyMap::default(),
+            descriptions: HashMap::new(),
+            invalid_names: HashSet::new(),
+            units: HashMap::new(),
+            sinks,
+            transport,
+        })
+    }
+
+    /// Handle a command from the recorder or a handle.
+    fn handle(&mut self, cmd: Command) {
+        match cmd {
+            Command::Operation(timestamp, key, op) => {
+                self.apply(timestamp, key, op);
+            }
+            Command::Interned(timestamp, id, op) => {
+                self.apply_interned(timestamp, id, op);
+            }
+            Command::Batch(operations) => {
+                for (timestamp, series, op) in operations {
+                    self.apply_series(timestamp, series, op);
+                }
+            }
+            Command::Metadata(key, metric_type, unit, help) => {
+                self.describe(key, metric_type, unit, help);
+            }
+            #[cfg(feature = "process-metrics")]
+            Command::Process(timestamp, samples) => self.record_process(timestamp, samples),
+            Command::ReplayDeadLetters(reply) => {
+                let _ = reply.send(self.replay_dead_letters());
+            }
+            Command::Flush(reply) => {
+                let _ = reply.send(self.flush());
+            }
+            Command::Render(reply) => {
+                self.collect();
+                if self.config.summary_window.is_some() {
+                    self.registry.expire_windows(self.config.clock.now());
+                }
+                let _ = reply.send(self.render_text());
+            }
+            Command::Backfill(samples, reply) => self.backfill(samples, reply),
+            Command::Shutdown(reply) => {
+                // only one shutdown is acted on
+                let _ = reply.send(());
+            }
+        }
+    }
+
+    /// Apply a metric operation to the registry.
+    fn apply(&mut self, timestamp: SystemTime, key: Key, op: MetricOperation) {
+        let registry = &mut self.registry;
+        match op {
+            MetricOperation::IncrementCounter(value) => {
+                registry.counter_increment(timestamp, key, value);
+            }
+            MetricOperation::SetCounter(value) => {
+                registry.counter_set(timestamp, key, value);
+            }
+            MetricOperation::IncrementGauge(value) => {
+                registry.gauge_increment(timestamp, key, value);
+            }
+            MetricOperation::DecrementGauge(value) => {
+                registry.gauge_decrement(timestamp, key, value);
+            }
+            MetricOperation::SetGauge(value) => {
+                registry.gauge_set(timestamp, key, value);
+            }
+            MetricOperation::RecordHistogram(value) => {
+                let mode = self.config.histogram_mode_of(key.name());
+                let window = self
+                    .config
+                    .summary_window
+                    .map(|window| Window::new(window, self.config.summary_age_buckets));
+                registry.histogram_record(
+                    timestamp,
+                    key,
+                    value,
+                    mode,
+                    self.config.histogram_schema,
+                    window,
+                );
+            }
+            MetricOperation::Remove => {
+                if let Some(atomics) = &self.atomics {
+                    atomics.remove(&key);
+                }
+                registry.remove(timestamp, key);
+            }
+            MetricOperation::Exemplar(value, labels) => {
+                registry.exemplar(timestamp, key, value, labels);
+            }
+        }
+    }
+
+    /// Hand all unsent samples to every remote.
+    ///
+    /// Returns `true` if every remote accepted everything pending.
+    fn write(&mut self) -> bool {
+        self.send(false)
+    }
+
+    /// Hand all unsent samples to every remote, waiting for them to be sent
+    /// if `wait` is set and sending is pipelined.
+    fn send(&mut self, wait: bool) -> bool {
+        self.collect();
+
+        if self.config.summary_window.is_some() {
+            self.registry.expire_windows(self.config.clock.now());
+        }
+
+        if let Some(timeout) = self.config.idle_timeout {
+            let now = self.config.clock.now();
+            if let Some(cutoff) = now.checked_sub(timeout) {
+                let expired = self
+                    .registry
+                    .expire(now, cutoff, self.config.idle_stale_markers);
+                if expired > 0 {
+                    debug!("forgot {expired} idle series");
+                }
+            }
+        }
+
+        let dropped = self.registry.trim(self.config.max_buffered_samples);
+        if dropped > 0
+            && let Some(suppressed) = self
+                .warnings
+                .record("Buffer full".to_owned(), Instant::now())
+        {
+            warn!(suppressed, "Buffer full, dropped {dropped} unsent samples");
+        }
+
+        if let Some(max) = self.config.max_registry_samples {
+            let evicted = self.registry.shrink(max);
+            if evicted > 0 {
+                self.evicted += evicted as u64;
+                if let Some(suppressed) = self
+                    .warnings
+                    .record("Registry over its budget".to_owned(), Instant::now())
+                {
+                    warn!(
+                        suppressed,
+                        "Registry over its budget of {max} samples, evicted {evicted}"
+                    );
+                }
+            }
+        }
+
+        let mut requests = self.requests();
+        let delivered = self
+            .delivery
+            .write(&self.config, &mut requests, &mut self.created, wait);
+        self.recycle(requests);
+
+        if self.config.self_metrics {
+            self.record_self_metrics();
+        }
+
+        delivered
+    }
+
+    /// Keep the allocations of written requests for the next write.
+    fn recycle(&mut self, requests: Vec<types::WriteRequest>) {
+        let mut largest = None;
+        for mut request in requests {
+            for series in request.timeseries.drain(..) {
+                self.label_cache.release(series.labels);
+            }
+            if request.timeseries.capacity() > largest.as_ref().map_or(0, Vec::capacity) {
+                largest = Some(request.timeseries);
+            }
+        }
+
+        if let Some(timeseries) = largest
+            && timeseries.capacity() > self.timeseries.capacity()
+        {
+            self.timeseries = timeseries;
+        }
+    }
+
+    /// Apply the operations of recording threads' buffers and the updates of
+    /// counters and gauges updated in place.
+    fn collect(&mut self) {
+        self.drain_queues();
+
+        if let Some(buffers) = &self.thread_buffers {
+            for (timestamp, series, op) in buffers.drain() {
+                self.apply_series(timestamp, series, op);
+            }
+        }
+
+        let Some(atomics) = &self.atomics else {
+            return;
+        };
+
+        let now = self.config.clock.now();
+        for (key, op) in atomics.collect() {
+            self.apply(now, key, op);
+        }
+    }
+
+    /// Handle the operations waiting in the lock-free queues.
+    fn drain_queues(&mut self) {
+        let Some(queues) = self.queues.clone() else {
+            return;
+        };
+
+        // bounded, so recording threads that keep up can't hold the worker
+        for queue in queues.iter() {
+            for _ in 0..queue.capacity() {
+                let Some(cmd) = queue.pop() else {
+                    break;
+                };
+                self.handle(cmd);
+            }
+        }
+    }
+
+    /// Apply an operation on the series of an interned key.
+    fn apply_interned(&mut self, timestamp: SystemTime, id: KeyId, op: MetricOperation) {
+        if let Some(key) = self.interner.resolve(id) {
+            self.apply(timestamp, key, op);
+        }
+    }
+
+    /// Apply an operation on a series by key or interned id.
+    fn apply_series(&mut self, timestamp: SystemTime, series: SeriesKey, op: MetricOperation) {
+        match series {
+            SeriesKey::Key(key) => self.apply(timestamp, key, op),
+            SeriesKey::Interned(id) => self.apply_interned(timestamp, id, op),
+        }
+    }
+
+    /// Write every metric, whether or not its flush interval has elapsed.
+    fn flush(&mut self) -> bool {
+        self.next_flushes.fill(self.config.clock.instant());
+        self.send(true)
+    }
+
+    /// Write historical samples directly, bypassing the registry, on a
+    /// thread of their own so operations keep being applied meanwhile,
+    /// replying whether every sample was delivered.
+    ///
+    /// Samples are sent in requests of at most [`BACKFILL_SAMPLES`] samples
+    /// unless `max_samples_per_send` is lower, one after the other.
+    fn backfill(&mut self, samples: Vec<(Key, SystemTime, f64)>, reply: Sender<bool>) {
+        // receivers reject samples past the age limit or from the future
+        let now = crate::registry::timestamp_millis(self.config.clock.now());
+        let cutoff = crate::remote::age_cutoff(&self.config).unwrap_or(i64::MIN);
+        let mut rejected = 0;
+
+        let mut series: BTreeMap<Key, Vec<types::Sample>> = BTreeMap::new();
+        for (key, timestamp, value) in samples {
+            let timestamp = crate::registry::timestamp_millis(timestamp);
+            if !(cutoff..=now).contains(&timestamp) {
+                rejected += 1;
+                continue;
+            }
+            series
+                .entry(key)
+                .or_default()
+                .push(types::Sample { value, timestamp });
+        }
+        if rejected > 0 {
+            warn!(
+                "Dropping {rejected} backfilled samples past the sample age limit or in the future"
+            );
+        }
+
+        let mut timeseries = vec![];
+        for (key, mut samples) in series {
+            // the last of several samples at the same time wins
+            samples.sort_by_key(|sample| sample.timestamp);
+            samples.dedup_by(|next, previous| {
+                let duplicate = next.timestamp == previous.timestamp;
+                if duplicate {
+                    *previous = *next;
+                }
+                duplicate
+            });
+
+            match series_labels(
+                &key,
+                MetricType::Gauge,
+                self.units.get(key.name()),
+                &self.config,
+            ) {
+                Ok(labels) => timeseries.push(types::TimeSeries {
+                    labels,
+                    samples,
+                    exemplars: vec![],
+                    histograms: vec![],
+                }),
+                Err(name) => {
+                    warn!("Dropping backfill of series with invalid name {name:?}");
+                    rejected += samples.len();
+                }
+            }
+        }
+
+        let max_samples = self
+            .config
+            .max_samples_per_send
+            .map_or(BACKFILL_SAMPLES, |max| max.min(BACKFILL_SAMPLES));
+        let requests: Vec<_> = split(timeseries, self.config.max_request_bytes, Some(max_samples))
+            .into_iter()
+            .map(|timeseries| types::WriteRequest {
+                metadata: self.metadata(&timeseries),
+                timeseries,
+            })
+            .collect();
+
+        let config = self.config.clone();
+        let sinks = self.sinks.clone();
+        let transport = self.transport.clone();
+        let spawned = std::thread::Builder::new()
+            .name("prom-write-backfill".to_owned())
+            .spawn(move || {
+                let mut delivered = rejected == 0;
+                for (index, request) in requests.iter().enumerate() {
+                    if index > 0 {
+                        std::thread::sleep(config.backfill_interval);
+                    }
+                    delivered &= write_backfill(&config, &sinks, transport.as_deref(), request);
+                }
+                let _ = reply.send(delivered);
+            });
+        // the reply is dropped with the thread, so the caller sees a failure
+        if let Err(err) = spawned {
+            error!("Failed to start backfill thread: {err}");
+        }
+    }
+
+    /// Save counter totals to the state file, if configured.
+    fn save_counters(&self) {
+        if let Some(path) = &self.config.counter_state
+            && let Err(err) = counter_state::save(path, &self.registry.counters)
+        {
+            error!("Failed to save counters to {}: {err}", path.display());
+        }
+    }
+
+    /// Record exporter health into the registry, to be sent with the next
+    /// write.
+    ///
+    /// Counters are recorded when they increased and the send duration when
+    /// it changed. Sending these counts as traffic too, so the exporter keeps
+    /// writing every batch interval even while nothing else is recorded.
+    fn record_self_metrics(&mut self) {
+        let now = self.config.clock.now();
+
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > self.dropped_recorded {
+            self.registry.counter_increment(
+                now,
+                Key::from_name("prometheus_write_operations_dropped_total"),
+                dropped - self.dropped_recorded,
+            );
+            self.dropped_recorded = dropped;
+        }
+
+        if self.evicted > 0 {
+            self.registry.counter_increment(
+                now,
+                Key::from_name("prometheus_write_samples_evicted_total"),
+                self.evicted,
+            );
+            self.evicted = 0;
+        }
+
+        for (endpoint, stats) in &self.remote_stats {
+            let labels = vec![metrics::Label::new("url", endpoint.clone())];
+            let key = |name: &'static str| Key::from_parts(name, labels.clone());
+
+            let counters = [
+                ("prometheus_write_samples_total", &stats.samples),
+                ("prometheus_write_samples_dropped_total", &stats.dropped),
+                ("prometheus_write_requests_total", &stats.requests),
+                ("prometheus_write_request_failures_total", &stats.failures),
+                ("prometheus_write_sent_bytes_total", &stats.bytes),
+            ];
+
+            for (name, value) in counters {
+                let value = value.swap(0, Ordering::Relaxed);
+                if value > 0 {
+                    self.registry.counter_increment(now, key(name), value);
+                }
+            }
+
+            let latency = stats.latency.swap(0, Ordering::Relaxed);
+            let key = key("prometheus_write_send_duration_seconds");
+            let seconds = Duration::from_micros(latency).as_secs_f64();
+            let recorded = self
+                .registry
+                .gauges
+                .get(&key)
+                .and_then(|samples| samples.all().last().map(|sample| sample.value));
+            if latency > 0 && recorded != Some(seconds) {
+                self.registry.gauge_set(now, key, seconds);
+            }
+        }
+    }
+
+    /// Record the resource usage of the process, described under the
+    /// unprefixed names its marked series are sent as.
+    #[cfg(feature = "process-metrics")]
+    fn record_process(&mut self, timestamp: SystemTime, samples: Vec<crate::process::Sample>) {
+        for sample in samples {
+            if !self.descriptions.contains_key(sample.name) {
+                self.descriptions.insert(
+                    sample.name.to_owned(),
+                    types::MetricMetadata {
+                        r#type: MetricType::Gauge.into(),
+                        metric_family_name: sample.name.to_owned(),
+                        help: sample.help.to_owned(),
+                        unit: String::new(),
+                    },
+                );
+            }
+            self.apply(
+                timestamp,
+                sample.key(),
+                MetricOperation::SetGauge(sample.value),
+            );
+        }
+    }
+
+    /// Store the description of a metric, replacing any previous one.
+    fn describe(
+        &mut self,
+        key: KeyName,
+        metric_type: MetricType,
+        unit: Option<Unit>,
+        help: SharedString,
+    ) {
+        if let Some(unit) = unit
+            && self.units.insert(key.as_str().to_owned(), unit) != Some(unit)
+        {
+            // names of the metric's series may have a unit suffix
+            self.label_cache.clear();
+        }
+
+        let mode = match metric_type {
+            MetricType::Histogram => Some(self.config.histogram_mode_of(key.as_str())),
+            _ => None,
+        };
+        let (metric_type, suffixes): (_, &[&str]) = match mode {
+            Some(HistogramMode::Summary(_)) => (MetricType::Summary, &[""]),
+            // each gauge is its own family
+            Some(HistogramMode::Gauges) => (MetricType::Gauge, &["_min", "_max", "_sum", "_count"]),
+            _ => (metric_type, &[""]),
+        };
+
+        let Some(metric_family_name) = metric_name(
+            key.as_str(),
+            &self.config.prefix,
+            metric_type,
+            unit.as_ref(),
+            &self.config,
+        ) else {
+            return;
+        };
+
+        for suffix in suffixes {
+            let metric_family_name = format!("{metric_family_name}{suffix}");
+            self.descriptions.insert(
+                metric_family_name.clone(),
+                types::MetricMetadata {
+                    r#type: metric_type.into(),
+                    metric_family_name,
+                    help: help.to_string(),
+                    unit: unit
+                        .map(|unit| unit.as_str().to_owned())
+                        .unwrap_or_default(),
+                },
+            );
+        }
+    }
+
+    /// Descriptions of the metric families in `timeseries`.
+    fn metadata(&self, timeseries: &[types::TimeSeries]) -> Vec<types::MetricMetadata> {
+        if self.descriptions.is_empty() {
+            return vec![];
+        }
+
+        timeseries
+            .iter()
+            .flat_map(|series| &series.labels)
+            .filter(|label| label.name == "__name__")
+            .map(|label| label.value.as_str())
+            .filter_map(|name| protocol::family_metadata(name, |name| self.descriptions.get(name)))
+            .map(|metadata| (metadata.metric_family_name.as_str(), metadata))
+            .collect::<BTreeMap<_, _>>()
+            .into_values()
+            .cloned()
+            .collect()
+    }
+
+    /// Render the latest value of every series in the Prometheus text
+    /// format, grouped by metric family.
+    fn render_text(&self) -> String {
+        let labels_of = |key: &Key, metric_type| {
+            series_labels(key, metric_type, self.units.get(key.name()), &self.config).ok()
+        };
+
+        let mut families: BTreeMap<String, (MetricType, String)> = BTreeMap::new();
+
+        for (metric_type, series) in [
+            (MetricType::Counter, &self.registry.counters),
+            (MetricType::Gauge, &self.registry.gauges),
+        ] {
+            for (key, samples) in sorted(series) {
+                if let Some(sample) = samples.all().last()
+                    && let Some(labels) = labels_of(key, metric_type)
+                    && let Some(name) = protocol::metric_name(&labels)
+                {
+                    let (_, lines) = families
+                        .entry(name.to_owned())
+                        .or_insert_with(|| (metric_type, String::new()));
+                    exposition::sample(lines, &labels, sample.value, sample.timestamp);
+                }
+            }
+        }
+
+        for (key, histogram) in sorted(&self.registry.histograms) {
+            let Some(labels) = labels_of(key, MetricType::Histogram) else {
+                continue;
+            };
+            let Some(name) = protocol::metric_name(&labels) else {
+                continue;
+            };
+
+            let metric_type = match histogram {
+                Distribution::Summary(_) => MetricType::Summary,
+                Distribution::Gauges(_) => MetricType::Gauge,
+                _ => MetricType::Histogram,
+            };
+            for (labels, value) in sample_series(&labels, histogram) {
+                // each gauge is its own family
+                let family = match metric_type {
+                    MetricType::Gauge => protocol::metric_name(&labels).unwrap_or(name),
+                    _ => name,
+                };
+                let (_, lines) = families
+                    .entry(family.to_owned())
+                    .or_insert_with(|| (metric_type, String::new()));
+                exposition::sample(lines, &labels, value, histogram.timestamp());
+            }
+        }
+
+        let mut text = String::new();
+        for (name, (metric_type, lines)) in families {
+            let help = self
+                .descriptions
+                .get(&name)
+                .map(|metadata| metadata.help.as_str());
+            exposition::header(&mut text, &name, metric_type, help);
+            text.push_str(&lines);
+        }
+        text
+    }
+
+    /// Timestamp to re-send the last values at, if a heartbeat is due.
+    fn heartbeat_due(&mut self) -> Option<i64> {
+        let interval = self.config.heartbeat?;
+        let now = self.config.clock.instant();
+        if now < self.next_heartbeat {
+            return None;
+        }
+
+        self.next_heartbeat = now + interval;
+        Some(crate::registry::timestamp_millis(self.config.clock.now()))
+    }
+
+    /// Whether the metrics of each flush interval override are due to be
+    /// sent, followed by the rest, rescheduling those that are.
+    fn flushes_due(&mut self) -> Vec<bool> {
+        if self.config.flush_intervals.is_empty() {
+            return vec![true];
+        }
+
+        let now = self.config.clock.instant();
+        // ticks may come slightly early
+        let slack = self.config.tick_interval() / 2;
+        let intervals = self
+            .config
+            .flush_intervals
+            .iter()
+            .map(|(_, interval)| *interval)
+            .chain([self.config.batch_interval]);
+
+        self.next_flushes
+            .iter_mut()
+            .zip(intervals)
+            .map(|(next, interval)| {
+                let due = now + slack >= *next;
+                if due {
+                    *next = now + interval;
+                }
+                due
+            })
+            .collect()
+    }
+
+    /// Start of the current interval of each flush interval override,
+    /// followed by the batch interval, for aligned timestamps.
+    fn aligned_ticks(&self) -> Vec<i64> {
+        let now = crate::registry::timestamp_millis(self.config.clock.now());
+        self.config
+            .flush_intervals
+            .iter()
+            .map(|(_, interval)| *interval)
+            .chain([self.config.batch_interval])
+            .map(|interval| {
+                let interval = (interval.as_millis() as i64).max(1);
+                now - now.rem_euclid(interval)
+            })
+            .collect()
+    }
+
+    /// Group unsent samples into write requests, marking them as sent.
+    fn requests(&mut self) -> Vec<types::WriteRequest> {
+        let _span = debug_span!("assemble").entered();
+
+        let flushes = self.flushes_due();
+        let heartbeat = self.heartbeat_due();
+        let config = &self.config;
+        // with aligned timestamps, series already sent at the current tick of
+        // their flush group wait for the next
+        let ticks = config.aligned_timestamps.then(|| self.aligned_ticks());
+        let tick = |key: &Key| Some(ticks.as_ref()?[config.flush_group(key.name())]);
+        let aligned = &self.aligned;
+        let due = |key: &Key| {
+            flushes[config.flush_group(key.name())]
+                && tick(key).is_none_or(|tick| aligned.get(key) != Some(&tick))
+        };
+        // samples whose value differs from the one before, if enabled
+        let send_on_change = self.config.send_on_change;
+        let changed = |samples: &Samples, values: Vec<types::Sample>| {
+            if !send_on_change {
+                return values;
+            }
+            let mut previous = samples.sent_value();
+            values
+                .into_iter()
+                .filter(|sample| {
+                    let changed =
+                        previous.is_none_or(|value| value.to_bits() != sample.value.to_bits());
+                    previous = Some(sample.value);
+                    changed
+                })
+                .collect()
+        };
+        // unsent samples, or the last value again on a heartbeat
+        let pending = |samples: &Samples| match heartbeat {
+            _ if !samples.is_sent() => changed(samples, samples.all().clone()),
+            Some(timestamp) => samples
+                .all()
+                .last()
+                .map(|last| types::Sample {
+                    value: last.value,
+                    timestamp,
+                })
+                .into_iter()
+                .collect(),
+            None => vec![],
+        };
+
+        let registry = &self.registry;
+        let units = &self.units;
+        let label_cache = &mut self.label_cache;
+        let created = &mut self.created;
+        created.clear();
+
+        let mut timeseries = std::mem::take(&mut self.timeseries);
+        let mut invalid = BTreeSet::new();
+        let mut sent_at = vec![];
+        // values sent of each counter and gauge, to compare the next against
+        let mut sent_counters = vec![];
+        let mut sent_gauges = vec![];
+
+        for (key, samples) in sorted(&registry.counters) {
+            if !due(key) {
+                continue;
+            }
+
+            let exemplars: Vec<_> = registry.exemplars.get(key).cloned().into_iter().collect();
+
+            // skip if this metric has already been sent
+            if samples.is_sent() && exemplars.is_empty() && heartbeat.is_none() {
+                continue;
+            }
+
+            let labels = match label_cache.get(key, MetricType::Counter, || {
+                series_labels(key, MetricType::Counter, units.get(key.name()), config)
+            }) {
+                Ok(labels) => labels,
+                Err(name) => {
+                    invalid.insert(name);
+                    continue;
+                }
+            };
+
+            let values = pending(samples);
+            // unchanged since last sent
+            if values.is_empty() && exemplars.is_empty() {
+                label_cache.release(labels);
+                continue;
+            }
+
+            if send_on_change && let Some(last) = values.last() {
+                sent_counters.push((key.clone(), last.value));
+            }
+
+            let start = timeseries.len();
+            if config.created_timestamps
+                && !samples.is_sent()
+                && let Some(last) = samples.all().last()
+            {
+                let series = protocol::created_series(&labels, samples.created(), last.timestamp);
+                created.insert(protocol::created_key(&series.labels));
+                timeseries.push(series);
+            }
+
+            timeseries.push(types::TimeSeries {
+                labels,
+                samples: values,
+                exemplars,
+                histograms: vec![],
+            });
+            if let Some(tick) = tick(key) {
+                timeseries[start..]
+                    .iter_mut()
+                    .for_each(|series| align(series, tick));
+                sent_at.push((key.clone(), tick));
+            }
+        }
+
+        for (key, samples) in sorted(&registry.gauges) {
+            if !due(key) {
+                continue;
+            }
+
+            let exemplars: Vec<_> = registry.exemplars.get(key).cloned().into_iter().collect();
+
+            // skip if this metric has already been sent
+            if samples.is_sent() && exemplars.is_empty() && heartbeat.is_none() {
+                continue;
+            }
+
+            let labels = match label_cache.get(key, MetricType::Gauge, || {
+                series_labels(key, MetricType::Gauge, units.get(key.name()), config)
+            }) {
+                Ok(labels) => labels,
+                Err(name) => {
+                    invalid.insert(name);
+                    continue;
+                }
+            };
+
+            let values = if samples.is_sent() {
+                pending(samples)
+            } else {
+                let aggregation = config
+                    .gauge_aggregations
+                    .get(key.name())
+                    .unwrap_or(&config.gauge_aggregation);
+                changed(samples, aggregation.apply(samples))
+            };
+            // unchanged since last sent
+            if values.is_empty() && exemplars.is_empty() {
+                label_cache.release(labels);
+                continue;
+            }
+
+            if send_on_change && let Some(last) = values.last() {
+                sent_gauges.push((key.clone(), last.value));
+            }
+
+            let mut series = types::TimeSeries {
+                labels,
+                samples: values,
+                exemplars,
+                histograms: vec![],
+            };
+            if let Some(tick) = tick(key) {
+                align(&mut series, tick);
+                sent_at.push((key.clone(), tick));
+            }
+            timeseries.push(series);
+        }
+
+        for (key, histogram) in sorted(&registry.histograms) {
+            if !due(key) {
+                continue;
+            }
+
+            let exemplars: Vec<_> = registry.exemplars.get(key).cloned().into_iter().collect();
+
+            // skip if this metric has already been sent
+            if histogram.is_sent() && exemplars.is_empty() {
+                continue;
+            }
+
+            let labels = match label_cache.get(key, MetricType::Histogram, || {
+                series_labels(key, MetricType::Histogram, units.get(key.name()), config)
+            }) {
+                Ok(labels) => labels,
+                Err(name) => {
+                    invalid.insert(name);
+                    continue;
+                }
+            };
+
+            let start = timeseries.len();
+            timeseries.extend(histogram_series(labels, histogram, exemplars));
+            if let Some(tick) = tick(key) {
+                timeseries[start..]
+                    .iter_mut()
+                    .for_each(|series| align(series, tick));
+                sent_at.push((key.clone(), tick));
+            }
+        }
+
+        for (key, stale) in &registry.stale {
+            // the series was recorded again since
+            if registry.counters.contains_key(key)
+                || registry.gauges.contains_key(key)
+                || registry.histograms.contains_key(key)
+            {
+                continue;
+            }
+
+            // series with invalid names were never sent
+            let metric_type = if stale.counter {
+                MetricType::Counter
+            } else {
+                MetricType::Gauge
+            };
+            let Ok(labels) = label_cache.get(key, metric_type, || {
+                series_labels(key, metric_type, units.get(key.name()), config)
+            }) else {
+                continue;
+            };
+
+            let labels = match &stale.histogram {
+                None | Some(Distribution::Native(_)) => vec![labels],
+                Some(histogram) => sample_series(&labels, histogram)
+                    .into_iter()
+                    .map(|(labels, _)| labels)
+                    .collect(),
+            };
+
+            // after the last aligned sample, which may be later than removal
+            let timestamp = match (tick(key), aligned.get(key)) {
+                (Some(tick), Some(last)) => tick.max(last + 1),
+                (Some(tick), None) => tick,
+                (None, _) => stale.timestamp,
+            };
+            timeseries.extend(labels.into_iter().map(|labels| types::TimeSeries {
+                labels,
+                samples: vec![types::Sample {
+                    value: STALE_NAN,
+                    timestamp,
+                }],
+                exemplars: vec![],
+                histograms: vec![],
+            }));
+        }
+
+        // forget the labels of removed series
+        let series = registry.counters.len()
+            + registry.gauges.len()
+            + registry.histograms.len()
+            + registry.stale.len();
+        if label_cache.len() > series {
+            label_cache.retain(|key| {
+                registry.counters.contains_key(key)
+                    || registry.gauges.contains_key(key)
+                    || registry.histograms.contains_key(key)
+                    || registry.stale.contains_key(key)
+            });
+        }
+
+        for name in invalid {
+            if self.invalid_names.insert(name.clone()) {
+                warn!("Dropping series with invalid name {name:?}");
+            }
+        }
+
+        if timeseries.is_empty() {
+            debug!("no new samples. skipping send");
+            self.timeseries = timeseries;
+            return vec![];
+        }
+
+        self.registry.sent(due);
+        for (key, value) in sent_counters {
+            if let Some(samples) = self.registry.counters.get_mut(&key) {
+                samples.sent_as(value);
+            }
+        }
+        for (key, value) in sent_gauges {
+            if let Some(samples) = self.registry.gauges.get_mut(&key) {
+                samples.sent_as(value);
+            }
+        }
+        self.aligned.extend(sent_at);
+        if self.aligned.len() > series {
+            let registry = &self.registry;
+            self.aligned.retain(|key, _| {
+                registry.counters.contains_key(key)
+                    || registry.gauges.contains_key(key)
+                    || registry.histograms.contains_key(key)
+            });
+        }
+
+        debug!(
+            series = timeseries.len(),
+            samples = timeseries
+                .iter()
+                .map(|series| series.samples.len())
+                .sum::<usize>(),
+            "Assembled samples"
+        );
+
+        split(
+            timeseries,
+            self.config.max_request_bytes,
+            self.config.max_samples_per_send,
+        )
+        .into_iter()
+        .map(|timeseries| types::WriteRequest {
+            metadata: self.metadata(&timeseries),
+            timeseries,
+        })
+        .collect()
+    }
+
+    /// Resend dead-lettered batches, returning how many were delivered.
+    fn replay_dead_letters(&mut self) -> usize {
+        self.delivery.replay_dead_letters(&self.config)
+    }
+}
+
+/// Most samples in each backfill request, matching the default
+/// `max_samples_per_send` of Prometheus.
+const BACKFILL_SAMPLES: usize = 2000;
+
+/// Keep only the latest sample of `series`, stamped with `timestamp`.
+fn align(series: &mut types::TimeSeries, timestamp: i64) {
+    if let Some(last) = series.samples.pop() {
+        series.samples = vec![types::Sample { timestamp, ..last }];
+    }
+    if let Some(last) = series.histograms.pop() {
+        series.histograms = vec![types::Histogram { timestamp, ..last }];
+    }
+}
+
+/// Group series into requests whose encoded size stays under `max_bytes` and
+/// which contain at most `max_samples` samples.
+fn split(
+    timeseries: Vec<types::TimeSeries>,
+    max_bytes: Option<usize>,
+    max_samples: Option<usize>,
+) -> Vec<Vec<types::TimeSeries>> {
+    if max_bytes.is_none() && max_samples.is_none() {
+        return vec![timeseries];
+    }
+
+    let max_bytes = max_bytes.unwrap_or(usize::MAX);
+    let max_samples = max_samples.unwrap_or(usize::MAX);
+
+    let mut requests = vec![];
+    let mut current = vec![];
+    let mut current_bytes = 0;
+    let mut current_samples = 0;
+
+    for series in timeseries {
+        // series with too many samples are split into several with the same labels
+        let parts: Vec<_> = if series.samples.len() > max_samples {
+            let mut exemplars = series.exemplars;
+            let mut histograms = series.histograms;
+            series
+                .samples
+                .chunks(max_samples)
+                .map(|samples| types::TimeSeries {
+                    labels: series.labels.clone(),
+                    samples: samples.to_vec(),
+                    exemplars: std::mem::take(&mut exemplars),
+                    histograms: std::mem::take(&mut histograms),
+                })
+                .collect()
+        } else {
+            vec![series]
+        };
+
+        for series in parts {
+            // size of the series as a repeated field of the write request
+            let len = series.encoded_len();
+            let bytes = 1 + prost::length_delimiter_len(len) + len;
+            let samples = series.samples.len() + series.histograms.len();
+
+            if !current.is_empty()
+                && (current_bytes + bytes > max_bytes || current_samples + samples > max_samples)
+            {
+                requests.push(std::mem::take(&mut current));
+                current_bytes = 0;
+                current_samples = 0;
+            }
+
+            current.push(series);
+            current_bytes += bytes;
+            current_samples += samples;
+        }
+    }
+
+    if !current.is_empty() {
+        requests.push(current);
+    }
+
+    requests
+}
+
+/// The worker loop that batches recorded samples and writes them.
+///
+/// Returned by [`Builder::build_worker`] for embedders that run the worker
+/// themselves, either blocking with [`run`](Self::run) or step by step with
+/// [`poll`](Self::poll).
+pub struct BatchLoop {
+    rx_cmd: Receiver<Command>,
+    /// Queues for the operations of recording threads, if sharded.
+    rx_shards: Vec<Receiver<Command>>,
+    worker: Worker,
+    next_write: Instant,
+    stopped: bool,
+}
+
+impl BatchLoop {
+    fn new(rx_cmd: Receiver<Command>, rx_shards: Vec<Receiver<Command>>, worker: Worker) -> Self {
+        let next_write = worker.config.clock.instant() + worker.config.tick_interval();
+        Self {
+            rx_cmd,
+            rx_shards,
+            worker,
+            next_write,
+            stopped: false,
+        }
+    }
+
+    /// Run until the exporter is shut down or every recorder and handle has
+    /// been dropped.
+    pub fn run(mut self) {
+        let rx_tick = crossbeam::channel::tick(self.worker.config.tick_interval());
+        let rx_cmd = self.rx_cmd.clone();
+        let rx_shards = self.rx_shards.clone();
+
+        let rx_poll = match self.worker.queues {
+            Some(_) => crossbeam::channel::tick(QUEUE_POLL_INTERVAL),
+            None => crossbeam::channel::never(),
+        };
+
+        let mut select = Select::new();
+        let tick = select.recv(&rx_tick);
+        let poll = select.recv(&rx_poll);
+        let main = select.recv(&rx_cmd);
+        for rx in &rx_shards {
+            select.recv(rx);
+        }
+
+        loop {
+            let operation = select.select();
+            match operation.index() {
+                index if index == tick => {
+                    let _ = operation.recv(&rx_tick);
+                    self.worker.write();
+                }
+                index if index == poll => {
+                    let _ = operation.recv(&rx_poll);
+                    self.worker.drain_queues();
+                }
+                index if index == main => match operation.recv(&rx_cmd) {
+                    Ok(Command::Shutdown(reply)) => return self.stop(Some(reply)),
+                    Ok(cmd) => {
+                        // operations queued before a control command
+                        if !cmd.is_operation() {
+                            self.drain_shards();
+                        }
+                        self.worker.handle(cmd);
+                    }
+                    Err(_) => return self.stop(None),
+                },
+                index => match operation.recv(&rx_shards[index - main - 1]) {
+                    Ok(cmd) => self.worker.handle(cmd),
+                    Err(_) => return self.stop(None),
+                },
+            }
+        }
+    }
+
+    /// Handle the operations queued on every shard.
+    fn drain_shards(&mut self) {
+        for rx in &self.rx_shards {
+            while let Ok(cmd) = rx.try_recv() {
+                self.worker.handle(cmd);
+            }
+        }
+        self.worker.drain_queues();
+    }
+
+    /// Handle everything queued and write if the batch interval has elapsed,
+    /// without waiting for more.
+    ///
+    /// Returns `false` once the exporter has stopped.
+    pub fn poll(&mut self) -> bool {
+        if self.stopped {
+            return false;
+        }
+
+        self.drain_shards();
+        loop {
+            match self.rx_cmd.try_recv() {
+                Ok(Command::Shutdown(reply)) => {
+                    self.stop(Some(reply));
+                    return false;
+                }
+                Ok(cmd) => self.worker.handle(cmd),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.stop(None);
+                    return false;
+                }
+            }
+        }
+
+        let now = self.worker.config.clock.instant();
+        if now >= self.next_write {
+            self.worker.write();
+            self.next_write = now + self.worker.config.tick_interval();
+        }
+
+        true
+    }
+
+    /// Write everything and stop, replying to a shutdown request.
+    fn stop(&mut self, reply: Option<Sender<()>>) {
+        // apply everything queued before the shutdown request
+        self.drain_shards();
+        while let Ok(cmd) = self.rx_cmd.try_recv() {
+            self.worker.handle(cmd);
+        }
+        self.worker.flush();
+        self.worker.save_counters();
+        self.stopped = true;
+
+        if let Some(reply) = reply {
+            let _ = reply.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::SinkError;
+
+    #[test]
+    fn global_labels_appended() {
+        let key = Key::from_parts("requests", vec![metrics::Label::new("path", "/")]);
+        let config = Builder::new().global_label("env", "prod");
+        let labels = series_labels(&key, MetricType::Gauge, None, &config).unwrap();
+
+        let pairs: Vec<_> = labels
+            .iter()
+            .map(|l| (l.name.as_str(), l.value.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![("__name__", "requests"), ("env", "prod"), ("path", "/")]
+        );
+    }
+
+    #[test]
+    fn instance_labels_added() {
+        let config = Builder::new().instance_labels("api");
+        let instance = format!(
+            "{}:{}",
+            gethostname::gethostname().to_string_lossy(),
+            std::process::id()
+        );
+        assert_eq!(
+            config.global_labels,
+            vec![
+                ("job".to_owned(), "api".to_owned()),
+                ("instance".to_owned(), instance)
+            ]
+        );
+
+        let labels = series_labels(
+            &Key::from_name("requests"),
+            MetricType::Gauge,
+            None,
+            &config,
+        )
+        .unwrap();
+        let names: Vec<_> = labels.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["__name__", "instance", "job"]);
+    }
+
+    #[test]
+    fn kubernetes_labels_read_from_env() {
+        let env = |name: &str| match name {
+            "POD_NAME" => Some("api-7d9f".to_owned()),
+            "NAMESPACE" => Some("prod".to_owned()),
+            _ => None,
+        };
+        let config = Builder::new().kubernetes_labels_from(env);
+        assert_eq!(
+            config.global_labels,
+            vec![
+                ("pod".to_owned(), "api-7d9f".to_owned()),
+                ("namespace".to_owned(), "prod".to_owned())
+            ]
+        );
+
+        let env = |name: &str| (name != "NAMESPACE").then(|| name.to_lowercase());
+        let config = Builder::new().kubernetes_labels_from(env);
+        assert_eq!(
+            config.global_labels,
+            vec![
+                ("pod".to_owned(), "pod_name".to_owned()),
+                ("namespace".to_owned(), "pod_namespace".to_owned()),
+                ("node".to_owned(), "node_name".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_sorted_and_deduplicated() {
+        let key = Key::from_parts(
+            "requests",
+            vec![
+                metrics::Label::new("path", "/"),
+                metrics::Label::new("env", "dev"),
+                metrics::Label::new("empty", ""),
+                metrics::Label::new("Method", "GET"),
+            ],
+        );
+        let config = Builder::new().global_label("env", "prod");
+        let labels = series_labels(&key, MetricType::Gauge, None, &config).unwrap();
+
+        let pairs: Vec<_> = labels
+            .iter()
+            .map(|l| (l.name.as_str(), l.value.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("Method", "GET"),
+                ("__name__", "requests"),
+                ("env", "dev"),
+                ("path", "/")
+            ]
+        );
+    }
+
+    #[test]
+    fn endpoints_added() {
+        let config = Builder::new().remote("http://a/write");
+        assert_eq!(
+            config.endpoints,
+            ["http://localhost:9090/api/v1/write", "http://a/write"]
+        );
+
+        let config = Builder::new()
+            .endpoint("http://a/write")
+            .endpoint("http://b/write")
+            .remote("http://c/write");
+        assert_eq!(config.endpoints, ["http://b/write", "http://c/write"]);
+
+        let err = Builder::new()
+            .endpoint("http://a/write")
+            .remote("http://a/write")
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, BuildError::InvalidConfig(_)));
+    }
+
+    #[test]
+    fn invalid_configuration_rejected() {
+        let err = Builder::new().endpoint("not a url").validate().unwrap_err();
+        assert!(matches!(err, BuildError::InvalidEndpoint(_)));
+
+        let err = Builder::new()
+            .endpoint("ftp://localhost/write")
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, BuildError::InvalidEndpoint(_)));
+
+        let err = Builder::new()
+            .batch_interval(Duration::ZERO)
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, BuildError::InvalidConfig(_)));
+
+        let err = Builder::new()
+            .histogram_mode(HistogramMode::Buckets(vec![1.0, 0.5]))
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, BuildError::InvalidConfig(_)));
+
+        let err = Builder::new()
+            .histogram_mode_for("latency", HistogramMode::Summary(vec![1.5]))
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, BuildError::InvalidConfig(_)));
+
+        let err = Builder::new()
+            .summary_window(Duration::from_secs(60))
+            .summary_age_buckets(0)
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, BuildError::InvalidConfig(_)));
+
+        let err = Builder::new()
+            .timestamp_resolution(Duration::ZERO)
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, BuildError::InvalidConfig(_)));
+
+        #[cfg(feature = "zstd")]
+        {
+            let zstd = Builder::new().compression(Compression::Zstd(3));
+            let err = zstd.clone().validate().unwrap_err();
+            assert!(matches!(err, BuildError::InvalidConfig(_)));
+            assert!(zstd.protocol(Protocol::V2).validate().is_ok());
+        }
+
+        assert!(Builder::new().validate().is_ok());
+    }
+
+    #[cfg(any(feature = "ureq", feature = "reqwest"))]
+    #[test]
+    fn root_certificates_validated() {
+        const CA: &str = "-----BEGIN CERTIFICATE-----
+MIIBizCCATGgAwIBAgIUaYCPKjjZmLEtYFHUuSvfr3TjgGwwCgYIKoZIzj0EAwIw
+GjEYMBYGA1UEAwwPcHJvbS13cml0ZS10ZXN0MCAXDTI2MTAxNjEwMjgxOFoYDzIx
+MjYwOTIyMTAyODE4WjAaMRgwFgYDVQQDDA9wcm9tLXdyaXRlLXRlc3QwWTATBgcq
+hkjOPQIBBggqhkjOPQMBBwNCAAT3Y4OUfbKuY38+MxRfc4ghY/c9bXuXX2VDy9F/
+JR7ETQmxXCRzz7qHxptWE6KGUiQDYf2Tj1ha3Sa3MSOQH3dgo1MwUTAdBgNVHQ4E
+FgQUtu4B4mOsFhBTk0Jom1laLHcr59owHwYDVR0jBBgwFoAUtu4B4mOsFhBTk0Jo
+m1laLHcr59owDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiBNp64B
+Ukt+quilMWKKC2ILy8P8QtAlY/OKqN8cMTD9FAIhAIvINqdvZAWRZJEOa35Qm6Rs
+1UMu8aoavf632zl+XuUf
+-----END CERTIFICATE-----
+";
+        assert!(Builder::new().root_certificates(CA).validate().is_ok());
+
+        let invalid = [
+            "",
+            "not a certificate",
+            "-----BEGIN CERTIFICATE-----\n!!!\n-----END CERTIFICATE-----\n",
+        ];
+        for pem in invalid {
+            let err = Builder::new()
+                .root_certificates(pem)
+                .validate()
+                .unwrap_err();
+            assert!(matches!(err, BuildError::InvalidTls(_)), "{pem:?}");
+        }
+    }
+
+    fn inner(capacity: usize, overflow: OverflowPolicy) -> BatcherInner {
+        let (tx_cmds, rx_cmds) = crossbeam::channel::bounded(capacity);
+        BatcherInner {
+            tx_cmds,
+            rx_cmds,
+            shards: vec![],
+            queues: None,
+            overflow,
+            dropped: Arc::new(AtomicU64::new(0)),
+            health: Arc::new(Health::default()),
+            shutdown: AtomicBool::new(false),
+            stopped: Mutex::new(None),
+            worker: Mutex::new(None),
+            filter: Filter::default(),
+            clock: SharedClock::default(),
+            atomics: None,
+            interner: None,
+            thread_buffers: None,
+            #[cfg(feature = "opentelemetry")]
+            span_exemplars: None,
+        }
+    }
+
+    fn outputs(worker: &Worker) -> &Outputs {
+        let Delivery::Inline(outputs) = &worker.delivery else {
+            panic!("sends are pipelined");
+        };
+        outputs
+    }
+
+    fn gauge_op(value: f64) -> Command {
+        Command::Operation(
+            SystemTime::now(),
+            Key::from_name("gauge"),
+            MetricOperation::SetGauge(value),
+        )
+    }
+
+    fn queued_value(inner: &BatcherInner) -> f64 {
+        match inner.rx_cmds.try_recv().unwrap() {
+            Command::Operation(_, _, MetricOperation::SetGauge(value)) => value,
+            cmd => panic!("unexpected command {cmd:?}"),
+        }
+    }
+
+    #[test]
+    fn overflow_drop_newest() {
+        let inner = inner(1, OverflowPolicy::DropNewest);
+        inner.send(gauge_op(1.0));
+        inner.send(gauge_op(2.0));
+
+        assert_eq!(inner.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(queued_value(&inner), 1.0);
+    }
+
+    #[test]
+    fn overflow_drop_oldest() {
+        let inner = inner(1, OverflowPolicy::DropOldest);
+        inner.send(gauge_op(1.0));
+        inner.send(gauge_op(2.0));
+
+        assert_eq!(inner.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(queued_value(&inner), 2.0);
+    }
+
+    #[test]
+    fn overflow_drop_oldest_keeps_metadata() {
+        let metadata = || {
+            Command::Metadata(
+                KeyName::from("gauge"),
+                MetricType::Gauge,
+                None,
+                SharedString::from("A gauge"),
+            )
+        };
+
+        let queue = inner(2, OverflowPolicy::DropOldest);
+        queue.send(metadata());
+        queue.send(gauge_op(1.0));
+        queue.send(gauge_op(2.0));
+
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 1);
+        assert!(matches!(
+            queue.rx_cmds.try_recv(),
+            Ok(Command::Metadata(..))
+        ));
+        assert_eq!(queued_value(&queue), 2.0);
+
+        // nothing but metadata queued
+        let queue = inner(1, OverflowPolicy::DropOldest);
+        queue.send(metadata());
+        queue.send(gauge_op(1.0));
+
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 1);
+        assert!(matches!(
+            queue.rx_cmds.try_recv(),
+            Ok(Command::Metadata(..))
+        ));
+    }
+
+    #[test]
+    fn dropped_batch_counts_each_operation() {
+        let batch = || {
+            let key = SeriesKey::Key(Key::from_name("requests"));
+            let operations = (0..3)
+                .map(|_| {
+                    let op = MetricOperation::IncrementCounter(1);
+                    (SystemTime::now(), key.clone(), op)
+                })
+                .collect();
+            Command::Batch(operations)
+        };
+
+        let queue = inner(1, OverflowPolicy::DropOldest);
+        queue.send(batch());
+        queue.send(gauge_op(1.0));
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 3);
+
+        let queue = inner(1, OverflowPolicy::DropNewest);
+        queue.send(gauge_op(1.0));
+        queue.send(batch());
+        assert_eq!(queue.dropped.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn overflow_block_times_out() {
+        let inner = inner(1, OverflowPolicy::Block(Duration::from_millis(1)));
+        inner.send(gauge_op(1.0));
+        inner.send(gauge_op(2.0));
+
+        assert_eq!(inner.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(queued_value(&inner), 1.0);
+    }
+
+    #[test]
+    fn fail_fast_rejects_unreachable_endpoint() {
+        let result = Builder::new()
+            .endpoint("http://127.0.0.1:1/api/v1/write")
+            .fail_fast(true)
+            .build();
+        assert!(matches!(result, Err(BuildError::Unreachable(_))));
+    }
+
+    #[test]
+    fn shutdown_joins_worker() {
+        let batcher = Builder::new()
+            .endpoint("http://127.0.0.1:1/api/v1/write")
+            .retry(RetryPolicy::disabled())
+            .build()
+            .unwrap();
+        let handle = batcher.handle();
+
+        batcher.send(gauge_op(1.0));
+        assert!(handle.shutdown(Duration::from_secs(5)));
+        assert!(handle.shutdown(Duration::ZERO));
+    }
+
+    #[test]
+    fn shutdown_times_out_when_stalled() {
+        // no worker drains the queue
+        let inner = Arc::new(inner(1, OverflowPolicy::DropNewest));
+        let handle = Handle::new(inner.clone());
+
+        inner.send(gauge_op(1.0));
+        assert!(!handle.shutdown(Duration::from_millis(10)));
+
+        // the request is queued but never answered
+        inner.rx_cmds.try_recv().unwrap();
+        assert!(!handle.shutdown(Duration::from_millis(10)));
+        assert!(!handle.shutdown(Duration::from_millis(10)));
+
+        inner.rx_cmds.try_recv().unwrap();
+        assert!(inner.rx_cmds.is_empty());
+    }
+
+    #[test]
+    fn flush_reports_failed_delivery() {
+        let batcher = Builder::new()
+            .endpoint("http://127.0.0.1:1/api/v1/write")
+            .retry(RetryPolicy::disabled())
+            .build()
+            .unwrap();
+        let handle = batcher.handle();
+
+        // nothing recorded, so there is nothing to deliver
+        assert!(handle.flush(Duration::from_secs(5)));
+
+        batcher.send(gauge_op(1.0));
+        assert!(!handle.flush(Duration::from_secs(5)));
+
+        // the queue stays full without a worker
+        let inner = Arc::new(inner(1, OverflowPolicy::DropNewest));
+        inner.send(gauge_op(1.0));
+        assert!(!Handle::new(inner).flush(Duration::from_millis(10)));
+    }
+
+    #[cfg(all(feature = "test-util", feature = "ureq"))]
+    #[test]
+    fn exhausted_retries_dead_lettered() {
+        use crate::test_util::MockServer;
+        use crate::transport::HttpResponse;
+
+        let server = MockServer::start().unwrap();
+        for _ in 0..2 {
+            server.respond_with(HttpResponse {
+                status: 503,
+                ..Default::default()
+            });
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let batcher = Builder::new()
+            .endpoint(server.url())
+            .retry(RetryPolicy {
+                max_attempts: 2,
+                min_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(10),
+                jitter: false,
+            })
+            .dead_letter_dir(dir.path())
+            .build()
+            .unwrap();
+        let handle = batcher.handle();
+
+        batcher.send(gauge_op(1.0));
+        assert!(!handle.flush(Duration::from_secs(5)));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!handle.flush(Duration::from_secs(5)));
+        assert_eq!(server.requests().len(), 2);
+
+        // given up on rather than retried forever
+        assert!(handle.flush(Duration::from_secs(5)));
+        assert_eq!(server.requests().len(), 2);
+
+        assert_eq!(handle.replay_dead_letters(Duration::from_secs(5)), Some(1));
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2], requests[0]);
+    }
+
+    #[test]
+    fn flush_on_panic_writes_before_unwinding() {
+        use std::io::Read;
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/api/v1/write", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let read = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            request[..read].starts_with(b"POST")
+        });
+
+        let batcher = Builder::new()
+            .endpoint(endpoint)
+            .retry(RetryPolicy::disabled())
+            .batch_interval(Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        batcher.send(gauge_op(1.0));
+
+        // as registered by `install`, restoring the test harness' hook after
+        let previous = std::panic::take_hook();
+        flush_on_panic_hook(&batcher.inner, Duration::from_secs(5));
+        let result = std::thread::spawn(|| panic!("boom")).join();
+        std::panic::set_hook(previous);
+        assert!(result.is_err());
+
+        // the hook blocks until the write completes
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn self_metrics_recorded() {
+        let config = Builder::new()
+            .endpoint("http://127.0.0.1:1/api/v1/write")
+            .retry(RetryPolicy::disabled())
+            .self_metrics(true);
+        let dropped = Arc::new(AtomicU64::new(2));
+        let mut worker = Worker::new(config, dropped, Arc::default()).unwrap();
+
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("test"),
+            MetricOperation::SetGauge(1.0),
+        );
+        assert!(!worker.write());
+
+        let counter = |name: &'static str| {
+            let labels = vec![metrics::Label::new(
+                "url",
+                "http://127.0.0.1:1/api/v1/write",
+            )];
+            worker.registry.counters[&Key::from_parts(name, labels)]
+                .all()
+                .last()
+                .unwrap()
+                .value
+        };
+        assert_eq!(counter("prometheus_write_samples_total"), 1.0);
+        assert_eq!(counter("prometheus_write_requests_total"), 1.0);
+        assert_eq!(counter("prometheus_write_request_failures_total"), 1.0);
+
+        let dropped =
+            &worker.registry.counters[&Key::from_name("prometheus_write_operations_dropped_total")];
+        assert_eq!(dropped.all().last().unwrap().value, 2.0);
+
+        // an unchanged send duration isn't recorded again
+        let latency = Key::from_parts(
+            "prometheus_write_send_duration_seconds",
+            vec![metrics::Label::new(
+                "url",
+                "http://127.0.0.1:1/api/v1/write",
+            )],
+        );
+        let stats = worker.remote_stats[0].1.clone();
+        stats.latency.store(1500, Ordering::Relaxed);
+        worker.record_self_metrics();
+        worker.registry.sent(|_| true);
+        stats.latency.store(1500, Ordering::Relaxed);
+        worker.record_self_metrics();
+        assert!(worker.registry.gauges[&latency].is_sent());
+
+        std::thread::sleep(Duration::from_millis(2));
+        stats.latency.store(2500, Ordering::Relaxed);
+        worker.record_self_metrics();
+        assert!(!worker.registry.gauges[&latency].is_sent());
+    }
+
+    #[test]
+    fn on_result_reports_failures() {
+        let outcomes = Arc::new(Mutex::new(vec![]));
+        let recorded = outcomes.clone();
+
+        let config = Builder::new()
+            .endpoint("http://127.0.0.1:1/api/v1/write")
+            .retry(RetryPolicy {
+                max_attempts: 2,
+                min_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                jitter: false,
+            })
+            .on_result(move |outcome| {
+                recorded.lock().unwrap().push((
+                    outcome.is_success(),
+                    outcome.status,
+                    outcome.retries,
+                ));
+            });
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("test"),
+            MetricOperation::SetGauge(1.0),
+        );
+        assert!(!worker.write());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!worker.write());
+
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            vec![(false, None, 0), (false, None, 1)]
+        );
+    }
+
+    #[test]
+    fn status_tracks_failures() {
+        let batcher = Builder::new()
+            .endpoint("http://127.0.0.1:1/api/v1/write")
+            .retry(RetryPolicy::disabled())
+            .build()
+            .unwrap();
+        let handle = batcher.handle();
+
+        batcher.send(gauge_op(1.0));
+        assert!(!handle.flush(Duration::from_secs(5)));
+        batcher.send(gauge_op(2.0));
+        assert!(!handle.flush(Duration::from_secs(5)));
+
+        let status = handle.status();
+        assert_eq!(status.consecutive_failures, 2);
+        assert!(status.last_error.is_some());
+        assert!(status.last_success.is_none());
+        assert_eq!(status.queue_depth, 0);
+    }
+
+    #[test]
+    fn status_tracked_per_endpoint() {
+        use crate::transport::HttpRequest;
+        use crate::transport::HttpResponse;
+        use crate::transport::TransportError;
+
+        struct RejectsBad;
+
+        impl Transport for RejectsBad {
+            fn post(&self, request: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+                Ok(HttpResponse {
+                    status: if request.url.contains("bad") {
+                        400
+                    } else {
+                        204
+                    },
+                    ..Default::default()
+                })
+            }
+        }
+
+        let batcher = Builder::new()
+            .endpoint("http://good/api/v1/write")
+            .remote("http://bad/api/v1/write")
+            .max_samples_per_send(1)
+            .transport(RejectsBad)
+            .build()
+            .unwrap();
+        let handle = batcher.handle();
+
+        // two requests rejected in one write count as one failure
+        batcher.send(gauge_op(1.0));
+        batcher.send(Command::Operation(
+            SystemTime::now(),
+            Key::from_name("other"),
+            MetricOperation::SetGauge(1.0),
+        ));
+        assert!(!handle.flush(Duration::from_secs(5)));
+
+        let status = handle.status();
+        assert_eq!(status.consecutive_failures, 1);
+        assert!(status.last_error.is_some());
+
+        let [good, bad] = &status.endpoints[..] else {
+            panic!("{:?}", status.endpoints);
+        };
+        assert_eq!(good.endpoint, "http://good/api/v1/write");
+        assert_eq!(good.consecutive_failures, 0);
+        assert!(good.last_success.is_some());
+        assert_eq!(bad.endpoint, "http://bad/api/v1/write");
+        assert_eq!(bad.consecutive_failures, 1);
+    }
+
+    #[test]
+    fn metadata_sent_with_described_series() {
+        let config = Builder::new().prefix("app_");
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        worker.handle(Command::Metadata(
+            KeyName::from("latency"),
+            MetricType::Gauge,
+            Some(Unit::Seconds),
+            SharedString::from("Request latency."),
+        ));
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("latency"),
+            MetricOperation::SetGauge(1.0),
+        );
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("undescribed"),
+            MetricOperation::SetGauge(1.0),
+        );
+
+        let requests = worker.requests();
+        assert_eq!(
+            requests[0].metadata,
+            [types::MetricMetadata {
+                r#type: MetricType::Gauge.into(),
+                metric_family_name: "app_latency".to_owned(),
+                help: "Request latency.".to_owned(),
+                unit: "seconds".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn unit_suffixes_appended() {
+        let config = Builder::new().unit_suffixes(true);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        for (name, unit) in [
+            ("latency", Unit::Seconds),
+            ("size_bytes", Unit::Bytes),
+            ("requests", Unit::Count),
+        ] {
+            worker.handle(Command::Metadata(
+                KeyName::from(name),
+                MetricType::Gauge,
+                Some(unit),
+                SharedString::from(""),
+            ));
+            worker.apply(
+                SystemTime::now(),
+                Key::from_name(name),
+                MetricOperation::SetGauge(1.0),
+            );
+        }
+
+        let requests = worker.requests();
+        let names: Vec<_> = requests[0]
+            .timeseries
+            .iter()
+            .map(|series| series.labels[0].value.as_str())
+            .collect();
+        assert_eq!(names, ["latency_seconds", "requests", "size_bytes"]);
+
+        let families: Vec<_> = requests[0]
+            .metadata
+            .iter()
+            .map(|metadata| (metadata.metric_family_name.as_str(), metadata.unit.as_str()))
+            .collect();
+        assert_eq!(
+            families,
+            [
+                ("latency_seconds", "seconds"),
+                ("requests", "count"),
+                ("size_bytes", "bytes")
+            ]
+        );
+    }
+
+    #[test]
+    fn exemplar_attached_to_series() {
+        let mut worker = Worker::new(Builder::new(), Arc::default(), Arc::default()).unwrap();
+
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("requests"),
+            MetricOperation::IncrementCounter(1),
+        );
+        assert_eq!(worker.requests()[0].timeseries[0].exemplars, []);
+
+        let trace_id = types::Label {
+            name: "trace_id".to_owned(),
+            value: "abc".to_owned(),
+        };
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("requests"),
+            MetricOperation::Exemplar(1.0, vec![trace_id.clone()]),
+        );
+
+        // sent without repeating the already sent sample
+        let requests = worker.requests();
+        let series = &requests[0].timeseries[0];
+        assert!(series.samples.is_empty());
+        assert_eq!(series.exemplars.len(), 1);
+        assert_eq!(series.exemplars[0].labels, [trace_id]);
+        assert_eq!(series.exemplars[0].value, 1.0);
+
+        assert!(worker.requests().is_empty());
+    }
+
+    #[test]
+    fn histogram_sent_as_native_histogram() {
+        let mut worker = Worker::new(Builder::new(), Arc::default(), Arc::default()).unwrap();
+
+        for value in [0.5, 2.0] {
+            worker.apply(
+                SystemTime::now(),
+                Key::from_name("latency"),
+                MetricOperation::RecordHistogram(value),
+            );
+        }
+
+        let requests = worker.requests();
+        let series = &requests[0].timeseries[0];
+        assert!(series.samples.is_empty());
+        assert_eq!(series.histograms.len(), 1);
+        assert_eq!(
+            series.histograms[0].count,
+            Some(types::histogram::Count::CountInt(2))
+        );
+        assert_eq!(series.histograms[0].sum, 2.5);
+
+        // histograms survive the round trip through Remote Write 2.0
+        let encoded = Protocol::V2.encode_with(&requests[0], &worker.created);
+        let downgraded = crate::protocol::downgrade(&encoded).unwrap();
+        assert_eq!(
+            types::WriteRequest::decode(downgraded.as_slice()).unwrap(),
+            requests[0]
+        );
+
+        assert!(worker.requests().is_empty());
+    }
+
+    #[test]
+    fn histogram_sent_as_classic_buckets() {
+        let config = Builder::new().histogram_mode(HistogramMode::Buckets(vec![1.0, 2.0]));
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        worker.handle(Command::Metadata(
+            KeyName::from("latency"),
+            MetricType::Histogram,
+            None,
+            SharedString::from("Request latency."),
+        ));
+        let at = SystemTime::UNIX_EPOCH + Duration::from_millis(1000);
+        for value in [0.5, 1.5, 5.0] {
+            worker.apply(
+                at,
+                Key::from_parts("latency", &[("path", "/")]),
+                MetricOperation::RecordHistogram(value),
+            );
+        }
+        worker.apply(
+            at,
+            Key::from_parts("latency", &[("path", "/")]),
+            MetricOperation::Exemplar(1.5, vec![]),
+        );
+
+        let requests = worker.requests();
+        let series: Vec<_> = requests[0]
+            .timeseries
+            .iter()
+            .map(|series| {
+                let labels: Vec<_> = series
+                    .labels
+                    .iter()
+                    .map(|label| format!("{}={}", label.name, label.value))
+                    .collect();
+                (labels.join(","), series.samples[0].value)
+            })
+            .collect();
+        assert_eq!(
+            series,
+            [
+                ("__name__=latency_bucket,le=1,path=/".to_owned(), 1.0),
+                ("__name__=latency_bucket,le=2,path=/".to_owned(), 2.0),
+                ("__name__=latency_bucket,le=+Inf,path=/".to_owned(), 3.0),
+                ("__name__=latency_sum,path=/".to_owned(), 7.0),
+                ("__name__=latency_count,path=/".to_owned(), 3.0),
+            ]
+        );
+        assert_eq!(requests[0].timeseries[1].exemplars.len(), 1);
+        assert_eq!(requests[0].metadata.len(), 1);
+        assert_eq!(requests[0].metadata[0].metric_family_name, "latency");
+
+        // the family survives the round trip through Remote Write 2.0
+        let encoded = Protocol::V2.encode_with(&requests[0], &worker.created);
+        let downgraded = crate::protocol::downgrade(&encoded).unwrap();
+        assert_eq!(
+            types::WriteRequest::decode(downgraded.as_slice()).unwrap(),
+            requests[0]
+        );
+
+        // every series of a removed histogram is marked stale
+        worker.apply(
+            at,
+            Key::from_parts("latency", &[("path", "/")]),
+            MetricOperation::Remove,
+        );
+        let requests = worker.requests();
+        assert_eq!(requests[0].timeseries.len(), 5);
+        assert!(
+            requests[0]
+                .timeseries
+                .iter()
+                .all(|series| series.samples[0].value.to_bits() == STALE_NAN.to_bits())
+        );
+    }
+
+    #[test]
+    fn last_values_resent_on_heartbeat() {
+        let config = Builder::new().heartbeat(Duration::from_secs(3600));
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        let at = SystemTime::UNIX_EPOCH + Duration::from_millis(1000);
+        worker.apply(
+            at,
+            Key::from("temperature"),
+            MetricOperation::SetGauge(21.5),
+        );
+        worker.apply(
+            at,
+            Key::from("requests"),
+            MetricOperation::IncrementCounter(2),
+        );
+        assert_eq!(worker.requests()[0].timeseries.len(), 2);
+        assert!(worker.requests().is_empty());
+
+        worker.next_heartbeat = Instant::now();
+        let requests = worker.requests();
+        let samples: Vec<_> = requests[0]
+            .timeseries
+            .iter()
+            .map(|series| series.samples[0])
+            .collect();
+        assert_eq!(samples.len(), 2);
+        assert!(samples.iter().all(|sample| sample.timestamp > 1000));
+        assert_eq!(samples[0].value, 2.0);
+        assert_eq!(samples[1].value, 21.5);
+    }
+
+    #[test]
+    fn metrics_flushed_at_their_own_interval() {
+        let config = Builder::new()
+            .batch_interval(Duration::from_secs(30))
+            .flush_interval_for("heartbeat*", Duration::from_secs(1));
+        assert_eq!(config.tick_interval(), Duration::from_secs(1));
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        let at = SystemTime::UNIX_EPOCH + Duration::from_millis(1000);
+        worker.apply(at, Key::from("heartbeat"), MetricOperation::SetGauge(1.0));
+        worker.apply(
+            at,
+            Key::from("orders"),
+            MetricOperation::IncrementCounter(1),
+        );
+        assert!(worker.requests().is_empty());
+
+        let names = |requests: Vec<types::WriteRequest>| -> Vec<String> {
+            requests
+                .iter()
+                .flat_map(|request| &request.timeseries)
+                .map(|series| series.labels[0].value.clone())
+                .collect()
+        };
+        worker.next_flushes[0] = Instant::now();
+        assert_eq!(names(worker.requests()), ["heartbeat"]);
+
+        worker.apply(at, Key::from("heartbeat"), MetricOperation::SetGauge(2.0));
+        worker.next_flushes[1] = Instant::now();
+        assert_eq!(names(worker.requests()), ["orders"]);
+
+        // a flush sends everything
+        worker.apply(
+            at,
+            Key::from("orders"),
+            MetricOperation::IncrementCounter(1),
+        );
+        worker.next_flushes = vec![Instant::now() + Duration::from_secs(60); 2];
+        worker.flush();
+        assert!(worker.requests().is_empty());
+    }
+
+    #[test]
+    fn unchanged_values_suppressed() {
+        let config = Builder::new().send_on_change(true).raw_samples(true);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        let key = Key::from("temperature");
+        for (millis, value) in [(1000, 21.5), (2000, 21.5), (3000, 22.0)] {
+            worker.apply(at(millis), key.clone(), MetricOperation::SetGauge(value));
+        }
+        let requests = worker.requests();
+        let values: Vec<_> = requests[0].timeseries[0]
+            .samples
+            .iter()
+            .map(|sample| (sample.timestamp, sample.value))
+            .collect();
+        assert_eq!(values, [(1000, 21.5), (3000, 22.0)]);
+
+        worker.apply(at(4000), key.clone(), MetricOperation::SetGauge(22.0));
+        worker.apply(
+            at(4000),
+            Key::from("requests"),
+            MetricOperation::IncrementCounter(0),
+        );
+        let requests = worker.requests();
+        assert_eq!(requests[0].timeseries.len(), 1);
+        assert_eq!(requests[0].timeseries[0].labels[0].value, "requests");
+
+        worker.apply(at(5000), key, MetricOperation::SetGauge(22.0));
+        assert!(worker.requests().is_empty());
+    }
+
+    #[test]
+    fn aggregated_values_compared_as_sent() {
+        let config = Builder::new()
+            .send_on_change(true)
+            .gauge_aggregation(GaugeAggregation::Mean);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        let key = Key::from("temperature");
+        worker.apply(at(1000), key.clone(), MetricOperation::SetGauge(20.0));
+        worker.apply(at(2000), key.clone(), MetricOperation::SetGauge(22.0));
+        let requests = worker.requests();
+        assert_eq!(requests[0].timeseries[0].samples[0].value, 21.0);
+
+        // the mean sent before, not the last value set
+        worker.apply(at(3000), key, MetricOperation::SetGauge(21.0));
+        assert!(worker.requests().is_empty());
+    }
+
+    #[test]
+    fn timestamps_aligned_to_batch_interval() {
+        let config = Builder::new()
+            .batch_interval(Duration::from_secs(10))
+            .raw_samples(true)
+            .aligned_timestamps(true);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        let key = Key::from("temperature");
+        worker.apply(at(1000), key.clone(), MetricOperation::SetGauge(21.5));
+        worker.apply(at(2500), key.clone(), MetricOperation::SetGauge(22.0));
+        worker.apply(at(2500), key, MetricOperation::SetGauge(22.0));
+
+        let requests = worker.requests();
+        let samples = &requests[0].timeseries[0].samples;
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].value, 22.0);
+        assert_eq!(samples[0].timestamp % 10_000, 0);
+        assert!(samples[0].timestamp > 2500);
+    }
+
+    #[test]
+    fn aligned_timestamp_sent_once() {
+        let config = Builder::new()
+            .batch_interval(Duration::from_secs(24 * 60 * 60))
+            .flush_interval_for("requests", Duration::from_secs(60 * 60))
+            .aligned_timestamps(true);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        let temperature = Key::from("temperature");
+        let requests = Key::from("requests");
+        worker.apply(
+            at(1000),
+            temperature.clone(),
+            MetricOperation::SetGauge(21.5),
+        );
+        worker.apply(at(1000), requests, MetricOperation::IncrementCounter(1));
+        worker.next_flushes.fill(Instant::now());
+        let sent = worker.requests();
+        let timestamp = |name: &str| {
+            sent[0]
+                .timeseries
+                .iter()
+                .find(|series| series.labels[0].value == name)
+                .unwrap()
+                .samples[0]
+                .timestamp
+        };
+        assert_eq!(timestamp("temperature") % (24 * 60 * 60 * 1000), 0);
+        assert_eq!(timestamp("requests") % (60 * 60 * 1000), 0);
+
+        // a flush in the same interval leaves the new value for the next
+        worker.apply(
+            at(2000),
+            temperature.clone(),
+            MetricOperation::SetGauge(22.0),
+        );
+        worker.next_flushes.fill(Instant::now());
+        assert!(worker.requests().is_empty());
+        assert!(!worker.registry.gauges[&temperature].is_sent());
+    }
+
+    #[test]
+    fn samples_recorded_with_timestamps() {
+        let batcher = Batcher::builder().build().unwrap();
+        let handle = batcher.handle();
+
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        handle.gauge_sample("temperature", at(1000), 21.5);
+        handle.gauge_sample("temperature", at(2000), 22.0);
+        handle.counter_sample("readings", at(2000), 2);
+        handle.gauge_sample(
+            "voltage",
+            SystemTime::UNIX_EPOCH - Duration::from_secs(1),
+            3.3,
+        );
+
+        let text = handle.render_text(Duration::from_secs(5)).unwrap();
+        assert!(text.contains("temperature 22 2000\n"));
+        assert!(text.contains("readings 2 2000\n"));
+        assert!(text.contains("voltage 3.3 0\n"));
+    }
+
+    #[test]
+    fn histograms_recorded_through_recorder() {
+        let batcher = Batcher::builder().build().unwrap();
+        let handle = batcher.handle();
+
+        let record = || {
+            metrics::describe_histogram!("latency", "Request latency.");
+            metrics::histogram!("latency").record(1.5);
+        };
+        metrics::with_local_recorder(&batcher, record);
+        let text = handle.render_text(Duration::from_secs(5)).unwrap();
+        assert!(text.contains("latency_count 1 "));
+
+        // recording after shutdown is dropped, not a panic
+        handle.shutdown(Duration::from_secs(1));
+        metrics::with_local_recorder(&batcher, record);
+    }
+
+    #[test]
+    fn histogram_sent_as_gauges() {
+        let config = Builder::new().histogram_mode(HistogramMode::Gauges);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        worker.handle(Command::Metadata(
+            KeyName::from("latency"),
+            MetricType::Histogram,
+            None,
+            SharedString::from("Request latency."),
+        ));
+        let at = SystemTime::UNIX_EPOCH + Duration::from_millis(1000);
+        for value in [2.0, 1.0, 4.0] {
+            worker.apply(
+                at,
+                Key::from("latency"),
+                MetricOperation::RecordHistogram(value),
+            );
+        }
+
+        let requests = worker.requests();
+        let series: Vec<_> = requests[0]
+            .timeseries
+            .iter()
+            .map(|series| (&series.labels[0].value[..], series.samples[0].value))
+            .collect();
+        assert_eq!(
+            series,
+            [
+                ("latency_min", 1.0),
+                ("latency_max", 4.0),
+                ("latency_sum", 7.0),
+                ("latency_count", 3.0),
+            ]
+        );
+        assert_eq!(requests[0].metadata.len(), 4);
+        assert!(
+            requests[0]
+                .metadata
+                .iter()
+                .all(|metadata| { metadata.r#type() == types::metric_metadata::MetricType::Gauge })
+        );
+        assert!(worker.render_text().contains("# TYPE latency_max gauge\n"));
+    }
+
+    #[test]
+    fn histogram_sent_as_summary() {
+        let config =
+            Builder::new().histogram_mode_for("latency", HistogramMode::Summary(vec![0.5, 1.0]));
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        worker.handle(Command::Metadata(
+            KeyName::from("latency"),
+            MetricType::Histogram,
+            None,
+            SharedString::from("Request latency."),
+        ));
+        let at = SystemTime::UNIX_EPOCH + Duration::from_millis(1000);
+        for value in [1.0, 2.0, 4.0] {
+            worker.apply(
+                at,
+                Key::from("latency"),
+                MetricOperation::RecordHistogram(value),
+            );
+            worker.apply(
+                at,
+                Key::from("size"),
+                MetricOperation::RecordHistogram(value),
+            );
+        }
+
+        let requests = worker.requests();
+        let series: Vec<_> = requests[0]
+            .timeseries
+            .iter()
+            .map(|series| {
+                let labels: Vec<_> = series
+                    .labels
+                    .iter()
+                    .map(|label| format!("{}={}", label.name, label.value))
+                    .collect();
+                let value = series.samples.first().map(|sample| sample.value.round());
+                (labels.join(","), value)
+            })
+            .collect();
+        assert_eq!(
+            series,
+            [
+                ("__name__=latency,quantile=0.5".to_owned(), Some(2.0)),
+                ("__name__=latency,quantile=1".to_owned(), Some(4.0)),
+                ("__name__=latency_sum".to_owned(), Some(7.0)),
+                ("__name__=latency_count".to_owned(), Some(3.0)),
+                // other histograms keep the default mode
+                ("__name__=size".to_owned(), None),
+            ]
+        );
+        assert_eq!(
+            requests[0].metadata[0].r#type,
+            i32::from(types::metric_metadata::MetricType::Summary)
+        );
+    }
+
+    #[test]
+    fn created_timestamps_sent() {
+        let config = Builder::new().created_timestamps(true);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        let created = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        worker.apply(
+            created,
+            Key::from_name("requests"),
+            MetricOperation::IncrementCounter(1),
+        );
+        worker.apply(
+            created + Duration::from_secs(1),
+            Key::from_name("requests"),
+            MetricOperation::IncrementCounter(1),
+        );
+
+        let requests = worker.requests();
+        let timeseries = &requests[0].timeseries;
+        assert_eq!(timeseries.len(), 2);
+        assert_eq!(timeseries[0].labels[0].value, "requests_created");
+        assert_eq!(timeseries[0].samples[0].value, 1_700_000_000.123);
+        assert_eq!(timeseries[0].samples[0].timestamp, 1_700_000_001_123);
+
+        // 2.0 carries it on the counter itself
+        let encoded = Protocol::V2.encode_with(&requests[0], &worker.created);
+        let decoded = crate::types_v2::Request::decode(encoded.as_slice()).unwrap();
+        assert_eq!(decoded.timeseries.len(), 1);
+        assert_eq!(decoded.timeseries[0].created_timestamp, 1_700_000_000_123);
+
+        let downgraded = crate::protocol::downgrade(&encoded).unwrap();
+        assert_eq!(
+            types::WriteRequest::decode(downgraded.as_slice()).unwrap(),
+            requests[0]
+        );
+    }
+
+    #[test]
+    fn total_suffix_appended() {
+        let config = Builder::new()
+            .total_suffix(true)
+            .unit_suffixes(true)
+            .created_timestamps(true);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        worker.handle(Command::Metadata(
+            KeyName::from("busy"),
+            MetricType::Counter,
+            Some(Unit::Seconds),
+            SharedString::from(""),
+        ));
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("busy"),
+            MetricOperation::IncrementCounter(1),
+        );
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("requests_total"),
+            MetricOperation::IncrementCounter(1),
+        );
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("queued"),
+            MetricOperation::SetGauge(1.0),
+        );
+
+        let requests = worker.requests();
+        let names: Vec<_> = requests[0]
+            .timeseries
+            .iter()
+            .map(|series| series.labels[0].value.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "busy_seconds_created",
+                "busy_seconds_total",
+                "requests_created",
+                "requests_total",
+                "queued"
+            ]
+        );
+        assert_eq!(
+            requests[0].metadata[0].metric_family_name,
+            "busy_seconds_total"
+        );
+
+        // the created series are matched to their counter
+        let encoded = Protocol::V2.encode_with(&requests[0], &worker.created);
+        let decoded = crate::types_v2::Request::decode(encoded.as_slice()).unwrap();
+        assert_eq!(decoded.timeseries.len(), 3);
+        assert!(
+            decoded.timeseries[..2]
+                .iter()
+                .all(|series| series.created_timestamp != 0)
+        );
+
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("busy"),
+            MetricOperation::Remove,
+        );
+        let requests = worker.requests();
+        assert_eq!(
+            requests[0].timeseries[0].labels[0].value,
+            "busy_seconds_total"
+        );
+    }
+
+    #[test]
+    fn registry_rendered_as_text() {
+        let config = Builder::new().prefix("app_");
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+        let at = SystemTime::UNIX_EPOCH + Duration::from_millis(1000);
+
+        worker.handle(Command::Metadata(
+            KeyName::from("requests"),
+            MetricType::Counter,
+            None,
+            SharedString::from("Requests\nhandled."),
+        ));
+        worker.apply(
+            at,
+            Key::from_parts("requests", &[("path", "/\"a\"")]),
+            MetricOperation::IncrementCounter(2),
+        );
+        worker.apply(
+            at,
+            Key::from_name("temperature"),
+            MetricOperation::SetGauge(f64::NEG_INFINITY),
+        );
+        worker.apply(
+            at,
+            Key::from_name("latency"),
+            MetricOperation::RecordHistogram(0.5),
+        );
+
+        assert_eq!(
+            worker.render_text(),
+            "# TYPE app_latency histogram\n\
+             app_latency_bucket{le=\"+Inf\"} 1 1000\n\
+             app_latency_sum 0.5 1000\n\
+             app_latency_count 1 1000\n\
+             # HELP app_requests Requests\\nhandled.\n\
+             # TYPE app_requests counter\n\
+             app_requests{path=\"/\\\"a\\\"\"} 2 1000\n\
+             # TYPE app_temperature gauge\n\
+             app_temperature -Inf 1000\n"
+        );
+    }
+
+    #[test]
+    fn removed_series_marked_stale() {
+        let mut worker = Worker::new(Builder::new(), Arc::default(), Arc::default()).unwrap();
+
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("test"),
+            MetricOperation::SetGauge(1.0),
+        );
+        assert_eq!(worker.requests().len(), 1);
+
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("test"),
+            MetricOperation::Remove,
+        );
+        let requests = worker.requests();
+        let samples = &requests[0].timeseries[0].samples;
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].value.to_bits(), STALE_NAN.to_bits());
+
+        assert!(worker.requests().is_empty());
+        assert!(worker.registry.gauges.is_empty());
+    }
+
+    #[test]
+    fn invalid_names_dropped() {
+        let config = Builder::new().name_sanitization(NameSanitization::Drop);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        for name in ["http.requests", "http_requests"] {
+            worker.apply(
+                SystemTime::now(),
+                Key::from_name(name),
+                MetricOperation::IncrementCounter(1),
+            );
+        }
+
+        let requests = worker.requests();
+        assert_eq!(requests[0].timeseries.len(), 1);
+        assert_eq!(requests[0].timeseries[0].labels[0].value, "http_requests");
+        assert!(worker.invalid_names.contains("http.requests"));
+    }
+
+    #[test]
+    fn file_sink_replaces_endpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("requests.bin");
+
+        let config = Builder::new().file_sink(&path);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+        assert!(outputs(&worker).remotes.is_empty());
+
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("test"),
+            MetricOperation::SetGauge(1.0),
+        );
+        assert!(worker.write());
+
+        let contents = std::fs::read(&path).unwrap();
+        let request = types::WriteRequest::decode_length_delimited(contents.as_slice()).unwrap();
+        assert_eq!(request.timeseries.len(), 1);
+    }
+
+    #[test]
+    fn sinks_receive_every_request() {
+        struct Capture(Arc<Mutex<Vec<types::WriteRequest>>>);
+
+        impl Sink for Capture {
+            fn send(&mut self, request: &types::WriteRequest) -> Result<(), SinkError> {
+                self.0.lock().unwrap().push(request.clone());
+                Ok(())
+            }
+        }
+
+        let first = Arc::new(Mutex::new(vec![]));
+        let second = Arc::new(Mutex::new(vec![]));
+
+        let config = Builder::new()
+            .sink(Capture(first.clone()))
+            .sink(Capture(second.clone()));
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+        assert!(outputs(&worker).remotes.is_empty());
+
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("test"),
+            MetricOperation::SetGauge(1.0),
+        );
+        assert!(worker.write());
+
+        assert_eq!(first.lock().unwrap().len(), 1);
+        assert_eq!(*first.lock().unwrap(), *second.lock().unwrap());
+    }
+
+    #[test]
+    fn backfill_chunked() {
+        struct Capture(Arc<Mutex<Vec<types::WriteRequest>>>);
+
+        impl Sink for Capture {
+            fn send(&mut self, request: &types::WriteRequest) -> Result<(), SinkError> {
+                self.0.lock().unwrap().push(request.clone());
+                Ok(())
+            }
+        }
+
+        let captured = Arc::new(Mutex::new(vec![]));
+        let config = Builder::new()
+            .sink(Capture(captured.clone()))
+            .backfill_interval(Duration::ZERO);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+        let backfill = |worker: &mut Worker, samples| {
+            let (reply, rx) = crossbeam::channel::bounded(1);
+            worker.handle(Command::Backfill(samples, reply));
+            rx.recv_timeout(Duration::from_secs(5)).unwrap()
+        };
+
+        // newest first, with a duplicate of the latest
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let samples = (0..4500)
+            .rev()
+            .chain([4499])
+            .map(|i| (Key::from_name("temperature"), at(i), i as f64))
+            .collect();
+        assert!(backfill(&mut worker, samples));
+
+        let requests = std::mem::take(&mut *captured.lock().unwrap());
+        let sizes: Vec<_> = requests
+            .iter()
+            .map(|request| request.timeseries[0].samples.len())
+            .collect();
+        assert_eq!(sizes, [2000, 2000, 500]);
+        let timestamps: Vec<_> = requests
+            .iter()
+            .flat_map(|request| &request.timeseries[0].samples)
+            .map(|sample| sample.timestamp)
+            .collect();
+        assert!(timestamps.is_sorted());
+        assert!(worker.registry.gauges.is_empty());
+
+        // samples from the future are dropped
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        let samples = vec![
+            (Key::from_name("temperature"), at(1), 1.0),
+            (Key::from_name("temperature"), future, 2.0),
+        ];
+        assert!(!backfill(&mut worker, samples));
+        let requests = captured.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].timeseries[0].samples.len(), 1);
+    }
+
+    #[test]
+    fn atomic_handles_collected_without_queueing() {
+        let (batcher, mut batch_loop) = Builder::new().atomic_handles(true).build_worker().unwrap();
+
+        metrics::with_local_recorder(&batcher, || {
+            for _ in 0..3 {
+                metrics::counter!("requests").increment(1);
+            }
+            metrics::gauge!("temperature").set(21.5);
+            metrics::histogram!("latency").record(0.5);
+        });
+        // only the histogram was queued
+        assert_eq!(batcher.inner.queue_depth(), 1);
+
+        let worker = &mut batch_loop.worker;
+        worker.collect();
+        let value = |samples: Option<&Samples>| samples.unwrap().all().last().unwrap().value;
+        assert_eq!(
+            value(worker.registry.counters.get(&Key::from("requests"))),
+            3.0
+        );
+        assert_eq!(
+            value(worker.registry.gauges.get(&Key::from("temperature"))),
+            21.5
+        );
+    }
+
+    #[test]
+    fn keys_interned_if_enabled() {
+        for enabled in [false, true] {
+            let (batcher, mut batch_loop) =
+                Builder::new().intern_keys(enabled).build_worker().unwrap();
+
+            metrics::with_local_recorder(&batcher, || {
+                metrics::counter!("requests").increment(2);
+            });
+            assert_eq!(batcher.inner.interner.is_some(), enabled);
+
+            let worker = &mut batch_loop.worker;
+            while let Ok(cmd) = batch_loop.rx_cmd.try_recv() {
+                worker.handle(cmd);
+            }
+            let samples = &worker.registry.counters[&Key::from("requests")];
+            assert_eq!(samples.all().last().unwrap().value, 2.0);
+        }
+    }
+
+    #[test]
+    fn thread_buffers_collected_at_write() {
+        let (batcher, mut batch_loop) = Builder::new()
+            .thread_batching(Duration::from_secs(3600))
+            .build_worker()
+            .unwrap();
+
+        metrics::with_local_recorder(&batcher, || {
+            for _ in 0..3 {
+                metrics::counter!("requests").increment(1);
+            }
+        });
+        assert_eq!(batcher.inner.queue_depth(), 0);
+
+        let worker = &mut batch_loop.worker;
+        worker.collect();
+        let samples = worker
+            .registry
+            .counters
+            .get(&Key::from("requests"))
+            .unwrap();
+        assert_eq!(samples.all().last().unwrap().value, 3.0);
+    }
+
+    #[test]
+    fn operations_sharded_by_thread() {
+        let (batcher, mut batch_loop) = Builder::new().command_shards(4).build_worker().unwrap();
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    metrics::with_local_recorder(&batcher, || {
+                        for _ in 0..100 {
+                            metrics::counter!("requests").increment(1);
+                        }
+                    })
+                });
+            }
+        });
+        metrics::with_local_recorder(&batcher, || {
+            metrics::describe_counter!("requests", "Requests served");
+        });
+        assert_eq!(batcher.inner.rx_cmds.len(), 1);
+        assert_eq!(batcher.inner.queue_depth(), 801);
+
+        assert!(batch_loop.poll());
+        assert_eq!(batcher.inner.queue_depth(), 0);
+        let samples = &batch_loop.worker.registry.counters[&Key::from("requests")];
+        assert_eq!(samples.all().last().unwrap().value, 800.0);
+    }
+
+    #[test]
+    fn buffers_reused_across_writes() {
+        use crate::transport::HttpRequest;
+        use crate::transport::HttpResponse;
+        use crate::transport::TransportError;
+
+        struct Accepting;
+
+        impl Transport for Accepting {
+            fn post(&self, _: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+                Ok(HttpResponse {
+                    status: 204,
+                    ..Default::default()
+                })
+            }
+        }
+
+        let config = Builder::new()
+            .endpoint("http://localhost/api/v1/write")
+            .transport(Accepting);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("a"),
+            MetricOperation::SetGauge(1.0),
+        );
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("b"),
+            MetricOperation::SetGauge(1.0),
+        );
+        assert!(worker.write());
+        assert!(worker.timeseries.is_empty());
+        assert!(worker.timeseries.capacity() >= 2);
+        let encoded = outputs(&worker).encode_buffers[0].as_ptr();
+
+        worker.apply(
+            SystemTime::now(),
+            Key::from_name("a"),
+            MetricOperation::SetGauge(2.0),
+        );
+        assert!(worker.write());
+        assert_eq!(outputs(&worker).encode_buffers.len(), 1);
+        assert_eq!(outputs(&worker).encode_buffers[0].as_ptr(), encoded);
+    }
+
+    #[test]
+    fn slow_sends_pipelined() {
+        use crate::transport::HttpRequest;
+        use crate::transport::HttpResponse;
+        use crate::transport::TransportError;
+
+        struct Gated(Receiver<()>, Arc<Mutex<usize>>);
+
+        impl Transport for Gated {
+            fn post(&self, _: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+                let _ = self.0.recv();
+                *self.1.lock().unwrap() += 1;
+                Ok(HttpResponse {
+                    status: 204,
+                    ..Default::default()
+                })
+            }
+        }
+
+        let (open, gate) = crossbeam::channel::unbounded();
+        let sent = Arc::new(Mutex::new(0));
+        let config = Builder::new()
+            .endpoint("http://localhost/api/v1/write")
+            .transport(Gated(gate, sent.clone()))
+            .pipelined_sends(true);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        // the worker keeps going while the sender thread is stuck
+        for index in 0..5 {
+            worker.apply(
+                SystemTime::now(),
+                Key::from_name(format!("gauge_{index}")),
+                MetricOperation::SetGauge(1.0),
+            );
+            assert!(worker.write());
+        }
+        assert_eq!(*sent.lock().unwrap(), 0);
+
+        for _ in 0..5 {
+            open.send(()).unwrap();
+        }
+        assert!(worker.flush());
+        // held back requests are sent with the flush
+        assert_eq!(*sent.lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn held_back_sends_bounded() {
+        use crate::transport::HttpRequest;
+        use crate::transport::HttpResponse;
+        use crate::transport::TransportError;
+
+        struct Gated(Receiver<()>);
+
+        impl Transport for Gated {
+            fn post(&self, _: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+                let _ = self.0.recv();
+                Ok(HttpResponse {
+                    status: 204,
+                    ..Default::default()
+                })
+            }
+        }
+
+        let (open, gate) = crossbeam::channel::unbounded();
+        let config = Builder::new()
+            .endpoint("http://localhost/api/v1/write")
+            .transport(Gated(gate))
+            .max_buffered_samples(1)
+            .overflow_policy(OverflowPolicy::DropOldest)
+            .pipelined_sends(true);
+        let mut worker = Worker::new(config, Arc::default(), Arc::default()).unwrap();
+
+        let mut accepted = 0;
+        for index in 0..8 {
+            worker.apply(
+                SystemTime::now(),
+                Key::from_name(format!("gauge_{index}")),
+                MetricOperation::SetGauge(1.0),
+            );
+            accepted += usize::from(worker.write());
+        }
+        assert!(accepted < 8);
+        let stats = worker.remote_stats[0].1.clone();
+        assert!(stats.dropped.load(Ordering::Relaxed) > 0);
+
+        for _ in 0..8 {
+            open.send(()).unwrap();
+        }
+        assert!(worker.flush());
+    }
+
+    #[test]
+    fn lock_free_queue_block_times_out() {
+        let (batcher, _batch_loop) = Builder::new()
+            .lock_free_queue(1)
+            .overflow_policy(OverflowPolicy::Block(Duration::from_millis(5)))
+            .build_worker()
+            .unwrap();
+
+        let start = Instant::now();
+        metrics::with_local_recorder(&batcher, || {
+            metrics::counter!("requests").increment(1);
+            metrics::counter!("requests").increment(1);
+        });
+        assert!(start.elapsed() >= Duration::from_millis(5));
+        assert_eq!(batcher.inner.dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn operations_queued_lock_free() {
+        let (batcher, mut batch_loop) = Builder::new().lock_free_queue(4).build_worker().unwrap();
+
+        metrics::with_local_recorder(&batcher, || {
+            for _ in 0..6 {
+                metrics::counter!("requests").increment(1);
+            }
+            metrics::describe_counter!("requests", "Requests served");
+        });
+        assert_eq!(batcher.inner.rx_cmds.len(), 1);
+        assert_eq!(batcher.inner.queue_depth(), 5);
+        assert_eq!(batcher.inner.dropped.load(Ordering::Relaxed), 2);
+
+        assert!(batch_loop.poll());
+        assert_eq!(batcher.inner.queue_depth(), 0);
+        let samples = &batch_loop.worker.registry.counters[&Key::from("requests")];
+        assert_eq!(samples.all().last().unwrap().value, 4.0);
+    }
+
+    #[test]
+    fn batch_loop_polled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("requests.bin");
+
+        let (batcher, mut batch_loop) = Builder::new()
+            .file_sink(&path)
+            .batch_interval(Duration::from_millis(1))
+            .build_worker()
+            .unwrap();
+        let handle = batcher.handle();
+
+        batcher.send(gauge_op(1.0));
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(batch_loop.poll());
+        assert!(!std::fs::read(&path).unwrap().is_empty());
+
+        // the shutdown reply is sent by the next poll
+        let shutdown = std::thread::spawn(move || handle.shutdown(Duration::from_secs(5)));
+        while batch_loop.poll() {}
+        assert!(shutdown.join().unwrap());
+        assert!(!batch_loop.poll());
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn batch_loop_driven_by_clock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("requests.bin");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = crate::test_util::ManualClock::new(start);
+
+        let (batcher, mut batch_loop) = Builder::new()
+            .file_sink(&path)
+            .batch_interval(Duration::from_secs(10))
+            .clock(clock.clone())
+            .build_worker()
+            .unwrap();
+
+        metrics::with_local_recorder(&batcher, || metrics::gauge!("temperature").set(21.5));
+        assert!(batch_loop.poll());
+        assert!(std::fs::read(&path).unwrap_or_default().is_empty());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(batch_loop.poll());
+        let contents = std::fs::read(&path).unwrap();
+        let request = types::WriteRequest::decode_length_delimited(contents.as_slice()).unwrap();
+        assert_eq!(
+            request.timeseries[0].samples[0].timestamp,
+            1_700_000_000_000
+        );
+    }
+
+    #[test]
+    fn queue_config_applied() {
+        let mut queue = QueueConfig {
+            capacity: 50,
+            batch_send_deadline: Duration::from_secs(1),
+            min_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(2),
+            max_samples_per_send: 100,
+            ..Default::default()
+        };
+        let config = Builder::new().queue_config(queue);
+
+        assert_eq!(config.queue_capacity, Some(50));
+        assert_eq!(config.batch_interval, Duration::from_secs(1));
+        assert_eq!(config.retry.min_backoff, Duration::from_millis(10));
+        assert_eq!(config.retry.max_backoff, Duration::from_secs(2));
+        assert_eq!(config.max_samples_per_send, Some(100));
+        assert_eq!(config.max_in_flight, 1);
+        assert!(config.validate().is_ok());
+
+        queue.max_shards = 50;
+        let err = Builder::new().queue_config(queue).validate().unwrap_err();
+        assert!(matches!(err, BuildError::InvalidConfig(_)));
+    }
+
+    fn series(name: &str, samples: usize) -> types::TimeSeries {
+        types::TimeSeries {
+            labels: series_labels(
+                &Key::from_name(name.to_owned()),
+                MetricType::Gauge,
+                None,
+                &Builder::new(),
+            )
+            .unwrap(),
+            samples: vec![types::Sample::default(); samples],
+            exemplars: vec![],
+            histograms: vec![],
+        }
+    }
+
+    #[test]
+    fn split_by_request_bytes() {
+        let timeseries = vec![series("a", 10), series("b", 10), series("c", 10)];
+        let one = types::WriteRequest {
+            timeseries: vec![timeseries[0].clone()],
+            metadata: vec![],
+        }
+        .encoded_len();
+
+        let requests = split(timeseries.clone(), Some(one * 2), None);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].len(), 2);
+        assert_eq!(requests[1].len(), 1);
+
+        for request in requests {
+            let encoded = types::WriteRequest {
+                timeseries: request,
+                metadata: vec![],
+            }
+            .encoded_len();
+            assert!(encoded <= one * 2);
+        }
+
+        // a series larger than the limit is sent on its own
+        assert_eq!(split(timeseries.clone(), Some(1), None).len(), 3);
+        assert_eq!(split(timeseries, None, None).len(), 1);
+    }
+
+    #[test]
+    fn split_by_samples_per_send() {
+        let timeseries = vec![series("a", 3), series("b", 3), series("c", 7)];
+
+        let requests = split(timeseries, None, Some(5));
+        let counts: Vec<Vec<usize>> = requests
+            .iter()
+            .map(|request| request.iter().map(|series| series.samples.len()).collect())
+            .collect();
+        assert_eq!(counts, vec![vec![3], vec![3], vec![5], vec![2]]);
+        assert_eq!(requests[3][0].labels, requests[2][0].labels);
+    }
+
+    #[test]
+    fn prefix_applied_to_name() {
+        let key = Key::from_name("requests");
+        let config = Builder::new().prefix("myapp_");
+        let labels = series_labels(&key, MetricType::Gauge, None, &config).unwrap();
+
+        assert_eq!(labels[0].name, "__name__");
+        assert_eq!(labels[0].value, "myapp_requests");
+
+        // only the process collector's series keep their standard names
+        #[cfg(feature = "process-metrics")]
+        {
+            let config = config.process_metrics(Duration::from_secs(10));
+            let key = Key::from_name("process_threads");
+            let labels = series_labels(&key, MetricType::Gauge, None, &config).unwrap();
+            assert_eq!(labels[0].value, "myapp_process_threads");
+
+            let sample = crate::process::Sample {
+                name: "process_threads",
+                help: "",
+                value: 1.0,
+            };
+            let labels = series_labels(&sample.key(), MetricType::Gauge, None, &config).unwrap();
+            assert_eq!(labels.len(), 1);
+            assert_eq!(labels[0].value, "process_threads");
         }
     }
 }
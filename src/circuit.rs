@@ -0,0 +1,98 @@
+use std::time::Duration;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Writes are attempted as normal.
+    Closed,
+    /// Writes are skipped until the given time.
+    Open(Instant),
+    /// A single probe write is allowed to test the endpoint.
+    HalfOpen,
+}
+
+/// Stops sending after repeated failures.
+///
+/// After `threshold` consecutive failures the circuit opens and writes are
+/// skipped for `cooldown`. The next write is a probe: success closes the
+/// circuit, failure opens it again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: u32,
+    state: State,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            failures: 0,
+            state: State::Closed,
+        }
+    }
+
+    /// Whether a write may be attempted now.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.state {
+            State::Closed | State::HalfOpen => true,
+            State::Open(until) if now >= until => {
+                self.state = State::HalfOpen;
+                true
+            }
+            State::Open(_) => false,
+        }
+    }
+
+    /// Record a successful write.
+    pub fn success(&mut self) {
+        self.failures = 0;
+        self.state = State::Closed;
+    }
+
+    /// Record a failed write, returning `true` if this opened the circuit.
+    pub fn failure(&mut self, now: Instant) -> bool {
+        self.failures = self.failures.saturating_add(1);
+
+        if self.state == State::HalfOpen || self.failures >= self.threshold {
+            self.state = State::Open(now + self.cooldown);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold_and_probes() {
+        let cooldown = Duration::from_secs(10);
+        let mut breaker = CircuitBreaker::new(2, cooldown);
+        let now = Instant::now();
+
+        assert!(breaker.allow(now));
+        assert!(!breaker.failure(now));
+        assert!(breaker.allow(now));
+        assert!(breaker.failure(now));
+
+        // open
+        assert!(!breaker.allow(now + Duration::from_secs(5)));
+
+        // half open probe fails, circuit opens again
+        let probe = now + cooldown;
+        assert!(breaker.allow(probe));
+        assert!(breaker.failure(probe));
+        assert!(!breaker.allow(probe + Duration::from_secs(5)));
+
+        // probe succeeds, circuit closes
+        assert!(breaker.allow(probe + cooldown));
+        breaker.success();
+        assert!(breaker.allow(probe + cooldown));
+        assert!(!breaker.failure(probe + cooldown));
+    }
+}
@@ -0,0 +1,321 @@
+use crate::registry::timestamp_millis;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+#[cfg(feature = "test-util")]
+use std::sync::Mutex;
+#[cfg(feature = "test-util")]
+use std::sync::PoisonError;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Source of the time samples are stamped with and the worker schedules
+/// writes by.
+///
+/// Set with [`Builder::clock`](crate::Builder::clock), e.g. to drive time
+/// deterministically in tests or to use a device's own time source.
+pub trait Clock: Send + Sync {
+    /// Wall-clock time, used to timestamp samples.
+    fn now(&self) -> SystemTime;
+
+    /// Monotonic time, used to schedule heartbeats and writes when polling
+    /// a [`BatchLoop`](crate::BatchLoop).
+    ///
+    /// Default is [`Instant::now`].
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The operating system's clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Wall-clock time advanced by a monotonic clock, so samples stay in order
+/// when the system clock is stepped, e.g. by NTP.
+///
+/// The system clock is read once and then again every `reanchor`
+/// interval; in between, time advances with [`Instant`]. Re-anchoring never
+/// moves time backwards: after the system clock was stepped back, time keeps
+/// advancing from where it was until the system clock catches up.
+#[derive(Debug)]
+pub struct AnchoredClock {
+    reanchor: Duration,
+    origin: Instant,
+    /// Wall-clock time at `origin` by the latest anchor, in nanoseconds
+    /// since the Unix epoch. Only ever increases.
+    offset: AtomicU64,
+    /// Time since `origin` the system clock was last read at, in
+    /// nanoseconds.
+    anchored: AtomicU64,
+}
+
+impl AnchoredClock {
+    /// Anchor to the system clock now and every `reanchor` interval.
+    pub fn new(reanchor: Duration) -> Self {
+        Self {
+            reanchor,
+            origin: Instant::now(),
+            offset: AtomicU64::new(nanos_since_epoch(SystemTime::now())),
+            anchored: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Clock for AnchoredClock {
+    fn now(&self) -> SystemTime {
+        let elapsed = self.origin.elapsed().as_nanos() as u64;
+        let mut offset = self.offset.load(Ordering::Relaxed);
+
+        let since_anchor = elapsed.saturating_sub(self.anchored.load(Ordering::Relaxed));
+        if since_anchor >= self.reanchor.as_nanos() as u64 {
+            let wall = nanos_since_epoch(SystemTime::now());
+            // racing threads both keep the later time
+            let previous = self
+                .offset
+                .fetch_max(wall.saturating_sub(elapsed), Ordering::Relaxed);
+            offset = previous.max(wall.saturating_sub(elapsed));
+            self.anchored.store(elapsed, Ordering::Relaxed);
+        }
+
+        UNIX_EPOCH + Duration::from_nanos(offset + elapsed)
+    }
+}
+
+fn nanos_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+/// Wall-clock time advanced by the CPU's timestamp counter through
+/// [`quanta`], avoiding a system call for every timestamp.
+///
+/// The counter is calibrated against the system clock when created, and
+/// falls back to the operating system's monotonic clock where it isn't
+/// reliable. Like [`AnchoredClock`] without re-anchoring, time doesn't follow
+/// steps of the system clock after creation.
+#[cfg(feature = "quanta")]
+#[derive(Debug, Clone)]
+pub struct QuantaClock {
+    clock: quanta::Clock,
+    /// System time when `start` was read.
+    wall: SystemTime,
+    start: quanta::Instant,
+}
+
+#[cfg(feature = "quanta")]
+impl QuantaClock {
+    /// Calibrate the timestamp counter against the system clock now.
+    pub fn new() -> Self {
+        let clock = quanta::Clock::new();
+        Self {
+            start: clock.now(),
+            wall: SystemTime::now(),
+            clock,
+        }
+    }
+}
+
+#[cfg(feature = "quanta")]
+impl Default for QuantaClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "quanta")]
+impl Clock for QuantaClock {
+    fn now(&self) -> SystemTime {
+        self.wall + self.clock.now().duration_since(self.start)
+    }
+}
+
+/// A clock shared between clones of the builder, the handles and the worker.
+#[derive(Clone)]
+pub(crate) struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new(clock: impl Clock + 'static) -> Self {
+        Self(Arc::new(clock))
+    }
+
+    pub fn now(&self) -> SystemTime {
+        self.0.now()
+    }
+
+    pub fn instant(&self) -> Instant {
+        self.0.instant()
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+/// The time of another clock, read every `resolution` by a thread of its
+/// own so operations only load an atomic.
+pub(crate) struct CoarseClock {
+    clock: SharedClock,
+    millis: Arc<AtomicI64>,
+}
+
+impl CoarseClock {
+    /// Read `clock` every `resolution` until dropped.
+    pub fn spawn(clock: SharedClock, resolution: Duration) -> io::Result<Self> {
+        let millis = Arc::new(AtomicI64::new(timestamp_millis(clock.now())));
+        let latest = Arc::downgrade(&millis);
+        let source = clock.clone();
+
+        std::thread::Builder::new()
+            .name("prom-write-clock".to_owned())
+            .spawn(move || {
+                loop {
+                    std::thread::sleep(resolution);
+                    let Some(millis) = latest.upgrade() else {
+                        break;
+                    };
+                    millis.store(timestamp_millis(source.now()), Ordering::Relaxed);
+                }
+            })?;
+
+        Ok(Self { clock, millis })
+    }
+}
+
+impl Clock for CoarseClock {
+    fn now(&self) -> SystemTime {
+        let millis = self.millis.load(Ordering::Relaxed);
+        UNIX_EPOCH + Duration::from_millis(millis as u64)
+    }
+
+    fn instant(&self) -> Instant {
+        self.clock.instant()
+    }
+}
+
+/// A clock that only moves when advanced, for deterministic tests.
+///
+/// Clones share the same time.
+#[cfg(feature = "test-util")]
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: SystemTime,
+    origin: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+#[cfg(feature = "test-util")]
+impl ManualClock {
+    /// A clock reading `start`.
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            start,
+            origin: Instant::now(),
+            elapsed: Arc::default(),
+        }
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner) += duration;
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.start + self.elapsed()
+    }
+
+    fn instant(&self) -> Instant {
+        self.origin + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchored_time_never_goes_back() {
+        for reanchor in [Duration::ZERO, Duration::from_secs(60)] {
+            let clock = AnchoredClock::new(reanchor);
+
+            let mut previous = clock.now();
+            for _ in 0..1000 {
+                let now = clock.now();
+                assert!(now >= previous);
+                previous = now;
+            }
+
+            let drift = match SystemTime::now().duration_since(previous) {
+                Ok(drift) => drift,
+                Err(err) => err.duration(),
+            };
+            assert!(drift < Duration::from_secs(1));
+        }
+    }
+
+    #[cfg(feature = "quanta")]
+    #[test]
+    fn quanta_time_follows_system_time() {
+        let clock = QuantaClock::new();
+
+        let mut previous = clock.now();
+        for _ in 0..1000 {
+            let now = clock.now();
+            assert!(now >= previous);
+            previous = now;
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+        let drift = match SystemTime::now().duration_since(clock.now()) {
+            Ok(drift) => drift,
+            Err(err) => err.duration(),
+        };
+        assert!(drift < Duration::from_secs(1));
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn coarse_time_read_at_resolution() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = ManualClock::new(start);
+        let hourly =
+            CoarseClock::spawn(SharedClock::new(clock.clone()), Duration::from_secs(3600)).unwrap();
+        let frequent =
+            CoarseClock::spawn(SharedClock::new(clock.clone()), Duration::from_millis(1)).unwrap();
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(hourly.now(), start);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while frequent.now() != clock.now() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
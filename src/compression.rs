@@ -0,0 +1,172 @@
+use std::io::Read;
+use std::io::Write;
+
+/// Compression applied to the encoded write request body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Snappy block format. Required by the remote write 1.0 spec.
+    #[default]
+    Snappy,
+    /// Snappy framed (streaming) format, for receivers that don't accept the
+    /// block format.
+    SnappyFramed,
+    /// Zstandard with the given compression level, e.g. 3.
+    ///
+    /// Only allowed by Remote Write 2.0, see
+    /// [`Builder::protocol`](crate::Builder::protocol), so building with 1.0
+    /// fails. Endpoints that fall back to 1.0 are sent snappy instead.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    /// Send the body uncompressed.
+    None,
+}
+
+impl Compression {
+    /// Value of the `Content-Encoding` header, if any.
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::Snappy => Some("snappy"),
+            Compression::SnappyFramed => Some("x-snappy-framed"),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => Some("zstd"),
+            Compression::None => None,
+        }
+    }
+
+    /// Compress the given bytes.
+    pub fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::Snappy => Ok(snap::raw::Encoder::new().compress_vec(data)?),
+            Compression::SnappyFramed => {
+                let mut encoder = snap::write::FrameEncoder::new(Vec::new());
+                encoder.write_all(data)?;
+                encoder.into_inner().map_err(|err| err.into_error())
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => zstd::bulk::compress(data, *level),
+            Compression::None => Ok(data.to_vec()),
+        }
+    }
+
+    /// Decompress bytes compressed with [`compress`](Self::compress).
+    pub fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::Snappy => Ok(snap::raw::Decoder::new().decompress_vec(data)?),
+            Compression::SnappyFramed => {
+                let mut decompressed = vec![];
+                snap::read::FrameDecoder::new(data).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => zstd::stream::decode_all(data),
+            Compression::None => Ok(data.to_vec()),
+        }
+    }
+}
+
+/// Compresses request bodies, keeping the snappy encoder and the output
+/// buffer between requests.
+#[derive(Debug)]
+pub(crate) struct Compressor {
+    compression: Compression,
+    snappy: snap::raw::Encoder,
+    output: Vec<u8>,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            snappy: snap::raw::Encoder::new(),
+            output: vec![],
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Compress the given bytes, returning the compressed bytes until the
+    /// next call.
+    pub fn compress<'a>(&'a mut self, data: &'a [u8]) -> std::io::Result<&'a [u8]> {
+        let len = match self.compression {
+            Compression::Snappy => {
+                // only grown, the bytes past the compressed length are ignored
+                let max = snap::raw::max_compress_len(data.len());
+                if self.output.len() < max {
+                    self.output.resize(max, 0);
+                }
+                self.snappy.compress(data, &mut self.output)?
+            }
+            Compression::SnappyFramed => {
+                self.output.clear();
+                let mut encoder = snap::write::FrameEncoder::new(&mut self.output);
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                drop(encoder);
+                self.output.len()
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                self.output.clear();
+                zstd::stream::copy_encode(data, &mut self.output, level)?;
+                self.output.len()
+            }
+            Compression::None => return Ok(data),
+        };
+        Ok(&self.output[..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snappy_round_trip() {
+        let data = b"hello hello hello hello".to_vec();
+        let compressed = Compression::Snappy.compress(&data).unwrap();
+        let decompressed = snap::raw::Decoder::new()
+            .decompress_vec(&compressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn snappy_framed_round_trip() {
+        let data = b"hello hello hello hello".to_vec();
+        let compressed = Compression::SnappyFramed.compress(&data).unwrap();
+        let mut decompressed = vec![];
+        snap::read::FrameDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn compressor_reused() {
+        let data = b"hello hello hello hello".to_vec();
+        for compression in [
+            Compression::Snappy,
+            Compression::SnappyFramed,
+            Compression::None,
+        ] {
+            let mut compressor = Compressor::new(compression);
+            let long = compressor.compress(&data.repeat(10)).unwrap().to_vec();
+            assert_eq!(compression.decompress(&long).unwrap(), data.repeat(10));
+
+            // a shorter body after a longer one
+            let short = compressor.compress(&data).unwrap();
+            assert_eq!(compression.decompress(short).unwrap(), data);
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        let data = b"hello hello hello hello".to_vec();
+        let compressed = Compression::Zstd(3).compress(&data).unwrap();
+        let decompressed = zstd::bulk::decompress(&compressed, data.len()).unwrap();
+        assert_eq!(decompressed, data);
+    }
+}
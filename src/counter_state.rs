@@ -0,0 +1,132 @@
+use crate::registry::KeyMap;
+use crate::registry::Samples;
+use crate::types;
+use metrics::Key;
+use prost::Message;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A counter saved by a previous process.
+#[derive(Debug, PartialEq)]
+pub struct SavedCounter {
+    pub key: Key,
+    pub value: f64,
+    /// When the counter was first observed, in milliseconds.
+    pub created: i64,
+}
+
+/// Save the latest total of every counter to `path`.
+///
+/// The file holds an uncompressed write request with a series per counter,
+/// named before any prefix, whose single sample is the total timestamped
+/// with the counter's creation time.
+pub fn save(path: &Path, counters: &KeyMap<Samples>) -> io::Result<()> {
+    let timeseries = counters
+        .iter()
+        .filter_map(|(key, samples)| {
+            let last = samples.all().last()?;
+            let labels = [("__name__", key.name())]
+                .into_iter()
+                .chain(key.labels().map(|label| (label.key(), label.value())))
+                .map(|(name, value)| types::Label {
+                    name: name.to_owned(),
+                    value: value.to_owned(),
+                })
+                .collect();
+
+            Some(types::TimeSeries {
+                labels,
+                samples: vec![types::Sample {
+                    value: last.value,
+                    timestamp: samples.created(),
+                }],
+                exemplars: vec![],
+                histograms: vec![],
+            })
+        })
+        .collect();
+
+    let encoded = types::WriteRequest {
+        timeseries,
+        metadata: vec![],
+    }
+    .encode_to_vec();
+
+    // write then rename so a crash never leaves a partial file
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, encoded)?;
+    fs::rename(tmp, path)
+}
+
+/// Counters saved to `path`, none if it doesn't exist.
+pub fn load(path: &Path) -> io::Result<Vec<SavedCounter>> {
+    let encoded = match fs::read(path) {
+        Ok(encoded) => encoded,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+
+    let request = types::WriteRequest::decode(encoded.as_slice())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    Ok(request
+        .timeseries
+        .into_iter()
+        .filter_map(|series| {
+            let sample = *series.samples.first()?;
+            let mut name = None;
+            let mut labels = vec![];
+            for label in series.labels {
+                if label.name == "__name__" {
+                    name = Some(label.value);
+                } else {
+                    labels.push(metrics::Label::new(label.name, label.value));
+                }
+            }
+
+            Some(SavedCounter {
+                key: Key::from_parts(name?, labels),
+                value: sample.value,
+                created: sample.timestamp,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_saved_and_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters");
+        assert_eq!(load(&path).unwrap(), []);
+
+        let key = Key::from_parts("requests", &[("status", "200")]);
+        let mut counters = KeyMap::default();
+        let mut samples = Samples::new(types::Sample {
+            value: 2.0,
+            timestamp: 100,
+        });
+        samples.increment(types::Sample {
+            value: 3.0,
+            timestamp: 200,
+        });
+        counters.insert(key.clone(), samples);
+
+        save(&path, &counters).unwrap();
+        assert_eq!(
+            load(&path).unwrap(),
+            [SavedCounter {
+                key,
+                value: 5.0,
+                created: 100,
+            }]
+        );
+
+        fs::write(&path, b"\xff").unwrap();
+        assert!(load(&path).is_err());
+    }
+}
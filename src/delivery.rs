@@ -0,0 +1,376 @@
+use crate::batcher::Builder;
+use crate::batcher::OverflowPolicy;
+use crate::protocol::CreatedSeries;
+use crate::protocol::Protocol;
+use crate::remote::Batch;
+use crate::remote::Remote;
+use crate::remote::Stats;
+use crate::sink::SharedSink;
+use crate::types;
+use crossbeam::channel::Receiver;
+use crossbeam::channel::SendTimeoutError;
+use crossbeam::channel::Sender;
+use crossbeam::channel::TrySendError;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tracing::debug;
+use tracing::error;
+use tracing::warn;
+
+/// Most buffers of sent batches kept to encode the next ones into.
+const MAX_ENCODE_BUFFERS: usize = 16;
+
+/// Writes queued for the sender thread before more are held back.
+const PIPELINE_DEPTH: usize = 2;
+
+/// The sinks and remotes write requests are handed to.
+pub struct Outputs {
+    pub remotes: Vec<Remote>,
+    /// Replace the remotes if any are configured.
+    pub sinks: Vec<SharedSink>,
+    /// Buffers of sent batches, to encode the next ones into.
+    pub encode_buffers: Vec<Vec<u8>>,
+}
+
+impl Outputs {
+    pub fn new(remotes: Vec<Remote>, sinks: Vec<SharedSink>) -> Self {
+        Self {
+            remotes,
+            sinks,
+            encode_buffers: vec![],
+        }
+    }
+
+    /// Hand write requests to the sinks, or every remote if there are none,
+    /// with the `created` series of their counters.
+    ///
+    /// Returns `true` if everything was accepted.
+    pub fn deliver(
+        &mut self,
+        config: &Builder,
+        requests: &[types::WriteRequest],
+        created: &CreatedSeries,
+    ) -> bool {
+        let mut delivered = true;
+        if !self.sinks.is_empty() {
+            for request in requests {
+                for sink in &self.sinks {
+                    if let Err(err) = sink.send(request) {
+                        error!("Sink failed: {err}");
+                        delivered = false;
+                    }
+                }
+            }
+            return delivered;
+        }
+
+        // remotes may have fallen back to an older protocol
+        let mut encoded: Vec<(Protocol, Vec<Batch>)> = vec![];
+        for index in 0..self.remotes.len() {
+            let protocol = self.remotes[index].protocol();
+            let position = match encoded.iter().position(|(p, _)| *p == protocol) {
+                Some(position) => position,
+                None => {
+                    let buffers = &mut self.encode_buffers;
+                    let batches = requests
+                        .iter()
+                        .map(|request| {
+                            let buffer = buffers.pop().unwrap_or_default();
+                            encode(request, created, protocol, config.encode_threads, buffer)
+                        })
+                        .collect();
+                    encoded.push((protocol, batches));
+                    encoded.len() - 1
+                }
+            };
+
+            // the last remote using the protocol takes the batches
+            let batches = if self.remotes[index + 1..]
+                .iter()
+                .any(|remote| remote.protocol() == protocol)
+            {
+                encoded[position].1.clone()
+            } else {
+                std::mem::take(&mut encoded[position].1)
+            };
+
+            delivered &= self.remotes[index].write(config, batches);
+        }
+
+        for remote in &mut self.remotes {
+            self.encode_buffers.extend(remote.take_spent());
+        }
+        self.encode_buffers.truncate(MAX_ENCODE_BUFFERS);
+
+        delivered
+    }
+
+    /// Resend dead-lettered batches, returning how many were delivered.
+    pub fn replay_dead_letters(&mut self, config: &Builder) -> usize {
+        self.remotes
+            .iter_mut()
+            .map(|remote| remote.replay_dead_letters(config))
+            .sum()
+    }
+}
+
+/// Encode a write request for the HTTP remotes into `encoded`, on up to
+/// `threads` threads.
+fn encode(
+    request: &types::WriteRequest,
+    created: &CreatedSeries,
+    protocol: Protocol,
+    threads: usize,
+    mut encoded: Vec<u8>,
+) -> Batch {
+    let samples = samples(request);
+
+    protocol.encode_into(request, created, threads, &mut encoded);
+    debug!(samples, bytes = encoded.len(), "Encoded write request");
+
+    Batch {
+        encoded,
+        samples,
+        oldest: crate::remote::oldest(request),
+    }
+}
+
+/// Hands write requests to the outputs, from the worker or a sender thread.
+pub enum Delivery {
+    /// Written to by the worker itself.
+    Inline(Outputs),
+    /// Written to by a sender thread, so slow endpoints don't hold up the
+    /// worker.
+    Pipelined(Pipeline),
+}
+
+impl Delivery {
+    /// Start a sender thread for `outputs` if
+    /// [`Builder::pipelined_sends`] is enabled.
+    pub fn new(config: &Builder, outputs: Outputs) -> io::Result<Self> {
+        if !config.pipelined_sends {
+            return Ok(Delivery::Inline(outputs));
+        }
+        Pipeline::spawn(config.clone(), outputs).map(Delivery::Pipelined)
+    }
+
+    /// Hand write requests over, waiting for them to be sent if `wait` is
+    /// set.
+    ///
+    /// Requests handed to the sender thread are taken from `requests`, along
+    /// with the `created` series of their counters, and replaced by those it
+    /// has since sent, for the worker to reuse like those it writes itself.
+    /// Returns `true` if everything was accepted, which for requests left to
+    /// the sender thread only means they were queued or held back without
+    /// dropping any.
+    pub fn write(
+        &mut self,
+        config: &Builder,
+        requests: &mut Vec<types::WriteRequest>,
+        created: &mut CreatedSeries,
+        wait: bool,
+    ) -> bool {
+        match self {
+            Delivery::Inline(outputs) => outputs.deliver(config, requests, created),
+            Delivery::Pipelined(pipeline) => {
+                let requests_taken = std::mem::take(requests);
+                let created = std::mem::take(created);
+                let accepted = if wait {
+                    pipeline.flush(requests_taken, created)
+                } else {
+                    pipeline.write(config, requests_taken, created)
+                };
+                *requests = pipeline.spent();
+                accepted
+            }
+        }
+    }
+
+    /// Resend dead-lettered batches, returning how many were delivered.
+    pub fn replay_dead_letters(&mut self, config: &Builder) -> usize {
+        match self {
+            Delivery::Inline(outputs) => outputs.replay_dead_letters(config),
+            Delivery::Pipelined(pipeline) => pipeline.replay_dead_letters(),
+        }
+    }
+}
+
+/// Number of samples and histograms in `request`.
+fn samples(request: &types::WriteRequest) -> usize {
+    request
+        .timeseries
+        .iter()
+        .map(|series| series.samples.len() + series.histograms.len())
+        .sum()
+}
+
+/// Work for the sender thread.
+enum Job {
+    Write(Vec<types::WriteRequest>, CreatedSeries),
+    /// Write, replying whether everything pending was accepted.
+    Flush(Vec<types::WriteRequest>, CreatedSeries, Sender<bool>),
+    ReplayDeadLetters(Sender<usize>),
+}
+
+/// Queue of write requests to a thread encoding, compressing and sending
+/// them.
+pub struct Pipeline {
+    tx: Sender<Job>,
+    /// Requests the sender thread is done with, to reuse their allocations.
+    spent: Receiver<Vec<types::WriteRequest>>,
+    /// Requests held back while the queue was full, sent with the next.
+    held: Vec<types::WriteRequest>,
+    /// Created series of the held requests.
+    held_created: CreatedSeries,
+    /// Counters of each remote, which held back samples dropped count
+    /// against.
+    stats: Vec<Arc<Stats>>,
+}
+
+impl Pipeline {
+    /// Start the sender thread, which exits once the pipeline is dropped.
+    fn spawn(config: Builder, mut outputs: Outputs) -> io::Result<Self> {
+        let (tx, rx) = crossbeam::channel::bounded(PIPELINE_DEPTH);
+        let (tx_spent, spent) = crossbeam::channel::bounded(PIPELINE_DEPTH);
+        let stats = outputs
+            .remotes
+            .iter()
+            .map(|remote| remote.stats().clone())
+            .collect();
+
+        std::thread::Builder::new()
+            .name("prom-write-sender".to_owned())
+            .spawn(move || {
+                for job in rx {
+                    match job {
+                        Job::Write(requests, created) => {
+                            outputs.deliver(&config, &requests, &created);
+                            let _ = tx_spent.try_send(requests);
+                        }
+                        Job::Flush(requests, created, reply) => {
+                            let _ = reply.send(outputs.deliver(&config, &requests, &created));
+                            let _ = tx_spent.try_send(requests);
+                        }
+                        Job::ReplayDeadLetters(reply) => {
+                            let _ = reply.send(outputs.replay_dead_letters(&config));
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            tx,
+            spent,
+            held: vec![],
+            held_created: CreatedSeries::new(),
+            stats,
+        })
+    }
+
+    /// Queue write requests without waiting, holding them back for the next
+    /// write if the sender thread is behind, or waiting for it with
+    /// [`OverflowPolicy::Block`].
+    ///
+    /// Held back requests beyond
+    /// [`Builder::max_buffered_samples`] are dropped as the
+    /// [`overflow_policy`](Builder::overflow_policy) says. Returns `false` if
+    /// any were, or the sender thread stopped.
+    fn write(
+        &mut self,
+        config: &Builder,
+        requests: Vec<types::WriteRequest>,
+        created: CreatedSeries,
+    ) -> bool {
+        self.held.extend(requests);
+        self.held_created.extend(created);
+        if self.held.is_empty() {
+            return true;
+        }
+
+        let job = Job::Write(
+            std::mem::take(&mut self.held),
+            std::mem::take(&mut self.held_created),
+        );
+        let result = match config.overflow {
+            OverflowPolicy::Block(timeout) => {
+                self.tx.send_timeout(job, timeout).map_err(|err| match err {
+                    SendTimeoutError::Timeout(job) => TrySendError::Full(job),
+                    SendTimeoutError::Disconnected(job) => TrySendError::Disconnected(job),
+                })
+            }
+            _ => self.tx.try_send(job),
+        };
+        match result {
+            Ok(()) => true,
+            Err(TrySendError::Full(Job::Write(requests, created))) => {
+                debug!("sender busy. holding back {} requests", requests.len());
+                self.held = requests;
+                self.held_created = created;
+                self.enforce_budget(config)
+            }
+            Err(_) => {
+                error!("Sender thread stopped, dropping write requests");
+                false
+            }
+        }
+    }
+
+    /// Drop held back requests, the oldest with [`OverflowPolicy::DropOldest`]
+    /// and the newest otherwise, until at most `max_buffered_samples` samples
+    /// remain. One request is always kept.
+    ///
+    /// Returns `false` if any were dropped.
+    fn enforce_budget(&mut self, config: &Builder) -> bool {
+        let mut total: usize = self.held.iter().map(samples).sum();
+        let mut dropped = 0;
+
+        while total > config.max_buffered_samples && self.held.len() > 1 {
+            let index = match config.overflow {
+                OverflowPolicy::DropOldest => 0,
+                _ => self.held.len() - 1,
+            };
+            let request = self.held.remove(index);
+            total -= samples(&request);
+            dropped += samples(&request);
+        }
+
+        if dropped == 0 {
+            return true;
+        }
+
+        for stats in &self.stats {
+            stats.dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+        }
+        warn!("Sender busy, dropped {dropped} held back samples");
+        false
+    }
+
+    /// Requests the sender thread has sent since last asked.
+    fn spent(&self) -> Vec<types::WriteRequest> {
+        self.spent.try_iter().flatten().collect()
+    }
+
+    /// Send write requests along with everything queued before them,
+    /// waiting until done.
+    fn flush(&mut self, requests: Vec<types::WriteRequest>, created: CreatedSeries) -> bool {
+        let mut pending = std::mem::take(&mut self.held);
+        pending.extend(requests);
+        let mut pending_created = std::mem::take(&mut self.held_created);
+        pending_created.extend(created);
+
+        let (reply, rx) = crossbeam::channel::bounded(1);
+        self.tx
+            .send(Job::Flush(pending, pending_created, reply))
+            .is_ok()
+            && rx.recv().unwrap_or(false)
+    }
+
+    fn replay_dead_letters(&mut self) -> usize {
+        let (reply, rx) = crossbeam::channel::bounded(1);
+        if self.tx.send(Job::ReplayDeadLetters(reply)).is_err() {
+            return 0;
+        }
+        rx.recv().unwrap_or(0)
+    }
+}
@@ -0,0 +1,66 @@
+use crate::transport::TransportError;
+use metrics::SetRecorderError;
+use std::fmt;
+
+/// Error returned when building or installing a [`Batcher`](crate::Batcher).
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// The endpoint is not a valid `http` or `https` URL.
+    InvalidEndpoint(String),
+    /// The TLS settings can't be used, e.g. a malformed certificate bundle.
+    InvalidTls(String),
+    /// The built-in HTTP client could not be created.
+    Transport(TransportError),
+    /// A builder option is invalid or conflicts with another option.
+    InvalidConfig(String),
+    /// An endpoint failed the [`fail_fast`](crate::Builder::fail_fast) probe.
+    Unreachable(String),
+    /// The [`file_sink`](crate::Builder::file_sink) could not be opened.
+    File(std::io::Error),
+    /// The worker thread could not be spawned.
+    Spawn(std::io::Error),
+    /// The scrape endpoint address could not be bound.
+    Listen(std::io::Error),
+    /// A global recorder has already been installed.
+    ///
+    /// Holds the [`SetRecorderError`] of the recorder that wasn't installed,
+    /// a `Batcher` or for [`install_with`](crate::Builder::install_with) a
+    /// [`Fanout`](crate::Fanout), which `downcast` recovers.
+    SetRecorder(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::InvalidEndpoint(reason) => write!(f, "invalid endpoint: {reason}"),
+            BuildError::InvalidTls(reason) => write!(f, "invalid TLS configuration: {reason}"),
+            BuildError::Transport(err) => write!(f, "failed to create HTTP client: {err}"),
+            BuildError::InvalidConfig(reason) => write!(f, "invalid configuration: {reason}"),
+            BuildError::Unreachable(reason) => write!(f, "endpoint unreachable: {reason}"),
+            BuildError::File(err) => write!(f, "failed to open output file: {err}"),
+            BuildError::Spawn(err) => write!(f, "failed to spawn worker thread: {err}"),
+            BuildError::Listen(err) => write!(f, "failed to bind scrape endpoint: {err}"),
+            BuildError::SetRecorder(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Transport(err) => Some(&**err),
+            BuildError::File(err) => Some(err),
+            BuildError::Spawn(err) => Some(err),
+            BuildError::Listen(err) => Some(err),
+            BuildError::SetRecorder(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl<R: Send + Sync + 'static> From<SetRecorderError<R>> for BuildError {
+    fn from(err: SetRecorderError<R>) -> Self {
+        BuildError::SetRecorder(Box::new(err))
+    }
+}
@@ -0,0 +1,57 @@
+use crate::protocol;
+use crate::types;
+use crate::types::metric_metadata::MetricType;
+use std::fmt::Write;
+
+/// Write the `# HELP` and `# TYPE` lines of a metric family in the
+/// Prometheus text format.
+pub fn header(out: &mut String, name: &str, metric_type: MetricType, help: Option<&str>) {
+    if let Some(help) = help.filter(|help| !help.is_empty()) {
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        let _ = writeln!(out, "# HELP {name} {help}");
+    }
+
+    let metric_type = match metric_type {
+        MetricType::Counter => "counter",
+        MetricType::Gauge => "gauge",
+        MetricType::Histogram => "histogram",
+        MetricType::Summary => "summary",
+        _ => "untyped",
+    };
+    let _ = writeln!(out, "# TYPE {name} {metric_type}");
+}
+
+/// Write a sample line in the Prometheus text format.
+pub fn sample(out: &mut String, labels: &[types::Label], value: f64, timestamp: i64) {
+    let name = protocol::metric_name(labels).unwrap_or_default();
+    let labels: Vec<_> = labels
+        .iter()
+        .filter(|label| label.name != "__name__")
+        .map(|label| {
+            let value = label
+                .value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{value}\"", label.name)
+        })
+        .collect();
+
+    out.push_str(name);
+    if !labels.is_empty() {
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {} {timestamp}", format_value(value));
+}
+
+/// Format a value the way Prometheus parses it, e.g. `+Inf` for infinity.
+pub fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_owned()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_owned()
+    } else {
+        // NaN is formatted as `NaN`
+        value.to_string()
+    }
+}
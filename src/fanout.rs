@@ -0,0 +1,194 @@
+use metrics::Counter;
+use metrics::Gauge;
+use metrics::Histogram;
+use metrics::Key;
+use metrics::KeyName;
+use metrics::Metadata;
+use metrics::Recorder;
+use metrics::SharedString;
+use metrics::Unit;
+use std::fmt;
+use std::sync::Arc;
+
+/// A recorder forwarding every metric to several others, e.g. this exporter
+/// along with a local scrape endpoint or a debugging recorder.
+///
+/// Only one recorder can be installed globally, see
+/// [`Builder::install_with`](crate::Builder::install_with).
+#[derive(Default)]
+pub struct Fanout {
+    recorders: Vec<Box<dyn Recorder + Send + Sync>>,
+}
+
+impl Fanout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also forward metrics to `recorder`.
+    pub fn with(mut self, recorder: impl Recorder + Send + Sync + 'static) -> Self {
+        self.recorders.push(Box::new(recorder));
+        self
+    }
+}
+
+impl fmt::Debug for Fanout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Fanout")
+            .field("recorders", &self.recorders.len())
+            .finish()
+    }
+}
+
+impl Recorder for Fanout {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        for recorder in &self.recorders {
+            recorder.describe_counter(key.clone(), unit, description.clone());
+        }
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        for recorder in &self.recorders {
+            recorder.describe_gauge(key.clone(), unit, description.clone());
+        }
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        for recorder in &self.recorders {
+            recorder.describe_histogram(key.clone(), unit, description.clone());
+        }
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        let counters = self
+            .recorders
+            .iter()
+            .map(|recorder| recorder.register_counter(key, metadata))
+            .collect();
+        Counter::from_arc(Arc::new(FanoutCounter(counters)))
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        let gauges = self
+            .recorders
+            .iter()
+            .map(|recorder| recorder.register_gauge(key, metadata))
+            .collect();
+        Gauge::from_arc(Arc::new(FanoutGauge(gauges)))
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        let histograms = self
+            .recorders
+            .iter()
+            .map(|recorder| recorder.register_histogram(key, metadata))
+            .collect();
+        Histogram::from_arc(Arc::new(FanoutHistogram(histograms)))
+    }
+}
+
+struct FanoutCounter(Vec<Counter>);
+
+impl metrics::CounterFn for FanoutCounter {
+    fn increment(&self, value: u64) {
+        for counter in &self.0 {
+            counter.increment(value);
+        }
+    }
+
+    fn absolute(&self, value: u64) {
+        for counter in &self.0 {
+            counter.absolute(value);
+        }
+    }
+}
+
+struct FanoutGauge(Vec<Gauge>);
+
+impl metrics::GaugeFn for FanoutGauge {
+    fn increment(&self, value: f64) {
+        for gauge in &self.0 {
+            gauge.increment(value);
+        }
+    }
+
+    fn decrement(&self, value: f64) {
+        for gauge in &self.0 {
+            gauge.decrement(value);
+        }
+    }
+
+    fn set(&self, value: f64) {
+        for gauge in &self.0 {
+            gauge.set(value);
+        }
+    }
+}
+
+struct FanoutHistogram(Vec<Histogram>);
+
+impl metrics::HistogramFn for FanoutHistogram {
+    fn record(&self, value: f64) {
+        for histogram in &self.0 {
+            histogram.record(value);
+        }
+    }
+
+    fn record_many(&self, value: f64, count: usize) {
+        for histogram in &self.0 {
+            histogram.record_many(value, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::Ordering;
+
+    /// Records every counter and gauge into the same value.
+    #[derive(Clone, Default)]
+    struct Shared {
+        counter: Arc<AtomicU64>,
+        gauge: Arc<AtomicU64>,
+    }
+
+    impl Recorder for Shared {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, _: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.counter.clone())
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::from_arc(self.gauge.clone())
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[test]
+    fn metrics_forwarded_to_every_recorder() {
+        let first = Shared::default();
+        let second = Shared::default();
+        let fanout = Fanout::new().with(first.clone()).with(second.clone());
+
+        metrics::with_local_recorder(&fanout, || {
+            metrics::describe_counter!("requests", "Requests served");
+            metrics::counter!("requests").increment(2);
+            metrics::counter!("requests").increment(3);
+            metrics::gauge!("temperature").set(21.5);
+            metrics::histogram!("latency").record(0.5);
+        });
+
+        for recorder in [first, second] {
+            assert_eq!(recorder.counter.load(Ordering::Relaxed), 5);
+            assert_eq!(f64::from_bits(recorder.gauge.load(Ordering::Relaxed)), 21.5);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::sink::Sink;
+use crate::sink::SinkError;
+use crate::types::WriteRequest;
+use prost::Message;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// Appends encoded write requests to a file instead of sending them.
+///
+/// Each request is written uncompressed with a varint length prefix, the
+/// same framing as protobuf's `writeDelimitedTo`, so the file can be read
+/// back one request at a time.
+#[derive(Debug)]
+pub struct FileSink {
+    file: File,
+}
+
+impl FileSink {
+    /// Open the file for appending, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Append an encoded write request.
+    pub fn write(&mut self, encoded: &[u8]) -> io::Result<()> {
+        let mut framed = Vec::with_capacity(encoded.len() + 10);
+        prost::encoding::encode_varint(encoded.len() as u64, &mut framed);
+        framed.extend_from_slice(encoded);
+
+        // a single write so concurrent readers never see a partial prefix
+        self.file.write_all(&framed)?;
+        self.file.flush()
+    }
+}
+
+impl Sink for FileSink {
+    fn send(&mut self, request: &WriteRequest) -> Result<(), SinkError> {
+        Ok(self.write(&request.encode_to_vec())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types;
+
+    #[test]
+    fn requests_are_length_delimited() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("requests.bin");
+
+        let request = types::WriteRequest {
+            timeseries: vec![types::TimeSeries::default()],
+            metadata: vec![],
+        };
+
+        let mut sink = FileSink::open(&path).unwrap();
+        sink.write(&request.encode_to_vec()).unwrap();
+        sink.write(&request.encode_to_vec()).unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        let mut buf = contents.as_slice();
+        for _ in 0..2 {
+            let decoded = types::WriteRequest::decode_length_delimited(&mut buf).unwrap();
+            assert_eq!(decoded, request);
+        }
+        assert!(buf.is_empty());
+    }
+}
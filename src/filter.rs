@@ -0,0 +1,124 @@
+use metrics::Key;
+use metrics::Level;
+use metrics::Metadata;
+
+/// Which metrics are recorded, decided once when they're registered.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub min_level: Level,
+    /// Targets allowed along with their submodules, every target if empty.
+    pub targets: Vec<String>,
+    /// Name patterns allowed, every name if empty.
+    pub allow: Vec<String>,
+    /// Name patterns ignored even if allowed.
+    pub deny: Vec<String>,
+}
+
+impl Filter {
+    /// Should the metric `key` registered with `metadata` be recorded.
+    pub fn allows(&self, key: &Key, metadata: &Metadata<'_>) -> bool {
+        if *metadata.level() < self.min_level {
+            return false;
+        }
+
+        let name = key.name();
+        if self.deny.iter().any(|pattern| glob_match(pattern, name))
+            || !(self.allow.is_empty()
+                || self.allow.iter().any(|pattern| glob_match(pattern, name)))
+        {
+            return false;
+        }
+
+        let target = metadata.target();
+        self.targets.is_empty()
+            || self.targets.iter().any(|allowed| {
+                target
+                    .strip_prefix(allowed.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            min_level: Level::TRACE,
+            targets: vec![],
+            allow: vec![],
+            deny: vec![],
+        }
+    }
+}
+
+/// Does `name` match `pattern`, in which `*` matches any run of characters.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == name;
+    };
+    let Some(mut name) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<_> = rest.split('*').collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        match name.find(part) {
+            Some(index) => name = &name[index + part.len()..],
+            None => return false,
+        }
+    }
+    name.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_and_target_filtered() {
+        let filter = Filter {
+            min_level: Level::INFO,
+            targets: vec!["app::db".to_owned()],
+            ..Default::default()
+        };
+        let allows = |target, level| {
+            filter.allows(
+                &Key::from_name("queries"),
+                &Metadata::new(target, level, None),
+            )
+        };
+
+        assert!(allows("app::db", Level::INFO));
+        assert!(allows("app::db::pool", Level::ERROR));
+        assert!(!allows("app::db", Level::DEBUG));
+        assert!(!allows("app::dbx", Level::INFO));
+        assert!(!allows("hyper", Level::INFO));
+
+        assert!(Filter::default().allows(
+            &Key::from_name("a"),
+            &Metadata::new("hyper", Level::TRACE, None)
+        ));
+    }
+
+    #[test]
+    fn names_allowed_and_denied() {
+        let filter = Filter {
+            allow: vec!["http_*".to_owned(), "uptime".to_owned()],
+            deny: vec!["http_*_bytes".to_owned()],
+            ..Default::default()
+        };
+        let metadata = Metadata::new("app", Level::INFO, None);
+        let allows = |name: &'static str| filter.allows(&Key::from_name(name), &metadata);
+
+        assert!(allows("uptime"));
+        assert!(allows("http_requests"));
+        assert!(!allows("http_response_bytes"));
+        assert!(!allows("uptime_seconds"));
+        assert!(!allows("db_queries"));
+
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "abbc"));
+        assert!(!glob_match("a*b*c", "acb"));
+        assert!(!glob_match("ab*ba", "aba"));
+    }
+}
@@ -0,0 +1,254 @@
+// This file is @generated by prost-build.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MetricMetadata {
+    /// Represents the metric type, these match the set from Prometheus.
+    /// Refer to github.com/prometheus/common/model/metadata.go for details.
+    #[prost(enumeration = "metric_metadata::MetricType", tag = "1")]
+    pub r#type: i32,
+    #[prost(string, tag = "2")]
+    pub metric_family_name: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub help: ::prost::alloc::string::String,
+    #[prost(string, tag = "5")]
+    pub unit: ::prost::alloc::string::String,
+}
+/// Nested message and enum types in `MetricMetadata`.
+pub mod metric_metadata {
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum MetricType {
+        Unknown = 0,
+        Counter = 1,
+        Gauge = 2,
+        Histogram = 3,
+        Gaugehistogram = 4,
+        Summary = 5,
+        Info = 6,
+        Stateset = 7,
+    }
+    impl MetricType {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Unknown => "UNKNOWN",
+                Self::Counter => "COUNTER",
+                Self::Gauge => "GAUGE",
+                Self::Histogram => "HISTOGRAM",
+                Self::Gaugehistogram => "GAUGEHISTOGRAM",
+                Self::Summary => "SUMMARY",
+                Self::Info => "INFO",
+                Self::Stateset => "STATESET",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "UNKNOWN" => Some(Self::Unknown),
+                "COUNTER" => Some(Self::Counter),
+                "GAUGE" => Some(Self::Gauge),
+                "HISTOGRAM" => Some(Self::Histogram),
+                "GAUGEHISTOGRAM" => Some(Self::Gaugehistogram),
+                "SUMMARY" => Some(Self::Summary),
+                "INFO" => Some(Self::Info),
+                "STATESET" => Some(Self::Stateset),
+                _ => None,
+            }
+        }
+    }
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// timestamp is in ms format, see model/timestamp/timestamp.go for
+    /// conversion from time.Time to Prometheus timestamp.
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Exemplar {
+    /// Optional, can be empty.
+    #[prost(message, repeated, tag = "1")]
+    pub labels: ::prost::alloc::vec::Vec<Label>,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+    /// Timestamp is in ms format, see pkg/timestamp/timestamp.go for
+    /// conversion from time.Time to Prometheus timestamp.
+    #[prost(int64, tag = "3")]
+    pub timestamp: i64,
+}
+/// A native histogram, also known as a sparse histogram.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Histogram {
+    /// Sum of observations in the histogram.
+    #[prost(double, tag = "3")]
+    pub sum: f64,
+    /// The schema defines the bucket schema. Currently, valid numbers
+    /// are -4 <= n <= 8. They are all for base-2 bucket schemas, where 1
+    /// is a bucket boundary in each case, and then each power of two is
+    /// divided into 2^n logarithmic buckets. Or in other words, each
+    /// bucket boundary is the previous boundary times 2^(2^-n).
+    #[prost(sint32, tag = "4")]
+    pub schema: i32,
+    /// Breadth of the zero bucket.
+    #[prost(double, tag = "5")]
+    pub zero_threshold: f64,
+    /// Negative Buckets.
+    #[prost(message, repeated, tag = "8")]
+    pub negative_spans: ::prost::alloc::vec::Vec<BucketSpan>,
+    /// Use either "negative_deltas" or "negative_counts", the former for
+    /// regular histograms with integer counts, the latter for float
+    /// histograms.
+    ///
+    /// Count delta of each bucket compared to previous one (or to zero for 1st bucket).
+    #[prost(sint64, repeated, tag = "9")]
+    pub negative_deltas: ::prost::alloc::vec::Vec<i64>,
+    /// Absolute count of each bucket.
+    #[prost(double, repeated, tag = "10")]
+    pub negative_counts: ::prost::alloc::vec::Vec<f64>,
+    /// Positive Buckets.
+    #[prost(message, repeated, tag = "11")]
+    pub positive_spans: ::prost::alloc::vec::Vec<BucketSpan>,
+    /// Use either "positive_deltas" or "positive_counts", the former for
+    /// regular histograms with integer counts, the latter for float
+    /// histograms.
+    ///
+    /// Count delta of each bucket compared to previous one (or to zero for 1st bucket).
+    #[prost(sint64, repeated, tag = "12")]
+    pub positive_deltas: ::prost::alloc::vec::Vec<i64>,
+    /// Absolute count of each bucket.
+    #[prost(double, repeated, tag = "13")]
+    pub positive_counts: ::prost::alloc::vec::Vec<f64>,
+    #[prost(enumeration = "histogram::ResetHint", tag = "14")]
+    pub reset_hint: i32,
+    /// timestamp is in ms format, see model/timestamp/timestamp.go for
+    /// conversion from time.Time to Prometheus timestamp.
+    #[prost(int64, tag = "15")]
+    pub timestamp: i64,
+    /// Count of observations in the histogram.
+    #[prost(oneof = "histogram::Count", tags = "1, 2")]
+    pub count: ::core::option::Option<histogram::Count>,
+    /// Count in zero bucket.
+    #[prost(oneof = "histogram::ZeroCount", tags = "6, 7")]
+    pub zero_count: ::core::option::Option<histogram::ZeroCount>,
+}
+/// Nested message and enum types in `Histogram`.
+pub mod histogram {
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        Hash,
+        PartialOrd,
+        Ord,
+        ::prost::Enumeration
+    )]
+    #[repr(i32)]
+    pub enum ResetHint {
+        /// Need to test for a counter reset explicitly.
+        Unknown = 0,
+        /// This is the 1st histogram after a counter reset.
+        Yes = 1,
+        /// There was no counter reset between this and the previous Histogram.
+        No = 2,
+        /// This is a gauge histogram where counter resets don't happen.
+        Gauge = 3,
+    }
+    impl ResetHint {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Self::Unknown => "UNKNOWN",
+                Self::Yes => "YES",
+                Self::No => "NO",
+                Self::Gauge => "GAUGE",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "UNKNOWN" => Some(Self::Unknown),
+                "YES" => Some(Self::Yes),
+                "NO" => Some(Self::No),
+                "GAUGE" => Some(Self::Gauge),
+                _ => None,
+            }
+        }
+    }
+    /// Count of observations in the histogram.
+    #[derive(Clone, Copy, PartialEq, ::prost::Oneof)]
+    pub enum Count {
+        #[prost(uint64, tag = "1")]
+        CountInt(u64),
+        #[prost(double, tag = "2")]
+        CountFloat(f64),
+    }
+    /// Count in zero bucket.
+    #[derive(Clone, Copy, PartialEq, ::prost::Oneof)]
+    pub enum ZeroCount {
+        #[prost(uint64, tag = "6")]
+        ZeroCountInt(u64),
+        #[prost(double, tag = "7")]
+        ZeroCountFloat(f64),
+    }
+}
+/// A BucketSpan defines a number of consecutive buckets with their
+/// offset. Logically, it would be more straightforward to include the
+/// bucket counts in the Span. However, the protobuf representation is
+/// more compact in the way the data is structured here (with all the
+/// buckets in a single array separate from the Spans).
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct BucketSpan {
+    /// Gap to previous span, or starting point for 1st span (which can be negative).
+    #[prost(sint32, tag = "1")]
+    pub offset: i32,
+    /// Length of consecutive buckets.
+    #[prost(uint32, tag = "2")]
+    pub length: u32,
+}
+/// TimeSeries represents samples and labels for a single time series.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TimeSeries {
+    /// For a timeseries to be valid, and for the samples and exemplars
+    /// to be ingested by the remote system properly, the labels field is required.
+    #[prost(message, repeated, tag = "1")]
+    pub labels: ::prost::alloc::vec::Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: ::prost::alloc::vec::Vec<Sample>,
+    #[prost(message, repeated, tag = "3")]
+    pub exemplars: ::prost::alloc::vec::Vec<Exemplar>,
+    #[prost(message, repeated, tag = "4")]
+    pub histograms: ::prost::alloc::vec::Vec<Histogram>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub value: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: ::prost::alloc::vec::Vec<TimeSeries>,
+    #[prost(message, repeated, tag = "3")]
+    pub metadata: ::prost::alloc::vec::Vec<MetricMetadata>,
+}
@@ -0,0 +1,265 @@
+use crate::batcher::BatcherInner;
+use crate::batcher::Command;
+use crate::batcher::MetricOperation;
+use crate::status::Status;
+use crate::types;
+use crossbeam::channel::RecvTimeoutError;
+use crossbeam::channel::Sender;
+use metrics::Key;
+use std::sync::Arc;
+use std::sync::PoisonError;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+/// Handle for interacting with an installed exporter.
+///
+/// See [`Batcher::handle`](crate::Batcher::handle).
+#[derive(Clone)]
+pub struct Handle {
+    inner: Arc<BatcherInner>,
+}
+
+impl Handle {
+    pub(crate) fn new(inner: Arc<BatcherInner>) -> Self {
+        Self { inner }
+    }
+
+    /// Number of operations dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Current health of the exporter, e.g. for a readiness probe.
+    pub fn status(&self) -> Status {
+        Status {
+            queue_depth: self.inner.queue_depth(),
+            ..self.inner.health.status()
+        }
+    }
+
+    /// Attach an exemplar, such as a trace id, to a counter or gauge.
+    ///
+    /// `labels` describe the exemplar rather than the series, e.g.
+    /// `[("trace_id", id)]`. The exemplar is sent with the next write of the
+    /// series; if several are recorded in between only the latest is kept.
+    pub fn exemplar<'a>(
+        &self,
+        key: impl Into<Key>,
+        value: f64,
+        labels: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) {
+        let labels = labels
+            .into_iter()
+            .map(|(name, value)| types::Label {
+                name: name.to_owned(),
+                value: value.to_owned(),
+            })
+            .collect();
+
+        self.inner.send(Command::Operation(
+            self.inner.clock.now(),
+            key.into(),
+            MetricOperation::Exemplar(value, labels),
+        ));
+    }
+
+    /// Set a gauge to `value` at a chosen `timestamp`, e.g. for readings
+    /// buffered by a device while offline.
+    ///
+    /// Samples must be recorded in time order for each series; one older
+    /// than the series' latest is ignored unless
+    /// [`Builder::raw_samples`](crate::Builder::raw_samples) is enabled.
+    pub fn gauge_sample(&self, key: impl Into<Key>, timestamp: SystemTime, value: f64) {
+        self.inner.send(Command::Operation(
+            timestamp,
+            key.into(),
+            MetricOperation::SetGauge(value),
+        ));
+    }
+
+    /// Set a counter to the absolute `value` at a chosen `timestamp`, see
+    /// [`gauge_sample`](Self::gauge_sample).
+    pub fn counter_sample(&self, key: impl Into<Key>, timestamp: SystemTime, value: u64) {
+        self.inner.send(Command::Operation(
+            timestamp,
+            key.into(),
+            MetricOperation::SetCounter(value),
+        ));
+    }
+
+    /// Write historical samples, e.g. when migrating data or after a long
+    /// time offline, blocking until they were sent or `timeout` elapses.
+    ///
+    /// Samples are sent directly rather than through the registry, named
+    /// like gauges, sorted by time within each series with the last of
+    /// several at the same time kept. Samples past the
+    /// [`sample_age_limit`](crate::Builder::sample_age_limit) or from the
+    /// future are dropped, as receivers reject them.
+    ///
+    /// They're sent from a thread of their own, so metrics keep being
+    /// recorded meanwhile, in requests of at most 2,000 samples sent one
+    /// after the other, [`backfill_interval`](crate::Builder::backfill_interval)
+    /// apart. Failed requests are retried following the
+    /// [`RetryPolicy`](crate::RetryPolicy). Returns `true` if every sample
+    /// was accepted, `false` if any was dropped or failed, the timeout
+    /// elapsed or the exporter has stopped.
+    pub fn backfill<K: Into<Key>>(
+        &self,
+        samples: impl IntoIterator<Item = (K, SystemTime, f64)>,
+        timeout: Duration,
+    ) -> bool {
+        let samples = samples
+            .into_iter()
+            .map(|(key, timestamp, value)| (key.into(), timestamp, value))
+            .collect();
+
+        self.request(|reply| Command::Backfill(samples, reply), timeout)
+            .unwrap_or(false)
+    }
+
+    /// Stop sending a series and mark it stale, so receivers end it now
+    /// instead of showing its last value for another five minutes.
+    ///
+    /// Recording to the series again starts it afresh.
+    pub fn remove(&self, key: impl Into<Key>) {
+        self.inner.send(Command::Operation(
+            self.inner.clock.now(),
+            key.into(),
+            MetricOperation::Remove,
+        ));
+    }
+
+    /// Resend batches kept by [`Builder::dead_letter_dir`](crate::Builder::dead_letter_dir).
+    ///
+    /// Blocks until the worker has attempted every batch and returns the
+    /// number delivered, or `None` if `timeout` elapsed first or the
+    /// exporter has stopped. Batches that fail again are kept for a later
+    /// replay.
+    pub fn replay_dead_letters(&self, timeout: Duration) -> Option<usize> {
+        self.request(Command::ReplayDeadLetters, timeout)
+    }
+
+    /// Write everything recorded so far, blocking until the request
+    /// completes or `timeout` elapses.
+    ///
+    /// Returns `true` if every endpoint accepted all pending samples, and
+    /// `false` on timeout.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        self.request(Command::Flush, timeout).unwrap_or(false)
+    }
+
+    /// Render the latest value of every series in the Prometheus text
+    /// exposition format, for checking names and label sets locally.
+    ///
+    /// Returns `None` if `timeout` elapsed first or the exporter has
+    /// stopped.
+    pub fn render_text(&self, timeout: Duration) -> Option<String> {
+        self.request(Command::Render, timeout)
+    }
+
+    /// Send a request to the worker and wait for its reply, giving up once
+    /// `timeout` elapsed, including while the queue is full.
+    ///
+    /// A request given up on after being queued is still handled.
+    fn request<T>(
+        &self,
+        command: impl FnOnce(Sender<T>) -> Command,
+        timeout: Duration,
+    ) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        if !self.inner.control_until(command(tx), deadline) {
+            return None;
+        }
+        rx.recv_deadline(deadline).ok()
+    }
+
+    /// Stop the exporter, writing everything recorded so far.
+    ///
+    /// Operations recorded after this call are ignored. Blocks until the
+    /// final write completes and the worker thread exits, or `timeout`
+    /// elapses, including while the queue is full. Returns `false` on
+    /// timeout. Calling this again waits for the same shutdown.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        let stopped = {
+            let mut stopped = self
+                .inner
+                .stopped
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            match &*stopped {
+                Some(stopped) => stopped.clone(),
+                None => {
+                    self.inner.shutdown.store(true, Ordering::Relaxed);
+
+                    let (tx, rx) = crossbeam::channel::bounded(1);
+                    if !self.inner.control_until(Command::Shutdown(tx), deadline) {
+                        // never sent, so a later call can try again
+                        self.inner.shutdown.store(false, Ordering::Relaxed);
+                        return false;
+                    }
+                    stopped.insert(rx).clone()
+                }
+            }
+        };
+
+        // the worker replies to one caller, the others see it disconnect
+        if let Err(RecvTimeoutError::Timeout) = stopped.recv_deadline(deadline) {
+            return false;
+        }
+
+        let worker = self
+            .inner
+            .worker
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(worker) = worker {
+            let _ = worker.join();
+        }
+
+        true
+    }
+}
+
+/// Shuts the exporter down when dropped, writing everything recorded so far.
+///
+/// Returned by [`Builder::install_scoped`](crate::Builder::install_scoped)
+/// for short-lived jobs that must not lose their final batch.
+#[must_use = "the exporter is shut down when the guard is dropped"]
+pub struct FlushGuard {
+    handle: Handle,
+    timeout: Duration,
+}
+
+impl FlushGuard {
+    pub(crate) fn new(handle: Handle) -> Self {
+        Self {
+            handle,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Maximum time to block on drop. Default is 5s.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Handle to the installed exporter.
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+}
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        if !self.handle.shutdown(self.timeout) {
+            tracing::warn!("Timed out flushing metrics on drop");
+        }
+    }
+}
@@ -0,0 +1,506 @@
+use crate::summary::Summary;
+use crate::summary::Window;
+use crate::types;
+use std::collections::BTreeMap;
+
+/// Observations with an absolute value up to this go in the zero bucket.
+///
+/// Matches the default of the Prometheus client libraries.
+const ZERO_THRESHOLD: f64 = 2.938735877055719e-39;
+
+/// How `histogram!` recordings are aggregated before sending.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum HistogramMode {
+    /// Native histograms with exponential buckets, see
+    /// [`Builder::histogram_schema`](crate::Builder::histogram_schema).
+    /// Receivers must have native histograms enabled.
+    #[default]
+    Native,
+    /// Classic histograms with the given bucket upper bounds, sent as
+    /// `_bucket`, `_sum` and `_count` series understood by every receiver.
+    Buckets(Vec<f64>),
+    /// Summaries with the given quantiles from 0 to 1, sent as
+    /// `{quantile="0.5"}` series along with `_sum` and `_count`.
+    ///
+    /// Quantiles are estimated to within 1% of their true value, over every
+    /// observation since the series was created unless
+    /// [`Builder::summary_window`](crate::Builder::summary_window) is set.
+    Summary(Vec<f64>),
+    /// Only the count, sum, minimum and maximum of the observations, sent as
+    /// `_count`, `_sum`, `_min` and `_max` gauges. The cheapest mode, for
+    /// constrained devices.
+    ///
+    /// The minimum and maximum cover the observations since the previous
+    /// send, the count and sum every observation.
+    Gauges,
+}
+
+impl HistogramMode {
+    /// Classic histograms with the default buckets of the Prometheus client
+    /// libraries, from 5ms to 10s.
+    pub fn default_buckets() -> Self {
+        HistogramMode::Buckets(vec![
+            0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+        ])
+    }
+
+    /// Summaries with the median, 90th and 99th percentiles.
+    pub fn default_quantiles() -> Self {
+        HistogramMode::Summary(vec![0.5, 0.9, 0.99])
+    }
+
+    /// Why the mode can't be used, if it can't.
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        match self {
+            HistogramMode::Native | HistogramMode::Gauges => Ok(()),
+            HistogramMode::Buckets(bounds) => {
+                if bounds.iter().any(|bound| !bound.is_finite())
+                    || bounds.windows(2).any(|pair| pair[0] >= pair[1])
+                {
+                    return Err("histogram buckets must be finite and strictly increasing");
+                }
+                Ok(())
+            }
+            HistogramMode::Summary(quantiles) => {
+                if quantiles.iter().any(|q| !(0.0..=1.0).contains(q)) {
+                    return Err("summary quantiles must be between 0 and 1");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Observations of a series, aggregated according to its [`HistogramMode`].
+#[derive(Debug)]
+pub enum Distribution {
+    Native(NativeHistogram),
+    Classic(ClassicHistogram),
+    Summary(Summary),
+    Gauges(GaugeHistogram),
+}
+
+impl Distribution {
+    pub fn new(mode: &HistogramMode, schema: i32, window: Option<Window>) -> Self {
+        match mode {
+            HistogramMode::Native => Distribution::Native(NativeHistogram::new(schema)),
+            HistogramMode::Buckets(bounds) => {
+                Distribution::Classic(ClassicHistogram::new(bounds.clone()))
+            }
+            HistogramMode::Summary(quantiles) => {
+                Distribution::Summary(Summary::new(quantiles.clone(), window))
+            }
+            HistogramMode::Gauges => Distribution::Gauges(GaugeHistogram::default()),
+        }
+    }
+
+    /// Add an observation.
+    pub fn observe(&mut self, timestamp: i64, value: f64) {
+        match self {
+            Distribution::Native(histogram) => histogram.observe(timestamp, value),
+            Distribution::Classic(histogram) => histogram.observe(timestamp, value),
+            Distribution::Summary(summary) => summary.observe(timestamp, value),
+            Distribution::Gauges(histogram) => histogram.observe(timestamp, value),
+        }
+    }
+
+    /// Expire the observations of summaries that fell out of their window
+    /// by `now`.
+    pub fn expire(&mut self, now: i64) {
+        if let Distribution::Summary(summary) = self {
+            summary.expire(now);
+        }
+    }
+
+    /// Time of the latest observation, in milliseconds.
+    pub fn timestamp(&self) -> i64 {
+        match self {
+            Distribution::Native(histogram) => histogram.timestamp(),
+            Distribution::Classic(histogram) => histogram.timestamp(),
+            Distribution::Summary(summary) => summary.timestamp(),
+            Distribution::Gauges(histogram) => histogram.timestamp(),
+        }
+    }
+
+    /// Has the latest state been sent already.
+    pub fn is_sent(&self) -> bool {
+        match self {
+            Distribution::Native(histogram) => histogram.is_sent(),
+            Distribution::Classic(histogram) => histogram.is_sent(),
+            Distribution::Summary(summary) => summary.is_sent(),
+            Distribution::Gauges(histogram) => histogram.is_sent(),
+        }
+    }
+
+    /// Mark the latest state as sent.
+    pub fn sent(&mut self) {
+        match self {
+            Distribution::Native(histogram) => histogram.sent(),
+            Distribution::Classic(histogram) => histogram.sent(),
+            Distribution::Summary(summary) => summary.sent(),
+            Distribution::Gauges(histogram) => histogram.sent(),
+        }
+    }
+}
+
+/// Count, sum and extremes of a series' observations.
+#[derive(Debug, Default)]
+pub struct GaugeHistogram {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    timestamp: i64,
+    sent: bool,
+}
+
+impl GaugeHistogram {
+    /// Add an observation, restarting the extremes if the previous ones were
+    /// sent.
+    pub fn observe(&mut self, timestamp: i64, value: f64) {
+        if self.sent || self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+
+        self.count += 1;
+        self.sum += value;
+        self.timestamp = self.timestamp.max(timestamp);
+        self.sent = false;
+    }
+
+    /// Number of observations.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of the observations.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Smallest observation since the previous send.
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    /// Largest observation since the previous send.
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    /// Time of the latest observation, in milliseconds.
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Has the latest state been sent already.
+    pub fn is_sent(&self) -> bool {
+        self.sent
+    }
+
+    /// Mark the latest state as sent.
+    pub fn sent(&mut self) {
+        self.sent = true;
+    }
+}
+
+/// Cumulative histogram of a series' observations with fixed buckets.
+#[derive(Debug)]
+pub struct ClassicHistogram {
+    /// Upper bounds of the buckets, excluding `+Inf`.
+    bounds: Vec<f64>,
+    /// Observations in each bucket, not cumulative.
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+    timestamp: i64,
+    sent: bool,
+}
+
+impl ClassicHistogram {
+    pub fn new(bounds: Vec<f64>) -> Self {
+        Self {
+            counts: vec![0; bounds.len()],
+            bounds,
+            count: 0,
+            sum: 0.0,
+            timestamp: 0,
+            sent: false,
+        }
+    }
+
+    /// Add an observation.
+    pub fn observe(&mut self, timestamp: i64, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.timestamp = self.timestamp.max(timestamp);
+        self.sent = false;
+
+        // NaN is only counted in the `+Inf` bucket
+        let bucket = self.bucket(value);
+        if !value.is_nan()
+            && let Some(count) = self.counts.get_mut(bucket)
+        {
+            *count += 1;
+        }
+    }
+
+    /// Index of the bucket containing `value`, the number of bounds for
+    /// `+Inf`.
+    pub fn bucket(&self, value: f64) -> usize {
+        self.bounds.partition_point(|bound| *bound < value)
+    }
+
+    /// Upper bound and cumulative count of each bucket, ending with `+Inf`.
+    pub fn buckets(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        self.bounds
+            .iter()
+            .zip(&self.counts)
+            .scan(0, |cumulative, (&bound, &count)| {
+                *cumulative += count;
+                Some((bound, *cumulative))
+            })
+            .chain([(f64::INFINITY, self.count)])
+    }
+
+    /// Number of observations.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of the observations.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Time of the latest observation, in milliseconds.
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Has the latest state been sent already.
+    pub fn is_sent(&self) -> bool {
+        self.sent
+    }
+
+    /// Mark the latest state as sent.
+    pub fn sent(&mut self) {
+        self.sent = true;
+    }
+}
+
+/// Cumulative native histogram of a series' observations.
+///
+/// Buckets are exponential: each power of two is divided into `2^schema`
+/// buckets, bucket `i` covering `(base^(i-1), base^i]` with
+/// `base = 2^(2^-schema)`.
+#[derive(Debug)]
+pub struct NativeHistogram {
+    schema: i32,
+    count: u64,
+    sum: f64,
+    zero_count: u64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    timestamp: i64,
+    sent: bool,
+}
+
+impl NativeHistogram {
+    pub fn new(schema: i32) -> Self {
+        Self {
+            schema,
+            count: 0,
+            sum: 0.0,
+            zero_count: 0,
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            timestamp: 0,
+            sent: false,
+        }
+    }
+
+    /// Add an observation.
+    pub fn observe(&mut self, timestamp: i64, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.timestamp = self.timestamp.max(timestamp);
+        self.sent = false;
+
+        if value.abs() <= ZERO_THRESHOLD {
+            self.zero_count += 1;
+        } else if value.is_finite() {
+            let buckets = if value > 0.0 {
+                &mut self.positive
+            } else {
+                &mut self.negative
+            };
+            *buckets
+                .entry(bucket_index(value.abs(), self.schema))
+                .or_default() += 1;
+        }
+        // NaN and infinite observations only affect the count and sum
+    }
+
+    /// Number of observations.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of the observations.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Time of the latest observation, in milliseconds.
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Has the latest state been sent already.
+    pub fn is_sent(&self) -> bool {
+        self.sent
+    }
+
+    /// Mark the latest state as sent.
+    pub fn sent(&mut self) {
+        self.sent = true;
+    }
+
+    /// Encode the latest state.
+    pub fn to_proto(&self) -> types::Histogram {
+        let (negative_spans, negative_deltas) = encode_buckets(&self.negative);
+        let (positive_spans, positive_deltas) = encode_buckets(&self.positive);
+
+        types::Histogram {
+            count: Some(types::histogram::Count::CountInt(self.count)),
+            sum: self.sum,
+            schema: self.schema,
+            zero_threshold: ZERO_THRESHOLD,
+            zero_count: Some(types::histogram::ZeroCount::ZeroCountInt(self.zero_count)),
+            negative_spans,
+            negative_deltas,
+            negative_counts: vec![],
+            positive_spans,
+            positive_deltas,
+            positive_counts: vec![],
+            reset_hint: types::histogram::ResetHint::Unknown.into(),
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+/// Index of the bucket containing the positive `value`.
+fn bucket_index(value: f64, schema: i32) -> i32 {
+    (value.log2() * 2f64.powi(schema)).ceil() as i32
+}
+
+/// Encode bucket counts as spans of consecutive buckets and the delta of
+/// each count to the previous one.
+fn encode_buckets(buckets: &BTreeMap<i32, u64>) -> (Vec<types::BucketSpan>, Vec<i64>) {
+    let mut spans: Vec<types::BucketSpan> = vec![];
+    let mut deltas = vec![];
+    let mut previous: Option<(i32, u64)> = None;
+
+    for (&index, &count) in buckets {
+        match previous {
+            Some((last, _)) if index == last + 1 => {
+                if let Some(span) = spans.last_mut() {
+                    span.length += 1;
+                }
+            }
+            Some((last, _)) => spans.push(types::BucketSpan {
+                offset: index - last - 1,
+                length: 1,
+            }),
+            None => spans.push(types::BucketSpan {
+                offset: index,
+                length: 1,
+            }),
+        }
+
+        let last_count = previous.map(|(_, count)| count).unwrap_or(0);
+        deltas.push(count as i64 - last_count as i64);
+        previous = Some((index, count));
+    }
+
+    (spans, deltas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gauge_extremes_restart_after_send() {
+        let mut histogram = GaugeHistogram::default();
+        for value in [3.0, 1.0, 2.0] {
+            histogram.observe(100, value);
+        }
+        assert_eq!((histogram.min(), histogram.max()), (1.0, 3.0));
+
+        histogram.sent();
+        histogram.observe(200, 5.0);
+        assert_eq!((histogram.min(), histogram.max()), (5.0, 5.0));
+        assert_eq!((histogram.count(), histogram.sum()), (4, 11.0));
+    }
+
+    #[test]
+    fn classic_buckets_cumulative() {
+        let mut histogram = ClassicHistogram::new(vec![0.1, 1.0]);
+        for value in [0.05, 0.1, 0.5, 5.0, f64::NAN] {
+            histogram.observe(100, value);
+        }
+
+        assert_eq!(
+            histogram.buckets().collect::<Vec<_>>(),
+            [(0.1, 2), (1.0, 3), (f64::INFINITY, 5)]
+        );
+        assert_eq!(histogram.bucket(0.5), 1);
+        assert_eq!(histogram.bucket(5.0), 2);
+    }
+
+    #[test]
+    fn buckets_encoded_as_spans() {
+        let mut histogram = NativeHistogram::new(0);
+        for value in [1.0, 1.5, 2.0, 3.0, 16.0, 0.0, -1.0] {
+            histogram.observe(100, value);
+        }
+
+        let proto = histogram.to_proto();
+        assert_eq!(proto.count, Some(types::histogram::Count::CountInt(7)));
+        assert_eq!(proto.sum, 22.5);
+        assert_eq!(
+            proto.zero_count,
+            Some(types::histogram::ZeroCount::ZeroCountInt(1))
+        );
+
+        // (0.5, 1]: 1, (1, 2]: 2, (2, 4]: 1, (8, 16]: 1
+        assert_eq!(
+            proto.positive_spans,
+            [
+                types::BucketSpan {
+                    offset: 0,
+                    length: 3
+                },
+                types::BucketSpan {
+                    offset: 1,
+                    length: 1
+                },
+            ]
+        );
+        assert_eq!(proto.positive_deltas, [1, 1, -1, 0]);
+        assert_eq!(
+            proto.negative_spans,
+            [types::BucketSpan {
+                offset: 0,
+                length: 1
+            }]
+        );
+        assert_eq!(proto.negative_deltas, [1]);
+    }
+}
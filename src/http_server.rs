@@ -0,0 +1,103 @@
+use crate::HttpResponse;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// An HTTP/1.1 request read by [`read_request`].
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of the first header with the given name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Read a single request from a connection, answering `413 Payload Too
+/// Large` and failing if its body is over `max_body` bytes.
+///
+/// Only bodies with a `Content-Length` are read, which every built-in
+/// transport sends.
+pub fn read_request(stream: &TcpStream, max_body: usize) -> io::Result<Request> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
+
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: vec![],
+    };
+    let length = request
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    if length > max_body {
+        write_response(
+            stream,
+            &HttpResponse {
+                status: 413,
+                ..Default::default()
+            },
+        )?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("request body of {length} bytes is over the limit of {max_body}"),
+        ));
+    }
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body)?;
+
+    Ok(request)
+}
+
+/// Write a response, asking the client to close the connection afterwards.
+pub fn write_response(stream: &TcpStream, response: &HttpResponse) -> io::Result<()> {
+    let reason = http::StatusCode::from_u16(response.status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or_default();
+
+    let mut head = format!(
+        "HTTP/1.1 {} {reason}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+
+    let mut stream = stream;
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
@@ -0,0 +1,81 @@
+use crate::registry::KeyMap;
+use metrics::Key;
+use std::sync::PoisonError;
+use std::sync::RwLock;
+
+/// Id of a key registered with the [`Interner`], sent with each operation
+/// instead of the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyId(u32);
+
+/// The series an operation is on, by key or by the id of an interned key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SeriesKey {
+    Key(Key),
+    Interned(KeyId),
+}
+
+/// Keys of the registered metric handles, shared between the recorder and
+/// the worker resolving their ids.
+///
+/// Keys are kept for the life of the exporter, like the handles holding
+/// their ids, which is why interning is opt-in.
+#[derive(Debug, Default)]
+pub struct Interner {
+    ids: RwLock<KeyMap<KeyId>>,
+    keys: RwLock<Vec<Key>>,
+}
+
+impl Interner {
+    /// Id of `key`, assigned if new.
+    pub fn intern(&self, key: &Key) -> KeyId {
+        if let Some(&id) = self
+            .ids
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+        {
+            return id;
+        }
+
+        let mut ids = self.ids.write().unwrap_or_else(PoisonError::into_inner);
+        // assigned while waiting for the lock
+        if let Some(&id) = ids.get(key) {
+            return id;
+        }
+
+        let mut keys = self.keys.write().unwrap_or_else(PoisonError::into_inner);
+        let id = KeyId(keys.len() as u32);
+        keys.push(key.clone());
+        ids.insert(key.clone(), id);
+        id
+    }
+
+    /// The key assigned `id`.
+    pub fn resolve(&self, id: KeyId) -> Option<Key> {
+        self.keys
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(id.0 as usize)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_interned_once() {
+        let interner = Interner::default();
+        let requests = Key::from_parts("requests", &[("status", "200")]);
+        let errors = Key::from_name("errors");
+
+        let id = interner.intern(&requests);
+        assert_eq!(interner.intern(&requests.clone()), id);
+        assert_ne!(interner.intern(&errors), id);
+
+        assert_eq!(interner.resolve(id), Some(requests));
+        assert_eq!(interner.resolve(KeyId(2)), None);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::registry::KeyMap;
+use crate::types;
+use crate::types::metric_metadata::MetricType;
+use metrics::Key;
+use smallvec::SmallVec;
+
+/// Labels of a series as converted, inline for series with the usual
+/// handful of labels.
+type SeriesLabels = SmallVec<[types::Label; 8]>;
+
+/// Labels of a series as sent, or the invalid name it is dropped for.
+type Converted = Result<SeriesLabels, String>;
+
+/// Labels of each series converted for write requests, and the label vectors
+/// of sent requests to copy them into, so unchanged series don't allocate
+/// their labels every write. Converted labels are stored inline for series
+/// with up to 8 labels, saving an allocation per series cached.
+#[derive(Debug, Default)]
+pub struct LabelCache {
+    converted: KeyMap<Vec<(MetricType, Converted)>>,
+    spare: Vec<Vec<types::Label>>,
+}
+
+impl LabelCache {
+    /// Labels of the series `key` sent as `metric_type`, converted with
+    /// `convert` when first seen.
+    pub fn get(
+        &mut self,
+        key: &Key,
+        metric_type: MetricType,
+        convert: impl FnOnce() -> Result<Vec<types::Label>, String>,
+    ) -> Result<Vec<types::Label>, String> {
+        let entries = match self.converted.get_mut(key) {
+            Some(entries) => entries,
+            None => self.converted.entry(key.clone()).or_default(),
+        };
+        let position = match entries.iter().position(|(t, _)| *t == metric_type) {
+            Some(position) => position,
+            None => {
+                entries.push((metric_type, convert().map(SmallVec::from_vec)));
+                entries.len() - 1
+            }
+        };
+
+        let cached = entries[position].1.as_ref().map_err(String::clone)?;
+        let mut labels = self.spare.pop().unwrap_or_default();
+        copy_labels(&mut labels, cached);
+        Ok(labels)
+    }
+
+    /// Keep the labels of a series no longer needed, to copy others into.
+    ///
+    /// At most one spare is kept per series, as many as a write can use.
+    pub fn release(&mut self, labels: Vec<types::Label>) {
+        if self.spare.len() < self.converted.len() {
+            self.spare.push(labels);
+        }
+    }
+
+    /// Number of series with converted labels.
+    pub fn len(&self) -> usize {
+        self.converted.len()
+    }
+
+    /// Forget the labels of series for which `keep` returns false.
+    pub fn retain(&mut self, keep: impl Fn(&Key) -> bool) {
+        self.converted.retain(|key, _| keep(key));
+    }
+
+    /// Forget the labels of every series, e.g. after a unit changed.
+    pub fn clear(&mut self) {
+        self.converted.clear();
+    }
+}
+
+/// Copy `src` into `dst`, reusing the strings already allocated in `dst`.
+fn copy_labels(dst: &mut Vec<types::Label>, src: &[types::Label]) {
+    dst.truncate(src.len());
+    for (dst, src) in dst.iter_mut().zip(src) {
+        dst.name.clone_from(&src.name);
+        dst.value.clone_from(&src.value);
+    }
+    let copied = dst.len();
+    dst.extend_from_slice(&src[copied..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_converted_once_and_reused() {
+        let mut cache = LabelCache::default();
+        let key = Key::from_parts("requests", &[("status", "200")]);
+        let label = |name: &str, value: &str| types::Label {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        };
+        let convert = || Ok(vec![label("__name__", "requests"), label("status", "200")]);
+
+        let labels = cache.get(&key, MetricType::Counter, convert).unwrap();
+        assert_eq!(labels, convert().unwrap());
+        let value = labels[1].value.as_ptr();
+        cache.release(labels);
+
+        // copied into the strings of the released labels
+        let labels = cache
+            .get(&key, MetricType::Counter, || unreachable!())
+            .unwrap();
+        assert_eq!(labels, convert().unwrap());
+        assert_eq!(labels[1].value.as_ptr(), value);
+
+        let invalid = || Err("1nvalid".to_owned());
+        assert_eq!(cache.get(&key, MetricType::Gauge, invalid), invalid());
+        assert_eq!(
+            cache.get(&key, MetricType::Gauge, || unreachable!()),
+            invalid()
+        );
+        assert_eq!(cache.len(), 1);
+
+        cache.retain(|_| false);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn steady_state_writes_allocate_no_labels() {
+        let mut cache = LabelCache::default();
+        let keys: Vec<_> = (0..100)
+            .map(|index| Key::from_parts("requests", &[("status", format!("{index}"))]))
+            .collect();
+        let convert = |key: &Key| {
+            let labels = [("__name__", key.name())]
+                .into_iter()
+                .chain(key.labels().map(|label| (label.key(), label.value())))
+                .map(|(name, value)| types::Label {
+                    name: name.to_owned(),
+                    value: value.to_owned(),
+                })
+                .collect();
+            Ok(labels)
+        };
+        let mut sent = Vec::with_capacity(keys.len());
+        let mut write = |cache: &mut LabelCache| {
+            for key in &keys {
+                sent.push(cache.get(key, MetricType::Gauge, || convert(key)).unwrap());
+            }
+            for labels in sent.drain(..) {
+                cache.release(labels);
+            }
+        };
+
+        // the first write converts every series and allocates its labels,
+        // the second grows the strings of spares copied from shorter labels
+        write(&mut cache);
+        write(&mut cache);
+        let allocations = alloc_counter::count(|| write(&mut cache));
+        assert_eq!(allocations, 0);
+    }
+}
+
+/// Global allocator counting the allocations of the current thread.
+#[cfg(test)]
+mod alloc_counter {
+    use std::alloc::GlobalAlloc;
+    use std::alloc::Layout;
+    use std::alloc::System;
+    use std::cell::Cell;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct Counting;
+
+    // SAFETY: defers to the system allocator
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            // SAFETY: the caller upholds the contract of `alloc`
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            // SAFETY: the caller upholds the contract of `dealloc`
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static COUNTING: Counting = Counting;
+
+    /// Number of allocations `f` made on this thread.
+    pub fn count(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - before
+    }
+}
@@ -1,10 +1,98 @@
 #![doc = include_str!("../README.md")]
 
 mod types {
+    #[cfg(feature = "gen-proto")]
     include!(concat!(env!("OUT_DIR"), "/prometheus.rs"));
+    #[cfg(not(feature = "gen-proto"))]
+    include!("generated/prometheus.rs");
 }
+mod types_v2 {
+    #[cfg(feature = "gen-proto")]
+    include!(concat!(env!("OUT_DIR"), "/io.prometheus.write.v2.rs"));
+    #[cfg(not(feature = "gen-proto"))]
+    include!("generated/io.prometheus.write.v2.rs");
+}
+mod atomics;
 mod batcher;
+mod circuit;
+mod clock;
+mod compression;
+mod counter_state;
+mod delivery;
+mod error;
+mod exposition;
+mod fanout;
+mod file_sink;
+mod filter;
+mod handle;
+mod histogram;
+#[cfg(any(feature = "scrape", feature = "test-util"))]
+mod http_server;
+mod interner;
+mod label_cache;
+#[cfg(feature = "test-util")]
+mod mock_server;
+mod names;
+mod outcome;
+mod partial;
+#[cfg(feature = "process-metrics")]
+mod process;
+pub mod proto;
+mod protocol;
+mod queue_config;
 mod registry;
+mod remote;
+mod retry;
+#[cfg(feature = "scrape")]
+mod scrape;
+mod sink;
+#[cfg(feature = "opentelemetry")]
+mod span_exemplar;
+mod status;
+mod summary;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+mod thread_buffer;
+mod throttle;
+mod transport;
+mod wal;
 
+pub use batcher::BatchLoop;
 pub use batcher::Batcher;
 pub use batcher::Builder;
+pub use batcher::OverflowPolicy;
+pub use clock::AnchoredClock;
+pub use clock::Clock;
+#[cfg(feature = "quanta")]
+pub use clock::QuantaClock;
+pub use clock::SystemClock;
+pub use compression::Compression;
+pub use error::BuildError;
+pub use fanout::Fanout;
+pub use handle::FlushGuard;
+pub use handle::Handle;
+pub use histogram::HistogramMode;
+pub use names::NameSanitization;
+pub use names::NameValidation;
+pub use outcome::WriteOutcome;
+pub use protocol::Protocol;
+pub use queue_config::QueueConfig;
+pub use registry::CounterReset;
+pub use registry::GaugeAggregation;
+pub use registry::SampleEviction;
+pub use retry::RetryPolicy;
+pub use sink::Sink;
+pub use sink::SinkError;
+pub use status::EndpointStatus;
+pub use status::Status;
+pub use transport::HttpRequest;
+pub use transport::HttpResponse;
+#[cfg(feature = "hyper")]
+pub use transport::HyperTransport;
+#[cfg(feature = "reqwest")]
+pub use transport::ReqwestTransport;
+pub use transport::Transport;
+pub use transport::TransportError;
+#[cfg(feature = "ureq")]
+pub use transport::UreqTransport;
+pub use types::WriteRequest;
@@ -0,0 +1,172 @@
+use crate::HttpRequest;
+use crate::HttpResponse;
+use crate::http_server;
+use crate::test_util;
+use crate::types;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Largest request body accepted, well over any batch the exporter sends.
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+
+/// A remote write receiver on a random local port, recording the requests
+/// it receives.
+///
+/// Requests are answered `204 No Content` unless a response was queued with
+/// [`respond_with`](Self::respond_with), and `400 Bad Request` if they can't
+/// be decoded. The server stops when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<(Mutex<State>, Condvar)>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct State {
+    requests: Vec<types::WriteRequest>,
+    responses: VecDeque<HttpResponse>,
+}
+
+impl MockServer {
+    /// Start listening on `127.0.0.1` with a port chosen by the OS.
+    pub fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let thread = std::thread::Builder::new()
+            .name("prom-write-mock".to_owned())
+            .spawn({
+                let state = state.clone();
+                let shutdown = shutdown.clone();
+                move || {
+                    for stream in listener.incoming() {
+                        if shutdown.load(Ordering::Relaxed) {
+                            break;
+                        }
+
+                        if let Ok(stream) = stream {
+                            let _ = serve(stream, &state);
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            addr,
+            state,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// Endpoint to pass to [`Builder::endpoint`](crate::Builder::endpoint).
+    pub fn url(&self) -> String {
+        format!("http://{}/api/v1/write", self.addr)
+    }
+
+    /// Answer the next request not yet answered by an earlier queued
+    /// response with `response`, e.g. a 429 or 503 to exercise retries.
+    pub fn respond_with(&self, response: HttpResponse) {
+        self.lock().responses.push_back(response);
+    }
+
+    /// Every request received so far, including those answered with a
+    /// queued error.
+    pub fn requests(&self) -> Vec<types::WriteRequest> {
+        self.lock().requests.clone()
+    }
+
+    /// Wait until at least `count` requests were received, returning those
+    /// received so far once `timeout` has passed.
+    pub fn wait_for_requests(&self, count: usize, timeout: Duration) -> Vec<types::WriteRequest> {
+        let deadline = Instant::now() + timeout;
+        let (_, received) = &*self.state;
+
+        let mut state = self.lock();
+        while state.requests.len() < count {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+
+            state = received
+                .wait_timeout(state, remaining)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+
+        state.requests.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        // wake the listener so it sees the shutdown
+        let _ = TcpStream::connect(self.addr);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Answer a single request, closing the connection afterwards.
+fn serve(stream: TcpStream, state: &(Mutex<State>, Condvar)) -> io::Result<()> {
+    // the path is ignored, every request is taken as a write
+    let request = http_server::read_request(&stream, MAX_BODY_BYTES)?;
+    if request.method != "POST" {
+        return http_server::write_response(
+            &stream,
+            &HttpResponse {
+                status: 405,
+                ..Default::default()
+            },
+        );
+    }
+
+    let response = match test_util::decode(&HttpRequest {
+        url: &request.path,
+        headers: request.headers,
+        body: &request.body,
+        timeout: Duration::ZERO,
+    }) {
+        Ok(decoded) => {
+            let (lock, received) = state;
+            let mut state = lock.lock().unwrap_or_else(PoisonError::into_inner);
+            state.requests.push(decoded);
+            received.notify_all();
+
+            state.responses.pop_front().unwrap_or(HttpResponse {
+                status: 204,
+                ..Default::default()
+            })
+        }
+        Err(err) => HttpResponse {
+            status: 400,
+            body: err.to_string().into_bytes(),
+            ..Default::default()
+        },
+    };
+
+    http_server::write_response(&stream, &response)
+}
@@ -0,0 +1,147 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+
+/// Characters allowed in metric and label names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameValidation {
+    /// The classic Prometheus charset: `[a-zA-Z_:][a-zA-Z0-9_:]*` for metric
+    /// names and `[a-zA-Z_][a-zA-Z0-9_]*` for label names. Other names are
+    /// sanitized, see [`NameSanitization`].
+    #[default]
+    Legacy,
+    /// Any UTF-8 string, sent verbatim. Requires a receiver that accepts
+    /// UTF-8 names, such as Prometheus 3.
+    Utf8,
+}
+
+/// How names outside the [`NameValidation::Legacy`] charset are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameSanitization {
+    /// Replace each invalid character with `_`, e.g. `http.requests`
+    /// becomes `http_requests`.
+    #[default]
+    Underscores,
+    /// Prometheus' value escaping, which keeps names distinct and
+    /// reversible: `http.requests` becomes `U__http_2e_requests`.
+    Escape,
+    /// Drop series with an invalid metric or label name, logging a warning.
+    Drop,
+}
+
+impl NameValidation {
+    /// Make a metric name valid, or `None` if the series should be dropped.
+    pub fn metric_name<'a>(
+        &self,
+        name: &'a str,
+        sanitization: NameSanitization,
+    ) -> Option<Cow<'a, str>> {
+        match self {
+            NameValidation::Legacy => legacy(name, true, sanitization),
+            NameValidation::Utf8 => Some(Cow::Borrowed(name)),
+        }
+    }
+
+    /// Make a label name valid, or `None` if the series should be dropped.
+    pub fn label_name<'a>(
+        &self,
+        name: &'a str,
+        sanitization: NameSanitization,
+    ) -> Option<Cow<'a, str>> {
+        match self {
+            NameValidation::Legacy => legacy(name, false, sanitization),
+            NameValidation::Utf8 => Some(Cow::Borrowed(name)),
+        }
+    }
+}
+
+/// Sanitize a name containing characters outside the classic charset.
+fn legacy(name: &str, colons: bool, sanitization: NameSanitization) -> Option<Cow<'_, str>> {
+    let allowed = |index: usize, c: char| {
+        c.is_ascii_alphabetic()
+            || c == '_'
+            || (colons && c == ':')
+            || (index > 0 && c.is_ascii_digit())
+    };
+
+    if !name.is_empty() && name.char_indices().all(|(index, c)| allowed(index, c)) {
+        return Some(Cow::Borrowed(name));
+    }
+
+    let mut valid = String::with_capacity(name.len() + 3);
+    match sanitization {
+        NameSanitization::Underscores => {
+            if !name.starts_with(|c| allowed(0, c)) {
+                valid.push('_');
+            }
+            valid.extend(name.chars().map(|c| if allowed(1, c) { c } else { '_' }));
+        }
+        NameSanitization::Escape => {
+            valid.push_str("U__");
+            for (index, c) in name.char_indices() {
+                if c == '_' {
+                    valid.push_str("__");
+                } else if allowed(index, c) {
+                    valid.push(c);
+                } else {
+                    let _ = write!(valid, "_{:x}_", c as u32);
+                }
+            }
+        }
+        NameSanitization::Drop => return None,
+    }
+
+    Some(Cow::Owned(valid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_replaces_invalid_characters() {
+        let legacy = NameValidation::Legacy;
+        let underscores = NameSanitization::Underscores;
+        assert!(matches!(
+            legacy.metric_name("http_requests:rate", underscores),
+            Some(Cow::Borrowed(_))
+        ));
+        assert_eq!(
+            legacy
+                .metric_name("http.requests-total", underscores)
+                .unwrap(),
+            "http_requests_total"
+        );
+        assert_eq!(legacy.metric_name("2xx", underscores).unwrap(), "_2xx");
+        assert_eq!(legacy.label_name("a:b", underscores).unwrap(), "a_b");
+        assert_eq!(legacy.label_name("größe", underscores).unwrap(), "gr__e");
+
+        assert_eq!(
+            NameValidation::Utf8
+                .metric_name("http.requests", underscores)
+                .unwrap(),
+            "http.requests"
+        );
+    }
+
+    #[test]
+    fn sanitization_strategies() {
+        let legacy = NameValidation::Legacy;
+
+        assert_eq!(
+            legacy
+                .metric_name("http.requests_total", NameSanitization::Escape)
+                .unwrap(),
+            "U__http_2e_requests__total"
+        );
+        assert_eq!(
+            legacy.label_name("2xx", NameSanitization::Escape).unwrap(),
+            "U___32_xx"
+        );
+
+        assert_eq!(legacy.metric_name("a.b", NameSanitization::Drop), None);
+        assert_eq!(
+            legacy.metric_name("a_b", NameSanitization::Drop).unwrap(),
+            "a_b"
+        );
+    }
+}
@@ -0,0 +1,34 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Result of a write request, passed to
+/// [`Builder::on_result`](crate::Builder::on_result).
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct WriteOutcome<'a> {
+    /// Endpoint the request was sent to.
+    pub endpoint: &'a str,
+    /// HTTP status of the request, if a response was received.
+    pub status: Option<u16>,
+    /// Why the request failed, if it did.
+    pub error: Option<&'a (dyn std::error::Error + 'static)>,
+    /// Number of failed attempts to send the same data before this one.
+    pub retries: u32,
+}
+
+impl WriteOutcome<'_> {
+    /// Whether the endpoint accepted the request.
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Callback invoked with every [`WriteOutcome`].
+#[derive(Clone)]
+pub(crate) struct OnResult(pub(crate) Arc<dyn Fn(&WriteOutcome) + Send + Sync>);
+
+impl fmt::Debug for OnResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnResult")
+    }
+}
@@ -0,0 +1,149 @@
+use crate::types;
+
+/// Label names and values of a series, sorted by name.
+pub type LabelSet = Vec<(String, String)>;
+
+/// Series named in the body of a rejected write request.
+///
+/// Prometheus and Mimir ingest the valid series of a request and answer 400
+/// naming each rejected one as `series {__name__="a", b="c"}`, optionally
+/// with the metric name before the braces and the whole quoted.
+pub fn rejected_series(body: &str) -> Vec<LabelSet> {
+    let mut rejected = vec![];
+    let mut rest = body;
+
+    while let Some(start) = rest.find("series") {
+        rest = rest[start + "series".len()..].trim_start_matches([' ', ':', '\'', '"']);
+
+        if let Some((mut labels, len)) = parse_series(rest) {
+            labels.sort();
+            rejected.push(labels);
+            rest = &rest[len..];
+        }
+    }
+
+    rejected
+}
+
+/// Split a request into the series not named in `rejected` and those that
+/// are.
+pub fn split(
+    request: types::WriteRequest,
+    rejected: &[LabelSet],
+) -> (types::WriteRequest, types::WriteRequest) {
+    let (rejected, kept) = request
+        .timeseries
+        .into_iter()
+        .partition(|series| rejected.contains(&label_set(&series.labels)));
+
+    (
+        types::WriteRequest {
+            timeseries: kept,
+            metadata: request.metadata,
+        },
+        types::WriteRequest {
+            timeseries: rejected,
+            metadata: vec![],
+        },
+    )
+}
+
+/// Format labels the way Prometheus does, e.g. `{__name__="a", b="c"}`.
+pub fn format_labels(labels: &[types::Label]) -> String {
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|label| format!("{}={:?}", label.name, label.value))
+        .collect();
+    format!("{{{}}}", labels.join(", "))
+}
+
+fn label_set(labels: &[types::Label]) -> LabelSet {
+    let mut set: LabelSet = labels
+        .iter()
+        .map(|label| (label.name.clone(), label.value.clone()))
+        .collect();
+    set.sort();
+    set
+}
+
+/// Parse a label set such as `name{a="b"}` from the start of `input`,
+/// returning it with the number of bytes consumed.
+fn parse_series(input: &str) -> Option<(LabelSet, usize)> {
+    let brace = input.find('{')?;
+    let name = &input[..brace];
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+    {
+        return None;
+    }
+
+    let mut labels = vec![];
+    if !name.is_empty() {
+        labels.push(("__name__".to_owned(), name.to_owned()));
+    }
+
+    let mut pos = brace + 1;
+    loop {
+        pos += input[pos..].len() - input[pos..].trim_start_matches([' ', ',']).len();
+        if input[pos..].starts_with('}') {
+            return Some((labels, pos + 1));
+        }
+
+        let eq = pos + input[pos..].find('=')?;
+        let name = input[pos..eq].trim();
+        pos = eq + 1;
+        if !input[pos..].starts_with('"') {
+            return None;
+        }
+        pos += 1;
+
+        // values are quoted with Go's escaping rules
+        let mut value = String::new();
+        let mut chars = input[pos..].char_indices();
+        loop {
+            match chars.next()? {
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    escaped => value.push(escaped),
+                },
+                (offset, '"') => {
+                    pos += offset + 1;
+                    break;
+                }
+                (_, c) => value.push(c),
+            }
+        }
+
+        labels.push((name.to_owned(), value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> LabelSet {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn rejected_series_parsed() {
+        let body = "out of order sample for series {__name__=\"a\", job=\"x\\\"y\"}\n\
+            err-mimir-sample-too-far-in-future. The affected sample is from series b{job=\"x\"}\n\
+            too many series";
+
+        assert_eq!(
+            rejected_series(body),
+            [
+                labels(&[("__name__", "a"), ("job", "x\"y")]),
+                labels(&[("__name__", "b"), ("job", "x")]),
+            ]
+        );
+        assert!(rejected_series("bad request").is_empty());
+    }
+}
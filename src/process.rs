@@ -0,0 +1,233 @@
+use crate::batcher::BatcherInner;
+use crate::batcher::Command;
+use metrics::Key;
+use metrics::Label;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::sync::LazyLock;
+use std::sync::Weak;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Label marking the series of the collector, which keep their standard
+/// names whatever the configured prefix.
+pub const MARKER: &str = "__prom_write_process__";
+
+/// Labels of the collector's series, the marker left empty so it isn't sent.
+const MARKER_LABELS: &[Label] = &[Label::from_static_parts(MARKER, "")];
+
+/// How long to sleep between checks for shutdown while waiting.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Clock ticks per second of the CPU times in `/proc`.
+#[cfg(target_os = "linux")]
+static CLOCK_TICKS: LazyLock<f64> = LazyLock::new(clock_ticks);
+
+/// A process metric, recorded as a gauge.
+#[derive(Debug)]
+pub struct Sample {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub value: f64,
+}
+
+impl Sample {
+    /// Key of the series, marked as the collector's.
+    pub fn key(&self) -> Key {
+        Key::from_static_parts(self.name, MARKER_LABELS)
+    }
+}
+
+/// Resource usage of this process, each `None` where unavailable.
+#[derive(Debug, Default)]
+struct Usage {
+    cpu_seconds: Option<f64>,
+    resident_bytes: Option<f64>,
+    open_fds: Option<f64>,
+    threads: Option<f64>,
+}
+
+/// Record the resource usage of this process every `interval` on a thread of
+/// its own.
+///
+/// The thread exits soon after the exporter is dropped or shut down.
+pub fn spawn(inner: Weak<BatcherInner>, interval: Duration) -> io::Result<()> {
+    std::thread::Builder::new()
+        .name("prom-write-process".to_owned())
+        .spawn(move || {
+            loop {
+                let Some(batcher) = inner.upgrade() else {
+                    break;
+                };
+                if batcher.shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                // recorded by the worker rather than through the recorder, so
+                // the series are marked and skip its filters
+                let now = batcher.clock.now();
+                batcher.send(Command::Process(now, Usage::read().samples()));
+                drop(batcher);
+                if !wait(&inner, interval) {
+                    break;
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Sleep for `interval` in short steps, returning `false` as soon as the
+/// exporter is dropped or shut down.
+fn wait(inner: &Weak<BatcherInner>, interval: Duration) -> bool {
+    let deadline = Instant::now() + interval;
+    loop {
+        let running = inner
+            .upgrade()
+            .is_some_and(|inner| !inner.shutdown.load(Ordering::Relaxed));
+        let now = Instant::now();
+        if !running || now >= deadline {
+            return running;
+        }
+        std::thread::sleep((deadline - now).min(SHUTDOWN_CHECK_INTERVAL));
+    }
+}
+
+impl Usage {
+    #[cfg(target_os = "linux")]
+    fn read() -> Self {
+        let stat = std::fs::read_to_string("/proc/self/stat").ok();
+        let status = std::fs::read_to_string("/proc/self/status").ok();
+        let status_field = |name: &str| {
+            status
+                .as_deref()?
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+                .split_whitespace()
+                .next()?
+                .parse::<f64>()
+                .ok()
+        };
+
+        Self {
+            cpu_seconds: stat
+                .as_deref()
+                .and_then(|stat| cpu_seconds(stat, *CLOCK_TICKS)),
+            resident_bytes: status_field("VmRSS").map(|kib| kib * 1024.0),
+            open_fds: std::fs::read_dir("/proc/self/fd")
+                .ok()
+                .map(|entries| entries.count() as f64),
+            threads: status_field("Threads"),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn read() -> Self {
+        Self::default()
+    }
+
+    /// The available usage as gauges.
+    fn samples(&self) -> Vec<Sample> {
+        // CPU time is a gauge as counters only hold whole numbers, named like
+        // the counter of other clients so queries and dashboards work alike
+        let gauges = [
+            (
+                "process_cpu_seconds_total",
+                "Total user and system CPU time spent in seconds.",
+                self.cpu_seconds,
+            ),
+            (
+                "process_resident_memory_bytes",
+                "Resident memory size in bytes.",
+                self.resident_bytes,
+            ),
+            (
+                "process_open_fds",
+                "Number of open file descriptors.",
+                self.open_fds,
+            ),
+            (
+                "process_threads",
+                "Number of OS threads in the process.",
+                self.threads,
+            ),
+        ];
+
+        gauges
+            .into_iter()
+            .filter_map(|(name, help, value)| {
+                Some(Sample {
+                    name,
+                    help,
+                    value: value?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// User plus system CPU time from the contents of `/proc/self/stat`, counted
+/// in `ticks` per second.
+#[cfg(target_os = "linux")]
+fn cpu_seconds(stat: &str, ticks: f64) -> Option<f64> {
+    // the command name before the fields may contain spaces
+    let (_, fields) = stat.rsplit_once(')')?;
+    let mut fields = fields.split_whitespace().skip(11);
+    let utime = fields.next()?.parse::<f64>().ok()?;
+    let stime = fields.next()?.parse::<f64>().ok()?;
+    Some((utime + stime) / ticks)
+}
+
+/// Clock ticks per second, from the auxiliary vector the kernel passed the
+/// process, or 100 as on every mainstream platform if unavailable.
+#[cfg(target_os = "linux")]
+fn clock_ticks() -> f64 {
+    /// Type of the auxiliary vector entry holding the tick rate.
+    const AT_CLKTCK: usize = 17;
+
+    std::fs::read("/proc/self/auxv")
+        .ok()
+        .and_then(|auxv| auxv_value(&auxv, AT_CLKTCK))
+        .filter(|&ticks| ticks > 0)
+        .map_or(100.0, |ticks| ticks as f64)
+}
+
+/// Value of the entry of type `key` in the contents of `/proc/self/auxv`,
+/// pairs of native words.
+#[cfg(target_os = "linux")]
+fn auxv_value(auxv: &[u8], key: usize) -> Option<usize> {
+    const WORD: usize = size_of::<usize>();
+    let word = |bytes: &[u8]| bytes.try_into().ok().map(usize::from_ne_bytes);
+
+    auxv.chunks_exact(2 * WORD).find_map(|entry| {
+        let (entry_key, value) = entry.split_at(WORD);
+        (word(entry_key)? == key).then(|| word(value)).flatten()
+    })
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_read_from_proc() {
+        let stat = "4242 (prom write) S 1 4242 4242 0 -1 4194560 500 0 0 0 250 50 0 0 20 0 3 0";
+        assert_eq!(cpu_seconds(stat, 100.0), Some(3.0));
+        assert_eq!(cpu_seconds(stat, 250.0), Some(1.2));
+
+        let auxv: Vec<u8> = [6, 4096, 17, 250, 0, 0]
+            .into_iter()
+            .flat_map(usize::to_ne_bytes)
+            .collect();
+        assert_eq!(auxv_value(&auxv, 17), Some(250));
+        assert_eq!(auxv_value(&auxv, 3), None);
+        assert!(clock_ticks() > 0.0);
+
+        let usage = Usage::read();
+        assert!(usage.cpu_seconds.is_some());
+        assert!(usage.resident_bytes.is_some_and(|bytes| bytes > 0.0));
+        assert!(usage.open_fds.is_some_and(|fds| fds >= 3.0));
+        assert!(usage.threads.is_some_and(|threads| threads >= 1.0));
+    }
+}
@@ -0,0 +1,47 @@
+//! Protobuf messages of the remote write protocol, for building and
+//! inspecting requests, e.g. in a [`Sink`](crate::Sink).
+//!
+//! Requests are assembled as Remote Write 1.0 messages and converted when
+//! sent with [`Protocol::V2`](crate::Protocol::V2).
+
+pub use crate::types::BucketSpan;
+pub use crate::types::Exemplar;
+pub use crate::types::Histogram;
+pub use crate::types::Label;
+pub use crate::types::MetricMetadata;
+pub use crate::types::Sample;
+pub use crate::types::TimeSeries;
+pub use crate::types::WriteRequest;
+pub use crate::types::histogram;
+pub use crate::types::metric_metadata;
+pub use prost::Message;
+
+/// Remote Write 2.0 messages, where strings are interned in a symbol table
+/// referenced by index.
+pub mod v2 {
+    pub use crate::types_v2::BucketSpan;
+    pub use crate::types_v2::Exemplar;
+    pub use crate::types_v2::Histogram;
+    pub use crate::types_v2::Metadata;
+    pub use crate::types_v2::Request;
+    pub use crate::types_v2::Sample;
+    pub use crate::types_v2::TimeSeries;
+    pub use crate::types_v2::histogram;
+    pub use crate::types_v2::metadata;
+}
+
+#[cfg(all(test, feature = "gen-proto"))]
+mod tests {
+    #[test]
+    fn vendored_code_up_to_date() {
+        // copy the generated files to src/generated if this fails
+        assert_eq!(
+            include_str!(concat!(env!("OUT_DIR"), "/prometheus.rs")),
+            include_str!("generated/prometheus.rs")
+        );
+        assert_eq!(
+            include_str!(concat!(env!("OUT_DIR"), "/io.prometheus.write.v2.rs")),
+            include_str!("generated/io.prometheus.write.v2.rs")
+        );
+    }
+}
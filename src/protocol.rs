@@ -0,0 +1,689 @@
+use crate::types;
+use crate::types::metric_metadata::MetricType;
+use crate::types_v2;
+use prost::Message;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// Remote write protocol version used to encode requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// `prometheus.WriteRequest`, understood by every receiver.
+    #[default]
+    V1,
+    /// `io.prometheus.write.v2.Request`, which interns label strings into a
+    /// symbol table for smaller requests.
+    V2,
+}
+
+impl Protocol {
+    /// Value of the `Content-Type` header.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Protocol::V1 => "application/x-protobuf",
+            Protocol::V2 => "application/x-protobuf;proto=io.prometheus.write.v2.Request",
+        }
+    }
+
+    /// Value of the `X-Prometheus-Remote-Write-Version` header.
+    pub fn version(&self) -> &'static str {
+        match self {
+            Protocol::V1 => "1.0.0",
+            Protocol::V2 => "2.0.0",
+        }
+    }
+
+    /// Encode a write request in this protocol's wire format.
+    ///
+    /// Series named `<name>_created` are encoded like any other, even in 2.0.
+    pub fn encode(&self, request: &types::WriteRequest) -> Vec<u8> {
+        self.encode_with(request, &CreatedSeries::new())
+    }
+
+    /// Encode a write request, carrying the `created` series as the created
+    /// timestamp of their counter in 2.0.
+    pub(crate) fn encode_with(
+        &self,
+        request: &types::WriteRequest,
+        created: &CreatedSeries,
+    ) -> Vec<u8> {
+        match self {
+            Protocol::V1 => request.encode_to_vec(),
+            Protocol::V2 => to_v2(request, created).encode_to_vec(),
+        }
+    }
+
+    /// Encode a write request into `buf`, replacing its contents but keeping
+    /// its allocation, see [`Protocol::encode_with`].
+    ///
+    /// 1.0 requests with many series are encoded on up to `threads` threads.
+    /// 2.0 requests share a symbol table between their series, so are always
+    /// encoded on the calling thread.
+    pub(crate) fn encode_into(
+        &self,
+        request: &types::WriteRequest,
+        created: &CreatedSeries,
+        threads: usize,
+        buf: &mut Vec<u8>,
+    ) {
+        buf.clear();
+        let threads = threads.min(request.timeseries.len() / MIN_SERIES_PER_THREAD);
+        // encoding only fails without enough capacity, and vectors grow
+        let _ = match self {
+            Protocol::V1 if threads > 1 => {
+                encode_parallel(request, threads, buf);
+                Ok(())
+            }
+            Protocol::V1 => request.encode(buf),
+            Protocol::V2 => to_v2(request, created).encode(buf),
+        };
+    }
+
+    /// Decode a write request encoded in this protocol's wire format.
+    ///
+    /// The created timestamps of 2.0 series are decoded as `<name>_created`
+    /// series.
+    pub fn decode(&self, encoded: &[u8]) -> Option<types::WriteRequest> {
+        self.decode_with(encoded).map(|(request, _)| request)
+    }
+
+    /// Decode a write request along with the series decoded from created
+    /// timestamps, to encode it again with [`Protocol::encode_with`].
+    pub(crate) fn decode_with(
+        &self,
+        encoded: &[u8],
+    ) -> Option<(types::WriteRequest, CreatedSeries)> {
+        match self {
+            Protocol::V1 => types::WriteRequest::decode(encoded)
+                .ok()
+                .map(|request| (request, CreatedSeries::new())),
+            Protocol::V2 => types_v2::Request::decode(encoded)
+                .ok()
+                .map(|request| to_v1(&request)),
+        }
+    }
+}
+
+/// Fewest series worth encoding on a thread of their own.
+const MIN_SERIES_PER_THREAD: usize = 4096;
+
+/// Encode a 1.0 request's series in chunks on `threads` threads, followed by
+/// its metadata.
+///
+/// A repeated field may be split across the message, so the encoded chunks
+/// are simply concatenated, giving the same bytes as encoding on one thread.
+fn encode_parallel(request: &types::WriteRequest, threads: usize, buf: &mut Vec<u8>) {
+    // keys of the length-delimited `timeseries` (1) and `metadata` (3) fields
+    const TIMESERIES_KEY: u8 = 1 << 3 | 2;
+    const METADATA_KEY: u8 = 3 << 3 | 2;
+
+    let chunk = request.timeseries.len().div_ceil(threads);
+    let parts: Vec<Vec<u8>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = request
+            .timeseries
+            .chunks(chunk)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let mut part = vec![];
+                    for series in chunk {
+                        part.push(TIMESERIES_KEY);
+                        let _ = series.encode_length_delimited(&mut part);
+                    }
+                    part
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect()
+    });
+
+    buf.reserve(parts.iter().map(Vec::len).sum());
+    for part in parts {
+        buf.extend_from_slice(&part);
+    }
+    for metadata in &request.metadata {
+        buf.push(METADATA_KEY);
+        let _ = metadata.encode_length_delimited(buf);
+    }
+}
+
+/// Re-encode a Remote Write 2.0 request as 1.0.
+///
+/// Returns `None` if `encoded` isn't a 2.0 request. The two formats use
+/// distinct field numbers, so a 1.0 request decodes as an empty 2.0 one.
+pub fn downgrade(encoded: &[u8]) -> Option<Vec<u8>> {
+    let request = types_v2::Request::decode(encoded).ok()?;
+    if request.timeseries.is_empty() {
+        return None;
+    }
+
+    Some(to_v1(&request).0.encode_to_vec())
+}
+
+/// Series holding the creation time of a counter, in seconds.
+///
+/// This is the OpenMetrics `<name>_created` convention, as Remote Write 1.0
+/// has no field for it. `timestamp` is that of the counter's latest sample.
+pub fn created_series(labels: &[types::Label], created: i64, timestamp: i64) -> types::TimeSeries {
+    let labels = labels
+        .iter()
+        .map(|label| {
+            let mut label = label.clone();
+            if label.name == "__name__" {
+                if label.value.ends_with(TOTAL_SUFFIX) {
+                    label.value.truncate(label.value.len() - TOTAL_SUFFIX.len());
+                }
+                label.value.push_str(CREATED_SUFFIX);
+            }
+            label
+        })
+        .collect();
+
+    types::TimeSeries {
+        labels,
+        samples: vec![types::Sample {
+            value: created as f64 / 1000.0,
+            timestamp,
+        }],
+        exemplars: vec![],
+        histograms: vec![],
+    }
+}
+
+/// Labels of the series made by [`created_series`] for the counters of a
+/// request, which 2.0 carries as the created timestamp of their counter
+/// instead. Other series named `<name>_created` are sent as they are.
+pub(crate) type CreatedSeries = HashSet<Vec<(String, String)>>;
+
+/// Key of a series in [`CreatedSeries`].
+pub(crate) fn created_key(labels: &[types::Label]) -> Vec<(String, String)> {
+    labels
+        .iter()
+        .map(|label| (label.name.clone(), label.value.clone()))
+        .collect()
+}
+
+/// Name suffix of the series created by [`created_series`].
+const CREATED_SUFFIX: &str = "_created";
+
+/// Name suffix of counters, replaced by [`CREATED_SUFFIX`] in the name of
+/// their created series.
+pub const TOTAL_SUFFIX: &str = "_total";
+
+/// Label names and values, for looking up series.
+type LabelKey<'a> = Vec<(&'a str, &'a str)>;
+
+fn label_key(labels: &[types::Label]) -> LabelKey<'_> {
+    labels
+        .iter()
+        .map(|label| (label.name.as_str(), label.value.as_str()))
+        .collect()
+}
+
+/// Labels of a series with any `_total` suffix removed from its name, to
+/// match those of [`created_parent`].
+fn counter_key(labels: &[types::Label]) -> LabelKey<'_> {
+    labels
+        .iter()
+        .map(|label| match label.name.as_str() {
+            "__name__" => (
+                "__name__",
+                label
+                    .value
+                    .strip_suffix(TOTAL_SUFFIX)
+                    .unwrap_or(&label.value),
+            ),
+            name => (name, label.value.as_str()),
+        })
+        .collect()
+}
+
+/// Labels of the counter a `<name>_created` series belongs to, without any
+/// `_total` suffix.
+fn created_parent(labels: &[types::Label]) -> Option<LabelKey<'_>> {
+    metric_name(labels)?.strip_suffix(CREATED_SUFFIX)?;
+
+    Some(
+        labels
+            .iter()
+            .map(|label| match label.name.as_str() {
+                "__name__" => (
+                    "__name__",
+                    label.value.strip_suffix(CREATED_SUFFIX).unwrap_or_default(),
+                ),
+                name => (name, label.value.as_str()),
+            })
+            .collect(),
+    )
+}
+
+/// Interns strings into a Remote Write 2.0 symbol table.
+struct Symbols {
+    symbols: Vec<String>,
+    refs: HashMap<String, u32>,
+}
+
+impl Symbols {
+    fn new() -> Self {
+        // the spec requires the first symbol to be empty
+        Self {
+            symbols: vec![String::new()],
+            refs: HashMap::from([(String::new(), 0)]),
+        }
+    }
+
+    fn intern(&mut self, symbol: &str) -> u32 {
+        if let Some(reference) = self.refs.get(symbol) {
+            return *reference;
+        }
+
+        let reference = self.symbols.len() as u32;
+        self.symbols.push(symbol.to_owned());
+        self.refs.insert(symbol.to_owned(), reference);
+        reference
+    }
+
+    fn labels(&mut self, labels: &[types::Label]) -> Vec<u32> {
+        labels
+            .iter()
+            .flat_map(|label| [self.intern(&label.name), self.intern(&label.value)])
+            .collect()
+    }
+
+    fn metadata(&mut self, metadata: &types::MetricMetadata) -> types_v2::Metadata {
+        types_v2::Metadata {
+            // both protocols number the metric types the same way
+            r#type: metadata.r#type,
+            help_ref: self.intern(&metadata.help),
+            unit_ref: self.intern(&metadata.unit),
+        }
+    }
+}
+
+/// Value of the `__name__` label.
+pub fn metric_name(labels: &[types::Label]) -> Option<&str> {
+    labels
+        .iter()
+        .find(|label| label.name == "__name__")
+        .map(|label| label.value.as_str())
+}
+
+/// Name suffixes of the series of classic histograms and summaries.
+const FAMILY_SUFFIXES: [&str; 3] = ["_bucket", "_sum", "_count"];
+
+/// Name of the metric family of type `metric_type` a series belongs to.
+pub fn family_name(name: &str, metric_type: MetricType) -> &str {
+    match metric_type {
+        MetricType::Histogram | MetricType::Summary => FAMILY_SUFFIXES
+            .iter()
+            .find_map(|suffix| name.strip_suffix(suffix))
+            .unwrap_or(name),
+        _ => name,
+    }
+}
+
+/// Metadata of the family a series belongs to, including the `_bucket`,
+/// `_sum` and `_count` series of classic histograms and summaries.
+pub fn family_metadata<'a>(
+    name: &str,
+    metadata: impl Fn(&str) -> Option<&'a types::MetricMetadata>,
+) -> Option<&'a types::MetricMetadata> {
+    metadata(name).or_else(|| {
+        FAMILY_SUFFIXES
+            .iter()
+            .filter_map(|suffix| name.strip_suffix(suffix))
+            .filter_map(&metadata)
+            .find(|metadata| {
+                matches!(
+                    metadata.r#type(),
+                    MetricType::Histogram | MetricType::Summary
+                )
+            })
+    })
+}
+
+/// Convert a histogram between the two protocols, which encode histograms
+/// identically.
+fn convert<T: Message, U: Message + Default>(histogram: &T) -> U {
+    U::decode(histogram.encode_to_vec().as_slice()).unwrap_or_default()
+}
+
+/// Convert a Remote Write 1.0 request to 2.0, with the `created` series
+/// carried as created timestamps.
+fn to_v2(request: &types::WriteRequest, created_series: &CreatedSeries) -> types_v2::Request {
+    let mut symbols = Symbols::new();
+
+    // 2.0 carries metadata on each series rather than per metric family
+    let metadata: HashMap<&str, &types::MetricMetadata> = request
+        .metadata
+        .iter()
+        .map(|metadata| (metadata.metric_family_name.as_str(), metadata))
+        .collect();
+
+    // 2.0 carries the `<name>_created` series of a counter as its created
+    // timestamp instead
+    let series: HashMap<LabelKey, LabelKey> = if created_series.is_empty() {
+        HashMap::new()
+    } else {
+        request
+            .timeseries
+            .iter()
+            .map(|series| (counter_key(&series.labels), label_key(&series.labels)))
+            .collect()
+    };
+    let parent = |labels| {
+        created_parent(labels)
+            .filter(|_| created_series.contains(&created_key(labels)))
+            .and_then(|parent| series.get(&parent))
+    };
+    let mut created: HashMap<&LabelKey, i64> = HashMap::new();
+    for timeseries in &request.timeseries {
+        if let Some(parent) = parent(&timeseries.labels)
+            && let Some(sample) = timeseries.samples.last()
+        {
+            created.insert(parent, (sample.value * 1000.0).round() as i64);
+        }
+    }
+
+    let timeseries = request
+        .timeseries
+        .iter()
+        .filter(|series| parent(&series.labels).is_none_or(|parent| !created.contains_key(parent)))
+        .map(|series| types_v2::TimeSeries {
+            labels_refs: symbols.labels(&series.labels),
+            samples: series
+                .samples
+                .iter()
+                .map(|sample| types_v2::Sample {
+                    value: sample.value,
+                    timestamp: sample.timestamp,
+                })
+                .collect(),
+            exemplars: series
+                .exemplars
+                .iter()
+                .map(|exemplar| types_v2::Exemplar {
+                    labels_refs: symbols.labels(&exemplar.labels),
+                    value: exemplar.value,
+                    timestamp: exemplar.timestamp,
+                })
+                .collect(),
+            histograms: series.histograms.iter().map(convert).collect(),
+            metadata: metric_name(&series.labels)
+                .and_then(|name| family_metadata(name, |name| metadata.get(name).copied()))
+                .map(|metadata| symbols.metadata(metadata)),
+            created_timestamp: created
+                .get(&label_key(&series.labels))
+                .copied()
+                .unwrap_or_default(),
+        })
+        .collect();
+
+    types_v2::Request {
+        symbols: symbols.symbols,
+        timeseries,
+    }
+}
+
+/// Convert a Remote Write 2.0 request to 1.0, along with the series made from
+/// created timestamps.
+fn to_v1(request: &types_v2::Request) -> (types::WriteRequest, CreatedSeries) {
+    let symbol = |reference: &u32| {
+        request
+            .symbols
+            .get(*reference as usize)
+            .cloned()
+            .unwrap_or_default()
+    };
+    let labels = |refs: &[u32]| {
+        refs.chunks_exact(2)
+            .map(|pair| types::Label {
+                name: symbol(&pair[0]),
+                value: symbol(&pair[1]),
+            })
+            .collect()
+    };
+
+    let timeseries: Vec<types::TimeSeries> = request
+        .timeseries
+        .iter()
+        .map(|series| types::TimeSeries {
+            labels: labels(&series.labels_refs),
+            samples: series
+                .samples
+                .iter()
+                .map(|sample| types::Sample {
+                    value: sample.value,
+                    timestamp: sample.timestamp,
+                })
+                .collect(),
+            exemplars: series
+                .exemplars
+                .iter()
+                .map(|exemplar| types::Exemplar {
+                    labels: labels(&exemplar.labels_refs),
+                    value: exemplar.value,
+                    timestamp: exemplar.timestamp,
+                })
+                .collect(),
+            histograms: series.histograms.iter().map(convert).collect(),
+        })
+        .collect();
+
+    let mut families = HashSet::new();
+    let metadata = request
+        .timeseries
+        .iter()
+        .zip(&timeseries)
+        .filter_map(|(series, converted)| {
+            let metadata = series.metadata.as_ref()?;
+            let name = family_name(
+                metric_name(&converted.labels)?,
+                MetricType::try_from(metadata.r#type).unwrap_or_default(),
+            );
+            families.insert(name).then(|| types::MetricMetadata {
+                r#type: metadata.r#type,
+                metric_family_name: name.to_owned(),
+                help: symbol(&metadata.help_ref),
+                unit: symbol(&metadata.unit_ref),
+            })
+        })
+        .collect();
+
+    let mut created_keys = CreatedSeries::new();
+    let timeseries = request
+        .timeseries
+        .iter()
+        .zip(timeseries)
+        .flat_map(|(series, converted)| {
+            let created = converted
+                .samples
+                .last()
+                .filter(|_| series.created_timestamp != 0)
+                .map(|sample| {
+                    created_series(
+                        &converted.labels,
+                        series.created_timestamp,
+                        sample.timestamp,
+                    )
+                });
+            if let Some(created) = &created {
+                created_keys.insert(created_key(&created.labels));
+            }
+            created.into_iter().chain([converted])
+        })
+        .collect();
+
+    let request = types::WriteRequest {
+        timeseries,
+        metadata,
+    };
+    (request, created_keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v2_interns_symbols() {
+        let label = |name: &str, value: &str| types::Label {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        };
+        let request = types::WriteRequest {
+            timeseries: vec![
+                types::TimeSeries {
+                    labels: vec![label("__name__", "a"), label("env", "prod")],
+                    samples: vec![types::Sample {
+                        value: 1.0,
+                        timestamp: 100,
+                    }],
+                    exemplars: vec![],
+                    histograms: vec![],
+                },
+                types::TimeSeries {
+                    labels: vec![label("__name__", "b"), label("env", "prod")],
+                    samples: vec![],
+                    exemplars: vec![],
+                    histograms: vec![],
+                },
+            ],
+            metadata: vec![],
+        };
+
+        let encoded = Protocol::V2.encode(&request);
+        let decoded = types_v2::Request::decode(encoded.as_slice()).unwrap();
+
+        assert_eq!(decoded.symbols, ["", "__name__", "a", "env", "prod", "b"]);
+        assert_eq!(decoded.timeseries[0].labels_refs, [1, 2, 3, 4]);
+        assert_eq!(decoded.timeseries[1].labels_refs, [1, 5, 3, 4]);
+        assert_eq!(decoded.timeseries[0].samples[0].timestamp, 100);
+
+        let downgraded = downgrade(&encoded).unwrap();
+        assert_eq!(
+            types::WriteRequest::decode(downgraded.as_slice()).unwrap(),
+            request
+        );
+        assert_eq!(downgrade(&Protocol::V1.encode(&request)), None);
+    }
+
+    #[test]
+    fn v2_carries_metadata_per_series() {
+        let request = types::WriteRequest {
+            timeseries: vec![types::TimeSeries {
+                labels: vec![types::Label {
+                    name: "__name__".to_owned(),
+                    value: "requests".to_owned(),
+                }],
+                samples: vec![],
+                exemplars: vec![],
+                histograms: vec![],
+            }],
+            metadata: vec![types::MetricMetadata {
+                r#type: types::metric_metadata::MetricType::Counter.into(),
+                metric_family_name: "requests".to_owned(),
+                help: "Requests handled.".to_owned(),
+                unit: String::new(),
+            }],
+        };
+
+        let encoded = Protocol::V2.encode(&request);
+        let decoded = types_v2::Request::decode(encoded.as_slice()).unwrap();
+
+        let metadata = decoded.timeseries[0].metadata.unwrap();
+        assert_eq!(metadata.r#type(), types_v2::metadata::MetricType::Counter);
+        assert_eq!(
+            decoded.symbols[metadata.help_ref as usize],
+            "Requests handled."
+        );
+        assert_eq!(metadata.unit_ref, 0);
+
+        let downgraded = downgrade(&encoded).unwrap();
+        assert_eq!(
+            types::WriteRequest::decode(downgraded.as_slice()).unwrap(),
+            request
+        );
+    }
+
+    #[test]
+    fn v2_keeps_created_gauges() {
+        let series = |name: &str, value: f64| types::TimeSeries {
+            labels: vec![types::Label {
+                name: "__name__".to_owned(),
+                value: name.to_owned(),
+            }],
+            samples: vec![types::Sample {
+                value,
+                timestamp: 1_700_000_001_000,
+            }],
+            exemplars: vec![],
+            histograms: vec![],
+        };
+        let request = types::WriteRequest {
+            timeseries: vec![
+                series("requests_created", 1_700_000_000.5),
+                series("requests_total", 3.0),
+            ],
+            metadata: vec![],
+        };
+
+        // a gauge that happens to be named like a created series
+        let encoded = Protocol::V2.encode(&request);
+        let decoded = types_v2::Request::decode(encoded.as_slice()).unwrap();
+        assert_eq!(decoded.timeseries.len(), 2);
+        assert!(
+            decoded
+                .timeseries
+                .iter()
+                .all(|series| series.created_timestamp == 0)
+        );
+
+        // one made for the counter
+        let created = CreatedSeries::from([created_key(&request.timeseries[0].labels)]);
+        let encoded = Protocol::V2.encode_with(&request, &created);
+        let decoded = types_v2::Request::decode(encoded.as_slice()).unwrap();
+        assert_eq!(decoded.timeseries.len(), 1);
+        assert_eq!(decoded.timeseries[0].created_timestamp, 1_700_000_000_500);
+
+        let (downgraded, decoded_created) = Protocol::V2.decode_with(&encoded).unwrap();
+        assert_eq!(downgraded, request);
+        assert_eq!(decoded_created, created);
+    }
+
+    #[test]
+    fn parallel_encoding_matches_serial() {
+        let series = |index: usize| types::TimeSeries {
+            labels: vec![types::Label {
+                name: "__name__".to_owned(),
+                value: format!("series_{index}"),
+            }],
+            samples: vec![types::Sample {
+                value: index as f64,
+                timestamp: 1_700_000_000_000,
+            }],
+            exemplars: vec![],
+            histograms: vec![],
+        };
+        let request = types::WriteRequest {
+            timeseries: (0..3 * MIN_SERIES_PER_THREAD + 1).map(series).collect(),
+            metadata: vec![types::MetricMetadata {
+                r#type: MetricType::Gauge as i32,
+                metric_family_name: "series_0".to_owned(),
+                help: "A series.".to_owned(),
+                unit: String::new(),
+            }],
+        };
+
+        let mut encoded = vec![];
+        Protocol::V1.encode_into(&request, &CreatedSeries::new(), 4, &mut encoded);
+        assert_eq!(encoded, request.encode_to_vec());
+    }
+}
@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+/// Tuning knobs named after Prometheus' own remote write `queue_config`.
+///
+/// Apply with [`Builder::queue_config`](crate::Builder::queue_config). The
+/// defaults match Prometheus, except for the shards.
+///
+/// Requests aren't sharded, so `min_shards` and `max_shards` other than 1
+/// are rejected when building. Concurrent requests are set with
+/// [`Builder::max_in_flight`](crate::Builder::max_in_flight) instead, each
+/// sent from its own thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct QueueConfig {
+    /// Number of operations buffered before the overflow policy applies.
+    pub capacity: usize,
+    /// Maximum time samples wait before being sent.
+    pub batch_send_deadline: Duration,
+    /// Initial retry delay, doubled on every retry.
+    pub min_backoff: Duration,
+    /// Maximum retry delay.
+    pub max_backoff: Duration,
+    /// Maximum number of samples per request.
+    pub max_samples_per_send: usize,
+    /// Minimum number of shards. Only 1 is supported.
+    pub min_shards: usize,
+    /// Maximum number of shards. Only 1 is supported.
+    pub max_shards: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            batch_send_deadline: Duration::from_secs(5),
+            min_backoff: Duration::from_millis(30),
+            max_backoff: Duration::from_secs(5),
+            max_samples_per_send: 2_000,
+            min_shards: 1,
+            max_shards: 1,
+        }
+    }
+}
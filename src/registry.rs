@@ -1,6 +1,11 @@
+use crate::histogram::Distribution;
+use crate::histogram::HistogramMode;
+use crate::summary::Window;
 use crate::types;
 use metrics::Key;
-use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::hash::Hasher;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
@@ -8,25 +13,85 @@ use std::time::UNIX_EPOCH;
 pub struct Samples {
     sent: bool,
     samples: Vec<types::Sample>,
+    /// Timestamp of the first sample, or of the latest reset.
+    created: i64,
+    /// Added to absolute values set after a rebased reset.
+    offset: f64,
+    /// Keep every sample rather than coalescing those sharing a timestamp.
+    raw: bool,
+    /// Value of the last sample sent.
+    sent_value: Option<f64>,
+    /// Every value taken since last sent, including those replaced by a
+    /// later one in the same millisecond.
+    observed: Observed,
+}
+
+/// Count, sum and extremes of the values a series took.
+#[derive(Debug, Clone, Copy)]
+struct Observed {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Observed {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
 }
 
 impl Samples {
     /// Create a new sample stream.
     pub fn new(sample: types::Sample) -> Self {
+        let mut observed = Observed::new();
+        observed.add(sample.value);
         Self {
             sent: false,
+            created: sample.timestamp,
+            offset: 0.0,
+            raw: false,
+            sent_value: None,
+            observed,
             samples: vec![sample],
         }
     }
 
+    /// When the series was first observed, in milliseconds.
+    pub fn created(&self) -> i64 {
+        self.created
+    }
+
     pub fn all(&self) -> &Vec<types::Sample> {
         &self.samples
     }
 
     /// Increment, adding to the previous value.
     pub fn increment(&mut self, sample: types::Sample) {
+        if self.raw {
+            let previous = self.samples.last().map_or(0.0, |last| last.value);
+            self.observed.add(previous + sample.value);
+            return self.push(types::Sample {
+                value: previous + sample.value,
+                timestamp: sample.timestamp,
+            });
+        }
+
         if let Some(last) = self.samples.last_mut() {
             let current = last.value;
+            self.observed.add(current + sample.value);
 
             if sample.timestamp <= last.timestamp {
                 // increment old value
@@ -45,17 +110,25 @@ impl Samples {
             }
         } else {
             self.sent = false;
+            self.observed.add(sample.value);
             self.samples.push(sample);
         }
     }
 
     /// Set the new or next sample.
     pub fn set(&mut self, sample: types::Sample) {
+        if self.raw {
+            self.observed.add(sample.value);
+            return self.push(sample);
+        }
+
         if let Some(last) = self.samples.last_mut() {
             if sample.timestamp == last.timestamp {
                 // assign new value
-                last.value = sample.value
+                last.value = sample.value;
+                self.observed.add(sample.value);
             } else if sample.timestamp > last.timestamp {
+                self.observed.add(sample.value);
                 // the existing sample has already been sent
                 if self.sent {
                     self.samples.clear();
@@ -69,18 +142,95 @@ impl Samples {
             }
         } else {
             self.sent = false;
+            self.observed.add(sample.value);
             self.samples.push(sample);
         }
     }
 
+    /// Add a sample after the previous ones, whatever its timestamp.
+    fn push(&mut self, sample: types::Sample) {
+        // the existing sample has already been sent
+        if self.sent {
+            self.samples.clear();
+        }
+
+        self.samples.push(sample);
+        self.sent = false;
+    }
+
+    /// Set the absolute value of a counter, handling a value lower than the
+    /// previous one according to `reset`.
+    pub fn set_counter(&mut self, mut sample: types::Sample, reset: CounterReset) {
+        let previous = self
+            .samples
+            .last()
+            .map_or(0.0, |last| last.value - self.offset);
+
+        if sample.value < previous {
+            match reset {
+                CounterReset::Emit => {}
+                CounterReset::Rebase => self.offset += previous,
+                CounterReset::Created => self.created = sample.timestamp,
+            }
+        }
+
+        sample.value += self.offset;
+        self.set(sample);
+    }
+
+    /// Keep at most `max` unsent samples, evicting the excess.
+    pub fn limit(&mut self, max: usize, eviction: SampleEviction) {
+        let max = max.max(1);
+        if self.samples.len() <= max {
+            return;
+        }
+
+        let excess = self.samples.len() - max;
+        match eviction {
+            SampleEviction::DropOldest => {
+                self.samples.drain(..excess);
+            }
+            // keep the newest sample, which holds the latest value
+            SampleEviction::Coalesce => {
+                let last = self.samples.len() - 1;
+                self.samples.drain(last - excess..last);
+            }
+        }
+    }
+
+    /// Number of samples waiting to be sent.
+    pub fn pending(&self) -> usize {
+        if self.sent { 0 } else { self.samples.len() }
+    }
+
+    /// Drop the oldest samples, keeping at most `n` (and at least one).
+    ///
+    /// Returns the number of samples dropped.
+    pub fn retain_last(&mut self, n: usize) -> usize {
+        let excess = self.samples.len().saturating_sub(n.max(1));
+        self.samples.drain(..excess);
+        excess
+    }
+
     /// Has this sample been sent already.
     pub fn is_sent(&self) -> bool {
         self.sent
     }
 
+    /// Value of the last sample sent, if any.
+    pub fn sent_value(&self) -> Option<f64> {
+        self.sent_value
+    }
+
+    /// Remember `value` as the last one sent, as aggregated.
+    pub fn sent_as(&mut self, value: f64) {
+        self.sent_value = Some(value);
+    }
+
     /// Remove all elements except the last.
     pub fn sent(&mut self) {
         self.sent = true;
+        self.observed = Observed::new();
 
         let last = self.samples.last().copied();
         self.samples.clear();
@@ -90,28 +240,192 @@ impl Samples {
     }
 }
 
+/// Which samples of a series are discarded once it has
+/// [`Builder::max_series_samples`](crate::Builder::max_series_samples)
+/// unsent samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SampleEviction {
+    /// Discard the oldest samples.
+    DropOldest,
+    /// Discard the samples between the oldest and the newest, keeping the
+    /// latest value at a lower resolution.
+    #[default]
+    Coalesce,
+}
+
+/// How a counter set with `absolute` to a value lower than its previous one,
+/// e.g. after the process it mirrors restarted, is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CounterReset {
+    /// Send the lower value, which receivers take as a reset.
+    #[default]
+    Emit,
+    /// Add the value before the reset to every later value, so the series
+    /// keeps increasing.
+    Rebase,
+    /// Send the lower value and move the created timestamp of the series to
+    /// the reset, see
+    /// [`Builder::created_timestamps`](crate::Builder::created_timestamps).
+    Created,
+}
+
+/// How the values a gauge was set to between two writes are sent.
+///
+/// Every value counts, including those replaced within the same
+/// millisecond. Sets merged before reaching the registry by
+/// [`Builder::thread_batching`](crate::Builder::thread_batching) or
+/// [`Builder::atomic_handles`](crate::Builder::atomic_handles) count once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GaugeAggregation {
+    /// Send every sample, one per millisecond the gauge changed in.
+    #[default]
+    All,
+    /// Send only the latest value.
+    Last,
+    /// Send the lowest value.
+    Min,
+    /// Send the highest value.
+    Max,
+    /// Send the mean of the values.
+    Mean,
+    /// Send the sum of the values. An increment counts as setting the
+    /// gauge to its new value, so this suits gauges that are only set.
+    Sum,
+}
+
+impl GaugeAggregation {
+    /// Aggregate the values of `samples` taken since last sent into one
+    /// sample timestamped with the latest, unless sending all.
+    pub fn apply(self, samples: &Samples) -> Vec<types::Sample> {
+        let Some(last) = samples.all().last() else {
+            return vec![];
+        };
+        // values restored from state weren't observed
+        let observed = &samples.observed;
+        if observed.count == 0 && self != GaugeAggregation::All {
+            return vec![*last];
+        }
+
+        let value = match self {
+            GaugeAggregation::All => return samples.all().clone(),
+            GaugeAggregation::Last => last.value,
+            GaugeAggregation::Min => observed.min,
+            GaugeAggregation::Max => observed.max,
+            GaugeAggregation::Mean => observed.sum / observed.count as f64,
+            GaugeAggregation::Sum => observed.sum,
+        };
+
+        vec![types::Sample {
+            value,
+            timestamp: last.timestamp,
+        }]
+    }
+}
+
+/// Map keyed by [`Key`], hashed with the hash keys compute once when
+/// created rather than over their name and labels on every lookup.
+pub type KeyMap<V> = HashMap<Key, V, BuildHasherDefault<KeyHasher>>;
+
+/// Entries of `map` in key order, so series are sent and rendered in the
+/// same order whatever their hashes.
+pub fn sorted<V>(map: &KeyMap<V>) -> Vec<(&Key, &V)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+/// Hasher passing through the hash written by [`Key`].
+#[derive(Debug, Default)]
+pub struct KeyHasher(u64);
+
+impl Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // only called for types other than keys
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(byte);
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+}
+
 pub struct Registry {
-    pub counters: BTreeMap<Key, Samples>,
-    pub gauges: BTreeMap<Key, Samples>,
+    pub counters: KeyMap<Samples>,
+    pub gauges: KeyMap<Samples>,
+    pub histograms: KeyMap<Distribution>,
+    /// Latest unsent exemplar of each series.
+    pub exemplars: KeyMap<types::Exemplar>,
+    /// Removed series awaiting a staleness marker.
+    pub stale: KeyMap<Stale>,
+    /// Most unsent samples kept per counter or gauge, and how to evict the
+    /// rest.
+    pub sample_limit: Option<(usize, SampleEviction)>,
+    /// How absolute counters going backwards are handled.
+    pub counter_reset: CounterReset,
+    /// Record every operation as its own sample.
+    pub raw_samples: bool,
+}
+
+/// A removed series.
+#[derive(Debug)]
+pub struct Stale {
+    /// Time of removal, in milliseconds since the epoch.
+    pub timestamp: i64,
+    /// Whether the series was a counter, which affects its name.
+    pub counter: bool,
+    /// The removed histogram, whose mode determines the series to mark.
+    pub histogram: Option<Distribution>,
 }
 
 impl Registry {
     pub fn new() -> Self {
         Self {
-            counters: BTreeMap::new(),
-            gauges: BTreeMap::new(),
+            counters: KeyMap::default(),
+            gauges: KeyMap::default(),
+            histograms: KeyMap::default(),
+            exemplars: KeyMap::default(),
+            stale: KeyMap::default(),
+            sample_limit: None,
+            counter_reset: CounterReset::default(),
+            raw_samples: false,
         }
     }
 
-    /// Mark samples as sent.
-    pub fn sent(&mut self) {
-        for samples in self.counters.values_mut() {
-            samples.sent();
+    /// Samples of a new series, starting with `sample`.
+    fn new_samples(&self, sample: types::Sample) -> Samples {
+        let mut samples = Samples::new(sample);
+        samples.raw = self.raw_samples;
+        samples
+    }
+
+    /// Mark samples of the metrics `due` to be sent as sent.
+    pub fn sent(&mut self, due: impl Fn(&Key) -> bool) {
+        for (key, samples) in &mut self.counters {
+            if due(key) {
+                samples.sent();
+            }
+        }
+
+        for (key, samples) in &mut self.gauges {
+            if due(key) {
+                samples.sent();
+            }
         }
 
-        for samples in self.gauges.values_mut() {
-            samples.sent();
+        for (key, histogram) in &mut self.histograms {
+            if due(key) {
+                histogram.sent();
+            }
         }
+
+        self.exemplars.retain(|key, _| !due(key));
+        self.stale.clear();
     }
 
     /// Increment a counter, adding the given value to the last value.
@@ -123,11 +437,25 @@ impl Registry {
 
         if let Some(samples) = self.counters.get_mut(&key) {
             samples.increment(sample);
+            if let Some((max, eviction)) = self.sample_limit {
+                samples.limit(max, eviction);
+            }
         } else {
-            self.counters.insert(key, Samples::new(sample));
+            self.counters.insert(key, self.new_samples(sample));
         }
     }
 
+    /// Restore a counter saved by a previous process, recording its total
+    /// at `timestamp` and keeping its original creation time.
+    pub fn counter_restore(&mut self, timestamp: SystemTime, key: Key, value: f64, created: i64) {
+        let mut samples = self.new_samples(types::Sample {
+            timestamp: timestamp_millis(timestamp),
+            value,
+        });
+        samples.created = created;
+        self.counters.insert(key, samples);
+    }
+
     /// Set the absolute value of a counter.
     pub fn counter_set(&mut self, timestamp: SystemTime, key: Key, value: u64) {
         let sample = types::Sample {
@@ -136,9 +464,12 @@ impl Registry {
         };
 
         if let Some(samples) = self.counters.get_mut(&key) {
-            samples.set(sample);
+            samples.set_counter(sample, self.counter_reset);
+            if let Some((max, eviction)) = self.sample_limit {
+                samples.limit(max, eviction);
+            }
         } else {
-            self.counters.insert(key, Samples::new(sample));
+            self.counters.insert(key, self.new_samples(sample));
         }
     }
 
@@ -151,8 +482,11 @@ impl Registry {
 
         if let Some(samples) = self.gauges.get_mut(&key) {
             samples.increment(sample);
+            if let Some((max, eviction)) = self.sample_limit {
+                samples.limit(max, eviction);
+            }
         } else {
-            self.gauges.insert(key, Samples::new(sample));
+            self.gauges.insert(key, self.new_samples(sample));
         }
     }
 
@@ -170,21 +504,389 @@ impl Registry {
 
         if let Some(samples) = self.gauges.get_mut(&key) {
             samples.set(sample);
+            if let Some((max, eviction)) = self.sample_limit {
+                samples.limit(max, eviction);
+            }
         } else {
-            self.gauges.insert(key, Samples::new(sample));
+            self.gauges.insert(key, self.new_samples(sample));
+        }
+    }
+
+    /// Add an observation to a histogram, created with the given mode,
+    /// native bucket schema and summary window.
+    pub fn histogram_record(
+        &mut self,
+        timestamp: SystemTime,
+        key: Key,
+        value: f64,
+        mode: &HistogramMode,
+        schema: i32,
+        window: Option<Window>,
+    ) {
+        self.histograms
+            .entry(key)
+            .or_insert_with(|| Distribution::new(mode, schema, window))
+            .observe(timestamp_millis(timestamp), value);
+    }
+
+    /// Move the rolling windows of summaries on to `timestamp`, see
+    /// [`Builder::summary_window`](crate::Builder::summary_window).
+    pub fn expire_windows(&mut self, timestamp: SystemTime) {
+        let now = timestamp_millis(timestamp);
+        for histogram in self.histograms.values_mut() {
+            histogram.expire(now);
+        }
+    }
+
+    /// Forget a series, marking it stale.
+    pub fn remove(&mut self, timestamp: SystemTime, key: Key) {
+        let counter = self.counters.remove(&key).is_some();
+        let histogram = self.histograms.remove(&key);
+        let removed = counter | self.gauges.remove(&key).is_some() | histogram.is_some();
+        self.exemplars.remove(&key);
+
+        if removed {
+            self.stale.insert(
+                key,
+                Stale {
+                    timestamp: timestamp_millis(timestamp),
+                    counter,
+                    histogram,
+                },
+            );
+        }
+    }
+
+    /// Forget sent series not updated since `cutoff`, marking them stale at
+    /// `timestamp` if `mark_stale`. Returns the number of series forgotten.
+    pub fn expire(&mut self, timestamp: SystemTime, cutoff: SystemTime, mark_stale: bool) -> usize {
+        let cutoff = timestamp_millis(cutoff);
+        let idle = |samples: &Samples| {
+            samples.is_sent()
+                && samples
+                    .all()
+                    .last()
+                    .is_none_or(|sample| sample.timestamp < cutoff)
+        };
+
+        let keys: Vec<Key> = self
+            .counters
+            .iter()
+            .chain(&self.gauges)
+            .filter(|(_, samples)| idle(samples))
+            .map(|(key, _)| key)
+            .chain(
+                self.histograms
+                    .iter()
+                    .filter(|(_, histogram)| histogram.is_sent() && histogram.timestamp() < cutoff)
+                    .map(|(key, _)| key),
+            )
+            .cloned()
+            .collect();
+
+        for key in &keys {
+            if mark_stale {
+                self.remove(timestamp, key.clone());
+            } else {
+                self.counters.remove(key);
+                self.gauges.remove(key);
+                self.histograms.remove(key);
+                self.exemplars.remove(key);
+            }
+        }
+        keys.len()
+    }
+
+    /// Number of samples waiting to be sent across all series.
+    pub fn pending(&self) -> usize {
+        self.counters
+            .values()
+            .chain(self.gauges.values())
+            .map(Samples::pending)
+            .sum()
+    }
+
+    /// Drop the oldest unsent samples until at most `max` remain, sharing the
+    /// budget evenly between series. The latest sample of every series is
+    /// always kept.
+    ///
+    /// Returns the number of samples dropped.
+    pub fn trim(&mut self, max: usize) -> usize {
+        if self.pending() <= max {
+            return 0;
+        }
+
+        let series = self.counters.len() + self.gauges.len();
+        let per_series = max / series.max(1);
+
+        self.counters
+            .values_mut()
+            .chain(self.gauges.values_mut())
+            .map(|samples| samples.retain_last(per_series))
+            .sum()
+    }
+
+    /// Evict samples until at most `max` are held, histograms counting as
+    /// one: first older unsent samples, series with the oldest first, keeping
+    /// the latest value of each, then the least recently updated series. Returns the
+    /// number of samples evicted.
+    pub fn shrink(&mut self, max: usize) -> usize {
+        let held = self
+            .counters
+            .values()
+            .chain(self.gauges.values())
+            .map(|samples| samples.all().len())
+            .sum::<usize>()
+            + self.histograms.len();
+        let mut excess = held.saturating_sub(max);
+        let mut evicted = 0;
+
+        let mut series: Vec<_> = self
+            .counters
+            .values_mut()
+            .chain(self.gauges.values_mut())
+            .collect();
+        series.sort_by_key(|samples| samples.all().first().map_or(0, |sample| sample.timestamp));
+
+        for samples in series {
+            if excess == 0 {
+                break;
+            }
+
+            let len = samples.all().len();
+            let dropped = excess.min(len - 1);
+            samples.limit(len - dropped, SampleEviction::DropOldest);
+            excess -= dropped;
+            evicted += dropped;
+        }
+
+        if excess > 0 {
+            let last =
+                |samples: &Samples| samples.all().last().map_or(0, |sample| sample.timestamp);
+            let mut series: Vec<(i64, Key, usize)> = self
+                .counters
+                .iter()
+                .chain(&self.gauges)
+                .map(|(key, samples)| (last(samples), key.clone(), samples.all().len()))
+                .chain(
+                    self.histograms
+                        .iter()
+                        .map(|(key, histogram)| (histogram.timestamp(), key.clone(), 1)),
+                )
+                .collect();
+            series.sort_by_key(|(timestamp, _, _)| *timestamp);
+
+            for (_, key, len) in series {
+                if excess == 0 {
+                    break;
+                }
+
+                self.counters.remove(&key);
+                self.gauges.remove(&key);
+                self.histograms.remove(&key);
+                self.exemplars.remove(&key);
+                excess = excess.saturating_sub(len);
+                evicted += len;
+            }
         }
+
+        evicted
+    }
+
+    /// Record an exemplar, replacing any unsent exemplar of the series.
+    pub fn exemplar(
+        &mut self,
+        timestamp: SystemTime,
+        key: Key,
+        value: f64,
+        labels: Vec<types::Label>,
+    ) {
+        self.exemplars.insert(
+            key,
+            types::Exemplar {
+                labels,
+                value,
+                timestamp: timestamp_millis(timestamp),
+            },
+        );
     }
 }
 
-fn timestamp_millis(timestamp: SystemTime) -> i64 {
-    // todo: dont use SystemTime as we can't then set custom timestamps.
-    timestamp.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+/// Milliseconds since the epoch.
+pub fn timestamp_millis(timestamp: SystemTime) -> i64 {
+    // times before the epoch, e.g. from a device without a clock, are
+    // clamped to it
+    timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn remove_marks_stale() {
+        let mut registry = Registry::new();
+        let key = Key::from_name("test");
+
+        registry.remove(UNIX_EPOCH, key.clone());
+        assert!(registry.stale.is_empty());
+
+        registry.gauge_set(UNIX_EPOCH, key.clone(), 1.0);
+        registry.remove(UNIX_EPOCH, key.clone());
+        assert!(registry.gauges.is_empty());
+        let stale = &registry.stale[&key];
+        assert_eq!(stale.timestamp, 0);
+        assert!(!stale.counter);
+    }
+
+    #[test]
+    fn idle_series_expire() {
+        let at = |millis| UNIX_EPOCH + std::time::Duration::from_millis(millis);
+        let mut registry = Registry::new();
+
+        registry.gauge_set(at(100), Key::from_name("idle"), 1.0);
+        registry.counter_increment(at(100), Key::from_name("quiet"), 1);
+        registry.sent(|_| true);
+        registry.gauge_set(at(300), Key::from_name("busy"), 1.0);
+        registry.counter_increment(at(150), Key::from_name("unsent"), 1);
+
+        assert_eq!(registry.expire(at(400), at(200), true), 2);
+        assert_eq!(registry.gauges.len(), 1);
+        assert_eq!(registry.counters.len(), 1);
+        assert_eq!(registry.stale[&Key::from_name("idle")].timestamp, 400);
+        assert!(registry.stale[&Key::from_name("quiet")].counter);
+
+        registry.sent(|_| true);
+        assert_eq!(registry.expire(at(400), at(400), false), 2);
+        assert!(registry.stale.is_empty());
+        assert!(registry.gauges.is_empty() && registry.counters.is_empty());
+    }
+
+    #[test]
+    fn samples_limited() {
+        let at = |millis| UNIX_EPOCH + std::time::Duration::from_millis(millis);
+        let key = Key::from_name("test");
+
+        for (eviction, expected) in [
+            (SampleEviction::DropOldest, [(3.0, 3), (4.0, 4)]),
+            (SampleEviction::Coalesce, [(1.0, 1), (4.0, 4)]),
+        ] {
+            let mut registry = Registry::new();
+            registry.sample_limit = Some((2, eviction));
+            for millis in 1..=4 {
+                registry.gauge_set(at(millis), key.clone(), millis as f64);
+            }
+
+            let samples: Vec<_> = registry.gauges[&key]
+                .all()
+                .iter()
+                .map(|sample| (sample.value, sample.timestamp))
+                .collect();
+            assert_eq!(samples, expected);
+        }
+    }
+
+    #[test]
+    fn shrunk_to_budget() {
+        let at = |millis| UNIX_EPOCH + std::time::Duration::from_millis(millis);
+        let mut registry = Registry::new();
+
+        for millis in 1..=3 {
+            registry.gauge_set(at(millis), Key::from_name("old"), 1.0);
+            registry.gauge_set(at(millis + 10), Key::from_name("new"), 1.0);
+        }
+        assert_eq!(registry.shrink(6), 0);
+
+        // older samples go first
+        assert_eq!(registry.shrink(3), 3);
+        assert_eq!(registry.gauges[&Key::from_name("old")].all().len(), 1);
+        assert_eq!(registry.gauges[&Key::from_name("new")].all().len(), 2);
+
+        // then the least recently updated series
+        assert_eq!(registry.shrink(1), 2);
+        assert!(!registry.gauges.contains_key(&Key::from_name("old")));
+        assert_eq!(registry.gauges[&Key::from_name("new")].all().len(), 1);
+    }
+
+    #[test]
+    fn counter_resets_handled() {
+        let at = |millis| UNIX_EPOCH + std::time::Duration::from_millis(millis);
+        let key = Key::from_name("test");
+
+        for (reset, values, created) in [
+            (CounterReset::Emit, [5.0, 2.0, 3.0], 1),
+            (CounterReset::Rebase, [5.0, 7.0, 8.0], 1),
+            (CounterReset::Created, [5.0, 2.0, 3.0], 2),
+        ] {
+            let mut registry = Registry::new();
+            registry.counter_reset = reset;
+            registry.counter_set(at(1), key.clone(), 5);
+            registry.counter_set(at(2), key.clone(), 2);
+            registry.counter_set(at(3), key.clone(), 3);
+
+            let samples = &registry.counters[&key];
+            let sent: Vec<_> = samples.all().iter().map(|sample| sample.value).collect();
+            assert_eq!(sent, values, "{reset:?}");
+            assert_eq!(samples.created(), created);
+        }
+    }
+
+    #[test]
+    fn gauge_samples_aggregated() {
+        let sample = |value, timestamp| types::Sample { value, timestamp };
+        let mut samples = Samples::new(sample(4.0, 1));
+        samples.set(sample(1.0, 2));
+        // replaces the sample of the same millisecond, but still counts
+        samples.set(sample(2.0, 2));
+        samples.set(sample(7.0, 3));
+
+        for (aggregation, value) in [
+            (GaugeAggregation::Last, 7.0),
+            (GaugeAggregation::Min, 1.0),
+            (GaugeAggregation::Max, 7.0),
+            (GaugeAggregation::Mean, 3.5),
+            (GaugeAggregation::Sum, 14.0),
+        ] {
+            assert_eq!(
+                aggregation.apply(&samples),
+                [types::Sample {
+                    value,
+                    timestamp: 3
+                }]
+            );
+        }
+        assert_eq!(
+            GaugeAggregation::All.apply(&samples),
+            [sample(4.0, 1), sample(2.0, 2), sample(7.0, 3)]
+        );
+
+        // only values since last sent are aggregated
+        samples.sent();
+        samples.set(sample(5.0, 4));
+        assert_eq!(GaugeAggregation::Sum.apply(&samples), [sample(5.0, 4)]);
+    }
+
+    #[test]
+    fn raw_samples_kept() {
+        let mut registry = Registry::new();
+        registry.raw_samples = true;
+        let key = Key::from_name("test");
+
+        registry.counter_increment(UNIX_EPOCH, key.clone(), 1);
+        registry.counter_increment(UNIX_EPOCH, key.clone(), 2);
+        registry.gauge_set(UNIX_EPOCH, key.clone(), 5.0);
+        registry.gauge_set(UNIX_EPOCH, key.clone(), 4.0);
+
+        let values = |samples: &Samples| -> Vec<f64> {
+            samples.all().iter().map(|sample| sample.value).collect()
+        };
+        assert_eq!(values(&registry.counters[&key]), [1.0, 3.0]);
+        assert_eq!(values(&registry.gauges[&key]), [5.0, 4.0]);
+    }
+
     #[test]
     fn sample_duplicate() {
         let mut samples = Samples::new(types::Sample {
@@ -233,6 +935,31 @@ mod tests {
         assert_eq!(samples.all()[1].timestamp, 200);
     }
 
+    #[test]
+    fn trim_keeps_latest_samples() {
+        let mut registry = Registry::new();
+        for i in 0..10 {
+            registry.gauge_set(
+                UNIX_EPOCH + std::time::Duration::from_millis(i),
+                Key::from_name("a"),
+                1.0,
+            );
+            registry.gauge_set(
+                UNIX_EPOCH + std::time::Duration::from_millis(i),
+                Key::from_name("b"),
+                1.0,
+            );
+        }
+        assert_eq!(registry.pending(), 20);
+
+        assert_eq!(registry.trim(8), 12);
+        assert_eq!(registry.pending(), 8);
+
+        let samples = registry.gauges[&Key::from_name("a")].all();
+        assert_eq!(samples.first().unwrap().timestamp, 6);
+        assert_eq!(samples.last().unwrap().timestamp, 9);
+    }
+
     #[test]
     fn sample_increment_out_of_order() {
         let mut samples = Samples::new(types::Sample {
@@ -0,0 +1,1640 @@
+use crate::batcher::Builder;
+use crate::circuit::CircuitBreaker;
+use crate::compression::Compression;
+use crate::compression::Compressor;
+use crate::outcome::WriteOutcome;
+use crate::partial;
+use crate::protocol::CreatedSeries;
+use crate::protocol::Protocol;
+use crate::status::Health;
+use crate::throttle::LogThrottle;
+use crate::transport::HttpRequest;
+use crate::transport::Transport;
+use crate::transport::TransportError;
+use crate::types;
+use crate::wal::Wal;
+use prost::Message;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use tracing::Span;
+use tracing::debug;
+use tracing::debug_span;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+/// Time allowed for the [`fail_fast`](crate::Builder::fail_fast) probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Minimum time between log messages for the same repeated write failure.
+pub const ERROR_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An encoded write request waiting to be sent.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub encoded: Vec<u8>,
+    pub samples: usize,
+    /// Timestamp of the oldest sample, in milliseconds.
+    pub oldest: i64,
+}
+
+/// Counters describing the traffic to an endpoint since they were last
+/// taken.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Samples handed to the endpoint.
+    pub samples: AtomicU64,
+    /// Samples dropped because the buffer was full or they were rejected.
+    pub dropped: AtomicU64,
+    /// HTTP requests sent, including retries.
+    pub requests: AtomicU64,
+    /// HTTP requests that failed.
+    pub failures: AtomicU64,
+    /// Compressed bytes sent.
+    pub bytes: AtomicU64,
+    /// Duration of the last request in microseconds.
+    pub latency: AtomicU64,
+}
+
+/// A remote write endpoint with its own buffering and failure handling.
+///
+/// Every remote receives every batch. Failed batches are kept per remote, so
+/// one unreachable endpoint doesn't hold back the others.
+pub struct Remote {
+    endpoint: String,
+    transport: Arc<dyn Transport>,
+    /// Protocol version accepted by the endpoint.
+    protocol: Protocol,
+    /// Compression accepted by the endpoint, reused for sequential sends.
+    compressor: Compressor,
+    /// Batches that failed with a retryable error, oldest first.
+    pending: VecDeque<Batch>,
+    /// Buffers of batches sent since last taken, to encode new batches into.
+    spent: Vec<Vec<u8>>,
+    /// Don't send before this time, as requested by the endpoint or to back
+    /// off before a retry.
+    paused_until: Option<Instant>,
+    /// Attempts that failed since the last write that was counted as a
+    /// success or failure.
+    retries: u32,
+    wal: Option<Wal>,
+    /// Whether the WAL may contain batches.
+    wal_pending: bool,
+    dead_letters: Option<Wal>,
+    circuit: Option<CircuitBreaker>,
+    /// Error of a batch rejected during the current write, counted as one
+    /// failure however many batches were rejected.
+    rejected: Option<String>,
+    /// Limits how often repeated write failures are logged.
+    errors: LogThrottle,
+    stats: Arc<Stats>,
+    health: Arc<Health>,
+}
+
+impl Remote {
+    /// Create the remote writing to `endpoint`.
+    pub fn new(
+        endpoint: String,
+        config: &Builder,
+        transport: Arc<dyn Transport>,
+        health: Arc<Health>,
+    ) -> Self {
+        // each remote keeps its own subdirectory, found again after restarts
+        // whatever the order endpoints are configured in
+        let subdirectory = directory_name(&endpoint);
+        let open = |dir: &Option<std::path::PathBuf>| {
+            let dir = dir.as_ref()?.join(&subdirectory);
+            match Wal::open(&dir) {
+                Ok(wal) => Some(wal),
+                Err(err) => {
+                    error!("Failed to open directory {dir:?}: {err}");
+                    None
+                }
+            }
+        };
+
+        let wal = open(&config.wal_dir);
+
+        Self {
+            endpoint,
+            transport,
+            protocol: config.protocol,
+            compressor: Compressor::new(config.compression),
+            pending: VecDeque::new(),
+            spent: vec![],
+            paused_until: None,
+            retries: 0,
+            wal_pending: wal.is_some(),
+            wal,
+            dead_letters: open(&config.dead_letter_dir),
+            circuit: config
+                .circuit_breaker
+                .map(|(threshold, cooldown)| CircuitBreaker::new(threshold, cooldown)),
+            rejected: None,
+            errors: LogThrottle::new(ERROR_SUMMARY_INTERVAL),
+            stats: Arc::default(),
+            health,
+        }
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Protocol new batches must be encoded with.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Take the buffers of the batches sent since last called.
+    pub fn take_spent(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.spent)
+    }
+
+    /// Traffic counters, reset by the caller once recorded.
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Queue new batches and send everything pending.
+    ///
+    /// Returns `true` if the endpoint accepted every pending batch.
+    pub fn write(&mut self, config: &Builder, batches: Vec<Batch>) -> bool {
+        if !batches.is_empty() {
+            let samples: usize = batches.iter().map(|batch| batch.samples).sum();
+            self.stats
+                .samples
+                .fetch_add(samples as u64, Ordering::Relaxed);
+            self.pending.extend(batches);
+            self.enforce_budget(config.max_buffered_samples);
+        }
+        self.drop_expired(config);
+
+        if self.pending.is_empty() && !self.wal_pending {
+            return true;
+        }
+
+        let _span = debug_span!("write", endpoint = %self.endpoint).entered();
+
+        if let Some(until) = self.paused_until {
+            if Instant::now() < until {
+                debug!("backing off from {}. skipping send", self.endpoint);
+                return false;
+            }
+            self.paused_until = None;
+        }
+
+        if let Some(circuit) = &mut self.circuit
+            && !circuit.allow(Instant::now())
+        {
+            debug!("circuit open for {}. skipping send", self.endpoint);
+            return false;
+        }
+
+        self.rejected = None;
+
+        // send persisted batches first so samples arrive in order
+        let result = self
+            .drain_wal(config)
+            .and_then(|()| self.drain_pending(config));
+
+        // retried on a later write rather than blocking ingestion here
+        if let Err(err) = &result
+            && err.is_retryable()
+            && err.retry_after().is_none()
+            && self.retries + 1 < config.retry.max_attempts
+        {
+            let delay = config.retry.backoff(self.retries);
+            debug!(
+                retry = self.retries,
+                status = err.status(),
+                "Write failed, retrying in {delay:?}: {err}"
+            );
+            self.retries += 1;
+            self.paused_until = Some(Instant::now() + delay);
+            return false;
+        }
+        let exhausted = result
+            .as_ref()
+            .is_err_and(|err| err.is_retryable() && err.retry_after().is_none());
+        self.retries = 0;
+
+        if let Some(circuit) = &mut self.circuit {
+            match &result {
+                Ok(()) => circuit.success(),
+                Err(err) if err.is_retryable() => {
+                    if circuit.failure(Instant::now()) {
+                        warn!(
+                            "Circuit opened for {} after repeated write failures",
+                            self.endpoint
+                        );
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                if let Some(err) = self.rejected.take() {
+                    self.health.failure(&self.endpoint, err);
+                    return false;
+                }
+                self.health.success(&self.endpoint);
+
+                let suppressed = self.errors.reset();
+                if suppressed > 0 {
+                    info!(
+                        "Writes to {} recovered after {suppressed} failures",
+                        self.endpoint
+                    );
+                }
+                true
+            }
+            Err(err) => {
+                self.health.failure(&self.endpoint, err.to_string());
+
+                if let Some(suppressed) = self.errors.record(err.to_string(), Instant::now()) {
+                    if suppressed == 0 {
+                        error!(status = err.status(), "{err}");
+                    } else {
+                        error!(
+                            status = err.status(),
+                            suppressed, "{err} (repeated {suppressed} times)"
+                        );
+                    }
+                }
+                self.back_off(&err);
+                if exhausted && self.dead_letters.is_some() {
+                    self.give_up();
+                } else {
+                    self.persist_pending();
+                }
+                false
+            }
+        }
+    }
+
+    /// Move every batch held for the endpoint to the dead letters once
+    /// retries ran out, persisted ones included as they would fail alike.
+    fn give_up(&mut self) {
+        let mut dropped: usize = 0;
+
+        while let Some(batch) = self.pending.pop_front() {
+            dropped += batch.samples;
+            dead_letter(&mut self.dead_letters, &batch.encoded);
+        }
+
+        if self.wal_pending
+            && let Some(wal) = &self.wal
+        {
+            match wal.entries() {
+                Ok(entries) => {
+                    for path in entries {
+                        let encoded = match wal.read(&path) {
+                            Ok(encoded) => encoded,
+                            Err(err) => {
+                                error!("Failed to read WAL entry {path:?}: {err}");
+                                continue;
+                            }
+                        };
+                        if let Some((request, _)) = self.protocol.decode_with(&encoded) {
+                            dropped += sample_count(&request);
+                        }
+                        dead_letter(&mut self.dead_letters, &encoded);
+                        remove(wal, &path);
+                    }
+                    self.wal_pending = false;
+                }
+                Err(err) => error!("Failed to read WAL: {err}"),
+            }
+        }
+
+        if dropped > 0 {
+            self.stats
+                .dropped
+                .fetch_add(dropped as u64, Ordering::Relaxed);
+            warn!(
+                "Moved {dropped} samples for {} to the dead letters after exhausting retries",
+                self.endpoint
+            );
+        }
+    }
+
+    /// Pause writes if the endpoint asked us to.
+    fn back_off(&mut self, err: &SendError) {
+        if let Some(retry_after) = err.retry_after() {
+            debug!("Pausing writes to {} for {retry_after:?}", self.endpoint);
+            self.paused_until = Some(Instant::now() + retry_after);
+        }
+    }
+
+    /// Drop the oldest pending batches until at most `max` samples remain.
+    /// The newest batch is always kept.
+    fn enforce_budget(&mut self, max: usize) {
+        let mut total: usize = self.pending.iter().map(|batch| batch.samples).sum();
+        let mut dropped = 0;
+
+        while total > max && self.pending.len() > 1 {
+            if let Some(batch) = self.pending.pop_front() {
+                total -= batch.samples;
+                dropped += batch.samples;
+            }
+        }
+
+        if dropped > 0 {
+            self.stats
+                .dropped
+                .fetch_add(dropped as u64, Ordering::Relaxed);
+            warn!(
+                "Buffer full for {}, dropped {dropped} unsent samples",
+                self.endpoint
+            );
+        }
+    }
+
+    /// Drop pending samples older than the sample age limit.
+    fn drop_expired(&mut self, config: &Builder) {
+        let Some(cutoff) = age_cutoff(config) else {
+            return;
+        };
+
+        let protocol = self.protocol;
+        let mut dropped = 0;
+        self.pending.retain_mut(|batch| {
+            if batch.oldest >= cutoff {
+                return true;
+            }
+            let Some((request, created, expired)) = drop_older(protocol, &batch.encoded, cutoff)
+            else {
+                return true;
+            };
+
+            dropped += expired;
+            if request.timeseries.is_empty() {
+                return false;
+            }
+            *batch = Batch {
+                encoded: protocol.encode_with(&request, &created),
+                samples: sample_count(&request),
+                oldest: oldest(&request),
+            };
+            true
+        });
+
+        self.expired(dropped);
+    }
+
+    /// Count samples dropped for being older than the sample age limit.
+    fn expired(&mut self, dropped: usize) {
+        if dropped > 0 {
+            self.stats
+                .dropped
+                .fetch_add(dropped as u64, Ordering::Relaxed);
+            warn!(
+                "Dropped {dropped} samples for {} older than the sample age limit",
+                self.endpoint
+            );
+        }
+    }
+
+    /// Whether the endpoint doesn't accept Remote Write 2.0 requests.
+    fn unsupported(&self, err: &SendError) -> bool {
+        self.protocol == Protocol::V2 && err.status() == Some(415)
+    }
+
+    /// Switch to Remote Write 1.0 for this endpoint, re-encoding everything
+    /// pending.
+    fn fall_back(&mut self) {
+        warn!(
+            "{} doesn't support remote write 2.0, falling back to 1.0",
+            self.endpoint
+        );
+        self.protocol = Protocol::V1;
+
+        // 1.0 requires snappy
+        #[cfg(feature = "zstd")]
+        if matches!(self.compressor.compression(), Compression::Zstd(_)) {
+            self.compressor = Compressor::new(Compression::Snappy);
+        }
+
+        for batch in &mut self.pending {
+            if let Some(encoded) = crate::protocol::downgrade(&batch.encoded) {
+                batch.encoded = encoded;
+            }
+        }
+    }
+
+    /// Drop the series an endpoint named when rejecting a batch with 400,
+    /// returning the rest of the batch to resend, if any.
+    ///
+    /// Returns `None` if no series in the batch were named. Resending series
+    /// the endpoint already accepted is harmless as identical samples are
+    /// ignored.
+    fn strip_rejected(&mut self, encoded: &[u8], err: &SendError) -> Option<Option<Batch>> {
+        let SendError::Status(400, body, _) = err else {
+            return None;
+        };
+
+        let named = partial::rejected_series(body);
+        if named.is_empty() {
+            return None;
+        }
+
+        let (request, created) = self.protocol.decode_with(encoded)?;
+        let (kept, rejected) = partial::split(request, &named);
+        if rejected.timeseries.is_empty() {
+            return None;
+        }
+
+        let series: Vec<_> = rejected
+            .timeseries
+            .iter()
+            .map(|series| partial::format_labels(&series.labels))
+            .collect();
+        let samples = sample_count(&rejected);
+        if let Some(suppressed) = self.errors.record(err.to_string(), Instant::now()) {
+            error!(
+                samples,
+                ?series,
+                suppressed,
+                "{err}. Dropping rejected series"
+            );
+        }
+
+        self.rejected = Some(err.to_string());
+        self.stats
+            .dropped
+            .fetch_add(samples as u64, Ordering::Relaxed);
+        dead_letter(
+            &mut self.dead_letters,
+            &self.protocol.encode_with(&rejected, &created),
+        );
+
+        Some((!kept.timeseries.is_empty()).then(|| Batch {
+            samples: sample_count(&kept),
+            oldest: oldest(&kept),
+            encoded: self.protocol.encode_with(&kept, &created),
+        }))
+    }
+
+    /// Move pending batches to the WAL, if enabled.
+    fn persist_pending(&mut self) {
+        let Some(wal) = &mut self.wal else {
+            return;
+        };
+
+        while let Some(batch) = self.pending.front() {
+            if let Err(err) = wal.push(&batch.encoded) {
+                error!("Failed to persist batch: {err}");
+                return;
+            }
+            self.pending.pop_front();
+            self.wal_pending = true;
+        }
+    }
+
+    /// Send pending batches, stopping at the first retryable failure.
+    ///
+    /// Up to `max_in_flight` batches are sent concurrently.
+    fn drain_pending(&mut self, config: &Builder) -> Result<(), SendError> {
+        while !self.pending.is_empty() {
+            let in_flight = config.max_in_flight.min(self.pending.len());
+
+            let results: Vec<_> = if in_flight == 1 {
+                vec![send_batch(
+                    config,
+                    &*self.transport,
+                    &self.endpoint,
+                    &self.pending[0].encoded,
+                    self.protocol,
+                    &mut self.compressor,
+                    &self.stats,
+                    self.retries,
+                )]
+            } else {
+                let span = Span::current();
+                let (transport, endpoint, protocol, compression, stats, retries) = (
+                    &*self.transport,
+                    &self.endpoint,
+                    self.protocol,
+                    self.compressor.compression(),
+                    &self.stats,
+                    self.retries,
+                );
+                std::thread::scope(|scope| {
+                    let handles: Vec<_> = self
+                        .pending
+                        .iter()
+                        .take(in_flight)
+                        .map(|batch| {
+                            let span = span.clone();
+                            scope.spawn(move || {
+                                let _span = span.entered();
+                                send_batch(
+                                    config,
+                                    transport,
+                                    endpoint,
+                                    &batch.encoded,
+                                    protocol,
+                                    &mut Compressor::new(compression),
+                                    stats,
+                                    retries,
+                                )
+                            })
+                        })
+                        .collect();
+
+                    handles
+                        .into_iter()
+                        .map(|handle| {
+                            handle
+                                .join()
+                                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                        })
+                        .collect()
+                })
+            };
+
+            let mut failed = vec![];
+            let mut first_err = None;
+            let mut unsupported = false;
+
+            let sent: Vec<_> = self.pending.drain(..in_flight).collect();
+            for (batch, result) in sent.into_iter().zip(results) {
+                match result {
+                    Ok(()) => self.spent.push(batch.encoded),
+                    Err(err) if self.unsupported(&err) => {
+                        failed.push(batch);
+                        unsupported = true;
+                    }
+                    Err(err) if err.is_retryable() => {
+                        failed.push(batch);
+                        first_err.get_or_insert(err);
+                    }
+                    Err(err) => {
+                        if let Some(rest) = self.strip_rejected(&batch.encoded, &err) {
+                            // resent on the next iteration
+                            failed.extend(rest);
+                            continue;
+                        }
+
+                        // resending the same data will fail again
+                        if let Some(suppressed) =
+                            self.errors.record(err.to_string(), Instant::now())
+                        {
+                            error!(
+                                samples = batch.samples,
+                                status = err.status(),
+                                suppressed,
+                                "{err}. Dropping batch"
+                            );
+                        }
+                        self.rejected = Some(err.to_string());
+                        self.stats
+                            .dropped
+                            .fetch_add(batch.samples as u64, Ordering::Relaxed);
+                        dead_letter(&mut self.dead_letters, &batch.encoded);
+                    }
+                }
+            }
+
+            for batch in failed.into_iter().rev() {
+                self.pending.push_front(batch);
+            }
+
+            if let Some(err) = first_err {
+                return Err(err);
+            }
+
+            if unsupported {
+                // resent in the older format on the next iteration
+                self.fall_back();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send persisted batches, stopping at the first retryable failure.
+    fn drain_wal(&mut self, config: &Builder) -> Result<(), SendError> {
+        if !self.wal_pending {
+            return Ok(());
+        }
+
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+
+        let entries = match wal.entries() {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("Failed to read WAL: {err}");
+                return Ok(());
+            }
+        };
+
+        for path in entries {
+            // re-borrowed as rejected series are handled with `&mut self`
+            let Some(wal) = &self.wal else {
+                break;
+            };
+
+            let mut encoded = match wal.read(&path) {
+                Ok(encoded) => encoded,
+                Err(err) => {
+                    error!("Failed to read WAL entry {path:?}: {err}");
+                    continue;
+                }
+            };
+
+            // entries may have been persisted before falling back
+            if self.protocol == Protocol::V1
+                && let Some(downgraded) = crate::protocol::downgrade(&encoded)
+            {
+                encoded = downgraded;
+            }
+
+            if let Some(cutoff) = age_cutoff(config)
+                && let Some((request, created, dropped)) =
+                    drop_older(self.protocol, &encoded, cutoff)
+                && dropped > 0
+            {
+                self.expired(dropped);
+                if request.timeseries.is_empty() {
+                    if let Some(wal) = &self.wal {
+                        remove(wal, &path);
+                    }
+                    continue;
+                }
+                encoded = self.protocol.encode_with(&request, &created);
+            }
+
+            loop {
+                match send_batch(
+                    config,
+                    &*self.transport,
+                    &self.endpoint,
+                    &encoded,
+                    self.protocol,
+                    &mut self.compressor,
+                    &self.stats,
+                    self.retries,
+                ) {
+                    Ok(()) => {}
+                    Err(err) if self.unsupported(&err) => {
+                        self.fall_back();
+                        return self.drain_wal(config);
+                    }
+                    Err(err) if err.is_retryable() => return Err(err),
+                    Err(err) => {
+                        if let Some(rest) = self.strip_rejected(&encoded, &err) {
+                            if let Some(rest) = rest {
+                                encoded = rest.encoded;
+                                continue;
+                            }
+                        } else {
+                            if let Some(suppressed) =
+                                self.errors.record(err.to_string(), Instant::now())
+                            {
+                                error!(
+                                    status = err.status(),
+                                    suppressed, "{err}. Dropping persisted batch"
+                                );
+                            }
+                            self.rejected = Some(err.to_string());
+                            dead_letter(&mut self.dead_letters, &encoded);
+                        }
+                    }
+                }
+                break;
+            }
+
+            if let Some(wal) = &self.wal {
+                remove(wal, &path);
+            }
+        }
+
+        self.wal_pending = false;
+        Ok(())
+    }
+
+    /// Resend dead-lettered batches, returning how many were delivered.
+    ///
+    /// Batches that are rejected again are kept.
+    pub fn replay_dead_letters(&mut self, config: &Builder) -> usize {
+        let Some(dead_letters) = &self.dead_letters else {
+            return 0;
+        };
+
+        let entries = match dead_letters.entries() {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("Failed to read dead letters: {err}");
+                return 0;
+            }
+        };
+
+        let mut delivered = 0;
+        for path in entries {
+            let mut encoded = match dead_letters.read(&path) {
+                Ok(encoded) => encoded,
+                Err(err) => {
+                    error!("Failed to read dead letter {path:?}: {err}");
+                    continue;
+                }
+            };
+
+            if self.protocol == Protocol::V1
+                && let Some(downgraded) = crate::protocol::downgrade(&encoded)
+            {
+                encoded = downgraded;
+            }
+
+            match send_batch(
+                config,
+                &*self.transport,
+                &self.endpoint,
+                &encoded,
+                self.protocol,
+                &mut self.compressor,
+                &self.stats,
+                0,
+            ) {
+                Ok(()) => {
+                    delivered += 1;
+                    remove(dead_letters, &path);
+                }
+                Err(err) if err.is_retryable() => {
+                    error!("{err}. Stopping replay");
+                    self.back_off(&err);
+                    break;
+                }
+                Err(err) => error!("{err}. Keeping dead letter {path:?}"),
+            }
+        }
+
+        delivered
+    }
+}
+
+/// Name of the WAL and dead letter subdirectory of an endpoint, a hash of
+/// its URL that is stable across builds, unlike `DefaultHasher`.
+fn directory_name(endpoint: &str) -> String {
+    // 64-bit FNV-1a
+    let hash = endpoint
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{hash:016x}")
+}
+
+/// Remove a handled entry, logging failures.
+fn remove(wal: &Wal, path: &Path) {
+    if let Err(err) = wal.remove(path) {
+        error!("Failed to remove {path:?}: {err}");
+    }
+}
+
+/// Number of samples and histograms in a request.
+fn sample_count(request: &types::WriteRequest) -> usize {
+    request
+        .timeseries
+        .iter()
+        .map(|series| series.samples.len() + series.histograms.len())
+        .sum()
+}
+
+/// Timestamp of the oldest sample in a request, in milliseconds.
+pub fn oldest(request: &types::WriteRequest) -> i64 {
+    request
+        .timeseries
+        .iter()
+        .flat_map(|series| {
+            let samples = series.samples.iter().map(|sample| sample.timestamp);
+            samples.chain(
+                series
+                    .histograms
+                    .iter()
+                    .map(|histogram| histogram.timestamp),
+            )
+        })
+        .min()
+        .unwrap_or(i64::MAX)
+}
+
+/// Timestamp before which samples are dropped, if there's a sample age
+/// limit.
+pub fn age_cutoff(config: &Builder) -> Option<i64> {
+    let limit = config.sample_age_limit?;
+    let cutoff = config
+        .clock
+        .now()
+        .checked_sub(limit)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    Some(crate::registry::timestamp_millis(cutoff))
+}
+
+/// Decode a request without the samples older than `cutoff`, along with its
+/// created series and how many samples were dropped. Series left without
+/// samples are removed.
+fn drop_older(
+    protocol: Protocol,
+    encoded: &[u8],
+    cutoff: i64,
+) -> Option<(types::WriteRequest, CreatedSeries, usize)> {
+    let (mut request, created) = protocol.decode_with(encoded)?;
+    let before = sample_count(&request);
+
+    for series in &mut request.timeseries {
+        series.samples.retain(|sample| sample.timestamp >= cutoff);
+        series
+            .histograms
+            .retain(|histogram| histogram.timestamp >= cutoff);
+    }
+    request
+        .timeseries
+        .retain(|series| !series.samples.is_empty() || !series.histograms.is_empty());
+
+    let dropped = before - sample_count(&request);
+    Some((request, created, dropped))
+}
+
+/// Keep a rejected batch for later replay, if enabled.
+fn dead_letter(dead_letters: &mut Option<Wal>, encoded: &[u8]) {
+    if let Some(dead_letters) = dead_letters
+        && let Err(err) = dead_letters.push(encoded)
+    {
+        error!("Failed to persist dead letter: {err}");
+    }
+}
+
+/// Reasons a write request failed.
+#[derive(Debug)]
+pub enum SendError {
+    /// The request could not be completed.
+    Transport(TransportError),
+    /// The request body could not be compressed.
+    Compression(std::io::Error),
+    /// Prometheus responded with a non-success status, and optionally asked
+    /// us to wait before sending again.
+    Status(u16, String, Option<Duration>),
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendError::Transport(err) => Some(&**err),
+            SendError::Compression(err) => Some(err),
+            SendError::Status(_, _, _) => None,
+        }
+    }
+}
+
+impl SendError {
+    /// How long the endpoint asked us to wait before sending again.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            SendError::Status(_, _, retry_after) => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// HTTP status returned by the endpoint, if a response was received.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            SendError::Status(status, _, _) => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Whether the same request may succeed if sent again.
+    ///
+    /// Per the remote write spec, 5xx and 429 responses are retried while
+    /// other 4xx responses indicate the data itself was rejected.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SendError::Transport(_) => true,
+            SendError::Compression(_) => false,
+            SendError::Status(status, _, _) => *status >= 500 || *status == 429,
+        }
+    }
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Transport(err) => write!(f, "Request failed: {err}"),
+            SendError::Compression(err) => write!(f, "Compression failed: {err}"),
+            SendError::Status(status, body, _) if *status >= 500 => {
+                write!(f, "Prometheus returned a server error ({status}): {body}")
+            }
+            SendError::Status(status, body, _) if *status >= 400 => {
+                write!(f, "Prometheus returned a client error ({status}): {body}")
+            }
+            SendError::Status(status, body, _) => {
+                write!(
+                    f,
+                    "Prometheus returned an unexpected status ({status}): {body}"
+                )
+            }
+        }
+    }
+}
+
+/// Compress and send an encoded write request once, `retries` being the
+/// number of earlier failed attempts reported to
+/// [`on_result`](crate::Builder::on_result).
+///
+/// Failed requests aren't retried here, so the worker never sleeps, see
+/// [`Remote::write`].
+#[expect(clippy::too_many_arguments)]
+fn send_batch(
+    config: &Builder,
+    transport: &dyn Transport,
+    endpoint: &str,
+    encoded: &[u8],
+    protocol: Protocol,
+    compressor: &mut Compressor,
+    stats: &Stats,
+    retries: u32,
+) -> Result<(), SendError> {
+    let _span = debug_span!("send", bytes = encoded.len()).entered();
+
+    let compression = compressor.compression();
+    let compressed = compressor
+        .compress(encoded)
+        .map_err(SendError::Compression)?;
+    debug!(
+        bytes = encoded.len(),
+        compressed_bytes = compressed.len(),
+        "Compressed write request"
+    );
+
+    let started = Instant::now();
+    let result = send(
+        config,
+        transport,
+        endpoint,
+        compressed,
+        protocol,
+        compression,
+        config.write_timeout,
+    );
+
+    stats.requests.fetch_add(1, Ordering::Relaxed);
+    stats
+        .bytes
+        .fetch_add(compressed.len() as u64, Ordering::Relaxed);
+    stats
+        .latency
+        .store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+    if result.is_err() {
+        stats.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if let Some(on_result) = &config.on_result {
+        on_result.0(&WriteOutcome {
+            endpoint,
+            status: match &result {
+                Ok(status) => Some(*status),
+                Err(err) => err.status(),
+            },
+            error: result.as_ref().err().map(|err| err as _),
+            retries,
+        });
+    }
+
+    result.map(|_| ())
+}
+
+/// Send a compressed write request to the endpoint.
+///
+/// Redirects are followed manually, up to `config.max_redirects`, so the
+/// method, body and headers are preserved.
+fn send(
+    config: &Builder,
+    transport: &dyn Transport,
+    endpoint: &str,
+    body: &[u8],
+    protocol: Protocol,
+    compression: Compression,
+    timeout: Duration,
+) -> Result<u16, SendError> {
+    let mut url = endpoint.to_owned();
+    let mut redirects = 0;
+
+    let mut headers = vec![
+        (
+            "Content-Type".to_owned(),
+            protocol.content_type().to_owned(),
+        ),
+        ("User-Agent".to_owned(), "prom-push".to_owned()),
+        (
+            "X-Prometheus-Remote-Write-Version".to_owned(),
+            protocol.version().to_owned(),
+        ),
+    ];
+    if let Some(encoding) = compression.content_encoding() {
+        headers.push(("Content-Encoding".to_owned(), encoding.to_owned()));
+    }
+
+    loop {
+        let response = transport
+            .post(&HttpRequest {
+                url: &url,
+                headers: headers.clone(),
+                body,
+                timeout,
+            })
+            .map_err(SendError::Transport)?;
+
+        let status = response.status;
+        debug!(%url, status, bytes = body.len(), "Write request completed");
+
+        if (200..300).contains(&status) {
+            return Ok(status);
+        }
+
+        if (300..400).contains(&status) && redirects < config.max_redirects {
+            let location = response
+                .header("Location")
+                .and_then(|location| resolve_location(&url, location));
+
+            if let Some(location) = location {
+                debug!("Following redirect from {url} to {location}");
+                url = location;
+                redirects += 1;
+                continue;
+            }
+        }
+
+        let retry_after = match status {
+            429 | 503 => response.header("Retry-After").and_then(parse_retry_after),
+            _ => None,
+        };
+
+        return Err(SendError::Status(
+            status,
+            String::from_utf8_lossy(&response.body).into_owned(),
+            retry_after,
+        ));
+    }
+}
+
+/// Send a write request to `endpoint`, sleeping between attempts as the
+/// retry policy or the endpoint's `Retry-After` asks.
+///
+/// Only for threads of their own, e.g. backfills, as the caller is blocked
+/// until the request was accepted or given up on.
+pub fn send_blocking(
+    config: &Builder,
+    transport: &dyn Transport,
+    endpoint: &str,
+    request: &types::WriteRequest,
+) -> Result<(), SendError> {
+    let encoded = config.protocol.encode(request);
+    let mut compressor = Compressor::new(config.compression);
+    let stats = Stats::default();
+
+    let mut retries = 0;
+    loop {
+        match send_batch(
+            config,
+            transport,
+            endpoint,
+            &encoded,
+            config.protocol,
+            &mut compressor,
+            &stats,
+            retries,
+        ) {
+            Err(err) if err.is_retryable() && retries + 1 < config.retry.max_attempts => {
+                let delay = err
+                    .retry_after()
+                    .unwrap_or_else(|| config.retry.backoff(retries));
+                debug!(
+                    retry = retries,
+                    "Backfill failed, retrying in {delay:?}: {err}"
+                );
+                std::thread::sleep(delay);
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Check the endpoint is reachable by sending an empty write request.
+///
+/// Only connection failures and 404 responses are treated as errors, other
+/// statuses may be specific to the empty request.
+pub fn probe(config: &Builder, transport: &dyn Transport, endpoint: &str) -> Result<(), SendError> {
+    let encoded = types::WriteRequest::default().encode_to_vec();
+    let compressed = config
+        .compression
+        .compress(&encoded)
+        .map_err(SendError::Compression)?;
+
+    match send(
+        config,
+        transport,
+        endpoint,
+        &compressed,
+        config.protocol,
+        config.compression,
+        PROBE_TIMEOUT,
+    ) {
+        Ok(_) => Ok(()),
+        Err(err @ (SendError::Transport(_) | SendError::Status(404, _, _))) => Err(err),
+        Err(err) => {
+            warn!("Probe of {endpoint} returned an error: {err}");
+            Ok(())
+        }
+    }
+}
+
+/// Resolve a `Location` header against the URL that returned it.
+fn resolve_location(base: &str, location: &str) -> Option<String> {
+    let location_uri: http::Uri = location.parse().ok()?;
+    if location_uri.scheme().is_some() {
+        return Some(location.to_owned());
+    }
+
+    let base: http::Uri = base.parse().ok()?;
+    let scheme = base.scheme_str()?;
+    let authority = base.authority()?;
+
+    if location.starts_with('/') {
+        Some(format!("{scheme}://{authority}{location}"))
+    } else {
+        // relative to the directory of the base path
+        let path = base.path();
+        let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        Some(format!("{scheme}://{authority}{dir}{location}"))
+    }
+}
+
+/// Parse a `Retry-After` header given either as seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(value.trim()).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_classification() {
+        let status = |code| SendError::Status(code, String::new(), None);
+
+        assert!(status(500).is_retryable());
+        assert!(status(503).is_retryable());
+        assert!(status(429).is_retryable());
+        assert!(!status(400).is_retryable());
+        assert!(!status(404).is_retryable());
+    }
+
+    #[test]
+    fn retry_after_parsing() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+
+        let future = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let delay = parse_retry_after(&future).unwrap();
+        assert!(delay > Duration::from_secs(50) && delay <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn custom_transport_follows_redirects() {
+        use crate::transport::HttpResponse;
+        use std::sync::Mutex;
+
+        struct Redirecting(Mutex<Vec<String>>);
+
+        impl Transport for Redirecting {
+            fn post(&self, request: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+                let mut urls = self.0.lock().unwrap();
+                urls.push(request.url.to_owned());
+
+                let status = if urls.len() == 1 { 307 } else { 204 };
+                Ok(HttpResponse {
+                    status,
+                    headers: vec![("location".to_owned(), "/moved".to_owned())],
+                    body: vec![],
+                })
+            }
+        }
+
+        let config = crate::Batcher::builder().max_redirects(1);
+        let transport = Redirecting(Mutex::new(vec![]));
+
+        let status = send(
+            &config,
+            &transport,
+            "http://localhost/write",
+            b"",
+            Protocol::V1,
+            config.compression,
+            config.write_timeout,
+        );
+        assert_eq!(status.unwrap(), 204);
+        assert_eq!(
+            *transport.0.lock().unwrap(),
+            vec!["http://localhost/write", "http://localhost/moved"]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_v1_on_unsupported_media_type() {
+        use crate::transport::HttpResponse;
+        use std::sync::Mutex;
+
+        struct V1Only(Mutex<Vec<String>>);
+
+        impl Transport for V1Only {
+            fn post(&self, request: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+                let version = request
+                    .headers
+                    .iter()
+                    .find(|(name, _)| name == "X-Prometheus-Remote-Write-Version")
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or_default();
+                let status = if version == "2.0.0" { 415 } else { 204 };
+                self.0.lock().unwrap().push(version);
+
+                Ok(HttpResponse {
+                    status,
+                    ..Default::default()
+                })
+            }
+        }
+
+        let config = crate::Batcher::builder()
+            .protocol(Protocol::V2)
+            .retry(crate::RetryPolicy::disabled());
+        let transport = Arc::new(V1Only(Mutex::new(vec![])));
+        let mut remote = Remote::new(
+            "http://localhost".to_owned(),
+            &config,
+            transport.clone(),
+            Arc::default(),
+        );
+
+        let request = types::WriteRequest {
+            timeseries: vec![types::TimeSeries {
+                samples: vec![types::Sample::default()],
+                ..Default::default()
+            }],
+            metadata: vec![],
+        };
+        let batch = Batch {
+            encoded: Protocol::V2.encode(&request),
+            samples: 1,
+            oldest: 0,
+        };
+
+        assert!(remote.write(&config, vec![batch]));
+        assert_eq!(remote.protocol(), Protocol::V1);
+        assert_eq!(*transport.0.lock().unwrap(), ["2.0.0", "1.0.0"]);
+    }
+
+    #[cfg(all(feature = "test-util", feature = "ureq"))]
+    #[test]
+    fn retries_until_accepted() {
+        use crate::test_util::MockServer;
+        use crate::transport::HttpResponse;
+        use std::time::Duration;
+
+        let server = MockServer::start().unwrap();
+        server.respond_with(HttpResponse {
+            status: 503,
+            ..Default::default()
+        });
+        server.respond_with(HttpResponse {
+            status: 429,
+            ..Default::default()
+        });
+
+        let config = crate::Batcher::builder().retry(crate::RetryPolicy {
+            max_attempts: 3,
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(50),
+            jitter: false,
+        });
+        let mut remote = Remote::new(
+            server.url(),
+            &config,
+            Arc::new(crate::UreqTransport::new()),
+            Arc::default(),
+        );
+
+        let request = types::WriteRequest {
+            timeseries: vec![types::TimeSeries {
+                labels: vec![types::Label {
+                    name: "__name__".to_owned(),
+                    value: "a".to_owned(),
+                }],
+                samples: vec![types::Sample::default()],
+                ..Default::default()
+            }],
+            metadata: vec![],
+        };
+        let batch = Batch {
+            encoded: Protocol::V1.encode(&request),
+            samples: 1,
+            oldest: 0,
+        };
+
+        // retried by later writes once the backoff passed, not by sleeping
+        assert!(!remote.write(&config, vec![batch]));
+        assert!(!remote.write(&config, vec![]));
+        assert_eq!(server.requests(), std::slice::from_ref(&request));
+        assert_eq!(remote.health.status().consecutive_failures, 0);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!remote.write(&config, vec![]));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(remote.write(&config, vec![]));
+        assert_eq!(
+            server.requests(),
+            [request.clone(), request.clone(), request]
+        );
+    }
+
+    #[test]
+    fn partial_write_resends_accepted_series() {
+        use crate::transport::HttpResponse;
+        use std::sync::Mutex;
+
+        // rejects series "a" like Prometheus does for out of order samples
+        struct RejectsA(Mutex<Vec<types::WriteRequest>>);
+
+        impl Transport for RejectsA {
+            fn post(&self, request: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+                let decoded = types::WriteRequest::decode(request.body).unwrap();
+                let rejected = decoded
+                    .timeseries
+                    .iter()
+                    .any(|series| series.labels.iter().any(|label| label.value == "a"));
+                self.0.lock().unwrap().push(decoded);
+
+                Ok(HttpResponse {
+                    status: if rejected { 400 } else { 204 },
+                    body: b"out of order sample for series {__name__=\"a\"}".to_vec(),
+                    ..Default::default()
+                })
+            }
+        }
+
+        let config = crate::Batcher::builder()
+            .compression(crate::Compression::None)
+            .retry(crate::RetryPolicy::disabled());
+        let transport = Arc::new(RejectsA(Mutex::new(vec![])));
+        let mut remote = Remote::new(
+            "http://localhost".to_owned(),
+            &config,
+            transport.clone(),
+            Arc::default(),
+        );
+
+        let series = |name: &str| types::TimeSeries {
+            labels: vec![types::Label {
+                name: "__name__".to_owned(),
+                value: name.to_owned(),
+            }],
+            samples: vec![types::Sample::default()],
+            ..Default::default()
+        };
+        let request = types::WriteRequest {
+            timeseries: vec![series("a"), series("b")],
+            metadata: vec![],
+        };
+        let batch = Batch {
+            encoded: Protocol::V1.encode(&request),
+            samples: 2,
+            oldest: 0,
+        };
+
+        // the rejected series is dropped, so the write isn't fully delivered
+        assert!(!remote.write(&config, vec![batch]));
+        assert_eq!(remote.stats().dropped.load(Ordering::Relaxed), 1);
+
+        let sent = transport.0.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].timeseries, [series("b")]);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_falls_back_to_snappy_with_v1() {
+        use crate::transport::HttpResponse;
+        use std::sync::Mutex;
+
+        struct V1Only(Mutex<Vec<Option<String>>>);
+
+        impl Transport for V1Only {
+            fn post(&self, request: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+                let header = |name: &str| {
+                    request
+                        .headers
+                        .iter()
+                        .find(|(header, _)| header == name)
+                        .map(|(_, value)| value.clone())
+                };
+                let v2 = header("X-Prometheus-Remote-Write-Version").as_deref() == Some("2.0.0");
+                self.0.lock().unwrap().push(header("Content-Encoding"));
+
+                Ok(HttpResponse {
+                    status: if v2 { 415 } else { 204 },
+                    ..Default::default()
+                })
+            }
+        }
+
+        let config = crate::Batcher::builder()
+            .protocol(Protocol::V2)
+            .compression(Compression::Zstd(3))
+            .retry(crate::RetryPolicy::disabled());
+        let transport = Arc::new(V1Only(Mutex::new(vec![])));
+        let mut remote = Remote::new(
+            "http://localhost".to_owned(),
+            &config,
+            transport.clone(),
+            Arc::default(),
+        );
+
+        let batch = Batch {
+            encoded: Protocol::V2.encode(&types::WriteRequest::default()),
+            samples: 0,
+            oldest: i64::MAX,
+        };
+
+        assert!(remote.write(&config, vec![batch]));
+        assert_eq!(
+            *transport.0.lock().unwrap(),
+            [Some("zstd".to_owned()), Some("snappy".to_owned())]
+        );
+    }
+
+    #[test]
+    fn redirect_location_resolution() {
+        let base = "http://localhost:9090/api/v1/write";
+
+        assert_eq!(
+            resolve_location(base, "https://other:443/write").as_deref(),
+            Some("https://other:443/write")
+        );
+        assert_eq!(
+            resolve_location(base, "/region/api/v1/write").as_deref(),
+            Some("http://localhost:9090/region/api/v1/write")
+        );
+        assert_eq!(
+            resolve_location(base, "push").as_deref(),
+            Some("http://localhost:9090/api/v1/push")
+        );
+    }
+
+    #[test]
+    fn samples_older_than_age_limit_dropped() {
+        use crate::transport::HttpResponse;
+        use std::sync::Mutex;
+
+        struct Recording(Mutex<Vec<types::WriteRequest>>);
+
+        impl Transport for Recording {
+            fn post(&self, request: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+                let decoded = types::WriteRequest::decode(request.body).unwrap();
+                self.0.lock().unwrap().push(decoded);
+                Ok(HttpResponse {
+                    status: 204,
+                    ..Default::default()
+                })
+            }
+        }
+
+        let config = crate::Batcher::builder()
+            .compression(crate::Compression::None)
+            .sample_age_limit(Duration::from_secs(3600));
+        let transport = Arc::new(Recording(Mutex::new(vec![])));
+        let mut remote = Remote::new(
+            "http://localhost".to_owned(),
+            &config,
+            transport.clone(),
+            Arc::default(),
+        );
+
+        let now = crate::registry::timestamp_millis(SystemTime::now());
+        let series = |name: &str, timestamps: &[i64]| types::TimeSeries {
+            labels: vec![types::Label {
+                name: "__name__".to_owned(),
+                value: name.to_owned(),
+            }],
+            samples: timestamps
+                .iter()
+                .map(|&timestamp| types::Sample {
+                    value: 1.0,
+                    timestamp,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let batch = |timeseries| {
+            let request = types::WriteRequest {
+                timeseries,
+                metadata: vec![],
+            };
+            Batch {
+                encoded: Protocol::V1.encode(&request),
+                samples: sample_count(&request),
+                oldest: oldest(&request),
+            }
+        };
+
+        let old = now - 7_200_000;
+        let batches = vec![
+            batch(vec![series("a", &[old])]),
+            batch(vec![series("a", &[old, now]), series("b", &[old])]),
+        ];
+        assert!(remote.write(&config, batches));
+        assert_eq!(remote.stats().dropped.load(Ordering::Relaxed), 3);
+
+        let sent = transport.0.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].timeseries, [series("a", &[now])]);
+    }
+
+    #[test]
+    fn wal_keyed_by_endpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = crate::Batcher::builder().wal_dir(dir.path());
+        let transport = config.http_transport().unwrap();
+        let open = |endpoint: &str| {
+            let mut remote = Remote::new(
+                endpoint.to_owned(),
+                &config,
+                transport.clone(),
+                Arc::default(),
+            );
+            remote.pending.push_back(Batch {
+                encoded: endpoint.as_bytes().to_vec(),
+                samples: 1,
+                oldest: 0,
+            });
+            remote.persist_pending();
+            // entries are named by the millisecond they were written in
+            std::thread::sleep(Duration::from_millis(2));
+        };
+
+        open("http://a/write");
+        open("http://b/write");
+        open("http://a/write");
+
+        let wal = Wal::open(dir.path().join(directory_name("http://a/write"))).unwrap();
+        let entries = wal.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(wal.read(&entries[0]).unwrap(), b"http://a/write");
+    }
+
+    #[test]
+    fn budget_drops_oldest_batches() {
+        let config = crate::Batcher::builder();
+        let transport = config.http_transport().unwrap();
+        let mut remote = Remote::new(
+            "http://localhost".to_owned(),
+            &config,
+            transport,
+            Arc::default(),
+        );
+
+        for samples in [4, 4, 4] {
+            remote.pending.push_back(Batch {
+                encoded: vec![],
+                samples,
+                oldest: 0,
+            });
+        }
+
+        remote.enforce_budget(9);
+        assert_eq!(remote.pending.len(), 2);
+
+        remote.enforce_budget(1);
+        assert_eq!(remote.pending.len(), 1);
+    }
+}
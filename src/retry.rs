@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+/// Retry behaviour for failed writes.
+///
+/// Only retryable failures (connection errors and server errors) are retried.
+/// The delay between attempts starts at `min_backoff` and doubles after every
+/// attempt, up to `max_backoff`.
+///
+/// Retries are sent by the first write after the delay rather than by
+/// sleeping, so a failing endpoint doesn't stall ingestion. Failures only
+/// count towards the endpoint's health and circuit breaker once every attempt
+/// failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first. `1` disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub min_backoff: Duration,
+    /// Upper bound for the delay between retries.
+    pub max_backoff: Duration,
+    /// Randomise each delay between half and all of its value, so multiple
+    /// exporters don't retry in lockstep.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            min_backoff: Duration::from_millis(30),
+            max_backoff: Duration::from_secs(5),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn disabled() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Delay before the given retry, starting at zero.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .min_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+
+        if self.jitter {
+            let half = delay / 2;
+            half + half.mul_f64(fastrand::f64())
+        } else {
+            delay
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            min_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            jitter: false,
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(10));
+        assert_eq!(policy.backoff(1), Duration::from_millis(20));
+        assert_eq!(policy.backoff(2), Duration::from_millis(40));
+        assert_eq!(policy.backoff(3), Duration::from_millis(50));
+        assert_eq!(policy.backoff(100), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn backoff_jitter_within_bounds() {
+        let policy = RetryPolicy {
+            jitter: true,
+            ..RetryPolicy::default()
+        };
+
+        for retry in 0..5 {
+            let delay = policy.backoff(retry);
+            let max = RetryPolicy {
+                jitter: false,
+                ..policy
+            }
+            .backoff(retry);
+            assert!(delay >= max / 2 && delay <= max);
+        }
+    }
+}
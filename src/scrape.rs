@@ -0,0 +1,146 @@
+use crate::HttpResponse;
+use crate::batcher::BatcherInner;
+use crate::handle::Handle;
+use crate::http_server;
+use std::io;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Weak;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::debug;
+
+/// `Content-Type` of the text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Time allowed for the worker to render the registry.
+const RENDER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest request body read, as scrapes don't send one.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// How long to wait between checks for connections while idle.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Serve the registry at `/metrics` on a thread of its own.
+///
+/// The thread polls for connections, so exits within 100ms of the exporter
+/// being dropped or shut down, closing the listener.
+pub fn spawn(listener: TcpListener, inner: Weak<BatcherInner>) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+
+    std::thread::Builder::new()
+        .name("prom-write-scrape".to_owned())
+        .spawn(move || {
+            loop {
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                if inner.shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        drop(inner);
+                        std::thread::sleep(ACCEPT_INTERVAL);
+                        continue;
+                    }
+                    Err(err) => {
+                        debug!("Failed to accept scrape: {err}");
+                        continue;
+                    }
+                };
+
+                // accepted connections may inherit non-blocking mode
+                if let Err(err) = stream
+                    .set_nonblocking(false)
+                    .and_then(|()| serve(&stream, &Handle::new(inner)))
+                {
+                    debug!("Failed to serve scrape: {err}");
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Answer a single request, closing the connection afterwards.
+fn serve(stream: &TcpStream, handle: &Handle) -> io::Result<()> {
+    let request = http_server::read_request(stream, MAX_BODY_BYTES)?;
+
+    let response = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => match handle.render_text(RENDER_TIMEOUT) {
+            Some(text) => HttpResponse {
+                status: 200,
+                headers: vec![("Content-Type".to_owned(), CONTENT_TYPE.to_owned())],
+                body: text.into_bytes(),
+            },
+            None => HttpResponse {
+                status: 503,
+                ..Default::default()
+            },
+        },
+        (_, "/metrics") => HttpResponse {
+            status: 405,
+            ..Default::default()
+        },
+        _ => HttpResponse {
+            status: 404,
+            ..Default::default()
+        },
+    };
+
+    http_server::write_response(stream, &response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Batcher;
+    use std::io::Read;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn registry_served_at_metrics() {
+        let batcher = Batcher::builder().build().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(listener, Arc::downgrade(&batcher.inner)).unwrap();
+
+        metrics::with_local_recorder(&batcher, || metrics::gauge!("temperature").set(21.5));
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(CONTENT_TYPE));
+        assert!(response.contains("\r\n\r\n# TYPE temperature gauge\ntemperature 21.5 "));
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404 "));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /metrics HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_BYTES + 1
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413 "));
+
+        // the listener is closed once the exporter is gone
+        drop(batcher);
+        std::thread::sleep(ACCEPT_INTERVAL * 3);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
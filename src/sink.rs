@@ -0,0 +1,42 @@
+use crate::types::WriteRequest;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+
+/// Error returned by a [`Sink`].
+pub type SinkError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Destination for write requests, replacing the built-in HTTP endpoints.
+///
+/// See [`Builder::sink`](crate::Builder::sink). Sinks are called from the
+/// worker thread, once per request, after samples are batched and split.
+pub trait Sink: Send {
+    /// Deliver a write request.
+    ///
+    /// Failed requests are not retried, buffering is up to the sink.
+    fn send(&mut self, request: &WriteRequest) -> Result<(), SinkError>;
+}
+
+/// A sink shared between clones of the builder and the worker.
+#[derive(Clone)]
+pub(crate) struct SharedSink(Arc<Mutex<dyn Sink>>);
+
+impl SharedSink {
+    pub fn new(sink: impl Sink + 'static) -> Self {
+        Self(Arc::new(Mutex::new(sink)))
+    }
+
+    pub fn send(&self, request: &WriteRequest) -> Result<(), SinkError> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .send(request)
+    }
+}
+
+impl fmt::Debug for SharedSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Sink")
+    }
+}
@@ -0,0 +1,62 @@
+use crate::types;
+use opentelemetry::trace::TraceContextExt;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Exemplar labels identifying the current span's OpenTelemetry trace, if
+/// it has one.
+pub fn labels() -> Option<Vec<types::Label>> {
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+
+    if !span_context.is_valid() {
+        return None;
+    }
+
+    Some(vec![
+        types::Label {
+            name: "trace_id".to_owned(),
+            value: span_context.trace_id().to_string(),
+        },
+        types::Label {
+            name: "span_id".to_owned(),
+            value: span_context.span_id().to_string(),
+        },
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::Context;
+    use opentelemetry::trace::SpanContext;
+    use opentelemetry::trace::SpanId;
+    use opentelemetry::trace::TraceFlags;
+    use opentelemetry::trace::TraceId;
+    use opentelemetry::trace::TraceState;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn labels_from_current_span() {
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer());
+
+        tracing::subscriber::with_default(subscriber, || {
+            assert_eq!(labels(), None);
+
+            let parent = SpanContext::new(
+                TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+                SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+                TraceFlags::SAMPLED,
+                true,
+                TraceState::default(),
+            );
+            let span = tracing::info_span!("request");
+            let _ = span.set_parent(Context::new().with_remote_span_context(parent));
+            let _entered = span.enter();
+
+            let labels = labels().unwrap();
+            assert_eq!(labels[0].name, "trace_id");
+            assert_eq!(labels[0].value, "4bf92f3577b34da6a3ce929d0e0e4736");
+        });
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::SystemTime;
+
+/// Exporter health, see [`Handle::status`](crate::Handle::status).
+///
+/// The write results are those of the least healthy endpoint, the one with
+/// the most consecutive failures, with each endpoint's in `endpoints`.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct Status {
+    /// When a write last completed without errors.
+    pub last_success: Option<SystemTime>,
+    /// The most recent write error.
+    pub last_error: Option<String>,
+    /// Failed writes since the last successful one.
+    pub consecutive_failures: u32,
+    /// Operations waiting to be handled by the worker thread.
+    pub queue_depth: usize,
+    /// Health of each endpoint, in the order they were configured.
+    pub endpoints: Vec<EndpointStatus>,
+}
+
+/// Health of a single endpoint, see [`Status::endpoints`].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct EndpointStatus {
+    pub endpoint: String,
+    /// When a write last completed without errors.
+    pub last_success: Option<SystemTime>,
+    /// The most recent write error.
+    pub last_error: Option<String>,
+    /// Failed writes since the last successful one.
+    pub consecutive_failures: u32,
+}
+
+/// Write results of every endpoint shared between the worker and handles.
+#[derive(Debug, Default)]
+pub(crate) struct Health(Mutex<Vec<EndpointStatus>>);
+
+impl Health {
+    /// Track the health of `endpoints`, listed in this order.
+    pub fn new(endpoints: &[String]) -> Self {
+        Self(Mutex::new(
+            endpoints
+                .iter()
+                .map(|endpoint| EndpointStatus {
+                    endpoint: endpoint.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+        ))
+    }
+
+    pub fn success(&self, endpoint: &str) {
+        self.update(endpoint, |status| {
+            status.last_success = Some(SystemTime::now());
+            status.consecutive_failures = 0;
+        });
+    }
+
+    pub fn failure(&self, endpoint: &str, error: String) {
+        self.update(endpoint, |status| {
+            status.last_error = Some(error);
+            status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+        });
+    }
+
+    fn update(&self, endpoint: &str, update: impl FnOnce(&mut EndpointStatus)) {
+        let mut endpoints = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let index = match endpoints
+            .iter()
+            .position(|status| status.endpoint == endpoint)
+        {
+            Some(index) => index,
+            None => {
+                endpoints.push(EndpointStatus {
+                    endpoint: endpoint.to_owned(),
+                    ..Default::default()
+                });
+                endpoints.len() - 1
+            }
+        };
+        update(&mut endpoints[index]);
+    }
+
+    pub fn status(&self) -> Status {
+        let endpoints = self
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+
+        // the first endpoint with the most failures
+        let worst = endpoints
+            .iter()
+            .rev()
+            .max_by_key(|status| status.consecutive_failures)
+            .cloned()
+            .unwrap_or_default();
+
+        Status {
+            last_success: worst.last_success,
+            last_error: worst.last_error,
+            consecutive_failures: worst.consecutive_failures,
+            queue_depth: 0,
+            endpoints,
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Relative error of the quantiles estimated by a [`Sketch`].
+const RELATIVE_ACCURACY: f64 = 0.01;
+
+/// Values with an absolute value below this are counted as zero.
+const MIN_VALUE: f64 = 1e-9;
+
+/// Rolling window over which summary quantiles are estimated, divided into
+/// age buckets expiring in turn.
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    /// Time covered by each age bucket, in milliseconds.
+    width: i64,
+    buckets: i64,
+}
+
+impl Window {
+    pub fn new(length: Duration, buckets: u32) -> Self {
+        let buckets = i64::from(buckets.max(1));
+        let length = i64::try_from(length.as_millis()).unwrap_or(i64::MAX);
+        Self {
+            width: (length / buckets).max(1),
+            buckets,
+        }
+    }
+}
+
+/// Quantiles of a series' observations, sent as `{quantile="0.5"}` series
+/// along with `_sum` and `_count`.
+///
+/// With a [`Window`], quantiles only cover the observations of the window
+/// ending now, see [`Summary::expire`]. `_sum` and `_count` always cover
+/// every observation.
+#[derive(Debug)]
+pub struct Summary {
+    quantiles: Vec<f64>,
+    window: Option<Window>,
+    /// Sketch of each age bucket by index, oldest first. Without a window
+    /// there's a single bucket.
+    ages: VecDeque<(i64, Sketch)>,
+    count: u64,
+    sum: f64,
+    timestamp: i64,
+    sent: bool,
+}
+
+impl Summary {
+    pub fn new(quantiles: Vec<f64>, window: Option<Window>) -> Self {
+        Self {
+            quantiles,
+            window,
+            ages: VecDeque::new(),
+            count: 0,
+            sum: 0.0,
+            timestamp: 0,
+            sent: false,
+        }
+    }
+
+    /// Add an observation.
+    pub fn observe(&mut self, timestamp: i64, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.timestamp = self.timestamp.max(timestamp);
+        self.sent = false;
+
+        let index = self
+            .window
+            .map_or(0, |window| timestamp.div_euclid(window.width));
+        if self.ages.back().is_none_or(|(last, _)| *last < index) {
+            self.ages.push_back((index, Sketch::default()));
+        }
+
+        // late observations go in the bucket of their time, or the oldest
+        let position = self
+            .ages
+            .iter()
+            .rposition(|(age, _)| *age <= index)
+            .unwrap_or(0);
+        self.ages[position].1.add(value);
+
+        if let Some(&(last, _)) = self.ages.back() {
+            self.drop_ages(last);
+        }
+    }
+
+    /// Drop the age buckets that fell out of the window by `now`, so the
+    /// quantiles don't keep covering old observations while no new ones
+    /// arrive. The quantiles are sent again at `now` if any expired.
+    pub fn expire(&mut self, now: i64) {
+        let Some(window) = self.window else {
+            return;
+        };
+
+        if self.drop_ages(now.div_euclid(window.width)) {
+            self.timestamp = self.timestamp.max(now);
+            self.sent = false;
+        }
+    }
+
+    /// Drop the age buckets outside of the window ending with bucket `last`,
+    /// returning whether there were any.
+    fn drop_ages(&mut self, last: i64) -> bool {
+        let Some(window) = self.window else {
+            return false;
+        };
+
+        let before = self.ages.len();
+        while self
+            .ages
+            .front()
+            .is_some_and(|(age, _)| *age <= last - window.buckets)
+        {
+            self.ages.pop_front();
+        }
+        self.ages.len() != before
+    }
+
+    /// Each configured quantile with its estimated value.
+    pub fn quantiles(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        let mut sketch = Sketch::default();
+        for (_, age) in &self.ages {
+            sketch.merge(age);
+        }
+
+        self.quantiles
+            .iter()
+            .map(move |&quantile| (quantile, sketch.quantile(quantile)))
+    }
+
+    /// Number of observations.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of the observations.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Time of the latest observation, in milliseconds.
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Has the latest state been sent already.
+    pub fn is_sent(&self) -> bool {
+        self.sent
+    }
+
+    /// Mark the latest state as sent.
+    pub fn sent(&mut self) {
+        self.sent = true;
+    }
+}
+
+/// DDSketch, estimating quantiles to within 1% of their true value.
+///
+/// Values are counted in logarithmic buckets, bucket `i` covering
+/// `(gamma^(i-1), gamma^i]`, so memory grows with the logarithm of the value
+/// range rather than the number of observations.
+#[derive(Debug, Clone, Default)]
+pub struct Sketch {
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+}
+
+impl Sketch {
+    fn gamma() -> f64 {
+        (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+    }
+
+    /// Add a value, ignoring NaN.
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+
+        self.count += 1;
+        if value.abs() < MIN_VALUE {
+            self.zero += 1;
+            return;
+        }
+
+        let index = (value.abs().ln() / Self::gamma().ln()).ceil() as i32;
+        let buckets = if value > 0.0 {
+            &mut self.positive
+        } else {
+            &mut self.negative
+        };
+        *buckets.entry(index).or_default() += 1;
+    }
+
+    /// Add the values counted by `other`.
+    pub fn merge(&mut self, other: &Sketch) {
+        for (buckets, others) in [
+            (&mut self.positive, &other.positive),
+            (&mut self.negative, &other.negative),
+        ] {
+            for (&index, &count) in others {
+                *buckets.entry(index).or_default() += count;
+            }
+        }
+        self.zero += other.zero;
+        self.count += other.count;
+    }
+
+    /// Estimated value at quantile `q` from 0 to 1, NaN if empty.
+    pub fn quantile(&self, q: f64) -> f64 {
+        let gamma = Self::gamma();
+        let value = |index: i32| 2.0 * gamma.powi(index) / (gamma + 1.0);
+
+        let rank = (q * self.count.saturating_sub(1) as f64).floor() as u64;
+        let mut seen = 0;
+
+        for (&index, &count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return -value(index);
+            }
+        }
+
+        seen += self.zero;
+        if seen > rank {
+            return 0.0;
+        }
+
+        for (&index, &count) in &self.positive {
+            seen += count;
+            if seen > rank {
+                return value(index);
+            }
+        }
+
+        f64::NAN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantiles_within_relative_accuracy() {
+        let mut summary = Summary::new(vec![0.0, 0.5, 0.99, 1.0], None);
+        for value in 1..=1000 {
+            summary.observe(100, value as f64);
+        }
+        summary.observe(100, -5.0);
+
+        let quantiles: Vec<_> = summary.quantiles().collect();
+        let expected = [-5.0, 500.0, 990.0, 1000.0];
+        for ((_, estimate), expected) in quantiles.iter().zip(expected) {
+            let error = ((estimate - expected) / expected).abs();
+            assert!(error <= RELATIVE_ACCURACY, "{estimate} vs {expected}");
+        }
+        assert_eq!(summary.count(), 1001);
+
+        assert!(Sketch::default().quantile(0.5).is_nan());
+    }
+
+    #[test]
+    fn old_observations_expire() {
+        // a minute in buckets of 20s
+        let window = Window::new(Duration::from_secs(60), 3);
+        let mut summary = Summary::new(vec![0.0, 1.0], Some(window));
+
+        summary.observe(0, 100.0);
+        summary.observe(30_000, 10.0);
+        summary.observe(50_000, 1.0);
+        let extremes = |summary: &Summary| -> Vec<f64> {
+            summary
+                .quantiles()
+                .map(|(_, value)| value.round())
+                .collect()
+        };
+        assert_eq!(extremes(&summary), [1.0, 100.0]);
+
+        // the bucket of the first observation has expired
+        summary.observe(60_000, 5.0);
+        assert_eq!(extremes(&summary), [1.0, 10.0]);
+
+        // late observations are still counted
+        summary.observe(20_000, 50.0);
+        assert_eq!(extremes(&summary), [1.0, 50.0]);
+        assert_eq!(summary.count(), 5);
+
+        // without new observations the window still moves on
+        summary.sent();
+        summary.expire(79_999);
+        assert!(summary.is_sent());
+        summary.expire(80_000);
+        assert!(!summary.is_sent());
+        assert_eq!(summary.timestamp(), 80_000);
+        assert_eq!(extremes(&summary), [1.0, 5.0]);
+
+        summary.expire(120_000);
+        assert!(summary.quantiles().all(|(_, value)| value.is_nan()));
+        assert_eq!(summary.count(), 5);
+    }
+}
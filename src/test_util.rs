@@ -0,0 +1,146 @@
+//! Helpers for tests that check what would be pushed, e.g. through a mock
+//! [`Transport`](crate::Transport) or [`Sink`](crate::Sink), or to a
+//! [`MockServer`].
+
+pub use crate::clock::ManualClock;
+pub use crate::mock_server::MockServer;
+
+use crate::Compression;
+use crate::HttpRequest;
+use crate::Protocol;
+use crate::types;
+use std::io;
+
+/// Decode the body of a request sent to a [`Transport`](crate::Transport),
+/// using its `Content-Encoding` and `Content-Type` headers.
+///
+/// Remote Write 2.0 requests are converted to 1.0, as built by the exporter.
+pub fn decode(request: &HttpRequest<'_>) -> io::Result<types::WriteRequest> {
+    let compression = match request.header("Content-Encoding") {
+        None => Compression::None,
+        Some("snappy") => Compression::Snappy,
+        Some("x-snappy-framed") => Compression::SnappyFramed,
+        #[cfg(feature = "zstd")]
+        Some("zstd") => Compression::Zstd(0),
+        Some(encoding) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported content encoding {encoding}"),
+            ));
+        }
+    };
+
+    let protocol = match request.header("Content-Type") {
+        Some(content_type) if content_type == Protocol::V2.content_type() => Protocol::V2,
+        _ => Protocol::V1,
+    };
+
+    decode_body(request.body, compression, protocol)
+}
+
+/// Decompress and decode a request body.
+pub fn decode_body(
+    body: &[u8],
+    compression: Compression,
+    protocol: Protocol,
+) -> io::Result<types::WriteRequest> {
+    protocol
+        .decode(&compression.decompress(body)?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid write request"))
+}
+
+impl types::WriteRequest {
+    /// Samples of every series with the given metric name, in order.
+    pub fn samples_for(&self, name: &str) -> Vec<&types::Sample> {
+        self.series_matching(&[("__name__", name)])
+            .into_iter()
+            .flat_map(|series| &series.samples)
+            .collect()
+    }
+
+    /// Series having all the given labels, e.g.
+    /// `[("__name__", "requests"), ("status", "200")]`.
+    pub fn series_matching(&self, matchers: &[(&str, &str)]) -> Vec<&types::TimeSeries> {
+        self.timeseries
+            .iter()
+            .filter(|series| {
+                matchers
+                    .iter()
+                    .all(|&(name, value)| series.label(name) == Some(value))
+            })
+            .collect()
+    }
+}
+
+impl types::TimeSeries {
+    /// Value of the label with the given name, if any.
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|label| label.name == name)
+            .map(|label| label.value.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn requests_decoded() {
+        let series = |name: &str, status: &str, value: f64| types::TimeSeries {
+            labels: vec![
+                types::Label {
+                    name: "__name__".to_owned(),
+                    value: name.to_owned(),
+                },
+                types::Label {
+                    name: "status".to_owned(),
+                    value: status.to_owned(),
+                },
+            ],
+            samples: vec![types::Sample {
+                value,
+                timestamp: 1,
+            }],
+            exemplars: vec![],
+            histograms: vec![],
+        };
+        let request = types::WriteRequest {
+            timeseries: vec![
+                series("requests", "200", 1.0),
+                series("requests", "500", 2.0),
+                series("errors", "500", 3.0),
+            ],
+            metadata: vec![],
+        };
+
+        let body = Compression::Snappy
+            .compress(&Protocol::V2.encode(&request))
+            .unwrap();
+        let decoded = decode(&HttpRequest {
+            url: "http://localhost/api/v1/write",
+            headers: vec![
+                ("Content-Encoding".to_owned(), "snappy".to_owned()),
+                (
+                    "Content-Type".to_owned(),
+                    Protocol::V2.content_type().to_owned(),
+                ),
+            ],
+            body: &body,
+            timeout: Duration::from_secs(1),
+        })
+        .unwrap();
+        assert_eq!(decoded, request);
+
+        let values: Vec<_> = decoded
+            .samples_for("requests")
+            .iter()
+            .map(|sample| sample.value)
+            .collect();
+        assert_eq!(values, [1.0, 2.0]);
+        assert_eq!(decoded.series_matching(&[("status", "500")]).len(), 2);
+        assert!(decode_body(&body, Compression::SnappyFramed, Protocol::V1).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::batcher::MetricOperation;
+use crate::interner::SeriesKey;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// Most operations a thread buffers before sending them.
+const MAX_BUFFERED: usize = 256;
+
+/// An operation on a series.
+pub type Buffered = (SystemTime, SeriesKey, MetricOperation);
+
+/// A thread's buffer shared with the worker.
+type SharedBuffer = Arc<Mutex<Buffer>>;
+
+thread_local! {
+    /// This thread's buffer for each exporter.
+    static LOCAL: RefCell<Vec<(Weak<ThreadBuffers>, SharedBuffer)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// Operations buffered by each recording thread, sent to the worker together
+/// and collected by it at each write.
+#[derive(Debug)]
+pub struct ThreadBuffers {
+    /// How long a thread buffers operations before sending them.
+    window: Duration,
+    buffers: Mutex<Vec<SharedBuffer>>,
+}
+
+impl ThreadBuffers {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            buffers: Mutex::default(),
+        }
+    }
+
+    /// Buffer an operation of the current thread, returning the thread's
+    /// operations to send once its buffer is full or older than the window.
+    pub fn push(self: &Arc<Self>, operation: Buffered) -> Option<Vec<Buffered>> {
+        let buffer = self.local();
+        let mut buffer = buffer.lock().unwrap_or_else(PoisonError::into_inner);
+        buffer.push(operation);
+
+        let expired = buffer.since.is_some_and(|since| {
+            buffer
+                .latest
+                .duration_since(since)
+                .is_ok_and(|age| age >= self.window)
+        });
+        (expired || buffer.operations.len() >= MAX_BUFFERED).then(|| buffer.take())
+    }
+
+    /// Take the operations buffered by every thread, forgetting threads that
+    /// have exited.
+    pub fn drain(&self) -> Vec<Buffered> {
+        let mut buffers = self.buffers.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut operations = vec![];
+        for buffer in buffers.iter() {
+            operations.extend(buffer.lock().unwrap_or_else(PoisonError::into_inner).take());
+        }
+        buffers.retain(|buffer| Arc::strong_count(buffer) > 1);
+
+        operations
+    }
+
+    /// The current thread's buffer, created if new.
+    fn local(self: &Arc<Self>) -> SharedBuffer {
+        LOCAL.with_borrow_mut(|local| {
+            if let Some((_, buffer)) = local
+                .iter()
+                .find(|(owner, _)| owner.as_ptr() == Arc::as_ptr(self) && owner.strong_count() > 0)
+            {
+                return buffer.clone();
+            }
+
+            // buffers of dropped exporters
+            local.retain(|(owner, _)| owner.strong_count() > 0);
+
+            let buffer = Arc::new(Mutex::new(Buffer::default()));
+            self.buffers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(buffer.clone());
+            local.push((Arc::downgrade(self), buffer.clone()));
+            buffer
+        })
+    }
+}
+
+/// Operations buffered by a thread, consecutive increments and sets of a
+/// series merged.
+#[derive(Debug)]
+struct Buffer {
+    operations: Vec<Buffered>,
+    /// Time of the first operation buffered.
+    since: Option<SystemTime>,
+    /// Time of the latest operation buffered.
+    latest: SystemTime,
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Self {
+            operations: vec![],
+            since: None,
+            latest: SystemTime::UNIX_EPOCH,
+        }
+    }
+}
+
+impl Buffer {
+    fn push(&mut self, (timestamp, series, op): Buffered) {
+        self.since.get_or_insert(timestamp);
+        self.latest = timestamp;
+
+        // only the latest operation on the series is merged into, so those
+        // of another kind in between keep their order
+        if let Some((last_timestamp, _, last)) = self
+            .operations
+            .iter_mut()
+            .rev()
+            .find(|(_, other, _)| *other == series)
+        {
+            let merged = match (last, &op) {
+                (MetricOperation::IncrementCounter(a), MetricOperation::IncrementCounter(b)) => {
+                    *a = a.saturating_add(*b);
+                    true
+                }
+                (MetricOperation::IncrementGauge(a), MetricOperation::IncrementGauge(b))
+                | (MetricOperation::DecrementGauge(a), MetricOperation::DecrementGauge(b)) => {
+                    *a += b;
+                    true
+                }
+                (MetricOperation::SetCounter(a), MetricOperation::SetCounter(b)) => {
+                    *a = *b;
+                    true
+                }
+                (MetricOperation::SetGauge(a), MetricOperation::SetGauge(b)) => {
+                    *a = *b;
+                    true
+                }
+                _ => false,
+            };
+            if merged {
+                *last_timestamp = timestamp;
+                return;
+            }
+        }
+
+        self.operations.push((timestamp, series, op));
+    }
+
+    fn take(&mut self) -> Vec<Buffered> {
+        self.since = None;
+        std::mem::take(&mut self.operations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interner::Interner;
+    use metrics::Key;
+
+    #[test]
+    fn operations_merged_until_window_elapses() {
+        let interner = Interner::default();
+        let requests = SeriesKey::Interned(interner.intern(&Key::from_name("requests")));
+        let temperature = SeriesKey::Key(Key::from_name("temperature"));
+
+        let buffers = Arc::new(ThreadBuffers::new(Duration::from_millis(10)));
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        let ops = [
+            (
+                at(0),
+                requests.clone(),
+                MetricOperation::IncrementCounter(1),
+            ),
+            (at(1), temperature.clone(), MetricOperation::SetGauge(20.0)),
+            (
+                at(2),
+                requests.clone(),
+                MetricOperation::IncrementCounter(2),
+            ),
+            (
+                at(3),
+                temperature.clone(),
+                MetricOperation::IncrementGauge(1.0),
+            ),
+            (at(4), temperature.clone(), MetricOperation::SetGauge(22.0)),
+            (at(5), temperature.clone(), MetricOperation::SetGauge(23.0)),
+        ];
+        for op in ops {
+            assert!(buffers.push(op).is_none());
+        }
+
+        let sent = buffers
+            .push((
+                at(10),
+                requests.clone(),
+                MetricOperation::IncrementCounter(3),
+            ))
+            .unwrap();
+        assert!(matches!(
+            sent.as_slice(),
+            [
+                (_, _, MetricOperation::IncrementCounter(6)),
+                (_, _, MetricOperation::SetGauge(20.0)),
+                (_, _, MetricOperation::IncrementGauge(1.0)),
+                (_, _, MetricOperation::SetGauge(23.0)),
+            ]
+        ));
+        assert_eq!(sent[0].0, at(10));
+
+        // collected by the worker from every thread
+        buffers.push((at(11), requests, MetricOperation::IncrementCounter(1)));
+        let shared = buffers.clone();
+        std::thread::spawn(move || {
+            shared.push((at(12), temperature, MetricOperation::SetGauge(1.0)));
+        })
+        .join()
+        .unwrap();
+        assert_eq!(buffers.drain().len(), 2);
+        assert_eq!(buffers.buffers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn interleaved_operations_not_merged() {
+        let requests = SeriesKey::Key(Key::from_name("requests"));
+        let temperature = SeriesKey::Key(Key::from_name("temperature"));
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+
+        let mut buffer = Buffer::default();
+        buffer.push((at(0), requests.clone(), MetricOperation::SetCounter(10)));
+        buffer.push((at(1), temperature.clone(), MetricOperation::SetGauge(20.0)));
+        buffer.push((
+            at(2),
+            requests.clone(),
+            MetricOperation::IncrementCounter(1),
+        ));
+        buffer.push((at(3), temperature, MetricOperation::SetGauge(21.0)));
+        buffer.push((at(4), requests.clone(), MetricOperation::SetCounter(5)));
+        buffer.push((at(5), requests, MetricOperation::IncrementCounter(2)));
+
+        let counter: Vec<_> = buffer
+            .take()
+            .into_iter()
+            .filter(|(_, series, _)| *series == SeriesKey::Key(Key::from_name("requests")))
+            .map(|(_, _, op)| op)
+            .collect();
+        assert!(matches!(
+            counter.as_slice(),
+            [
+                MetricOperation::SetCounter(10),
+                MetricOperation::IncrementCounter(1),
+                MetricOperation::SetCounter(5),
+                MetricOperation::IncrementCounter(2),
+            ]
+        ));
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Most distinct errors tracked at once, the least recently reported is
+/// forgotten beyond this.
+const MAX_MESSAGES: usize = 64;
+
+/// Deduplicates repeated errors so a failing endpoint doesn't flood the log.
+///
+/// The first occurrence of an error is reported straight away. Identical
+/// errors after that are counted and reported at most once per `interval`
+/// as a summary, separately for every distinct error.
+#[derive(Debug)]
+pub struct LogThrottle {
+    interval: Duration,
+    /// When each error was last reported, and how often it was suppressed
+    /// since.
+    messages: HashMap<String, (Instant, u64)>,
+}
+
+impl LogThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            messages: HashMap::new(),
+        }
+    }
+
+    /// Record an error, returning the number of identical errors suppressed
+    /// since the last report if this one should be logged.
+    pub fn record(&mut self, message: String, now: Instant) -> Option<u64> {
+        if let Some((reported, suppressed)) = self.messages.get_mut(&message) {
+            *suppressed += 1;
+
+            if now.duration_since(*reported) < self.interval {
+                return None;
+            }
+            *reported = now;
+            return Some(std::mem::take(suppressed));
+        }
+
+        if self.messages.len() >= MAX_MESSAGES
+            && let Some(oldest) = self
+                .messages
+                .iter()
+                .min_by_key(|(_, (reported, _))| *reported)
+                .map(|(message, _)| message.clone())
+        {
+            self.messages.remove(&oldest);
+        }
+        self.messages.insert(message, (now, 0));
+        Some(0)
+    }
+
+    /// Forget every error, returning the number of errors suppressed since
+    /// they were last reported.
+    pub fn reset(&mut self) -> u64 {
+        self.messages
+            .drain()
+            .map(|(_, (_, suppressed))| suppressed)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_errors_summarised() {
+        let interval = Duration::from_secs(60);
+        let mut throttle = LogThrottle::new(interval);
+        let now = Instant::now();
+
+        assert_eq!(throttle.record("down".to_owned(), now), Some(0));
+        assert_eq!(throttle.record("down".to_owned(), now), None);
+        assert_eq!(throttle.record("down".to_owned(), now), None);
+
+        // a different error is reported straight away
+        assert_eq!(throttle.record("rejected".to_owned(), now), Some(0));
+        assert_eq!(throttle.record("rejected".to_owned(), now), None);
+
+        // a summary once the interval elapses, counting this occurrence and
+        // only the same error
+        assert_eq!(throttle.record("down".to_owned(), now + interval), Some(3));
+        assert_eq!(throttle.record("down".to_owned(), now + interval), None);
+
+        assert_eq!(throttle.reset(), 2);
+        assert_eq!(
+            throttle.record("rejected".to_owned(), now + interval),
+            Some(0)
+        );
+    }
+}
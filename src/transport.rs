@@ -0,0 +1,461 @@
+use crate::error::BuildError;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Error returned by a [`Transport`] when no response was received.
+pub type TransportError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An HTTP `POST` to send through a [`Transport`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct HttpRequest<'a> {
+    pub url: &'a str,
+    pub headers: Vec<(String, String)>,
+    pub body: &'a [u8],
+    /// Time allowed for the whole request, including connecting.
+    pub timeout: Duration,
+}
+
+impl HttpRequest<'_> {
+    /// Value of the first header with the given name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The response to an [`HttpRequest`].
+#[derive(Debug, Clone, Default)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Value of the first header with the given name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// HTTP client used to send write requests.
+///
+/// See [`Builder::transport`](crate::Builder::transport). Implementations
+/// must return non-success statuses as responses rather than errors and must
+/// not follow redirects, both are handled by the exporter.
+pub trait Transport: Send + Sync {
+    fn post(&self, request: &HttpRequest<'_>) -> Result<HttpResponse, TransportError>;
+}
+
+/// A transport shared between clones of the builder and the remotes.
+#[derive(Clone)]
+pub(crate) struct SharedTransport(pub(crate) Arc<dyn Transport>);
+
+impl fmt::Debug for SharedTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Transport")
+    }
+}
+
+/// The built-in transport, trusting only the CA certificates in the PEM
+/// bundle `root_certificates` if set.
+///
+/// Preference is `ureq`, then `reqwest`, then `hyper`.
+pub(crate) fn default_transport(
+    root_certificates: Option<&[u8]>,
+) -> Result<Arc<dyn Transport>, BuildError> {
+    #[cfg(feature = "ureq")]
+    return match root_certificates {
+        Some(pem) => UreqTransport::with_root_certificates(pem)
+            .map(|transport| Arc::new(transport) as Arc<dyn Transport>)
+            .map_err(|err| BuildError::InvalidTls(err.to_string())),
+        None => Ok(Arc::new(UreqTransport::new())),
+    };
+
+    #[cfg(all(feature = "reqwest", not(feature = "ureq")))]
+    return match root_certificates {
+        Some(pem) => ReqwestTransport::with_root_certificates(pem)
+            .map(|transport| Arc::new(transport) as Arc<dyn Transport>)
+            .map_err(|err| BuildError::InvalidTls(err.to_string())),
+        None => ReqwestTransport::new()
+            .map(|transport| Arc::new(transport) as Arc<dyn Transport>)
+            .map_err(BuildError::Transport),
+    };
+
+    #[cfg(all(feature = "hyper", not(any(feature = "ureq", feature = "reqwest"))))]
+    return match root_certificates {
+        Some(_) => Err(BuildError::InvalidTls(
+            "the hyper transport takes TLS settings through `HyperTransport::with_client`"
+                .to_owned(),
+        )),
+        None => HyperTransport::new()
+            .map(|transport| Arc::new(transport) as Arc<dyn Transport>)
+            .map_err(BuildError::Spawn),
+    };
+
+    #[cfg(not(any(feature = "ureq", feature = "reqwest", feature = "hyper")))]
+    {
+        let _ = root_certificates;
+        Err(BuildError::InvalidConfig(
+            "no HTTP transport, enable the `ureq`, `reqwest` or `hyper` feature or set one"
+                .to_owned(),
+        ))
+    }
+}
+
+/// Transport using a [ureq](https://docs.rs/ureq) agent, the default.
+#[cfg(feature = "ureq")]
+pub struct UreqTransport {
+    /// Reused across writes so connections are kept alive.
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "ureq")]
+impl Default for UreqTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "ureq")]
+impl UreqTransport {
+    pub fn new() -> Self {
+        Self::with_tls(ureq::tls::TlsConfig::default())
+    }
+
+    /// Agent trusting only the CA certificates in the PEM bundle `pem`, e.g.
+    /// of a private CA.
+    pub fn with_root_certificates(pem: &[u8]) -> Result<Self, TransportError> {
+        let mut certificates = vec![];
+        for item in ureq::tls::parse_pem(pem) {
+            if let ureq::tls::PemItem::Certificate(certificate) = item? {
+                certificates.push(certificate);
+            }
+        }
+        if certificates.is_empty() {
+            return Err("no certificates in PEM bundle".into());
+        }
+
+        let tls = ureq::tls::TlsConfig::builder()
+            .root_certs(ureq::tls::RootCerts::new_with_certs(&certificates))
+            .build();
+        Ok(Self::with_tls(tls))
+    }
+
+    fn with_tls(tls: ureq::tls::TlsConfig) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .max_redirects(0)
+            .tls_config(tls)
+            .build()
+            .into();
+
+        Self { agent }
+    }
+}
+
+#[cfg(feature = "ureq")]
+impl Transport for UreqTransport {
+    fn post(&self, request: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+        let mut builder = self.agent.post(request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+
+        let mut response = builder
+            .config()
+            .timeout_global(Some(request.timeout))
+            .build()
+            .send(request.body)?;
+
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect();
+
+        Ok(HttpResponse {
+            status: response.status().as_u16(),
+            headers,
+            body: response.body_mut().read_to_vec().unwrap_or_default(),
+        })
+    }
+}
+
+/// Transport using a blocking [reqwest](https://docs.rs/reqwest) client.
+///
+/// The client runs a Tokio runtime of its own, so reqwest panics if it is
+/// dropped or used from within an async runtime. Requests are sent from the
+/// worker thread, which never is, but build the exporter outside async code,
+/// e.g. before starting Tokio or in `spawn_blocking`, as a transport built
+/// to validate the configuration is dropped on the calling thread.
+#[cfg(feature = "reqwest")]
+pub struct ReqwestTransport {
+    /// Reused across writes so connections are kept alive.
+    client: reqwest::blocking::Client,
+}
+
+#[cfg(feature = "reqwest")]
+impl ReqwestTransport {
+    /// Client with reqwest's default settings, failing if e.g. the TLS
+    /// backend can't be initialised.
+    pub fn new() -> Result<Self, TransportError> {
+        let client = reqwest::blocking::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        Ok(Self { client })
+    }
+
+    /// Client trusting only the CA certificates in the PEM bundle `pem`,
+    /// e.g. of a private CA.
+    pub fn with_root_certificates(pem: &[u8]) -> Result<Self, TransportError> {
+        let certificates = reqwest::Certificate::from_pem_bundle(pem)?;
+        if certificates.is_empty() {
+            return Err("no certificates in PEM bundle".into());
+        }
+
+        let mut builder = reqwest::blocking::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .tls_built_in_root_certs(false);
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+        })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl Transport for ReqwestTransport {
+    fn post(&self, request: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+        let mut builder = self
+            .client
+            .post(request.url)
+            .timeout(request.timeout)
+            .body(request.body.to_vec());
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+
+        let response = builder.send()?;
+
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect();
+
+        Ok(HttpResponse {
+            status,
+            headers,
+            body: response
+                .bytes()
+                .map(|body| body.to_vec())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Transport using a [hyper](https://docs.rs/hyper) client, for control over
+/// connection pooling and HTTP/2.
+///
+/// Requests are driven by a dedicated current-thread Tokio runtime on the
+/// sending thread, so the transport works without an application runtime.
+/// A runtime can't be entered from within another, so requests sent from
+/// async code fail with an error, and dropping the transport there panics.
+/// The worker thread is never in a runtime, but build the exporter outside
+/// async code, as a transport built to validate the configuration is dropped
+/// on the calling thread.
+#[cfg(feature = "hyper")]
+pub struct HyperTransport<C = hyper_util::client::legacy::connect::HttpConnector> {
+    client: hyper_util::client::legacy::Client<C, http_body_util::Full<bytes::Bytes>>,
+    runtime: tokio::runtime::Runtime,
+}
+
+#[cfg(feature = "hyper")]
+impl HyperTransport {
+    /// Plain HTTP client with hyper's default settings.
+    ///
+    /// Use [`HyperTransport::with_client`] for TLS or HTTP/2.
+    pub fn new() -> std::io::Result<Self> {
+        let client =
+            hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
+                .build_http();
+
+        Self::with_client(client)
+    }
+}
+
+#[cfg(feature = "hyper")]
+impl<C> HyperTransport<C> {
+    /// Send requests through a client configured by the application, e.g.
+    /// with a TLS connector or `http2_only`.
+    ///
+    /// The client must be built with a Tokio executor.
+    pub fn with_client(
+        client: hyper_util::client::legacy::Client<C, http_body_util::Full<bytes::Bytes>>,
+    ) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        Ok(Self { client, runtime })
+    }
+}
+
+#[cfg(feature = "hyper")]
+impl<C> Transport for HyperTransport<C>
+where
+    C: hyper_util::client::legacy::connect::Connect + Clone + Send + Sync + 'static,
+{
+    fn post(&self, request: &HttpRequest<'_>) -> Result<HttpResponse, TransportError> {
+        use http_body_util::BodyExt;
+
+        let mut builder = http::Request::post(request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        let body = http_body_util::Full::new(bytes::Bytes::copy_from_slice(request.body));
+        let http_request = builder.body(body)?;
+
+        // `block_on` panics inside a runtime
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err("can't send from within an async runtime".into());
+        }
+
+        self.runtime.block_on(async {
+            let send = async {
+                let response = self.client.request(http_request).await?;
+
+                let status = response.status().as_u16();
+                let headers = response
+                    .headers()
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                    })
+                    .collect();
+                let body = response.into_body().collect().await?.to_bytes().to_vec();
+
+                Ok::<_, TransportError>(HttpResponse {
+                    status,
+                    headers,
+                    body,
+                })
+            };
+
+            tokio::time::timeout(request.timeout, send).await?
+        })
+    }
+}
+
+#[cfg(all(test, any(feature = "reqwest", feature = "hyper")))]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn reqwest_transport_posts() {
+        use std::io::Read;
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/write", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let read = stream.read(&mut request).unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 204 No Content\r\nRetry-After: 5\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            String::from_utf8_lossy(&request[..read]).into_owned()
+        });
+
+        let transport = ReqwestTransport::new().unwrap();
+        let response = transport
+            .post(&HttpRequest {
+                url: &url,
+                headers: vec![("X-Test".to_owned(), "1".to_owned())],
+                body: b"body",
+                timeout: Duration::from_secs(5),
+            })
+            .unwrap();
+
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("retry-after"), Some("5"));
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /api/v1/write"));
+        assert!(request.to_ascii_lowercase().contains("x-test: 1"));
+    }
+
+    #[cfg(feature = "hyper")]
+    #[test]
+    fn hyper_transport_posts() {
+        use std::io::Read;
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/write", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let read = stream.read(&mut request).unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 204 No Content\r\nRetry-After: 5\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            String::from_utf8_lossy(&request[..read]).into_owned()
+        });
+
+        let transport = HyperTransport::new().unwrap();
+        let response = transport
+            .post(&HttpRequest {
+                url: &url,
+                headers: vec![("X-Test".to_owned(), "1".to_owned())],
+                body: b"body",
+                timeout: Duration::from_secs(5),
+            })
+            .unwrap();
+
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("retry-after"), Some("5"));
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /api/v1/write"));
+        assert!(request.to_ascii_lowercase().contains("x-test: 1"));
+    }
+
+    #[cfg(feature = "hyper")]
+    #[test]
+    fn hyper_transport_rejects_nested_runtime() {
+        let transport = HyperTransport::new().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let result = runtime.block_on(async {
+            transport.post(&HttpRequest {
+                url: "http://127.0.0.1:1/api/v1/write",
+                headers: vec![],
+                body: b"",
+                timeout: Duration::from_secs(5),
+            })
+        });
+        assert!(result.is_err());
+    }
+}
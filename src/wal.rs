@@ -0,0 +1,85 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Directory of encoded write requests waiting to be sent.
+///
+/// Each request is stored uncompressed in its own file. File names sort in
+/// the order the requests were written.
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    sequence: u64,
+}
+
+impl Wal {
+    /// Open the directory, creating it if it doesn't exist.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, sequence: 0 })
+    }
+
+    /// Persist an encoded write request.
+    pub fn push(&mut self, encoded: &[u8]) -> io::Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name = format!("{millis:020}-{:06}.wal", self.sequence);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        // write then rename so a crash never leaves a partial entry
+        let tmp = self.dir.join(format!("{name}.tmp"));
+        fs::write(&tmp, encoded)?;
+        fs::rename(tmp, self.dir.join(name))
+    }
+
+    /// Paths of all persisted requests, oldest first.
+    pub fn entries(&self) -> io::Result<Vec<PathBuf>> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "wal") {
+                entries.push(path);
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    /// Read a persisted request.
+    pub fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    /// Remove a persisted request once it has been handled.
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_in_write_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(dir.path()).unwrap();
+
+        wal.push(b"first").unwrap();
+        wal.push(b"second").unwrap();
+
+        let entries = wal.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(wal.read(&entries[0]).unwrap(), b"first");
+        assert_eq!(wal.read(&entries[1]).unwrap(), b"second");
+
+        wal.remove(&entries[0]).unwrap();
+        assert_eq!(wal.entries().unwrap(), &entries[1..]);
+    }
+}